@@ -6,6 +6,32 @@ fn main() {
 
     prost_build::Config::new()
         .file_descriptor_set_path(out_dir.join("foxglove_descriptor.bin"))
-        .compile_protos(&["proto/CompressedVideo.proto"], &["proto/"])
+        // Frames are assembled once and moved into the message
+        .bytes([
+            ".foxglove.CompressedImage.data",
+            ".foxglove.CompressedVideo.data",
+        ])
+        .compile_protos(
+            &[
+                "proto/Bitrate.proto",
+                "proto/CameraCalibration.proto",
+                "proto/CompressedImage.proto",
+                "proto/CompressedVideo.proto",
+                "proto/FrameTransforms.proto",
+                "proto/FrameHash.proto",
+                "proto/FrameStats.proto",
+                "proto/GeoJSON.proto",
+                "proto/ImageAnnotations.proto",
+                "proto/Keyframe.proto",
+                "proto/KlvPacket.proto",
+                "proto/LocationFix.proto",
+                "proto/Log.proto",
+                "proto/PoseInFrame.proto",
+                "proto/SceneChange.proto",
+                "proto/TimedMetadata.proto",
+                "proto/Vector3Stamped.proto",
+            ],
+            &["proto/"],
+        )
         .unwrap();
 }
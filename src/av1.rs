@@ -0,0 +1,113 @@
+// OBU types of the AV1 low overhead bitstream format
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+/// Reads an unsigned LEB128 value, returning it and its length in bytes
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in data.iter().take(8).enumerate() {
+        value |= ((byte & 0x7F) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Splits a temporal unit into its OBUs as (type, bytes including the
+/// header), stopping at the first malformed OBU
+fn obus(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut obus = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data[pos];
+        let obu_type = (header >> 3) & 0x0F;
+        let header_size = if header & 0x04 != 0 { 2 } else { 1 };
+        let end = if header & 0x02 != 0 {
+            let Some((size, leb_size)) = data.get(pos + header_size..).and_then(read_leb128) else {
+                break;
+            };
+            pos + header_size + leb_size + size
+        } else {
+            // Without a size field the OBU extends to the end of the data
+            data.len()
+        };
+        if end > data.len() {
+            break;
+        }
+        obus.push((obu_type, &data[pos..end]));
+        pos = end;
+    }
+    obus
+}
+
+/// Remembers the last sequence header the encoder emitted and inserts it
+/// into keyframes that lack one, as encoders usually write it only once
+#[derive(Default)]
+pub struct SequenceHeaders {
+    last: Option<Vec<u8>>,
+}
+
+impl SequenceHeaders {
+    /// Returns the temporal unit with a sequence header inserted after the
+    /// temporal delimiter, or `None` if it can be used as is
+    pub fn process(&mut self, data: &[u8], is_key: bool) -> Option<Vec<u8>> {
+        let obus = obus(data);
+        if let Some((_, header)) = obus.iter().find(|(t, _)| *t == OBU_SEQUENCE_HEADER) {
+            self.last = Some(header.to_vec());
+            return None;
+        }
+        let header = self.last.as_ref().filter(|_| is_key)?;
+
+        let mut fixed = Vec::with_capacity(data.len() + header.len());
+        let mut inserted = false;
+        for (obu_type, obu) in &obus {
+            if !inserted && *obu_type != OBU_TEMPORAL_DELIMITER {
+                fixed.extend_from_slice(header);
+                inserted = true;
+            }
+            fixed.extend_from_slice(obu);
+        }
+        if !inserted {
+            fixed.extend_from_slice(header);
+        }
+        Some(fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn obu(obu_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut obu = vec![(obu_type << 3) | 0x02, payload.len() as u8];
+        obu.extend_from_slice(payload);
+        obu
+    }
+
+    #[test]
+    fn test_read_leb128() {
+        assert_eq!(read_leb128(&[0x05]), Some((5, 1)));
+        assert_eq!(read_leb128(&[0x80, 0x01]), Some((128, 2)));
+        assert_eq!(read_leb128(&[0x80]), None);
+    }
+
+    #[test]
+    fn test_sequence_header_insertion() {
+        let delimiter = obu(OBU_TEMPORAL_DELIMITER, &[]);
+        let sequence_header = obu(OBU_SEQUENCE_HEADER, &[1, 2, 3]);
+        let frame = obu(6, &[4, 5]);
+
+        let mut headers = SequenceHeaders::default();
+        let first = [delimiter.clone(), sequence_header.clone(), frame.clone()].concat();
+        assert_eq!(headers.process(&first, true), None);
+
+        let inter = [delimiter.clone(), frame.clone()].concat();
+        assert_eq!(headers.process(&inter, false), None);
+
+        assert_eq!(
+            headers.process(&inter, true),
+            Some([delimiter, sequence_header, frame].concat())
+        );
+    }
+}
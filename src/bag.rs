@@ -0,0 +1,608 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::VideoConverter;
+use crate::timesync;
+
+/// What the output file is written as
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Mcap,
+    /// rosbag v2.0, with the video as sensor_msgs/CompressedImage
+    Bag,
+    /// A rosbag2 directory with an MCAP of the video as CDR
+    /// sensor_msgs/msg/CompressedImage
+    Rosbag2,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mcap" => Ok(OutputFormat::Mcap),
+            "bag" => Ok(OutputFormat::Bag),
+            "rosbag2" => Ok(OutputFormat::Rosbag2),
+            other => Err(format!(
+                "Unsupported output format {:?}, expected mcap, bag or rosbag2",
+                other
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Mcap => "mcap",
+            OutputFormat::Bag => "bag",
+            OutputFormat::Rosbag2 => "rosbag2",
+        }
+    }
+}
+
+const MAGIC: &[u8] = b"#ROSBAG V2.0\n";
+/// The bag header record is padded to this size, so it can be rewritten
+/// in place once the index position is known
+const BAG_HEADER_SIZE: usize = 4096;
+/// Chunks are closed once their data is larger than this
+const CHUNK_SIZE: usize = 768 * 1024;
+
+const OP_MESSAGE_DATA: u8 = 0x02;
+const OP_BAG_HEADER: u8 = 0x03;
+const OP_INDEX_DATA: u8 = 0x04;
+const OP_CHUNK: u8 = 0x05;
+const OP_CHUNK_INFO: u8 = 0x06;
+const OP_CONNECTION: u8 = 0x07;
+
+pub const COMPRESSED_IMAGE_TYPE: &str = "sensor_msgs/CompressedImage";
+pub const COMPRESSED_IMAGE_MD5: &str = "8f7a12909da2c9d3332d540a0977563f";
+pub const COMPRESSED_IMAGE_DEFINITION: &str = "\
+Header header
+string format
+uint8[] data
+
+================================================================================
+MSG: std_msgs/Header
+uint32 seq
+time stamp
+string frame_id
+";
+
+/// ROS time as seconds and nanoseconds, both little-endian u32
+fn ros_time(timestamp_ns: u64) -> [u8; 8] {
+    let mut time = [0; 8];
+    time[..4].copy_from_slice(&((timestamp_ns / 1_000_000_000) as u32).to_le_bytes());
+    time[4..].copy_from_slice(&((timestamp_ns % 1_000_000_000) as u32).to_le_bytes());
+    time
+}
+
+/// Record header fields, each a length-prefixed `name=value`
+fn fields(fields: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut header = Vec::new();
+    for (name, value) in fields {
+        header.extend_from_slice(&((name.len() + 1 + value.len()) as u32).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.push(b'=');
+        header.extend_from_slice(value);
+    }
+    header
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    header: &[(&str, &[u8])],
+    data: &[u8],
+) -> std::io::Result<()> {
+    let header = fields(header);
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)
+}
+
+/// A sensor_msgs/CompressedImage in the ROS 1 serialization
+pub fn compressed_image(
+    seq: u32,
+    timestamp_ns: u64,
+    frame_id: &str,
+    format: &str,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(28 + frame_id.len() + format.len() + data.len());
+    message.extend_from_slice(&seq.to_le_bytes());
+    message.extend_from_slice(&ros_time(timestamp_ns));
+    for bytes in [frame_id.as_bytes(), format.as_bytes(), data] {
+        message.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        message.extend_from_slice(bytes);
+    }
+    message
+}
+
+struct Connection {
+    topic: String,
+    /// The connection header: topic, type, md5sum and message_definition
+    header: Vec<u8>,
+}
+
+/// Where a closed chunk is and what's in it
+struct ChunkInfo {
+    position: u64,
+    start_ns: u64,
+    end_ns: u64,
+    /// Messages per connection
+    counts: BTreeMap<u32, u32>,
+}
+
+/// Writes a rosbag v2.0 file with uncompressed chunks
+pub struct BagWriter<W: Write + Seek> {
+    writer: W,
+    connections: Vec<Connection>,
+    chunk: Vec<u8>,
+    /// Time range of the open chunk
+    chunk_times: Option<(u64, u64)>,
+    /// Time and offset in the chunk of each message, per connection
+    chunk_index: BTreeMap<u32, Vec<(u64, u32)>>,
+    chunk_infos: Vec<ChunkInfo>,
+}
+
+impl<W: Write + Seek> BagWriter<W> {
+    pub fn new(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        write_bag_header(&mut writer, 0, 0, 0)?;
+        Ok(Self {
+            writer,
+            connections: Vec::new(),
+            chunk: Vec::new(),
+            chunk_times: None,
+            chunk_index: BTreeMap::new(),
+            chunk_infos: Vec::new(),
+        })
+    }
+
+    /// Adds a connection for a topic and returns its ID
+    pub fn add_connection(
+        &mut self,
+        topic: &str,
+        message_type: &str,
+        md5sum: &str,
+        definition: &str,
+    ) -> u32 {
+        let id = self.connections.len() as u32;
+        let header = fields(&[
+            ("topic", topic.as_bytes()),
+            ("type", message_type.as_bytes()),
+            ("md5sum", md5sum.as_bytes()),
+            ("message_definition", definition.as_bytes()),
+        ]);
+        self.connections.push(Connection {
+            topic: topic.to_string(),
+            header,
+        });
+        id
+    }
+
+    fn write_connection(
+        writer: &mut impl Write,
+        id: u32,
+        connection: &Connection,
+    ) -> std::io::Result<()> {
+        write_record(
+            writer,
+            &[
+                ("op", &[OP_CONNECTION]),
+                ("conn", &id.to_le_bytes()),
+                ("topic", connection.topic.as_bytes()),
+            ],
+            &connection.header,
+        )
+    }
+
+    pub fn write(
+        &mut self,
+        connection: u32,
+        timestamp_ns: u64,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        // Each chunk has the connections of its messages before them
+        if !self.chunk_index.contains_key(&connection) {
+            let info = self.connections.get(connection as usize).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "unknown bag connection")
+            })?;
+            Self::write_connection(&mut self.chunk, connection, info)?;
+        }
+        let offset = self.chunk.len() as u32;
+        write_record(
+            &mut self.chunk,
+            &[
+                ("op", &[OP_MESSAGE_DATA]),
+                ("conn", &connection.to_le_bytes()),
+                ("time", &ros_time(timestamp_ns)),
+            ],
+            data,
+        )?;
+        self.chunk_index
+            .entry(connection)
+            .or_default()
+            .push((timestamp_ns, offset));
+        self.chunk_times = Some(match self.chunk_times {
+            Some((start, end)) => (start.min(timestamp_ns), end.max(timestamp_ns)),
+            None => (timestamp_ns, timestamp_ns),
+        });
+        if self.chunk.len() > CHUNK_SIZE {
+            self.finish_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the open chunk and its index
+    fn finish_chunk(&mut self) -> std::io::Result<()> {
+        let Some((start_ns, end_ns)) = self.chunk_times.take() else {
+            return Ok(());
+        };
+        let position = self.writer.stream_position()?;
+        write_record(
+            &mut self.writer,
+            &[
+                ("op", &[OP_CHUNK]),
+                ("compression", b"none"),
+                ("size", &(self.chunk.len() as u32).to_le_bytes()),
+            ],
+            &self.chunk,
+        )?;
+        let mut counts = BTreeMap::new();
+        for (connection, entries) in std::mem::take(&mut self.chunk_index) {
+            let mut data = Vec::with_capacity(entries.len() * 12);
+            for (timestamp_ns, offset) in &entries {
+                data.extend_from_slice(&ros_time(*timestamp_ns));
+                data.extend_from_slice(&offset.to_le_bytes());
+            }
+            write_record(
+                &mut self.writer,
+                &[
+                    ("op", &[OP_INDEX_DATA]),
+                    ("ver", &1u32.to_le_bytes()),
+                    ("conn", &connection.to_le_bytes()),
+                    ("count", &(entries.len() as u32).to_le_bytes()),
+                ],
+                &data,
+            )?;
+            counts.insert(connection, entries.len() as u32);
+        }
+        self.chunk_infos.push(ChunkInfo {
+            position,
+            start_ns,
+            end_ns,
+            counts,
+        });
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the index section and the final bag header
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.finish_chunk()?;
+        let index_position = self.writer.stream_position()?;
+        for (id, connection) in self.connections.iter().enumerate() {
+            Self::write_connection(&mut self.writer, id as u32, connection)?;
+        }
+        for info in &self.chunk_infos {
+            let data: Vec<u8> = info
+                .counts
+                .iter()
+                .flat_map(|(connection, count)| {
+                    connection
+                        .to_le_bytes()
+                        .into_iter()
+                        .chain(count.to_le_bytes())
+                })
+                .collect();
+            write_record(
+                &mut self.writer,
+                &[
+                    ("op", &[OP_CHUNK_INFO]),
+                    ("ver", &1u32.to_le_bytes()),
+                    ("chunk_pos", &info.position.to_le_bytes()),
+                    ("start_time", &ros_time(info.start_ns)),
+                    ("end_time", &ros_time(info.end_ns)),
+                    ("count", &(info.counts.len() as u32).to_le_bytes()),
+                ],
+                &data,
+            )?;
+        }
+        self.writer.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        write_bag_header(
+            &mut self.writer,
+            index_position,
+            self.connections.len() as u32,
+            self.chunk_infos.len() as u32,
+        )?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_bag_header(
+    writer: &mut impl Write,
+    index_position: u64,
+    connections: u32,
+    chunks: u32,
+) -> std::io::Result<()> {
+    let header = [
+        ("op", &[OP_BAG_HEADER][..]),
+        ("index_pos", &index_position.to_le_bytes()),
+        ("conn_count", &connections.to_le_bytes()),
+        ("chunk_count", &chunks.to_le_bytes()),
+    ];
+    // Both length prefixes count towards the padded size
+    let padding = BAG_HEADER_SIZE - 8 - fields(&header).len();
+    write_record(writer, &header, &vec![b' '; padding])
+}
+
+/// Where the frames of an output of the video alone are written
+pub trait FrameSink {
+    fn write_frame(
+        &mut self,
+        sequence: u32,
+        timestamp_ns: u64,
+        format: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// The video channel of a bag being converted to
+struct VideoBag<'a> {
+    bag: BagWriter<BufWriter<File>>,
+    connection: u32,
+    frame_id: &'a str,
+}
+
+impl FrameSink for VideoBag<'_> {
+    fn write_frame(
+        &mut self,
+        sequence: u32,
+        timestamp_ns: u64,
+        format: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let message = compressed_image(sequence, timestamp_ns, self.frame_id, format, data);
+        self.bag.write(self.connection, timestamp_ns, &message)?;
+        Ok(())
+    }
+}
+
+/// Writes the frames of the video stream to a sink
+struct VideoOnly<'a, S> {
+    sink: &'a mut S,
+    frames: u32,
+}
+
+impl<S: FrameSink> VideoOnly<'_, S> {
+    fn write_frame(
+        &mut self,
+        converter: &mut VideoConverter,
+        pts: i64,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let timestamp_ns = converter.check_timestamp(converter.get_timestamp(pts))?;
+        self.sink
+            .write_frame(self.frames, timestamp_ns, converter.format_str(), data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Writes every frame the decoder and then the encoder have ready
+    fn transcode_frames(
+        &mut self,
+        converter: &mut VideoConverter,
+        frame: &mut ffmpeg::frame::Video,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            match converter.receive_frame(frame) {
+                Ok(_) => {
+                    converter.encode_frame(frame)?;
+                    self.drain_encoder(converter)?;
+                }
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn drain_encoder(&mut self, converter: &mut VideoConverter) -> Result<(), Box<dyn Error>> {
+        let mut packet = ffmpeg::Packet::empty();
+        loop {
+            match converter.receive_encoded(&mut packet) {
+                Ok(_) => self.write_frame(
+                    converter,
+                    packet.pts().unwrap_or(0),
+                    packet.data().unwrap_or_default(),
+                )?,
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Writes the data of the packets batched into each frame the decoder
+    /// of the passed through video has ready
+    fn pass_frames(
+        &mut self,
+        converter: &mut VideoConverter,
+        frame: &mut ffmpeg::frame::Video,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            match converter.receive_frame(frame) {
+                Ok(_) => {
+                    let (frame_packet, data) = converter.take_frame_data();
+                    // Empty when it went out with a frame put out before it
+                    if !data.is_empty() {
+                        self.write_frame(converter, frame_packet.pts().unwrap_or(0), &data)?;
+                    }
+                }
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Converts the video stream alone to a bag, with a CompressedImage per
+/// frame. Returns the number of frames written.
+pub fn convert(
+    converter: &mut VideoConverter,
+    input: &mut ffmpeg::format::context::Input,
+    video_stream_index: usize,
+    path: &Path,
+    topic: &str,
+    frame_id: &str,
+    time_offset_ns: Option<i64>,
+) -> Result<u32, Box<dyn Error>> {
+    let mut bag = BagWriter::new(BufWriter::new(File::create(path)?))?;
+    let connection = bag.add_connection(
+        topic,
+        COMPRESSED_IMAGE_TYPE,
+        COMPRESSED_IMAGE_MD5,
+        COMPRESSED_IMAGE_DEFINITION,
+    );
+    let mut sink = VideoBag {
+        bag,
+        connection,
+        frame_id,
+    };
+    let frames = convert_video(
+        converter,
+        input,
+        video_stream_index,
+        &mut sink,
+        time_offset_ns,
+    )?;
+    sink.bag.finish()?;
+    Ok(frames)
+}
+
+/// Converts the video stream alone, writing each frame to `sink`. Returns
+/// the number of frames written.
+pub fn convert_video(
+    converter: &mut VideoConverter,
+    input: &mut ffmpeg::format::context::Input,
+    video_stream_index: usize,
+    sink: &mut impl FrameSink,
+    time_offset_ns: Option<i64>,
+) -> Result<u32, Box<dyn Error>> {
+    let mut output = VideoOnly { sink, frames: 0 };
+    let mut frame = ffmpeg::frame::Video::empty();
+    for (stream, mut packet) in input.packets() {
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        converter.apply_edit_gaps(&mut packet);
+        if let Some(offset_ns) = time_offset_ns {
+            timesync::shift_packet(&mut packet, stream.time_base(), offset_ns);
+        }
+        if converter.process_packet(&packet)?.is_none() {
+            continue;
+        }
+        converter.send_packet(&packet)?;
+        if converter.is_transcoding() {
+            output.transcode_frames(converter, &mut frame)?;
+        } else {
+            output.pass_frames(converter, &mut frame)?;
+        }
+    }
+    converter.send_eof()?;
+    if converter.is_transcoding() {
+        output.transcode_frames(converter, &mut frame)?;
+        converter.send_encoder_eof()?;
+        output.drain_encoder(converter)?;
+    } else {
+        output.pass_frames(converter, &mut frame)?;
+        // The packets the decoder made nothing of
+        if converter.pending_frame_bytes() > 0 {
+            let (frame_packet, data) = converter.take_frame_data();
+            output.write_frame(converter, frame_packet.pts().unwrap_or(0), &data)?;
+        }
+    }
+    converter.finish()?;
+    Ok(output.frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_compressed_image() {
+        let message = compressed_image(7, 1_500_000_000, "cam", "h264", &[0, 0, 0, 1, 0x65]);
+        assert_eq!(
+            message,
+            [
+                7, 0, 0, 0, // seq
+                1, 0, 0, 0, 0x00, 0x65, 0xcd, 0x1d, // stamp, 1s and 5e8 ns
+                3, 0, 0, 0, b'c', b'a', b'm', // frame_id
+                4, 0, 0, 0, b'h', b'2', b'6', b'4', // format
+                5, 0, 0, 0, 0, 0, 0, 1, 0x65, // data
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bag_writer() {
+        let mut bag = BagWriter::new(std::io::Cursor::new(Vec::new())).unwrap();
+        let connection = bag.add_connection(
+            "video",
+            COMPRESSED_IMAGE_TYPE,
+            COMPRESSED_IMAGE_MD5,
+            COMPRESSED_IMAGE_DEFINITION,
+        );
+        bag.write(connection, 1_000_000_000, b"one").unwrap();
+        bag.write(connection, 2_000_000_000, b"two").unwrap();
+        let data = bag.finish().unwrap().into_inner();
+
+        assert!(data.starts_with(MAGIC));
+        // The padded bag header, then the chunk
+        let header_len = read_u32(&data, MAGIC.len()) as usize;
+        let data_len = read_u32(&data, MAGIC.len() + 4 + header_len) as usize;
+        assert_eq!(8 + header_len + data_len, BAG_HEADER_SIZE);
+        let header = &data[MAGIC.len() + 4..MAGIC.len() + 4 + header_len];
+        let index_pos = header
+            .windows(10)
+            .position(|w| w == b"index_pos=")
+            .map(|i| u64::from_le_bytes(header[i + 10..i + 18].try_into().unwrap()))
+            .unwrap();
+        assert!(index_pos as usize > MAGIC.len() + BAG_HEADER_SIZE);
+        assert!(index_pos < data.len() as u64);
+
+        let chunk = &data[MAGIC.len() + BAG_HEADER_SIZE..];
+        let chunk_header_len = read_u32(chunk, 0) as usize;
+        assert!(chunk[4..4 + chunk_header_len]
+            .windows(16)
+            .any(|w| w == b"compression=none"));
+        // The connection of the messages is in the chunk before them
+        let chunk_data = &chunk[8 + chunk_header_len..];
+        let first_topic = chunk_data.windows(11).position(|w| w == b"topic=video");
+        let first_message = chunk_data.windows(3).position(|w| w == b"one");
+        assert!(first_topic.unwrap() < first_message.unwrap());
+        assert!(chunk_data.windows(3).any(|w| w == b"two"));
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+/// A step of the conversion timed by `--bench`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Reading packets of all streams from the input
+    Demux,
+    /// Turning the video packets into Annex B frames
+    AnnexB,
+    Decode,
+    /// Filtering and encoding the frames, when re-encoding or writing images
+    Encode,
+    /// Serializing the CompressedVideo messages
+    Serialize,
+    /// Writing the video messages to the MCAP file
+    McapWrite,
+}
+
+impl Stage {
+    const ALL: [Stage; 6] = [
+        Stage::Demux,
+        Stage::AnnexB,
+        Stage::Decode,
+        Stage::Encode,
+        Stage::Serialize,
+        Stage::McapWrite,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::Demux => "demux",
+            Stage::AnnexB => "Annex B conversion",
+            Stage::Decode => "decode",
+            Stage::Encode => "encode",
+            Stage::Serialize => "message encode",
+            Stage::McapWrite => "MCAP write",
+        }
+    }
+}
+
+/// Time spent in each stage, and what came out of them
+#[derive(Debug, Default)]
+pub struct Bench {
+    stages: [Duration; Stage::ALL.len()],
+    frames: u64,
+    bytes: u64,
+}
+
+impl Bench {
+    pub fn time<T>(&mut self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(stage, start.elapsed());
+        result
+    }
+
+    pub fn add(&mut self, stage: Stage, duration: Duration) {
+        self.stages[stage as usize] += duration;
+    }
+
+    pub fn elapsed(&self, stage: Stage) -> Duration {
+        self.stages[stage as usize]
+    }
+
+    /// Counts a video frame written, of `bytes` of data
+    pub fn add_frame(&mut self, bytes: usize) {
+        self.frames += 1;
+        self.bytes += bytes as u64;
+    }
+
+    /// One line per stage with its share of `total`, then the rest and the
+    /// throughput
+    pub fn summary(&self, total: Duration) -> Vec<String> {
+        let seconds = total.as_secs_f64().max(1e-9);
+        let line = |name: &str, duration: Duration| {
+            format!(
+                "  {:<20}{:>9.3}s {:>5.1}%",
+                name,
+                duration.as_secs_f64(),
+                duration.as_secs_f64() / seconds * 100.0
+            )
+        };
+        let mut lines = vec!["Time per stage:".to_string()];
+        for stage in Stage::ALL {
+            lines.push(line(stage.name(), self.elapsed(stage)));
+        }
+        let timed: Duration = self.stages.iter().sum();
+        lines.push(line("other", total.saturating_sub(timed)));
+        lines.push(format!(
+            "{} frames in {:.3}s: {:.1} frames/s, {:.2} MB/s of video data",
+            self.frames,
+            total.as_secs_f64(),
+            self.frames as f64 / seconds,
+            self.bytes as f64 / 1e6 / seconds
+        ));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut bench = Bench::default();
+        bench.add(Stage::Demux, Duration::from_millis(500));
+        bench.add(Stage::McapWrite, Duration::from_millis(250));
+        bench.add(Stage::McapWrite, Duration::from_millis(250));
+        for _ in 0..30 {
+            bench.add_frame(100_000);
+        }
+        assert_eq!(bench.elapsed(Stage::McapWrite), Duration::from_millis(500));
+        assert_eq!(bench.time(Stage::Decode, || 7), 7);
+
+        let lines = bench.summary(Duration::from_secs(2));
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[1], "  demux                   0.500s  25.0%");
+        assert_eq!(lines[6], "  MCAP write              0.500s  25.0%");
+        assert_eq!(
+            lines[8],
+            "30 frames in 2.000s: 15.0 frames/s, 1.50 MB/s of video data"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use crate::foxglove::CameraCalibration;
+use crate::yaml;
+
+/// Parses a `[1, 2, 3]` sequence of numbers
+fn numbers(value: &str) -> Option<Vec<f64>> {
+    let list = value.strip_prefix('[')?.strip_suffix(']')?;
+    list.split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse().ok())
+        .collect()
+}
+
+/// Parses a ROS camera_info or OpenCV calibration YAML file. The width and
+/// height are left at 0 if the file has none, and a missing rectification or
+/// projection matrix is filled in as for a monocular camera.
+pub fn parse_yaml(text: &str) -> Result<CameraCalibration, String> {
+    let values = yaml::flatten(text);
+    // The first of the names the formats use that is present, as a matrix
+    // with `data` or a plain sequence
+    let lookup = |names: &[&str]| {
+        names.iter().find_map(|name| {
+            values
+                .get(&format!("{}.data", name))
+                .or_else(|| values.get(*name))
+        })
+    };
+    let matrix = |names: &[&str], len: usize| -> Result<Option<Vec<f64>>, String> {
+        let Some(value) = lookup(names) else {
+            return Ok(None);
+        };
+        match numbers(value) {
+            Some(data) if len == 0 || data.len() == len => Ok(Some(data)),
+            _ if len == 0 => Err(format!("{} is not a list of numbers", names[0])),
+            _ => Err(format!("{} is not a list of {} numbers", names[0], len)),
+        }
+    };
+    let dimension = |names: &[&str]| -> Result<u32, String> {
+        match lookup(names) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("{} is not an integer", names[0])),
+            None => Ok(0),
+        }
+    };
+
+    let k = matrix(&["camera_matrix", "K", "k"], 9)?
+        .ok_or("No camera_matrix found, expected a ROS camera_info or OpenCV calibration file")?;
+    let d = matrix(&["distortion_coefficients", "D", "d"], 0)?.unwrap_or_default();
+    let r = matrix(&["rectification_matrix", "R", "r"], 9)?
+        .unwrap_or_else(|| vec![1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0]);
+    let p = matrix(&["projection_matrix", "P", "p"], 12)?.unwrap_or_else(|| {
+        vec![
+            k[0], k[1], k[2], 0.0, k[3], k[4], k[5], 0.0, k[6], k[7], k[8], 0.0,
+        ]
+    });
+    // OpenCV files don't name the model, so go by the number of coefficients
+    let distortion_model = match lookup(&["distortion_model"]) {
+        Some(model) => model.clone(),
+        None if d.len() > 5 => "rational_polynomial".to_string(),
+        None => "plumb_bob".to_string(),
+    };
+
+    Ok(CameraCalibration {
+        width: dimension(&["image_width", "width"])?,
+        height: dimension(&["image_height", "height"])?,
+        distortion_model,
+        d,
+        k,
+        r,
+        p,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ros_camera_info() {
+        let yaml = "image_width: 640
+image_height: 480
+camera_name: narrow_stereo
+camera_matrix:
+  rows: 3
+  cols: 3
+  data: [500.0, 0, 320, 0, 500, 240, 0, 0, 1]
+distortion_model: plumb_bob
+distortion_coefficients:
+  rows: 1
+  cols: 5
+  data: [-0.1, 0.01, 0, 0, 0]
+rectification_matrix:
+  rows: 3
+  cols: 3
+  data: [1, 0, 0, 0, 1, 0, 0, 0, 1]
+projection_matrix:
+  rows: 3
+  cols: 4
+  data: [490, 0, 321, 0, 0, 490, 241, 0, 0, 0, 1, 0]
+";
+        let calibration = parse_yaml(yaml).unwrap();
+        assert_eq!((calibration.width, calibration.height), (640, 480));
+        assert_eq!(calibration.distortion_model, "plumb_bob");
+        assert_eq!(calibration.k[0], 500.0);
+        assert_eq!(calibration.d, [-0.1, 0.01, 0.0, 0.0, 0.0]);
+        assert_eq!(calibration.p[2], 321.0);
+    }
+
+    #[test]
+    fn test_parse_opencv() {
+        let yaml = "%YAML:1.0
+---
+image_width: 1920
+image_height: 1080
+camera_matrix: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 1.4e+03, 0., 960., 0.,
+       1.4e+03, 540., 0., 0., 1. ]
+distortion_coefficients: !!opencv-matrix
+   rows: 1
+   cols: 8
+   dt: d
+   data: [ 0.1, 0.2, 0., 0., 0.3, 0., 0., 0. ]
+";
+        let calibration = parse_yaml(yaml).unwrap();
+        assert_eq!((calibration.width, calibration.height), (1920, 1080));
+        assert_eq!(calibration.distortion_model, "rational_polynomial");
+        assert_eq!(calibration.k[4], 1400.0);
+        assert_eq!(calibration.r[4], 1.0);
+        assert_eq!(
+            calibration.p,
+            [1400.0, 0.0, 960.0, 0.0, 0.0, 1400.0, 540.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+
+        assert!(parse_yaml("image_width: 640").is_err());
+        assert!(parse_yaml("camera_matrix: [1, 2]").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::foxglove::Quaternion;
+
+/// Sample entry of a camera motion metadata track, see
+/// https://developers.google.com/streetview/publish/camm-spec
+const CAMM_TAG: u32 = u32::from_le_bytes(*b"camm");
+
+/// Whether the stream is a CAMM track
+pub fn is_camm(parameters: &ffmpeg::codec::Parameters) -> bool {
+    unsafe { (*parameters.as_ptr()).codec_tag == CAMM_TAG }
+}
+
+/// A record of a CAMM packet, in the camera coordinate system
+#[derive(Debug, PartialEq)]
+pub enum CammRecord {
+    /// Rotation from the camera to the world as an angle axis in radians
+    Orientation([f32; 3]),
+    /// Angular velocity in rad/s
+    Gyro([f32; 3]),
+    /// Acceleration in m/s²
+    Acceleration([f32; 3]),
+    /// Magnetic field in microtesla
+    MagneticField([f32; 3]),
+    Gps {
+        latitude: f64,
+        longitude: f64,
+        altitude: f64,
+        /// Horizontal and vertical accuracy in meters, if known
+        accuracy: Option<(f32, f32)>,
+    },
+}
+
+impl CammRecord {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            CammRecord::Orientation(_) => "camm/orientation",
+            CammRecord::Gyro(_) => "camm/gyro",
+            CammRecord::Acceleration(_) => "camm/accelerometer",
+            CammRecord::MagneticField(_) => "camm/magnetometer",
+            CammRecord::Gps { .. } => "camm/location",
+        }
+    }
+}
+
+/// Converts an angle axis rotation to a quaternion
+pub fn angle_axis_to_quaternion([x, y, z]: [f32; 3]) -> Quaternion {
+    let (x, y, z) = (x as f64, y as f64, z as f64);
+    let angle = (x * x + y * y + z * z).sqrt();
+    if angle == 0.0 {
+        return Quaternion {
+            w: 1.0,
+            ..Default::default()
+        };
+    }
+    let scale = (angle / 2.0).sin() / angle;
+    Quaternion {
+        x: x * scale,
+        y: y * scale,
+        z: z * scale,
+        w: (angle / 2.0).cos(),
+    }
+}
+
+/// Size of the payload after the four byte header for each packet type
+fn payload_size(packet_type: u16) -> Option<usize> {
+    match packet_type {
+        0 | 2 | 3 | 4 | 7 => Some(12),
+        1 => Some(8),
+        5 => Some(24),
+        6 => Some(56),
+        _ => None,
+    }
+}
+
+/// Parses the packets in the data of a CAMM sample. Exposure and position
+/// packets are skipped.
+pub fn parse_sample(data: &[u8]) -> Result<Vec<CammRecord>, String> {
+    let f32_at = |payload: &[u8], offset: usize| {
+        f32::from_le_bytes(payload[offset..offset + 4].try_into().unwrap())
+    };
+    let f64_at = |payload: &[u8], offset: usize| {
+        f64::from_le_bytes(payload[offset..offset + 8].try_into().unwrap())
+    };
+    let vector = |payload: &[u8]| [f32_at(payload, 0), f32_at(payload, 4), f32_at(payload, 8)];
+
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + 4)
+            .ok_or("Truncated CAMM packet header")?;
+        let packet_type = u16::from_le_bytes([header[2], header[3]]);
+        let size = payload_size(packet_type)
+            .ok_or_else(|| format!("Unknown CAMM packet type {}", packet_type))?;
+        let payload = data
+            .get(pos + 4..pos + 4 + size)
+            .ok_or_else(|| format!("Truncated CAMM packet of type {}", packet_type))?;
+        pos += 4 + size;
+
+        records.push(match packet_type {
+            0 => CammRecord::Orientation(vector(payload)),
+            2 => CammRecord::Gyro(vector(payload)),
+            3 => CammRecord::Acceleration(vector(payload)),
+            7 => CammRecord::MagneticField(vector(payload)),
+            5 => CammRecord::Gps {
+                latitude: f64_at(payload, 0),
+                longitude: f64_at(payload, 8),
+                altitude: f64_at(payload, 16),
+                accuracy: None,
+            },
+            // GPS time and fix type come first, with a fix type of 0 meaning none
+            6 if i32::from_le_bytes(payload[8..12].try_into().unwrap()) == 0 => continue,
+            6 => CammRecord::Gps {
+                latitude: f64_at(payload, 12),
+                longitude: f64_at(payload, 20),
+                altitude: f32_at(payload, 28) as f64,
+                accuracy: Some((f32_at(payload, 32), f32_at(payload, 36))),
+            },
+            _ => continue,
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(packet_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0];
+        packet.extend_from_slice(&packet_type.to_le_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_parse_sample() {
+        let gyro: Vec<u8> = [0.5f32, -1.0, 2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let gps: Vec<u8> = [47.25f64, 8.5, 410.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut detailed = 1_000_000_000f64.to_le_bytes().to_vec();
+        detailed.extend_from_slice(&3i32.to_le_bytes());
+        detailed.extend_from_slice(&1.5f64.to_le_bytes());
+        detailed.extend_from_slice(&2.5f64.to_le_bytes());
+        for value in [10.0f32, 3.0, 5.0, 0.0, 0.0, 0.0, 0.0] {
+            detailed.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let data = [
+            packet(2, &gyro),
+            packet(1, &[0; 8]),
+            packet(5, &gps),
+            packet(6, &detailed),
+        ]
+        .concat();
+        assert_eq!(
+            parse_sample(&data),
+            Ok(vec![
+                CammRecord::Gyro([0.5, -1.0, 2.0]),
+                CammRecord::Gps {
+                    latitude: 47.25,
+                    longitude: 8.5,
+                    altitude: 410.0,
+                    accuracy: None,
+                },
+                CammRecord::Gps {
+                    latitude: 1.5,
+                    longitude: 2.5,
+                    altitude: 10.0,
+                    accuracy: Some((3.0, 5.0)),
+                },
+            ])
+        );
+
+        assert!(parse_sample(&packet(9, &[0; 12])).is_err());
+        assert!(parse_sample(&gyro[..6]).is_err());
+    }
+
+    #[test]
+    fn test_angle_axis_to_quaternion() {
+        let identity = angle_axis_to_quaternion([0.0; 3]);
+        assert_eq!(identity.w, 1.0);
+
+        let half_turn = angle_axis_to_quaternion([0.0, 0.0, std::f32::consts::PI]);
+        assert!((half_turn.z - 1.0).abs() < 1e-6);
+        assert!(half_turn.w.abs() < 1e-6);
+    }
+}
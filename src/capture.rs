@@ -0,0 +1,107 @@
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ffmpeg_next as ffmpeg;
+
+/// The device demuxer of the platform, v4l2 on Linux and avfoundation on
+/// macOS
+pub fn default_device_format() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "avfoundation"
+    } else {
+        "v4l2"
+    }
+}
+
+/// How to open a camera with --capture
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureOptions {
+    /// The device demuxer, like v4l2
+    pub device_format: String,
+    /// WIDTHxHEIGHT to ask the camera for
+    pub size: Option<String>,
+    /// Frames per second to ask the camera for
+    pub fps: Option<u32>,
+}
+
+impl CaptureOptions {
+    /// The options of the device demuxer
+    fn device_options(&self) -> Vec<(&'static str, String)> {
+        let mut options = Vec::new();
+        if let Some(size) = &self.size {
+            options.push(("video_size", size.clone()));
+        }
+        if let Some(fps) = self.fps {
+            options.push(("framerate", fps.to_string()));
+        }
+        options
+    }
+
+    /// Opens the camera at `device`, like /dev/video0 or an AVFoundation
+    /// device index
+    pub fn open(&self, device: &Path) -> Result<ffmpeg::format::context::Input, Box<dyn Error>> {
+        ffmpeg::device::register_all();
+        let format = ffmpeg::device::input::video()
+            .find(|format| format.name() == self.device_format)
+            .ok_or_else(|| {
+                format!(
+                    "Capture device format {} not available in this FFmpeg",
+                    self.device_format
+                )
+            })?;
+        let mut options = ffmpeg::Dictionary::new();
+        for (key, value) in self.device_options() {
+            options.set(key, &value);
+        }
+        let context = ffmpeg::format::open_with(
+            device,
+            &ffmpeg::format::format::Format::Input(format),
+            options,
+        )
+        .map_err(|e| format!("Could not open camera {:?}: {}", device, e))?;
+        Ok(context.input())
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C end the capture instead of the process, so the MCAP is
+/// finished with its summary
+pub fn stop_on_interrupt() -> Result<(), Box<dyn Error>> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
+        .map_err(|e| format!("Could not handle Ctrl-C: {}", e))?;
+    Ok(())
+}
+
+/// Whether Ctrl-C was pressed since stop_on_interrupt
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_options() {
+        let options = CaptureOptions {
+            device_format: "v4l2".to_string(),
+            size: Some("1280x720".to_string()),
+            fps: Some(30),
+        };
+        assert_eq!(
+            options.device_options(),
+            vec![
+                ("video_size", "1280x720".to_string()),
+                ("framerate", "30".to_string())
+            ]
+        );
+        let options = CaptureOptions {
+            device_format: "v4l2".to_string(),
+            size: None,
+            fps: None,
+        };
+        assert!(options.device_options().is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, error::Error, fs::File, io::BufWriter};
+
+use ffmpeg_next as ffmpeg;
+use mcap::{records::Metadata, Writer};
+
+use crate::foxglove::log::Level;
+use crate::interleave::Interleaver;
+use crate::setup_mcap_channel;
+use crate::tracks;
+
+/// How the chapters of the input are written
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ChapterMode {
+    /// A metadata record per chapter
+    #[default]
+    Metadata,
+    /// A log message at the start of each chapter on the `chapters` topic
+    Log,
+    None,
+}
+
+impl std::str::FromStr for ChapterMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "metadata" => Ok(ChapterMode::Metadata),
+            "log" => Ok(ChapterMode::Log),
+            "none" => Ok(ChapterMode::None),
+            other => Err(format!(
+                "Unsupported chapter mode {:?}, expected metadata, log or none",
+                other
+            )),
+        }
+    }
+}
+
+pub struct Chapter {
+    pub title: String,
+    pub start_ns: u64,
+    pub end_ns: u64,
+}
+
+/// The chapters of the input, untitled ones named after their number
+pub fn read_chapters(input: &ffmpeg::format::context::Input) -> Vec<Chapter> {
+    input
+        .chapters()
+        .enumerate()
+        .map(|(index, chapter)| {
+            let time_base = chapter.time_base();
+            let to_ns = |time: i64| {
+                (time as f64 * time_base.numerator() as f64 / time_base.denominator() as f64
+                    * 1_000_000_000.0) as u64
+            };
+            let metadata = chapter.metadata();
+            Chapter {
+                title: metadata
+                    .get("title")
+                    .map_or_else(|| format!("Chapter {}", index + 1), str::to_string),
+                start_ns: to_ns(chapter.start()),
+                end_ns: to_ns(chapter.end()),
+            }
+        })
+        .collect()
+}
+
+/// Writes the chapters as metadata records, or adds them to `interleaver`
+/// as log messages to write along with the frames
+pub fn write_chapters(
+    writer: &mut Writer<BufWriter<File>>,
+    interleaver: &mut Interleaver,
+    chapters: &[Chapter],
+    mode: ChapterMode,
+) -> Result<(), Box<dyn Error>> {
+    match mode {
+        ChapterMode::Metadata => {
+            for chapter in chapters {
+                writer.write_metadata(&Metadata {
+                    name: "chapter".to_string(),
+                    metadata: BTreeMap::from([
+                        ("title".to_string(), chapter.title.clone()),
+                        ("start_ns".to_string(), chapter.start_ns.to_string()),
+                        ("end_ns".to_string(), chapter.end_ns.to_string()),
+                    ]),
+                })?;
+            }
+        }
+        ChapterMode::Log if !chapters.is_empty() => {
+            let channel_id =
+                setup_mcap_channel(writer, "chapters", "foxglove.Log", BTreeMap::new())?;
+            for chapter in chapters {
+                interleaver.add(
+                    channel_id.try_into()?,
+                    chapter.start_ns,
+                    tracks::encode_log(Level::Info, "chapters", chapter.start_ns, &chapter.title),
+                );
+            }
+        }
+        ChapterMode::Log => {}
+        ChapterMode::None => {}
+    }
+    Ok(())
+}
@@ -1,22 +1,68 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 
+use bytes::{BufMut, Bytes, BytesMut};
 use ffmpeg_next as ffmpeg;
 
+use crate::capture::CaptureOptions;
+use crate::editlist::{self, EditGaps};
+use crate::failure::Failure;
+use crate::memory::{self, MemoryBudget};
+use crate::nal;
+use crate::playlist;
+use crate::sps::{self, SpsInfo};
+use crate::stats::Stats;
+use crate::stream_info;
+use crate::transcode::{timestamp_overlay, ToneMap, TranscodeOptions, Transcoder};
+use crate::validation::{Strictness, Validator};
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CodecType {
     H264,
     H265,
+    /// Only available as a transcode output
+    Av1,
+}
+
+impl std::str::FromStr for CodecType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "h264" => Ok(CodecType::H264),
+            "h265" | "hevc" => Ok(CodecType::H265),
+            "av1" => Ok(CodecType::Av1),
+            other => Err(format!(
+                "Unsupported codec {:?}, expected h264, h265 or av1",
+                other
+            )),
+        }
+    }
+}
+
+/// Codecs of older dashcams and DVRs, which are always re-encoded as their
+/// users can't record anything else
+fn is_legacy_codec(id: ffmpeg::codec::Id) -> bool {
+    use ffmpeg::codec::Id;
+    matches!(
+        id,
+        Id::MPEG1VIDEO | Id::MPEG2VIDEO | Id::MPEG4 | Id::MSMPEG4V3 | Id::H263 | Id::H263P
+    )
 }
 
 impl CodecType {
     const H264_NAL_SPS: u8 = 0x7;
     const H264_NAL_PPS: u8 = 0x8;
     const H264_NAL_SEI: u8 = 0x6;
+    const H264_NAL_AUD: u8 = 9;
+    const H264_NAL_FILLER: u8 = 12;
 
     const H265_NAL_VPS: u8 = 32;
     const H265_NAL_SPS: u8 = 33;
     const H265_NAL_PPS: u8 = 34;
     const H265_NAL_SEI: u8 = 39;
+    const H265_NAL_AUD: u8 = 35;
+    const H265_NAL_FILLER: u8 = 38;
 
     pub fn from_ffmpeg_id(id: ffmpeg::codec::Id) -> Result<Self, Box<dyn Error>> {
         match id {
@@ -26,17 +72,98 @@ impl CodecType {
         }
     }
 
+    pub fn ffmpeg_id(&self) -> ffmpeg::codec::Id {
+        match self {
+            CodecType::H264 => ffmpeg::codec::Id::H264,
+            CodecType::H265 => ffmpeg::codec::Id::HEVC,
+            CodecType::Av1 => ffmpeg::codec::Id::AV1,
+        }
+    }
+
     pub fn format_str(&self) -> &'static str {
         match self {
             CodecType::H264 => "h264",
             CodecType::H265 => "h265",
+            CodecType::Av1 => "av1",
         }
     }
 
     pub fn encoder_lib(&self) -> &'static str {
+        self.encoder_libs()[0]
+    }
+
+    /// Software encoders in order of preference
+    pub fn encoder_libs(&self) -> &'static [&'static str] {
+        match self {
+            CodecType::H264 => &["libx264"],
+            CodecType::H265 => &["libx265"],
+            CodecType::Av1 => &["libsvtav1", "libaom-av1"],
+        }
+    }
+
+    /// The type of a NAL unit from its first header byte, or None for AV1,
+    /// whose bitstream is made of OBUs rather than NAL units
+    pub fn nal_type(&self, header: u8) -> Option<u8> {
+        match self {
+            CodecType::H264 => Some(header & 0x1F),
+            CodecType::H265 => Some((header >> 1) & 0x3F),
+            CodecType::Av1 => None,
+        }
+    }
+
+    /// The nuh_layer_id of an H.265 NAL unit, which is above 0 for the
+    /// extra layers of multi-layer streams, like the alpha layer of HEVC with
+    /// alpha. Always 0 for H.264.
+    pub fn layer_id(&self, nal: &[u8]) -> u8 {
+        match (self, nal) {
+            (CodecType::H265, [first, second, ..]) => ((first & 0x01) << 5) | (second >> 3),
+            _ => 0,
+        }
+    }
+
+    fn nal_header_size(&self) -> usize {
         match self {
-            CodecType::H264 => "libx264",
-            CodecType::H265 => "libx265",
+            CodecType::H265 => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn is_sei(&self, nal_type: u8) -> bool {
+        match self {
+            CodecType::H264 => nal_type == Self::H264_NAL_SEI,
+            CodecType::H265 => nal_type == Self::H265_NAL_SEI,
+            CodecType::Av1 => false,
+        }
+    }
+
+    /// Access unit delimiters and filler data, which carry no picture data
+    pub fn is_aud_or_filler(&self, nal_type: u8) -> bool {
+        match self {
+            CodecType::H264 => matches!(nal_type, Self::H264_NAL_AUD | Self::H264_NAL_FILLER),
+            CodecType::H265 => matches!(nal_type, Self::H265_NAL_AUD | Self::H265_NAL_FILLER),
+            CodecType::Av1 => false,
+        }
+    }
+
+    pub fn nal_type_name(&self, nal_type: u8) -> Option<&'static str> {
+        match (self, nal_type) {
+            (CodecType::H264, 1) => Some("non-IDR slice"),
+            (CodecType::H264, 5) => Some("IDR slice"),
+            (CodecType::H264, Self::H264_NAL_SEI) => Some("SEI"),
+            (CodecType::H264, Self::H264_NAL_SPS) => Some("SPS"),
+            (CodecType::H264, Self::H264_NAL_PPS) => Some("PPS"),
+            (CodecType::H264, Self::H264_NAL_AUD) => Some("AUD"),
+            (CodecType::H264, Self::H264_NAL_FILLER) => Some("filler"),
+            (CodecType::H265, 0..=9) => Some("non-IRAP slice"),
+            (CodecType::H265, 16..=21) => Some("IRAP slice"),
+            (CodecType::H265, Self::H265_NAL_VPS) => Some("VPS"),
+            (CodecType::H265, Self::H265_NAL_SPS) => Some("SPS"),
+            (CodecType::H265, Self::H265_NAL_PPS) => Some("PPS"),
+            (CodecType::H265, Self::H265_NAL_AUD) => Some("AUD"),
+            (CodecType::H265, Self::H265_NAL_FILLER) => Some("filler"),
+            (CodecType::H265, Self::H265_NAL_SEI) => Some("prefix SEI"),
+            (CodecType::H265, 40) => Some("suffix SEI"),
+            _ => None,
         }
     }
 
@@ -57,14 +184,18 @@ impl CodecType {
                         | Self::H265_NAL_SEI
                 )
             }
+            CodecType::Av1 => false,
         }
     }
 }
 
+#[derive(Default, PartialEq)]
 pub struct ParameterSets {
     pub vps: Vec<u8>,
     pub sps: Vec<u8>,
     pub pps: Vec<u8>,
+    /// Size in bytes of the length prefix of each NAL unit in the samples
+    pub nal_length_size: usize,
 }
 
 impl ParameterSets {
@@ -75,6 +206,15 @@ impl ParameterSets {
         match codec {
             CodecType::H264 => Self::parse_avcc(extradata),
             CodecType::H265 => Self::parse_hvcc(extradata),
+            CodecType::Av1 => Err("AV1 has no parameter sets".into()),
+        }
+    }
+
+    /// Reads the `lengthSizeMinusOne` field shared by avcC and hvcC
+    fn parse_nal_length_size(byte: u8) -> Result<usize, Box<dyn Error>> {
+        match (byte & 0x03) + 1 {
+            3 => Err("Invalid NAL unit length size of 3 bytes".into()),
+            size => Ok(size as usize),
         }
     }
 
@@ -83,6 +223,7 @@ impl ParameterSets {
             return Err("AVCC header too short".into());
         }
 
+        let nal_length_size = Self::parse_nal_length_size(extradata[4])?;
         let mut offset = Self::AVCC_HEADER_SIZE;
         let mut sps_nals = Vec::new();
         let mut pps_nals = Vec::new();
@@ -132,6 +273,7 @@ impl ParameterSets {
             vps: Vec::new(), // H.264 doesn't use VPS
             sps: sps_nals,
             pps: pps_nals,
+            nal_length_size,
         })
     }
 
@@ -140,6 +282,7 @@ impl ParameterSets {
             return Err("HVCC header too short".into());
         }
 
+        let nal_length_size = Self::parse_nal_length_size(extradata[21])?;
         let mut vps_nals = Vec::new();
         let mut sps_nals = Vec::new();
         let mut pps_nals = Vec::new();
@@ -174,6 +317,8 @@ impl ParameterSets {
 
                 let nal_data = &[0, 0, 0, 1];
                 match nal_type {
+                    // Only the base layer is passed through
+                    _ if CodecType::H265.layer_id(&extradata[offset..offset + nal_size]) > 0 => {}
                     32 => {
                         vps_nals.extend_from_slice(nal_data);
                         vps_nals.extend_from_slice(&extradata[offset..offset + nal_size]);
@@ -200,15 +345,67 @@ impl ParameterSets {
             vps: vps_nals,
             sps: sps_nals,
             pps: pps_nals,
+            nal_length_size,
         })
     }
 
-    pub fn write_to(&self, codec: CodecType, buffer: &mut Vec<u8>) {
+    /// Collects the parameter sets among NAL units of an Annex B stream
+    pub fn from_nal_units(nals: &[&[u8]], codec: CodecType) -> Self {
+        let mut parameter_sets = Self::default();
+        for nal in nals {
+            let Some(&header) = nal.first() else {
+                continue;
+            };
+            if codec.layer_id(nal) > 0 {
+                continue;
+            }
+            let list = match (codec, codec.nal_type(header)) {
+                (CodecType::H264, Some(CodecType::H264_NAL_SPS)) => &mut parameter_sets.sps,
+                (CodecType::H264, Some(CodecType::H264_NAL_PPS)) => &mut parameter_sets.pps,
+                (CodecType::H265, Some(CodecType::H265_NAL_VPS)) => &mut parameter_sets.vps,
+                (CodecType::H265, Some(CodecType::H265_NAL_SPS)) => &mut parameter_sets.sps,
+                (CodecType::H265, Some(CodecType::H265_NAL_PPS)) => &mut parameter_sets.pps,
+                _ => continue,
+            };
+            list.extend_from_slice(&[0, 0, 0, 1]);
+            list.extend_from_slice(nal);
+        }
+        parameter_sets
+    }
+
+    pub fn write_to(&self, codec: CodecType, buffer: &mut impl BufMut) {
         if codec == CodecType::H265 {
-            buffer.extend_from_slice(&self.vps);
+            buffer.put_slice(&self.vps);
+        }
+        buffer.put_slice(&self.sps);
+        buffer.put_slice(&self.pps);
+    }
+
+    fn len(&self, codec: CodecType) -> usize {
+        let vps = if codec == CodecType::H265 {
+            self.vps.len()
+        } else {
+            0
+        };
+        vps + self.sps.len() + self.pps.len()
+    }
+
+    /// H.265 parameter sets without a VPS, which a few encoders omit even
+    /// though decoders cope without it
+    pub fn lacks_only_vps(&self, codec: CodecType) -> bool {
+        codec == CodecType::H265
+            && self.vps.is_empty()
+            && !self.sps.is_empty()
+            && !self.pps.is_empty()
+    }
+
+    /// Picture size and aspect ratio from the first SPS
+    pub fn sps_info(&self, codec: CodecType) -> Option<SpsInfo> {
+        match codec {
+            CodecType::H264 => sps::parse_h264(&self.sps),
+            CodecType::H265 => sps::parse_h265(&self.sps),
+            CodecType::Av1 => None,
         }
-        buffer.extend_from_slice(&self.sps);
-        buffer.extend_from_slice(&self.pps);
     }
 
     pub fn validate(&self, codec: CodecType) -> Result<(), Box<dyn Error>> {
@@ -222,43 +419,166 @@ impl ParameterSets {
     }
 }
 
-pub fn convert_to_annex_b(data: &[u8], codec: CodecType) -> Vec<u8> {
-    let mut converted = Vec::new();
-    let mut pos = 0;
+/// Where the parameter sets are written in the output stream
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ParameterSetMode {
+    /// Every keyframe can be decoded on its own, which seeking relies on
+    #[default]
+    EveryKeyframe,
+    /// Only the first frame, and again whenever in-band parameter sets change
+    FirstOnly,
+}
 
-    while pos < data.len() {
-        if pos + 4 > data.len() {
-            break;
+impl std::str::FromStr for ParameterSetMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "every-keyframe" => Ok(ParameterSetMode::EveryKeyframe),
+            "first-only" => Ok(ParameterSetMode::FirstOnly),
+            other => Err(format!(
+                "Unsupported parameter set mode {:?}, expected every-keyframe or first-only",
+                other
+            )),
         }
-        let nal_size =
-            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+    }
+}
 
-        let nal_type = if pos + 4 < data.len() {
-            match codec {
-                CodecType::H264 => data[pos + 4] & 0x1F,
-                CodecType::H265 => (data[pos + 4] >> 1) & 0x3F,
-            }
-        } else {
-            0
-        };
+/// Notices video where every frame is a keyframe, like screen captures and
+/// some editing proxies, from its first frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct AllIntra {
+    /// Keyframes in a row from the start, None once another frame shows up
+    keyframes: Option<usize>,
+}
 
-        pos += 4;
+impl Default for AllIntra {
+    fn default() -> Self {
+        Self { keyframes: Some(0) }
+    }
+}
 
-        if !codec.should_skip_nal(nal_type) {
-            converted.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
-            if pos + nal_size <= data.len() {
-                converted.extend_from_slice(&data[pos..pos + nal_size]);
+impl AllIntra {
+    /// Keyframes in a row it takes
+    const FRAMES: usize = 30;
+    /// Seconds between the parameter sets of all-intra video, unless
+    /// --parameter-sets is given
+    const PARAMETER_SET_INTERVAL: f64 = 1.0;
+
+    /// Counts a frame, and returns whether it's the one the video is found
+    /// to be all-intra at
+    fn add(&mut self, keyframe: bool) -> bool {
+        self.keyframes = self.keyframes.filter(|_| keyframe).map(|count| count + 1);
+        self.keyframes == Some(Self::FRAMES)
+    }
+
+    fn detected(&self) -> bool {
+        self.keyframes.is_some_and(|count| count >= Self::FRAMES)
+    }
+}
+
+/// Decides which NAL units of the source packets reach the output.
+/// Parameter sets are always replaced by the ones from the extradata.
+#[derive(Clone, Debug, Default)]
+pub struct NalFilter {
+    /// Pass SEI units through, only those carrying one of these payload
+    /// types if not empty
+    pub keep_sei: Option<Vec<u32>>,
+    /// Drop access unit delimiters and filler data
+    pub strip_aud_filler: bool,
+    /// Number of AUD and filler NAL units dropped so far
+    pub stripped: usize,
+    /// Number of NAL units of layers above the base dropped so far, which
+    /// single-layer decoders can't handle
+    pub upper_layers: usize,
+}
+
+impl NalFilter {
+    pub fn keep(&mut self, codec: CodecType, nal: &[u8]) -> bool {
+        let Some(&header) = nal.first() else {
+            return true;
+        };
+        if codec.layer_id(nal) > 0 {
+            self.upper_layers += 1;
+            return false;
+        }
+        let Some(nal_type) = codec.nal_type(header) else {
+            return true;
+        };
+        if self.strip_aud_filler && codec.is_aud_or_filler(nal_type) {
+            self.stripped += 1;
+            return false;
+        }
+        if let (true, Some(types)) = (codec.is_sei(nal_type), &self.keep_sei) {
+            if types.is_empty() {
+                return true;
             }
+            let rbsp = nal::unescape(nal.get(codec.nal_header_size()..).unwrap_or_default());
+            return nal::sei_payload_types(&rbsp)
+                .iter()
+                .any(|payload_type| types.contains(payload_type));
         }
+        !codec.should_skip_nal(nal_type)
+    }
+}
 
-        if pos + nal_size <= data.len() {
-            pos += nal_size;
-        } else {
-            break;
+/// Appends the NAL units in `data`, each prefixed with its length in
+/// `length_size` bytes, that pass `filter` to `converted` in Annex B form.
+/// Returns false if the packet ended in a truncated NAL unit, which is
+/// dropped.
+pub fn append_annex_b(
+    data: &[u8],
+    codec: CodecType,
+    length_size: usize,
+    filter: &mut NalFilter,
+    stats: &mut Stats,
+    converted: &mut impl BufMut,
+) -> bool {
+    let mut pos = 0;
+
+    while pos < data.len() {
+        if pos + length_size > data.len() {
+            return false;
         }
+        let nal_size = data[pos..pos + length_size]
+            .iter()
+            .fold(0, |size, &byte| (size << 8) | byte as usize);
+        pos += length_size;
+
+        if pos + nal_size > data.len() {
+            return false;
+        }
+
+        append_nal_unit(&data[pos..pos + nal_size], codec, filter, stats, converted);
+        pos += nal_size;
     }
 
-    converted
+    true
+}
+
+/// Appends a NAL unit with a start code if it passes `filter`
+fn append_nal_unit(
+    nal: &[u8],
+    codec: CodecType,
+    filter: &mut NalFilter,
+    stats: &mut Stats,
+    converted: &mut impl BufMut,
+) {
+    let kept = filter.keep(codec, nal);
+    let nal_type = nal.first().and_then(|&header| codec.nal_type(header));
+    if let Some(nal_type) = nal_type {
+        stats.record_nal_unit(nal_type, kept);
+    }
+    if kept {
+        converted.put_slice(&[0x00, 0x00, 0x00, 0x01]);
+        converted.put_slice(nal);
+    } else if let Some(nal_type) = nal_type {
+        trace!(
+            "Dropped a {} NAL unit of {} bytes",
+            codec.nal_type_name(nal_type).unwrap_or("reserved"),
+            nal.len()
+        );
+    }
 }
 
 pub fn extract_extradata(params: &ffmpeg::codec::Parameters) -> Result<&[u8], Box<dyn Error>> {
@@ -274,38 +594,403 @@ pub fn extract_extradata(params: &ffmpeg::codec::Parameters) -> Result<&[u8], Bo
     }
 }
 
+/// Clockwise rotation of the stream's display matrix, 0 without one
+fn upright_rotation(params: &ffmpeg::codec::Parameters) -> u32 {
+    stream_info::coded_side_data(
+        params,
+        ffmpeg::codec::packet::side_data::Type::DisplayMatrix,
+    )
+    .and_then(|matrix| stream_info::display_rotation(&matrix))
+    .map(stream_info::upright_rotation)
+    .unwrap_or(0)
+}
+
+/// Filters to run the decoded frames through when transcoding. Properties
+/// that are left alone are recorded in `metadata` instead.
+fn plan_filters(
+    params: &ffmpeg::codec::Parameters,
+    transcode: Option<&TranscodeOptions>,
+    validator: &mut Validator,
+    metadata: &mut BTreeMap<String, String>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut filters = Vec::new();
+
+    let deinterlace = transcode.is_some_and(|transcode| transcode.deinterlace);
+    if deinterlace {
+        // Only frames flagged as interlaced are touched
+        filters.push("yadif=mode=send_frame:deint=interlaced".to_string());
+    } else if stream_info::is_interlaced(stream_info::field_order(params)) {
+        validator.warn(
+            "interlaced video",
+            "Video is interlaced and passed through as is; use --deinterlace to remove combing"
+                .to_string(),
+        )?;
+    }
+
+    let tonemap = transcode.and_then(|transcode| transcode.tonemap);
+    let hdr = stream_info::is_hdr_transfer(stream_info::color_transfer(params));
+    let mut colors = stream_info::Colors::of(params);
+    if let Some(ToneMap::Sdr) = tonemap {
+        if hdr {
+            filters.push(ToneMap::SDR_FILTER.to_string());
+            colors = stream_info::Colors::BT709;
+        } else {
+            validator.warn(
+                "tone mapping skipped",
+                "Video is not HDR (PQ or HLG), --tonemap has no effect".to_string(),
+            )?;
+        }
+    } else if hdr {
+        // Needed to interpret the colors, as the SEI carrying them is
+        // stripped from the passed through stream unless --keep-sei is set
+        use ffmpeg::codec::packet::side_data::Type;
+        if let Some(data) = stream_info::coded_side_data(params, Type::MasteringDisplayMetadata) {
+            metadata.extend(
+                stream_info::mastering_display_metadata(&data)
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value)),
+            );
+        }
+        if let Some(data) = stream_info::coded_side_data(params, Type::ContentLightLevel) {
+            metadata.extend(
+                stream_info::content_light_metadata(&data)
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value)),
+            );
+        }
+    }
+
+    // Renderers otherwise guess, which for SD video is BT.601 or BT.709
+    // depending on who you ask
+    metadata.extend(
+        colors
+            .metadata()
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value)),
+    );
+
+    let rotation = upright_rotation(params);
+    if rotation != 0 {
+        let apply_rotation = transcode.is_some_and(|transcode| transcode.apply_rotation);
+        match stream_info::rotation_filter(rotation) {
+            Some(filter) if apply_rotation => filters.push(filter.to_string()),
+            _ => {
+                metadata.insert("rotation".to_string(), rotation.to_string());
+                validator.warn(
+                    "display rotation",
+                    format!(
+                        "Video must be rotated {} degrees clockwise to be upright; \
+                        recorded in the channel metadata, use --apply-rotation to bake it in",
+                        rotation
+                    ),
+                )?;
+            }
+        }
+    }
+
+    // Custom filters see the frames upright and deinterlaced
+    let custom = transcode.and_then(|transcode| transcode.filter.as_deref());
+    if let Some(custom) = custom {
+        filters.push(custom.to_string());
+    }
+
+    // Drawn last so the text is not cropped or distorted by other filters
+    if let Some(transcode) = transcode.filter(|transcode| transcode.burn_timestamps) {
+        filters.push(timestamp_overlay(transcode.burn_frame_index));
+    }
+
+    Ok(filters)
+}
+
+/// Records the profile and bit depth of a passed through stream, warning
+/// about formats beyond the 8 and 10-bit 4:2:0 that most decoders support
+fn record_bit_depth(
+    decoder: &ffmpeg::decoder::Video,
+    validator: &mut Validator,
+    metadata: &mut BTreeMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let profile = stream_info::profile_name(decoder.profile());
+    if let Some(profile) = &profile {
+        metadata.insert("profile".to_string(), profile.clone());
+    }
+    let Some(bit_depth) = stream_info::bit_depth(decoder.format()) else {
+        return Ok(());
+    };
+    metadata.insert("bit_depth".to_string(), bit_depth.to_string());
+    if stream_info::is_widely_decodable(decoder.format()) == Some(false) {
+        validator.warn(
+            "unsupported pixel format",
+            format!(
+                "Video is {}-bit {:?} ({}), which most clients cannot decode; \
+                use --force-8bit to re-encode it",
+                bit_depth,
+                decoder.format(),
+                profile.as_deref().unwrap_or("unknown profile")
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct ConverterOptions {
+    pub strictness: Strictness,
+    /// Longest acceptable distance between keyframes in seconds, 0 to disable
+    pub max_keyframe_interval: f64,
+    /// Re-encode the stream instead of passing the source packets through
+    pub transcode: Option<TranscodeOptions>,
+    /// Re-encode to H.264 when the source codec cannot be passed through
+    pub auto_transcode: bool,
+    /// Re-encode to bake in the display matrix rotation, if the stream has
+    /// one other than 0°
+    pub apply_rotation: bool,
+    pub nal_filter: NalFilter,
+    /// None for every keyframe, or once a second if every frame is one
+    pub parameter_sets: Option<ParameterSetMode>,
+    /// Measure the PSNR and SSIM of the encoded frames when re-encoding
+    pub quality_metrics: bool,
+    /// Decoder threads, 0 for one per core
+    pub threads: usize,
+    /// Bounds the decoder threads and the data waiting for its frame
+    pub max_memory: Option<MemoryBudget>,
+    /// Open the input as a camera instead of a file
+    pub capture: Option<CaptureOptions>,
+    /// More options for the demuxer of the input, like those of a live stream
+    pub input_options: Vec<(&'static str, String)>,
+    /// Timestamps of the frames by PTS, from --timestamps, used instead of
+    /// the container's timing
+    pub frame_timestamps: Option<BTreeMap<i64, u64>>,
+    /// Play the segments of an edit list back to back, as FFmpeg does,
+    /// instead of keeping the pauses between them
+    pub collapse_edit_gaps: bool,
+}
+
+/// The frame of --timestamps nearest to `pts`, before or after it, the
+/// earlier one on a tie
+fn nearest_listed(timestamps: &BTreeMap<i64, u64>, pts: i64) -> Option<(i64, u64)> {
+    let before = timestamps.range(..=pts).next_back();
+    let after = timestamps.range(pts..).next();
+    let nearest = match (before, after) {
+        (Some(before), Some(after)) if after.0 - pts < pts - before.0 => after,
+        (Some(before), _) => before,
+        (None, after) => after?,
+    };
+    Some((*nearest.0, *nearest.1))
+}
+
+/// Log time of a frame at `pts`, from the nearest frame of --timestamps if
+/// any. In integer math, as the PTS can be Unix time in nanoseconds, past
+/// the precision of an f64.
+fn log_time_ns(pts: i64, time_base: ffmpeg::Rational, timestamps: &BTreeMap<i64, u64>) -> u64 {
+    // A frame missing from --timestamps keeps its distance to the nearest
+    // one listed
+    if let Some((listed_pts, timestamp)) = nearest_listed(timestamps, pts) {
+        return timestamp.saturating_add_signed(playlist::ticks_to_ns(
+            pts.saturating_sub(listed_pts),
+            time_base,
+        ));
+    }
+    playlist::ticks_to_ns(pts, time_base).max(0) as u64
+}
+
 pub struct VideoConverter {
     codec_type: CodecType,
     decoder: ffmpeg::decoder::Video,
     parameter_sets: ParameterSets,
     time_base_num: i32,
     time_base_den: i32,
-    frame_packets: Vec<Vec<u8>>,
+    frame_rate: ffmpeg::Rational,
+    /// The converted packets of the next frame. Frames are split off it, so
+    /// its allocation is reused once the messages of earlier frames are
+    /// written and dropped.
+    frame_data: BytesMut,
+    /// The timestamps and flags of the first packet in `frame_data`, which
+    /// the frame is written with
+    frame_packet: ffmpeg::Packet,
     last_timestamp: u64,
     last_progress: u64,
+    last_pts: Option<i64>,
+    seen_keyframe: bool,
+    /// Packets dropped because they precede the first keyframe
+    skipped_leading: usize,
+    last_keyframe_pts: Option<i64>,
+    max_keyframe_interval: f64,
+    validator: Validator,
+    transcoder: Option<Transcoder>,
+    metadata: BTreeMap<String, String>,
+    nal_filter: NalFilter,
+    /// The samples use start codes instead of length prefixes
+    annex_b_samples: bool,
+    parameter_set_mode: Option<ParameterSetMode>,
+    wrote_parameter_sets: bool,
+    /// PTS of the last frame written with the parameter sets
+    parameter_sets_pts: Option<i64>,
+    all_intra: AllIntra,
+    stats: Stats,
+    frame_timestamps: BTreeMap<i64, u64>,
+    edit_gaps: EditGaps,
 }
 
 impl VideoConverter {
+    /// Opens the input and prepares the conversion of the video stream at
+    /// `stream_index`, or of the best one if it isn't given
     pub fn new(
         input_path: &std::path::Path,
+        stream_index: Option<usize>,
+        options: &ConverterOptions,
     ) -> Result<(Self, ffmpeg::format::context::Input), Box<dyn Error>> {
-        let input = ffmpeg::format::input(input_path)?;
-        let video_stream = input
-            .streams()
-            .best(ffmpeg::media::Type::Video)
-            .ok_or(ffmpeg::Error::StreamNotFound)?;
-
-        let codec = ffmpeg::codec::context::Context::from_parameters(video_stream.parameters())?;
-        let codec_type = CodecType::from_ffmpeg_id(codec.id())?;
+        let input = match &options.capture {
+            Some(capture) => capture.open(input_path)?,
+            None => {
+                // The demuxer only exports XMP metadata on request
+                let mut input_options = ffmpeg::Dictionary::new();
+                input_options.set("export_xmp", "1");
+                for (key, value) in &options.input_options {
+                    input_options.set(key, value);
+                }
+                ffmpeg::format::input_with_dictionary(input_path, input_options)?
+            }
+        };
+        let video_stream = match stream_index {
+            Some(stream_index) => input
+                .stream(stream_index)
+                .ok_or(ffmpeg::Error::StreamNotFound)?,
+            None => stream_info::best_video(&input)?,
+        };
+        // Devices and URLs have no edit list to read
+        let edit_gaps = if options.collapse_edit_gaps || !input_path.is_file() {
+            EditGaps::default()
+        } else {
+            editlist::read(input_path, video_stream.id())?
+        };
+        if edit_gaps.pauses() > 0 {
+            info!(
+                "Keeping the {} pauses between edit list segments, {:.3}s in all",
+                edit_gaps.pauses(),
+                edit_gaps.total_ns() as f64 / 1e9
+            );
+        }
+
+        // Video that is upright already is passed through as it is
+        let transcode_options = match &options.transcode {
+            None if options.apply_rotation && upright_rotation(&video_stream.parameters()) != 0 => {
+                Some(TranscodeOptions {
+                    apply_rotation: true,
+                    ..Default::default()
+                })
+            }
+            transcode => transcode.clone(),
+        };
+
+        let mut codec =
+            ffmpeg::codec::context::Context::from_parameters(video_stream.parameters())?;
+        let codec_id = codec.id();
+        let source_codec = match CodecType::from_ffmpeg_id(codec_id) {
+            Ok(codec_type) => Some(codec_type),
+            Err(_)
+                if options.auto_transcode
+                    || transcode_options.is_some()
+                    || is_legacy_codec(codec_id) =>
+            {
+                None
+            }
+            Err(e) => {
+                return Err(format!("{}; use --auto-transcode to re-encode it", e).into());
+            }
+        };
+        // Frame threading holds frames back, which only the transcode path
+        // can wait for. Passing through writes each packet when its frame
+        // comes out, so it decodes the slices of a frame in parallel instead.
+        let transcoding = source_codec.is_none() || transcode_options.is_some();
+        let kind = if transcoding {
+            ffmpeg::threading::Type::Frame
+        } else {
+            ffmpeg::threading::Type::Slice
+        };
+        let count = match &options.max_memory {
+            Some(budget) => {
+                let (width, height) = stream_info::dimensions(&video_stream.parameters());
+                budget.decoder_threads(options.threads, memory::frame_bytes(width, height))
+            }
+            None => options.threads,
+        };
+        debug!(
+            "Decoding with {:?} threading on {} threads (0 is one per core)",
+            kind, count
+        );
+        codec.set_threading(ffmpeg::threading::Config { kind, count });
         let decoder = codec.decoder().video()?;
 
+        let mut validator = Validator::new(options.strictness);
+        let mut metadata = BTreeMap::new();
+
+        let mut annex_b_samples = false;
+
         // Create binding to extend lifetime of parameters
         let params = video_stream.parameters();
-        let extradata = extract_extradata(&params)?;
-        let parameter_sets = ParameterSets::parse(extradata, codec_type)?;
-        parameter_sets.validate(codec_type)?;
+        let (codec_type, parameter_sets, transcode) = match source_codec {
+            Some(codec_type) => {
+                let parameter_sets = match extract_extradata(&params) {
+                    Ok(extradata) if !extradata.is_empty() && !nal::has_start_code(extradata) => {
+                        let parameter_sets = ParameterSets::parse(extradata, codec_type)?;
+                        if parameter_sets.lacks_only_vps(codec_type) {
+                            validator.repair(
+                                "missing VPS",
+                                "hvcC has no VPS, continuing with only the SPS and PPS".to_string(),
+                            )?;
+                        } else {
+                            parameter_sets.validate(codec_type)?;
+                        }
+                        parameter_sets
+                    }
+                    // Some muxers store Annex B samples, with the parameter
+                    // sets in band or in Annex B extradata
+                    extradata => {
+                        validator.warn(
+                            "Annex B samples",
+                            "No avcC/hvcC extradata, expecting Annex B samples with in-band \
+                            parameter sets"
+                                .to_string(),
+                        )?;
+                        annex_b_samples = true;
+                        let nals = extradata.map(nal::split_annex_b).unwrap_or_default();
+                        ParameterSets::from_nal_units(&nals, codec_type)
+                    }
+                };
+                if transcode_options.is_none() {
+                    record_bit_depth(&decoder, &mut validator, &mut metadata)?;
+                }
+                (codec_type, parameter_sets, transcode_options)
+            }
+            None => {
+                let mut transcode = transcode_options.unwrap_or_default();
+                let codec_type = *transcode.codec.get_or_insert(CodecType::H264);
+                // Sources like ProRes are often 4:2:2 or 10-bit, which most
+                // players cannot decode in H.264
+                transcode.force_8bit |= options.transcode.is_none();
+                (codec_type, ParameterSets::default(), Some(transcode))
+            }
+        };
+
+        let filters = plan_filters(&params, transcode.as_ref(), &mut validator, &mut metadata)?;
 
         let time_base = video_stream.time_base();
+        let mut frame_rate = video_stream.avg_frame_rate();
+        if frame_rate.numerator() == 0 {
+            frame_rate = video_stream.rate();
+        }
+        let transcoder = match &transcode {
+            Some(transcode) => Some(Transcoder::new(
+                &decoder,
+                time_base,
+                frame_rate,
+                transcode.codec.unwrap_or(codec_type),
+                &filters,
+                transcode,
+                options.quality_metrics,
+            )?),
+            None => None,
+        };
 
         Ok((
             Self {
@@ -314,9 +999,28 @@ impl VideoConverter {
                 parameter_sets,
                 time_base_num: time_base.numerator(),
                 time_base_den: time_base.denominator(),
-                frame_packets: Vec::new(),
+                frame_rate,
+                frame_data: BytesMut::new(),
+                frame_packet: ffmpeg::Packet::empty(),
                 last_timestamp: u64::MAX,
                 last_progress: 0,
+                last_pts: None,
+                seen_keyframe: false,
+                skipped_leading: 0,
+                last_keyframe_pts: None,
+                max_keyframe_interval: options.max_keyframe_interval,
+                validator,
+                transcoder,
+                metadata,
+                nal_filter: options.nal_filter.clone(),
+                annex_b_samples,
+                parameter_set_mode: options.parameter_sets,
+                wrote_parameter_sets: false,
+                parameter_sets_pts: None,
+                all_intra: AllIntra::default(),
+                stats: Stats::default(),
+                frame_timestamps: options.frame_timestamps.clone().unwrap_or_default(),
+                edit_gaps,
             },
             input,
         ))
@@ -334,59 +1038,346 @@ impl VideoConverter {
         self.decoder.send_eof()
     }
 
+    pub fn is_transcoding(&self) -> bool {
+        self.transcoder.is_some()
+    }
+
+    /// Passes a decoded frame on to the encoder when transcoding
+    pub fn encode_frame(&mut self, frame: &mut ffmpeg::frame::Video) -> Result<(), Box<dyn Error>> {
+        match &mut self.transcoder {
+            Some(transcoder) => transcoder.send_frame(frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Receives the next packet produced by the encoder, ready to be written
+    /// as a message. Fails with `Eof` when not transcoding.
+    pub fn receive_encoded(&mut self, packet: &mut ffmpeg::Packet) -> Result<(), ffmpeg::Error> {
+        match &mut self.transcoder {
+            Some(transcoder) => transcoder.receive_packet(packet)?,
+            None => return Err(ffmpeg::Error::Eof),
+        }
+        self.record_encoded(packet.size(), packet.is_key());
+        Ok(())
+    }
+
+    /// Counts an encoded packet in the statistics, for packets from another
+    /// converter's encoder
+    pub fn record_encoded(&mut self, size: usize, keyframe: bool) {
+        self.stats.record_frame(size, keyframe);
+    }
+
+    /// Compares the frames left in the quality decoder and returns the
+    /// report, if the quality of the encoded frames is measured
+    pub fn finish_quality(&mut self) -> Result<Option<Vec<String>>, Box<dyn Error>> {
+        let Some(quality) = self.transcoder.as_mut().and_then(Transcoder::quality_mut) else {
+            return Ok(None);
+        };
+        quality.finish()?;
+        Ok(Some(quality.summary()))
+    }
+
+    pub fn send_encoder_eof(&mut self) -> Result<(), Box<dyn Error>> {
+        match &mut self.transcoder {
+            Some(transcoder) => transcoder.send_eof(),
+            None => Ok(()),
+        }
+    }
+
+    /// Another encoder of the decoded frames, for a stream derived from the
+    /// video. `frame_rate` is the one after the filters, if they change it.
+    pub fn derived_transcoder(
+        &self,
+        options: &TranscodeOptions,
+        filters: &[String],
+        frame_rate: Option<ffmpeg::Rational>,
+    ) -> Result<Transcoder, Box<dyn Error>> {
+        Transcoder::new(
+            &self.decoder,
+            self.time_base(),
+            frame_rate.unwrap_or(self.frame_rate),
+            options.codec.unwrap_or(self.output_codec()),
+            filters,
+            options,
+            false,
+        )
+    }
+
+    /// Queues the packet's NAL units for the next frame and returns its PTS,
+    /// or `None` if the packet was dropped and must not reach the decoder.
     pub fn process_packet(
         &mut self,
         packet: &ffmpeg::Packet,
-        is_first: bool,
-    ) -> Result<(), Box<dyn Error>> {
-        if let Some(data) = packet.data() {
-            if !data.is_empty() {
-                let pts = packet.pts().ok_or("Missing PTS")?;
-                let dts = packet.dts().ok_or("Missing DTS")?;
-
-                if pts != dts {
-                    return Err(format!(
-                        "This video contains B-frames or reordered frames (PTS={}, DTS={}). \
-                        Please re-encode the video without B-frames using: \
-                        ffmpeg -i <input> -c:v {} -bf 0 output.mp4",
-                        pts,
-                        dts,
-                        self.codec_type.encoder_lib()
-                    )
-                    .into());
-                }
+    ) -> Result<Option<i64>, Box<dyn Error>> {
+        let data = match packet.data() {
+            Some(data) if !data.is_empty() => data,
+            _ => return Ok(Some(packet.pts().unwrap_or(0))),
+        };
+
+        let (pts, dts) = self.resolve_timestamps(packet)?;
+
+        // The encoder produces its own bitstream from the decoded frames
+        if self.transcoder.is_some() {
+            self.last_pts = Some(pts);
+            return Ok(Some(pts));
+        }
 
-                if is_first || packet.is_key() {
-                    let mut frame_data = Vec::new();
-                    self.parameter_sets
-                        .write_to(self.codec_type, &mut frame_data);
-                    let converted = convert_to_annex_b(data, self.codec_type);
-                    frame_data.extend_from_slice(&converted);
-                    self.frame_packets.push(frame_data);
-                } else {
-                    let converted = convert_to_annex_b(data, self.codec_type);
-                    self.frame_packets.push(converted);
+        // Counted first, so the frame the video is found all-intra at is
+        // already let through
+        if self.all_intra.add(packet.is_key()) {
+            debug!(
+                "The first {} frames are all keyframes, taking the video to be all-intra",
+                AllIntra::FRAMES
+            );
+        }
+        // Frames that all are keyframes aren't reordered
+        if pts != dts && !self.all_intra.detected() {
+            return Err(Failure::new(
+                "b-frames",
+                format!(
+                    "This video contains B-frames or reordered frames (PTS={}, DTS={}). \
+                    Please re-encode the video without B-frames using: \
+                    ffmpeg -i <input> -c:v {} -bf 0 output.mp4",
+                    pts,
+                    dts,
+                    self.codec_type.encoder_lib()
+                ),
+                "Re-encode the video without B-frames, or convert with --transcode",
+            )
+            .into());
+        }
+
+        // Frames before the first keyframe reference pictures that were cut
+        // off and can never be decoded
+        if !self.seen_keyframe {
+            if !packet.is_key() {
+                if self.skipped_leading == 0 {
+                    self.validator.repair(
+                        "non-IDR start",
+                        format!(
+                            "Stream starts with a non-keyframe at PTS={} ({:.3}s), \
+                            the frames up to the first keyframe cannot be decoded",
+                            pts,
+                            self.pts_to_seconds(pts)
+                        ),
+                    )?;
                 }
+                self.skipped_leading += 1;
+                return Ok(None);
+            }
+            self.seen_keyframe = true;
+            if self.skipped_leading > 0 {
+                info!(
+                    "Skipped {} packets before the first keyframe, the video starts at {:.3}s",
+                    self.skipped_leading,
+                    self.pts_to_seconds(pts)
+                );
+            }
+        }
+
+        if packet.is_key() {
+            if let Some(last_keyframe_pts) = self.last_keyframe_pts {
+                self.check_keyframe_interval(last_keyframe_pts, pts)?;
+            }
+            self.last_keyframe_pts = Some(pts);
+        }
+
+        let annex_b_nals = if self.annex_b_samples {
+            if !nal::has_start_code(data) {
+                return Err("Samples have neither avcC/hvcC extradata nor start codes".into());
+            }
+            let nals = nal::split_annex_b(data);
+            let in_band = ParameterSets::from_nal_units(&nals, self.codec_type);
+            if in_band.validate(self.codec_type).is_ok() && in_band != self.parameter_sets {
+                self.parameter_sets = in_band;
+                self.wrote_parameter_sets = false;
             }
+            Some(nals)
+        } else {
+            None
+        };
+
+        let write_parameter_sets = match self.parameter_set_mode {
+            Some(ParameterSetMode::FirstOnly) => !self.wrote_parameter_sets,
+            // Decoders seeking to a keyframe without them go back to the
+            // last one with them, which is at most the interval earlier
+            None if self.all_intra.detected() => {
+                !self.wrote_parameter_sets
+                    || self.parameter_sets_pts.is_none_or(|last_pts| {
+                        self.pts_to_seconds(pts - last_pts) >= AllIntra::PARAMETER_SET_INTERVAL
+                    })
+            }
+            _ => !self.wrote_parameter_sets || packet.is_key(),
+        };
+        // Start codes replace the length prefixes, which are usually as long
+        let start = self.frame_data.len();
+        if start == 0 {
+            self.frame_packet = ffmpeg::Packet::empty();
+            self.frame_packet.set_pts(Some(pts));
+            self.frame_packet.set_dts(Some(dts));
+            self.frame_packet.set_flags(packet.flags());
+        }
+        let parameter_sets_len = if write_parameter_sets {
+            self.parameter_sets.len(self.codec_type)
+        } else {
+            0
+        };
+        self.frame_data.reserve(parameter_sets_len + data.len());
+        if write_parameter_sets {
+            if self.parameter_sets.validate(self.codec_type).is_err()
+                && !self.parameter_sets.lacks_only_vps(self.codec_type)
+            {
+                self.validator.warn(
+                    "missing parameter sets",
+                    format!("No parameter sets known for the keyframe at PTS={}", pts),
+                )?;
+            }
+            self.parameter_sets
+                .write_to(self.codec_type, &mut self.frame_data);
+            self.wrote_parameter_sets = true;
+            self.parameter_sets_pts = Some(pts);
+        }
+        let upper_layers = self.nal_filter.upper_layers;
+        if let Some(nals) = annex_b_nals {
+            for nal in nals {
+                append_nal_unit(
+                    nal,
+                    self.codec_type,
+                    &mut self.nal_filter,
+                    &mut self.stats,
+                    &mut self.frame_data,
+                );
+            }
+        } else if !append_annex_b(
+            data,
+            self.codec_type,
+            self.parameter_sets.nal_length_size,
+            &mut self.nal_filter,
+            &mut self.stats,
+            &mut self.frame_data,
+        ) {
+            self.validator.warn(
+                "truncated NAL unit",
+                format!("Dropped a truncated NAL unit at PTS={}", pts),
+            )?;
+        }
+        if upper_layers == 0 && self.nal_filter.upper_layers > 0 {
+            self.validator.warn(
+                "HEVC alpha",
+                "The video has a second coded layer, like the alpha of HEVC with alpha, \
+                passing through only the base layer without transparency"
+                    .to_string(),
+            )?;
+        }
+        self.stats
+            .record_frame(self.frame_data.len() - start, packet.is_key());
+        self.last_pts = Some(pts);
+
+        Ok(Some(pts))
+    }
+
+    fn resolve_timestamps(
+        &mut self,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(i64, i64), Box<dyn Error>> {
+        match (packet.pts(), packet.dts()) {
+            (Some(pts), Some(dts)) => Ok((pts, dts)),
+            (Some(pts), None) => {
+                self.validator
+                    .repair("missing DTS", format!("Missing DTS at PTS={}", pts))?;
+                Ok((pts, pts))
+            }
+            (None, Some(dts)) => {
+                self.validator
+                    .repair("missing PTS", format!("Missing PTS at DTS={}", dts))?;
+                Ok((dts, dts))
+            }
+            (None, None) => {
+                let pts = self.last_pts.map_or(0, |pts| pts + 1);
+                self.validator.repair(
+                    "missing PTS",
+                    format!("Missing PTS and DTS after PTS={}", pts - 1),
+                )?;
+                Ok((pts, pts))
+            }
+        }
+    }
+
+    fn check_keyframe_interval(
+        &mut self,
+        from_pts: i64,
+        to_pts: i64,
+    ) -> Result<(), Box<dyn Error>> {
+        let interval = self.pts_to_seconds(to_pts - from_pts);
+        if self.max_keyframe_interval > 0.0 && interval > self.max_keyframe_interval {
+            self.validator.warn(
+                "long keyframe interval",
+                format!(
+                    "Keyframes are {:.1}s apart at PTS={}, which exceeds {}s and makes seeking slow. \
+                    Consider re-encoding with --force-keyframe-interval <seconds>",
+                    interval, from_pts, self.max_keyframe_interval
+                ),
+            )?;
         }
         Ok(())
     }
 
+    /// Runs the checks that need the whole stream, once all packets were processed
+    pub fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if let (Some(last_keyframe_pts), Some(last_pts)) = (self.last_keyframe_pts, self.last_pts) {
+            self.check_keyframe_interval(last_keyframe_pts, last_pts)?;
+        }
+        Ok(())
+    }
+
+    fn pts_to_seconds(&self, pts: i64) -> f64 {
+        pts as f64 * self.time_base_num as f64 / self.time_base_den as f64
+    }
+
     pub fn get_timestamp(&self, pts: i64) -> u64 {
-        (pts as f64 * self.time_base_num as f64 / self.time_base_den as f64 * 1_000_000_000.0)
-            as u64
+        log_time_ns(pts, self.time_base(), &self.frame_timestamps)
     }
 
-    pub fn check_timestamp(&mut self, timestamp_ns: u64) -> Result<(), Box<dyn Error>> {
+    /// Moves a demuxed packet of the video by the pause before its edit list
+    /// segment. Called before any other shift of the timestamps, as the
+    /// segments are on the timeline of the file. The timestamps from
+    /// --timestamps replace that timeline, pauses and all.
+    pub fn apply_edit_gaps(&self, packet: &mut ffmpeg::Packet) {
+        if self.frame_timestamps.is_empty() {
+            self.edit_gaps.shift_packet(packet, self.time_base());
+        }
+    }
+
+    /// A PTS of the file moved like its packet by `apply_edit_gaps`
+    pub fn with_edit_gaps(&self, pts: i64) -> i64 {
+        if self.frame_timestamps.is_empty() {
+            self.edit_gaps.shift_ticks(pts, self.time_base())
+        } else {
+            pts
+        }
+    }
+
+    /// Time base of the PTS and DTS of the video packets
+    pub fn time_base(&self) -> ffmpeg::Rational {
+        ffmpeg::Rational::new(self.time_base_num, self.time_base_den)
+    }
+
+    /// Returns the timestamp to write, bumped past the previous one if the
+    /// input went backwards and repairs are allowed.
+    pub fn check_timestamp(&mut self, timestamp_ns: u64) -> Result<u64, Box<dyn Error>> {
+        let mut timestamp_ns = timestamp_ns;
         if timestamp_ns <= self.last_timestamp && self.last_timestamp != u64::MAX {
-            return Err(format!(
-                "Non-monotonic or duplicate timestamp detected! Current: {}ns, Last: {}ns",
-                timestamp_ns, self.last_timestamp
-            )
-            .into());
+            self.validator.repair(
+                "non-monotonic timestamp",
+                format!(
+                    "Non-monotonic or duplicate timestamp detected! Current: {}ns, Last: {}ns",
+                    timestamp_ns, self.last_timestamp
+                ),
+            )?;
+            timestamp_ns = self.last_timestamp + 1;
         }
         self.last_timestamp = timestamp_ns;
-        Ok(())
+        Ok(timestamp_ns)
     }
 
     pub fn update_progress(&mut self, timestamp_ns: u64) -> bool {
@@ -398,17 +1389,73 @@ impl VideoConverter {
         }
     }
 
-    pub fn take_frame_data(&mut self) -> Vec<u8> {
-        let mut frame_data = Vec::new();
-        for packet_data in self.frame_packets.iter() {
-            frame_data.extend_from_slice(packet_data);
-        }
-        self.frame_packets.clear();
-        frame_data
+    /// Writes the parameter sets again before the next frame, as at the start
+    pub fn rewrite_parameter_sets(&mut self) {
+        self.wrote_parameter_sets = false;
+    }
+
+    /// Size of the data taken by the next `take_frame_data`
+    pub fn pending_frame_bytes(&self) -> usize {
+        self.frame_data.len()
+    }
+
+    /// Drops the data queued after the first `len` bytes
+    pub fn truncate_frame_data(&mut self, len: usize) {
+        self.frame_data.truncate(len);
+    }
+
+    /// The data of the packets processed since the last call, as one frame,
+    /// with a packet holding the timestamps and flags of the first of them
+    pub fn take_frame_data(&mut self) -> (ffmpeg::Packet, Bytes) {
+        let packet = std::mem::replace(&mut self.frame_packet, ffmpeg::Packet::empty());
+        (packet, self.frame_data.split().freeze())
     }
 
     pub fn format_str(&self) -> &'static str {
-        self.codec_type.format_str()
+        self.output_codec().format_str()
+    }
+
+    /// Properties of the video recorded on the MCAP channel
+    pub fn channel_metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Codec of the written stream
+    pub fn output_codec(&self) -> CodecType {
+        match &self.transcoder {
+            Some(transcoder) => transcoder.codec_type(),
+            None => self.codec_type,
+        }
+    }
+
+    /// What the SPS of the written stream says about its pictures, only known
+    /// when passing the source through
+    pub fn sps_info(&self) -> Option<SpsInfo> {
+        if self.transcoder.is_some() {
+            return None;
+        }
+        self.parameter_sets.sps_info(self.codec_type)
+    }
+
+    /// Packets dropped because they precede the first keyframe
+    pub fn skipped_leading(&self) -> usize {
+        self.skipped_leading
+    }
+
+    pub fn stripped_nal_units(&self) -> usize {
+        self.nal_filter.stripped
+    }
+
+    pub fn validator(&self) -> &Validator {
+        &self.validator
+    }
+
+    pub fn validator_mut(&mut self) -> &mut Validator {
+        &mut self.validator
     }
 }
 
@@ -431,6 +1478,8 @@ mod tests {
             CodecType::H265
         );
         assert!(CodecType::from_ffmpeg_id(ffmpeg::codec::Id::VP9).is_err());
+        assert!(is_legacy_codec(ffmpeg::codec::Id::MPEG2VIDEO));
+        assert!(!is_legacy_codec(ffmpeg::codec::Id::VP9));
     }
 
     #[test]
@@ -439,6 +1488,14 @@ mod tests {
         assert_eq!(CodecType::H265.format_str(), "h265");
         assert_eq!(CodecType::H264.encoder_lib(), "libx264");
         assert_eq!(CodecType::H265.encoder_lib(), "libx265");
+        assert_eq!("h264".parse::<CodecType>(), Ok(CodecType::H264));
+        assert_eq!("HEVC".parse::<CodecType>(), Ok(CodecType::H265));
+        assert_eq!("av1".parse::<CodecType>(), Ok(CodecType::Av1));
+        assert_eq!(CodecType::Av1.format_str(), "av1");
+        assert_eq!(CodecType::Av1.encoder_libs(), ["libsvtav1", "libaom-av1"]);
+        assert_eq!(CodecType::H264.nal_type(0x65), Some(5));
+        assert_eq!(CodecType::Av1.nal_type(0x65), None);
+        assert!("vp9".parse::<CodecType>().is_err());
     }
 
     #[test]
@@ -463,6 +1520,7 @@ mod tests {
             vps: vec![],
             sps: vec![1],
             pps: vec![2],
+            nal_length_size: 4,
         };
         assert!(valid_h264.validate(CodecType::H264).is_ok());
 
@@ -470,6 +1528,7 @@ mod tests {
             vps: vec![1],
             sps: vec![2],
             pps: vec![3],
+            nal_length_size: 4,
         };
         assert!(valid_h265.validate(CodecType::H265).is_ok());
 
@@ -477,6 +1536,7 @@ mod tests {
             vps: vec![],
             sps: vec![],
             pps: vec![],
+            nal_length_size: 4,
         };
         assert!(invalid_h264.validate(CodecType::H264).is_err());
 
@@ -484,8 +1544,12 @@ mod tests {
             vps: vec![],
             sps: vec![1],
             pps: vec![2],
+            nal_length_size: 4,
         };
         assert!(invalid_h265.validate(CodecType::H265).is_err());
+        assert!(invalid_h265.lacks_only_vps(CodecType::H265));
+        assert!(!valid_h265.lacks_only_vps(CodecType::H265));
+        assert!(!invalid_h264.lacks_only_vps(CodecType::H264));
     }
 
     #[test]
@@ -498,12 +1562,145 @@ mod tests {
             0x07, 0x08, 0x09, // SPS NAL (should be skipped)
         ];
 
-        let output = convert_to_annex_b(&input, CodecType::H264);
+        let mut output = Vec::new();
+        assert!(append_annex_b(
+            &input,
+            CodecType::H264,
+            4,
+            &mut NalFilter::default(),
+            &mut Stats::default(),
+            &mut output
+        ));
         assert_eq!(&output[..4], &[0x00, 0x00, 0x00, 0x01]); // Start code
         assert_eq!(&output[4..9], &[0x01, 0x02, 0x03, 0x04, 0x05]);
         assert_eq!(output.len(), 9); // Second NAL should be skipped
     }
 
+    #[test]
+    fn test_parameter_set_mode() {
+        assert_eq!(
+            "first-only".parse::<ParameterSetMode>(),
+            Ok(ParameterSetMode::FirstOnly)
+        );
+        assert_eq!(
+            "every-keyframe".parse::<ParameterSetMode>(),
+            Ok(ParameterSetMode::EveryKeyframe)
+        );
+        assert!("never".parse::<ParameterSetMode>().is_err());
+    }
+
+    #[test]
+    fn test_all_intra() {
+        let mut all_intra = AllIntra::default();
+        let found: Vec<bool> = (0..AllIntra::FRAMES + 1)
+            .map(|_| all_intra.add(true))
+            .collect();
+        assert_eq!(
+            found.iter().position(|&found| found),
+            Some(AllIntra::FRAMES - 1)
+        );
+        assert!(all_intra.detected());
+        // A frame that isn't a keyframe ends it for good
+        all_intra.add(false);
+        all_intra.add(true);
+        assert!(!all_intra.detected());
+    }
+
+    #[test]
+    fn test_nal_filter_keep_sei() {
+        let sei = [CodecType::H264_NAL_SEI, 5, 1, 0xAA, 0x80];
+        let slice = [0x01, 0x02];
+        let mut strip = NalFilter::default();
+        assert!(!strip.keep(CodecType::H264, &sei));
+        assert!(strip.keep(CodecType::H264, &slice));
+
+        let mut keep_all = NalFilter {
+            keep_sei: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(keep_all.keep(CodecType::H264, &sei));
+
+        let mut keep_some = NalFilter {
+            keep_sei: Some(vec![4, 5]),
+            ..Default::default()
+        };
+        assert!(keep_some.keep(CodecType::H264, &sei));
+        let mut keep_other = NalFilter {
+            keep_sei: Some(vec![137]),
+            ..Default::default()
+        };
+        assert!(!keep_other.keep(CodecType::H264, &sei));
+
+        let aud = [CodecType::H264_NAL_AUD, 0xF0];
+        assert!(strip.keep(CodecType::H264, &aud));
+        strip.strip_aud_filler = true;
+        assert!(!strip.keep(CodecType::H264, &aud));
+        assert!(!strip.keep(CodecType::H264, &[CodecType::H264_NAL_FILLER, 0xFF, 0x80]));
+        assert!(strip.keep(CodecType::H264, &slice));
+        assert_eq!(strip.stripped, 2);
+
+        // H.265 SEI has a two byte header
+        let sei = [CodecType::H265_NAL_SEI << 1, 0x01, 137, 0, 0x80];
+        assert!(keep_other.keep(CodecType::H265, &sei));
+
+        // The alpha layer of HEVC with alpha has nuh_layer_id 1
+        let alpha_slice = [0x02, 0x09, 0xAF];
+        assert_eq!(CodecType::H265.layer_id(&alpha_slice), 1);
+        assert!(!strip.keep(CodecType::H265, &alpha_slice));
+        assert!(strip.keep(CodecType::H265, &[0x02, 0x01, 0xAF]));
+        assert_eq!(strip.upper_layers, 1);
+        assert_eq!(CodecType::H264.layer_id(&alpha_slice), 0);
+    }
+
+    #[test]
+    fn test_append_annex_b_length_sizes() {
+        let mut expected = vec![0x00, 0x00, 0x00, 0x01];
+        expected.extend_from_slice(&[0x65; 300]);
+
+        for length_size in [1, 2, 4] {
+            let nal_size = if length_size == 1 { 200 } else { 300 };
+            let mut input = (nal_size as u32).to_be_bytes()[4 - length_size..].to_vec();
+            input.extend_from_slice(&[0x65; 300][..nal_size]);
+
+            let mut output = Vec::new();
+            assert!(append_annex_b(
+                &input,
+                CodecType::H264,
+                length_size,
+                &mut NalFilter::default(),
+                &mut Stats::default(),
+                &mut output
+            ));
+            assert_eq!(
+                output,
+                expected[..4 + nal_size],
+                "{} byte prefix",
+                length_size
+            );
+        }
+    }
+
+    #[test]
+    fn test_append_annex_b_truncated() {
+        let input = vec![
+            0x00, 0x00, 0x00, 0x02, // NAL size
+            0x01, 0x02, // NAL data
+            0x00, 0x00, 0x00, 0x05, // NAL size larger than the remaining data
+            0x01, 0x02,
+        ];
+
+        let mut output = Vec::new();
+        assert!(!append_annex_b(
+            &input,
+            CodecType::H264,
+            4,
+            &mut NalFilter::default(),
+            &mut Stats::default(),
+            &mut output
+        ));
+        assert_eq!(output, vec![0x00, 0x00, 0x00, 0x01, 0x01, 0x02]);
+    }
+
     #[test]
     fn test_parameter_sets_parse() {
         // Test AVCC parsing (H.264)
@@ -520,6 +1717,16 @@ mod tests {
         assert!(params.vps.is_empty());
         assert!(!params.sps.is_empty());
         assert!(!params.pps.is_empty());
+        assert_eq!(params.nal_length_size, 1);
+
+        let mut avcc = avcc;
+        for (byte, size) in [(0xFD, 2), (0xFF, 4)] {
+            avcc[4] = byte;
+            let params = ParameterSets::parse(&avcc, CodecType::H264).unwrap();
+            assert_eq!(params.nal_length_size, size);
+        }
+        avcc[4] = 0xFE;
+        assert!(ParameterSets::parse(&avcc, CodecType::H264).is_err());
     }
 
     #[test]
@@ -533,6 +1740,40 @@ mod tests {
         assert_eq!(timestamp, 1_000_000_000); // 1 second
     }
 
+    #[test]
+    fn test_nearest_listed() {
+        let timestamps = BTreeMap::from([(100, 1000), (200, 2000)]);
+        assert_eq!(nearest_listed(&timestamps, 190), Some((200, 2000)));
+        assert_eq!(nearest_listed(&timestamps, 110), Some((100, 1000)));
+        assert_eq!(nearest_listed(&timestamps, 150), Some((100, 1000)));
+        assert_eq!(nearest_listed(&timestamps, 50), Some((100, 1000)));
+        assert_eq!(nearest_listed(&timestamps, 300), Some((200, 2000)));
+        assert_eq!(nearest_listed(&BTreeMap::new(), 0), None);
+    }
+
+    #[test]
+    fn test_log_time_ns() {
+        // 2024-01-01T00:00:00.000000001Z in nanoseconds, past 2^53
+        let pts = 1_704_067_200_000_000_001;
+        let time_base = ffmpeg::Rational::new(1, 1_000_000_000);
+        assert_eq!(
+            log_time_ns(pts, time_base, &BTreeMap::new()),
+            1_704_067_200_000_000_001
+        );
+        let timestamps = BTreeMap::from([(pts - 3, 1_704_067_200_000_000_000)]);
+        assert_eq!(
+            log_time_ns(pts, time_base, &timestamps),
+            1_704_067_200_000_000_003
+        );
+        // 90 kHz ticks
+        let time_base = ffmpeg::Rational::new(1, 90_000);
+        assert_eq!(
+            log_time_ns(153_366_048_000_001, time_base, &BTreeMap::new()),
+            1_704_067_200_000_011_111
+        );
+        assert_eq!(log_time_ns(-1, time_base, &BTreeMap::new()), 0);
+    }
+
     #[test]
     fn test_timestamp_monotonicity() {
         let mut last_timestamp = u64::MAX;
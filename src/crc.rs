@@ -0,0 +1,171 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// CRC-32 as MCAP uses it, the IEEE polynomial reflected
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// A CRC-32 computed over data as it comes
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self(!0)
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub const OP_FOOTER: u8 = 0x02;
+const OP_DATA_END: u8 = 0x0F;
+/// Opcode, length and the summary start, summary offset start and CRC
+const FOOTER_SIZE: u64 = 1 + 8 + 20;
+/// Opcode, length and the data section CRC
+pub const DATA_END_SIZE: u64 = 1 + 8 + 4;
+const MAGIC_SIZE: u64 = 8;
+
+/// Where the Data End record of a finished MCAP starts, right before the
+/// summary, or the footer without one
+pub fn data_end_position<F: Read + Seek>(file: &mut F) -> Result<u64, Box<dyn Error>> {
+    let size = file.seek(SeekFrom::End(0))?;
+    let footer_position = size
+        .checked_sub(MAGIC_SIZE + FOOTER_SIZE)
+        .ok_or("The MCAP is too short to have a footer")?;
+    let mut footer = [0; FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(footer_position))?;
+    file.read_exact(&mut footer)?;
+    if footer[0] != OP_FOOTER {
+        return Err("The MCAP doesn't end in a footer".into());
+    }
+    let summary_start = u64::from_le_bytes(footer[9..17].try_into()?);
+    let data_end_position = match summary_start {
+        0 => footer_position,
+        start => start,
+    }
+    .checked_sub(DATA_END_SIZE)
+    .ok_or("The MCAP has no Data End record")?;
+
+    let mut data_end = [0; DATA_END_SIZE as usize];
+    file.seek(SeekFrom::Start(data_end_position))?;
+    file.read_exact(&mut data_end)?;
+    if data_end[0] != OP_DATA_END || u64::from_le_bytes(data_end[1..9].try_into()?) != 4 {
+        return Err("The MCAP has no Data End record before its summary".into());
+    }
+    Ok(data_end_position)
+}
+
+/// Fills in the data section CRC of a finished MCAP, which the writer
+/// leaves at zero, from everything before its Data End record. Returns the
+/// CRC.
+pub fn write_data_section_crc<F: Read + Write + Seek>(file: &mut F) -> Result<u32, Box<dyn Error>> {
+    let data_end_position = data_end_position(file)?;
+    let mut crc = Crc32::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut remaining = data_end_position;
+    file.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(1 << 16) as usize];
+        file.read_exact(chunk)?;
+        crc.update(chunk);
+        remaining -= chunk.len() as u64;
+    }
+    let crc = crc.finish();
+    file.seek(SeekFrom::Start(data_end_position + 9))?;
+    file.write_all(&crc.to_le_bytes())?;
+    file.flush()?;
+    Ok(crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use mcap::records::Record;
+    use mcap::sans_io::read::{LinearReader, LinearReaderOptions, ReadAction};
+
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_write_data_section_crc() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = mcap::Writer::new(&mut file).unwrap();
+        writer
+            .write_metadata(&mcap::records::Metadata {
+                name: "test".to_string(),
+                metadata: Default::default(),
+            })
+            .unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let crc = write_data_section_crc(&mut file).unwrap();
+        assert_ne!(crc, 0);
+        let data = file.into_inner();
+        assert!(mcap::read::LinearReader::new(&data).unwrap().any(
+            |record| matches!(record, Ok(Record::DataEnd(end)) if end.data_section_crc == crc)
+        ));
+
+        // A reader that checks the CRC accepts it, and rejects a corrupted file
+        assert!(validate(&data).is_ok());
+        let mut corrupted = data.clone();
+        let name = data.windows(4).position(|w| w == b"test").unwrap();
+        corrupted[name] ^= 1;
+        assert!(validate(&corrupted).is_err());
+    }
+
+    fn validate(data: &[u8]) -> mcap::McapResult<()> {
+        let options = LinearReaderOptions::default().with_validate_data_section_crc(true);
+        let mut reader = LinearReader::new_with_options(options);
+        let mut remaining = data;
+        while let Some(action) = reader.next_action() {
+            if let ReadAction::NeedMore(n) = action? {
+                let n = n.min(remaining.len());
+                reader.insert(n).copy_from_slice(&remaining[..n]);
+                reader.set_written(n);
+                remaining = &remaining[n..];
+            }
+        }
+        Ok(())
+    }
+}
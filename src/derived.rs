@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+use mcap::{records::MessageHeader, Writer};
+use prost::Message;
+
+use crate::codec::VideoConverter;
+use crate::foxglove::CompressedVideo;
+use crate::transcode::{TranscodeOptions, Transcoder};
+use crate::{setup_mcap_channel, to_timestamp};
+
+/// A copy of the video on a topic of its own, encoded from the same decoded
+/// frames after its own filters, like the preview or a half of a stereo pair
+pub struct DerivedStream {
+    pub topic: String,
+    transcoder: Transcoder,
+    channel_id: u16,
+    sequence: u32,
+    frame_id: String,
+    format: &'static str,
+    packet: ffmpeg::Packet,
+}
+
+impl DerivedStream {
+    /// `frame_rate` is that of the video after the filters, if they change it
+    pub fn new(
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &VideoConverter,
+        topic: &str,
+        frame_id: &str,
+        options: &TranscodeOptions,
+        filters: &[String],
+        frame_rate: Option<ffmpeg::Rational>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let transcoder = converter.derived_transcoder(options, filters, frame_rate)?;
+        let channel_id =
+            setup_mcap_channel(writer, topic, "foxglove.CompressedVideo", BTreeMap::new())?;
+        Ok(Self {
+            topic: topic.to_string(),
+            format: transcoder.codec_type().format_str(),
+            transcoder,
+            channel_id: channel_id.try_into()?,
+            sequence: 0,
+            frame_id: frame_id.to_string(),
+            packet: ffmpeg::Packet::empty(),
+        })
+    }
+
+    /// Encodes a decoded frame of the video, and writes what the encoder
+    /// has ready
+    pub fn send_frame(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &VideoConverter,
+        frame: &mut ffmpeg::frame::Video,
+    ) -> Result<(), Box<dyn Error>> {
+        self.transcoder.send_frame(frame)?;
+        self.write_packets(writer, converter)
+    }
+
+    pub fn finish(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &VideoConverter,
+    ) -> Result<(), Box<dyn Error>> {
+        self.transcoder.send_eof()?;
+        self.write_packets(writer, converter)
+    }
+
+    fn write_packets(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &VideoConverter,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            match self.transcoder.receive_packet(&mut self.packet) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(format!("Could not encode {}: {}", self.topic, e).into()),
+            }
+            let timestamp_ns = converter.get_timestamp(self.packet.pts().unwrap_or(0));
+            let message = CompressedVideo {
+                timestamp: Some(to_timestamp(timestamp_ns)),
+                frame_id: self.frame_id.clone(),
+                data: Bytes::copy_from_slice(self.packet.data().unwrap_or_default()),
+                format: self.format.to_string(),
+            };
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id: self.channel_id,
+                    sequence: self.sequence,
+                    log_time: timestamp_ns,
+                    publish_time: timestamp_ns,
+                },
+                &message.encode_to_vec(),
+            )?;
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+    }
+
+    pub fn messages(&self) -> u32 {
+        self.sequence
+    }
+}
@@ -0,0 +1,174 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::CodecType;
+use crate::{hash, nal, stream_info};
+
+/// Distance between the luma samples compared of decoded frames
+const STEP: usize = 4;
+/// Largest mean difference of the luma samples, out of 255, for two decoded
+/// frames to count as the same picture
+const MAX_MEAN_DIFFERENCE: f64 = 0.5;
+
+/// Every `STEP`th luma sample of every `STEP`th row, reduced to 8 bits
+fn thumbnail(plane: &[u8], stride: usize, width: usize, height: usize, bit_depth: u8) -> Vec<u8> {
+    let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+    plane
+        .chunks(stride)
+        .take(height)
+        .step_by(STEP)
+        .flat_map(|row| {
+            row.chunks_exact(bytes_per_sample)
+                .take(width)
+                .step_by(STEP)
+                .map(move |sample| match sample {
+                    [high] => *high,
+                    _ => (u16::from_le_bytes([sample[0], sample[1]]) >> (bit_depth - 8)).min(255)
+                        as u8,
+                })
+        })
+        .collect()
+}
+
+fn looks_same(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() || a.is_empty() {
+        return false;
+    }
+    let difference: u64 = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b) as u64).sum();
+    difference as f64 / a.len() as f64 <= MAX_MEAN_DIFFERENCE
+}
+
+/// Whether no other frame can reference a frame of the bitstream, as all
+/// its slices are non-reference ones: nal_ref_idc of 0 for H.264, and the
+/// even sub-layer non-reference types below 16 for H.265
+pub fn is_non_reference(data: &[u8], codec: CodecType) -> bool {
+    let mut slices = nal::split_annex_b(data)
+        .into_iter()
+        .filter_map(|unit| unit.first().copied())
+        .filter(|&header| match codec {
+            CodecType::H264 => matches!(codec.nal_type(header), Some(1..=5)),
+            CodecType::H265 => codec.nal_type(header).is_some_and(|nal_type| nal_type < 32),
+            CodecType::Av1 => false,
+        })
+        .peekable();
+    slices.peek().is_some()
+        && slices.all(|header| match codec {
+            CodecType::H264 => header & 0x60 == 0,
+            _ => codec
+                .nal_type(header)
+                .is_some_and(|nal_type| nal_type < 16 && nal_type & 1 == 0),
+        })
+}
+
+/// Spots frames that repeat the one before, as pipelines that keep a constant
+/// frame rate insert them
+#[derive(Default)]
+pub struct DuplicateDetector {
+    /// Length and hash of the data of the last frame
+    previous_data: Option<(usize, u64)>,
+    previous_thumbnail: Option<Vec<u8>>,
+    dropped: usize,
+}
+
+impl DuplicateDetector {
+    /// Whether the data of a frame is byte-identical to that of the last one
+    /// and the frame can be dropped, as no other frame references it
+    pub fn is_duplicate_data(&mut self, data: &[u8], droppable: bool) -> bool {
+        let current = (data.len(), hash::xxh64(data, 0));
+        let duplicate = self.previous_data.replace(current) == Some(current) && droppable;
+        self.dropped += duplicate as usize;
+        duplicate
+    }
+
+    /// Whether a decoded frame shows the same picture as the last one
+    pub fn is_duplicate_frame(&mut self, frame: &ffmpeg::frame::Video) -> bool {
+        let Some(bit_depth) = stream_info::bit_depth(frame.format()) else {
+            return false;
+        };
+        let current = thumbnail(
+            frame.data(0),
+            frame.stride(0),
+            frame.plane_width(0) as usize,
+            frame.plane_height(0) as usize,
+            bit_depth,
+        );
+        let duplicate = self
+            .previous_thumbnail
+            .as_ref()
+            .is_some_and(|previous| looks_same(previous, &current));
+        // Compared with the last kept frame, so a slow fade isn't dropped
+        if !duplicate {
+            self.previous_thumbnail = Some(current);
+        }
+        self.dropped += duplicate as usize;
+        duplicate
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_data() {
+        let mut detector = DuplicateDetector::default();
+        assert!(!detector.is_duplicate_data(b"frame 1", true));
+        assert!(detector.is_duplicate_data(b"frame 1", true));
+        assert!(!detector.is_duplicate_data(b"frame 2", true));
+        assert!(!detector.is_duplicate_data(b"frame 1", true));
+        // A repeat that other frames reference is kept
+        assert!(!detector.is_duplicate_data(b"frame 1", false));
+        assert_eq!(detector.dropped(), 1);
+    }
+
+    #[test]
+    fn test_is_non_reference() {
+        let annex_b = |units: &[&[u8]]| -> Vec<u8> {
+            units
+                .iter()
+                .flat_map(|unit| [&[0, 0, 0, 1][..], unit].concat())
+                .collect()
+        };
+        // H.264: a slice with nal_ref_idc 0 after an SEI, an IDR and a
+        // referenced slice
+        assert!(is_non_reference(
+            &annex_b(&[&[0x06, 1], &[0x01, 2]]),
+            CodecType::H264
+        ));
+        assert!(!is_non_reference(&annex_b(&[&[0x65, 1]]), CodecType::H264));
+        assert!(!is_non_reference(&annex_b(&[&[0x41, 1]]), CodecType::H264));
+        assert!(!is_non_reference(&annex_b(&[&[0x06, 1]]), CodecType::H264));
+        // H.265: TRAIL_N, then TRAIL_R and an IDR
+        assert!(is_non_reference(
+            &annex_b(&[&[0x00, 1, 2]]),
+            CodecType::H265
+        ));
+        assert!(!is_non_reference(
+            &annex_b(&[&[0x02, 1, 2]]),
+            CodecType::H265
+        ));
+        assert!(!is_non_reference(
+            &annex_b(&[&[0x26, 1, 2]]),
+            CodecType::H265
+        ));
+    }
+
+    #[test]
+    fn test_looks_same() {
+        // An 8x8 plane with a row stride of 10 is sampled at 2x2 points
+        let plane: Vec<u8> = (0..80).map(|i| i as u8).collect();
+        let a = thumbnail(&plane, 10, 8, 8, 8);
+        assert_eq!(a, [0, 4, 40, 44]);
+        let noisy: Vec<u8> = plane.iter().map(|v| v + 1).collect();
+        let b = thumbnail(&noisy, 10, 8, 8, 8);
+        assert!(!looks_same(&a, &b));
+        assert!(looks_same(&a, &[0, 4, 40, 45]));
+
+        // 10-bit samples keep their top 8 bits
+        let deep = thumbnail(&[0xFF, 0x03, 0, 0], 4, 2, 1, 10);
+        assert_eq!(deep, [255]);
+    }
+}
@@ -0,0 +1,90 @@
+/// Suffixes of a duration and nanoseconds per unit, `ms` and `min` before
+/// the `s` and `m` they end in or start with
+const UNITS: [(&str, f64); 5] = [
+    ("ms", 1e6),
+    ("min", 60e9),
+    ("h", 3600e9),
+    ("s", 1e9),
+    ("m", 60e9),
+];
+
+/// Nanoseconds in a duration like 1.5h, 10min, 2m, 90s or 500ms, or a plain
+/// number of seconds if `bare_seconds`. `None` for anything else, including
+/// negative and non-finite numbers.
+pub fn parse_ns(value: &str, bare_seconds: bool) -> Option<f64> {
+    let value = value.trim();
+    let (number, scale) = match UNITS
+        .iter()
+        .find_map(|(suffix, scale)| Some((value.strip_suffix(suffix)?, *scale)))
+    {
+        Some(unit) => unit,
+        None if bare_seconds => (value, 1e9),
+        None => return None,
+    };
+    let number: f64 = number.parse().ok()?;
+    (number >= 0.0 && number.is_finite()).then_some(number * scale)
+}
+
+/// Nanoseconds from a duration like 10min, 90s, 500ms or 1.5h, with
+/// --duration
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    match parse_ns(value, false) {
+        Some(ns) if ns > 0.0 => Ok(ns.round() as u64),
+        _ => Err(format!(
+            "Invalid duration {:?}, expected one like 10min, 90s, 500ms or 1h",
+            value
+        )),
+    }
+}
+
+/// Nanoseconds into the video from a time like 12.5s, 1500ms, 2min or 1h,
+/// or plain seconds, with --at
+pub fn parse_time(value: &str) -> Result<u64, String> {
+    parse_ns(value, true)
+        .map(|ns| ns.round() as u64)
+        .ok_or_else(|| {
+            format!(
+                "Invalid time {:?}, expected one like 12.5s, 1500ms or 2min",
+                value
+            )
+        })
+}
+
+/// A signed duration like +3.2s, -250ms or +1m
+pub fn parse_offset(value: &str) -> Option<i64> {
+    let (sign, magnitude) = match value.split_at_checked(1)? {
+        ("+", magnitude) => (1.0, magnitude),
+        ("-", magnitude) => (-1.0, magnitude),
+        _ => return None,
+    };
+    Some((sign * parse_ns(magnitude, false)?).round() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_duration("10min"), Ok(600_000_000_000));
+        assert_eq!(parse_duration("10m"), Ok(600_000_000_000));
+        assert_eq!(parse_duration("1.5s"), Ok(1_500_000_000));
+        assert_eq!(parse_duration("250ms"), Ok(250_000_000));
+        assert_eq!(parse_duration("2h"), Ok(7_200_000_000_000));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("0s").is_err());
+
+        assert_eq!(parse_time("12.5s"), Ok(12_500_000_000));
+        assert_eq!(parse_time("73"), Ok(73_000_000_000));
+        assert_eq!(parse_time("0ms"), Ok(0));
+        assert_eq!(parse_time("1h"), Ok(3_600_000_000_000));
+        assert!(parse_time("-1s").is_err());
+        assert!(parse_time("inf").is_err());
+
+        assert_eq!(parse_offset("+3.2s"), Some(3_200_000_000));
+        assert_eq!(parse_offset("-250ms"), Some(-250_000_000));
+        assert_eq!(parse_offset("+1min"), Some(60_000_000_000));
+        assert_eq!(parse_offset("3.2s"), None);
+        assert_eq!(parse_offset("+3.2"), None);
+    }
+}
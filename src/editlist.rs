@@ -0,0 +1,247 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::mp4box::{be_u32, be_u64, boxes, child, full_box, read_moov};
+use crate::{playlist, timesync};
+
+/// An entry of an edit list, in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Edit {
+    /// Length on the presentation timeline
+    duration_ns: u64,
+    /// Where the segment starts in the media, None for an empty edit
+    media_time_ns: Option<u64>,
+}
+
+/// The pauses between the segments of an edit list. FFmpeg plays the
+/// segments back to back, and these offsets move the frames of each
+/// segment back to where they are in the media.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditGaps {
+    /// (start on the FFmpeg timeline, offset) of the segments after a pause
+    segments: Vec<(u64, u64)>,
+}
+
+impl EditGaps {
+    fn from_edits(edits: &[Edit]) -> Self {
+        let mut segments = Vec::new();
+        let mut start_ns = 0;
+        let mut offset_ns = 0;
+        // Where the first segment starts on both timelines
+        let mut origin: Option<(u64, u64)> = None;
+        for edit in edits {
+            if let Some(media_time_ns) = edit.media_time_ns {
+                let (origin_start_ns, origin_media_ns) =
+                    *origin.get_or_insert((start_ns, media_time_ns));
+                // Segments that go back in the media stay where they are
+                let target_ns = (media_time_ns + origin_start_ns).saturating_sub(origin_media_ns);
+                if target_ns.saturating_sub(start_ns) > offset_ns {
+                    offset_ns = target_ns - start_ns;
+                    segments.push((start_ns, offset_ns));
+                }
+            }
+            start_ns += edit.duration_ns;
+        }
+        Self { segments }
+    }
+
+    /// How far to move a frame at `timestamp_ns` on the FFmpeg timeline
+    pub fn offset_ns(&self, timestamp_ns: u64) -> u64 {
+        self.segments
+            .iter()
+            .rev()
+            .find(|(start_ns, _)| *start_ns <= timestamp_ns)
+            .map_or(0, |(_, offset_ns)| *offset_ns)
+    }
+
+    /// Moves a timestamp in `time_base`, as demuxed before any other shift,
+    /// by the pause before its segment
+    pub fn shift_ticks(&self, ticks: i64, time_base: ffmpeg::Rational) -> i64 {
+        if self.segments.is_empty() || ticks < 0 {
+            return ticks;
+        }
+        let offset_ns = self.offset_ns(playlist::ticks_to_ns(ticks, time_base) as u64);
+        ticks + timesync::offset_ticks(time_base, offset_ns as i64)
+    }
+
+    /// Moves the timestamps of a demuxed packet by the pause before the
+    /// segment of its PTS, both by as much to keep the decode order
+    pub fn shift_packet(&self, packet: &mut ffmpeg::Packet, time_base: ffmpeg::Rational) {
+        let Some(ticks) = packet.pts().or(packet.dts()) else {
+            return;
+        };
+        let offset = self.shift_ticks(ticks, time_base) - ticks;
+        if offset != 0 {
+            packet.set_pts(packet.pts().map(|pts| pts + offset));
+            packet.set_dts(packet.dts().map(|dts| dts + offset));
+        }
+    }
+
+    pub fn pauses(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn total_ns(&self) -> u64 {
+        self.segments.last().map_or(0, |(_, offset_ns)| *offset_ns)
+    }
+}
+
+fn to_ns(value: u64, timescale: u32) -> u64 {
+    (value as u128 * 1_000_000_000 / timescale.max(1) as u128) as u64
+}
+
+/// The field after the creation and modification times, which is the
+/// timescale in mvhd and mdhd and the track ID in tkhd
+fn field_after_times(payload: &[u8]) -> Option<u32> {
+    let (version, fields) = full_box(payload)?;
+    be_u32(fields, if version == 1 { 16 } else { 8 })
+}
+
+fn parse_elst(payload: &[u8], movie_timescale: u32, media_timescale: u32) -> Option<Vec<Edit>> {
+    let (version, fields) = full_box(payload)?;
+    let entry_size = if version == 1 { 20 } else { 12 };
+    let count = be_u32(fields, 0)? as usize;
+    (0..count)
+        .map(|index| {
+            let pos = 4 + index * entry_size;
+            let (duration, media_time) = if version == 1 {
+                (be_u64(fields, pos)?, be_u64(fields, pos + 8)? as i64)
+            } else {
+                (
+                    be_u32(fields, pos)? as u64,
+                    be_u32(fields, pos + 4)? as i32 as i64,
+                )
+            };
+            Some(Edit {
+                duration_ns: to_ns(duration, movie_timescale),
+                media_time_ns: u64::try_from(media_time)
+                    .ok()
+                    .map(|media_time| to_ns(media_time, media_timescale)),
+            })
+        })
+        .collect()
+}
+
+/// The edit list of the track with `track_id` in a moov box
+fn track_edits(moov: &[u8], track_id: u32) -> Option<Vec<Edit>> {
+    let moov = boxes(moov);
+    let movie_timescale = child(&moov, b"mvhd").and_then(field_after_times)?;
+    moov.iter()
+        .filter(|(box_type, _)| box_type == b"trak")
+        .map(|(_, trak)| boxes(trak))
+        .find(|trak| child(trak, b"tkhd").and_then(field_after_times) == Some(track_id))
+        .and_then(|trak| {
+            let mdia = boxes(child(&trak, b"mdia")?);
+            let media_timescale = child(&mdia, b"mdhd").and_then(field_after_times)?;
+            let elst = child(&boxes(child(&trak, b"edts")?), b"elst")?;
+            parse_elst(elst, movie_timescale, media_timescale)
+        })
+}
+
+/// The pauses in the edit list of the track with `track_id`, none if the
+/// file has no moov box or the track no edit list
+pub fn read(path: &Path, track_id: i32) -> Result<EditGaps, Box<dyn Error>> {
+    let mut file = File::open(path).map_err(|e| format!("Could not open {:?}: {}", path, e))?;
+    let edits = read_moov(&mut file)?
+        .zip(u32::try_from(track_id).ok())
+        .and_then(|(moov, track_id)| track_edits(&moov, track_id))
+        .unwrap_or_default();
+    Ok(EditGaps::from_edits(&edits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// A version 0 full box with the field after the times
+    fn header_box(box_type: &[u8; 4], value: u32) -> Vec<u8> {
+        let mut payload = vec![0; 12];
+        payload.extend_from_slice(&value.to_be_bytes());
+        make_box(box_type, &payload)
+    }
+
+    #[test]
+    fn test_track_edits() {
+        // Movie timescale 1000, media timescale 90000: 2 s from the start,
+        // a pause of 5 s, then 3 s
+        let mut elst = vec![0, 0, 0, 0, 0, 0, 0, 2];
+        for (duration, media_time) in [(2000u32, 0u32), (3000, 630_000)] {
+            elst.extend_from_slice(&duration.to_be_bytes());
+            elst.extend_from_slice(&media_time.to_be_bytes());
+            elst.extend_from_slice(&[0, 1, 0, 0]);
+        }
+        let trak = [
+            header_box(b"tkhd", 1),
+            make_box(b"edts", &make_box(b"elst", &elst)),
+            make_box(b"mdia", &header_box(b"mdhd", 90000)),
+        ]
+        .concat();
+        let moov = [header_box(b"mvhd", 1000), make_box(b"trak", &trak)].concat();
+
+        let edits = track_edits(&moov, 1).unwrap();
+        assert_eq!(
+            edits[1],
+            Edit {
+                duration_ns: 3_000_000_000,
+                media_time_ns: Some(7_000_000_000),
+            }
+        );
+        assert_eq!(track_edits(&moov, 2), None);
+
+        let gaps = EditGaps::from_edits(&edits);
+        assert_eq!(gaps.pauses(), 1);
+        assert_eq!(gaps.offset_ns(1_999_999_999), 0);
+        assert_eq!(gaps.offset_ns(2_000_000_000), 5_000_000_000);
+        assert_eq!(gaps.total_ns(), 5_000_000_000);
+    }
+
+    #[test]
+    fn test_shift_with_sync_offset() {
+        // A pause of 5 s after the first 2 s
+        let gaps = EditGaps {
+            segments: vec![(2_000_000_000, 5_000_000_000)],
+        };
+        let time_base = ffmpeg::Rational::new(1, 90000);
+        // Moved onto the wall clock after the pauses, like --sync-to does
+        let sync_ticks = timesync::offset_ticks(time_base, 1_700_000_000_000_000_000);
+        let shifted = |pts: i64| gaps.shift_ticks(pts, time_base) + sync_ticks;
+        assert_eq!(shifted(90_000), 90_000 + sync_ticks);
+        assert_eq!(shifted(270_000), 720_000 + sync_ticks);
+        // The order of the frames either side of the pause is kept
+        assert!(shifted(179_999) < shifted(180_000));
+        assert_eq!(gaps.shift_ticks(-3_000, time_base), -3_000);
+    }
+
+    #[test]
+    fn test_from_edits() {
+        let edit = |duration_s: u64, media_time_s: Option<u64>| Edit {
+            duration_ns: duration_s * 1_000_000_000,
+            media_time_ns: media_time_s.map(|time| time * 1_000_000_000),
+        };
+        // A leading empty edit delays the start, which FFmpeg already does
+        let gaps = EditGaps::from_edits(&[edit(1, None), edit(2, Some(0)), edit(2, Some(2))]);
+        assert_eq!(gaps, EditGaps::default());
+
+        let gaps = EditGaps::from_edits(&[
+            edit(2, Some(0)),
+            edit(2, Some(4)),
+            edit(1, Some(1)),
+            edit(1, Some(10)),
+        ]);
+        assert_eq!(gaps.offset_ns(3_000_000_000), 2_000_000_000);
+        // Going back in the media keeps the offset
+        assert_eq!(gaps.offset_ns(4_500_000_000), 2_000_000_000);
+        assert_eq!(gaps.offset_ns(5_000_000_000), 5_000_000_000);
+        assert_eq!(gaps.pauses(), 2);
+    }
+}
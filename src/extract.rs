@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::{ConverterOptions, NalFilter, VideoConverter};
+use crate::duration;
+use crate::image::{ImageEncoder, ImageFormat};
+use crate::playlist;
+use crate::stream_info;
+use crate::validation::Strictness;
+
+/// How often `mp42mcap extract` writes a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Every {
+    /// The first frame at or after each multiple of this many nanoseconds
+    Interval(u64),
+    /// Every Nth frame
+    Frames(u64),
+}
+
+impl std::str::FromStr for Every {
+    type Err = String;
+
+    /// Parses a duration like 1s, 500ms, 2m, 1h or 1.5 for seconds, or a frame
+    /// count like 10f
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid interval {:?}, expected a duration like 1s, 500ms or 2m, or frames like 10f",
+                s
+            )
+        };
+        let s = s.trim();
+        if let Some(frames) = s.strip_suffix('f') {
+            return match frames.parse::<u64>() {
+                Ok(frames) if frames > 0 => Ok(Every::Frames(frames)),
+                _ => Err(invalid()),
+            };
+        }
+        match duration::parse_ns(s, true) {
+            Some(ns) if ns > 0.0 => Ok(Every::Interval(ns.round().max(1.0) as u64)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Picks the frames to write
+struct Selector {
+    every: Every,
+    next_ns: u64,
+}
+
+impl Selector {
+    fn select(&mut self, index: u64, timestamp_ns: u64) -> bool {
+        match self.every {
+            Every::Frames(frames) => index.is_multiple_of(frames),
+            Every::Interval(interval_ns) => {
+                if timestamp_ns < self.next_ns {
+                    return false;
+                }
+                // Skips ahead over gaps longer than the interval
+                self.next_ns = (timestamp_ns / interval_ns + 1) * interval_ns;
+                true
+            }
+        }
+    }
+}
+
+/// Decodes the video stream of `input` and writes the chosen frames as
+/// images to `directory`, named after their frame index. Returns the number
+/// of images written.
+pub fn extract(
+    input_path: &Path,
+    every: Every,
+    directory: &Path,
+    format: ImageFormat,
+    quality: u32,
+) -> Result<usize, Box<dyn Error>> {
+    std::fs::create_dir_all(directory)
+        .map_err(|e| format!("Could not create {:?}: {}", directory, e))?;
+    let options = ConverterOptions {
+        // Stills are written from whatever frames decode
+        strictness: Strictness::Lenient,
+        max_keyframe_interval: 0.0,
+        transcode: None,
+        auto_transcode: false,
+        apply_rotation: false,
+        nal_filter: NalFilter::default(),
+        parameter_sets: None,
+        quality_metrics: false,
+        threads: 0,
+        max_memory: None,
+        capture: None,
+        input_options: Vec::new(),
+        frame_timestamps: None,
+        collapse_edit_gaps: true,
+    };
+    let (mut converter, mut input) = VideoConverter::new(input_path, None, &options)?;
+    let stream = stream_info::best_video(&input)?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+
+    let mut images = Images {
+        encoder: ImageEncoder::new(format, quality, false),
+        selector: Selector { every, next_ns: 0 },
+        directory,
+        index: 0,
+        written: 0,
+    };
+    let mut frame = ffmpeg::frame::Video::empty();
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        match converter.send_packet(&packet) {
+            Ok(()) => {}
+            // The frames around it still make good stills
+            Err(ffmpeg::Error::InvalidData) => {
+                warn!("Skipped an undecodable packet at PTS={:?}", packet.pts());
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        images.write_frames(&mut converter, &mut frame, time_base)?;
+    }
+    converter.send_eof()?;
+    images.write_frames(&mut converter, &mut frame, time_base)?;
+    Ok(images.written)
+}
+
+/// The stills written so far
+struct Images<'a> {
+    encoder: ImageEncoder,
+    selector: Selector,
+    directory: &'a Path,
+    /// Index of the next frame out of the decoder
+    index: u64,
+    written: usize,
+}
+
+impl Images<'_> {
+    /// Writes the chosen frames of those the decoder has ready
+    fn write_frames(
+        &mut self,
+        converter: &mut VideoConverter,
+        frame: &mut ffmpeg::frame::Video,
+        time_base: ffmpeg::Rational,
+    ) -> Result<(), Box<dyn Error>> {
+        loop {
+            match converter.receive_frame(frame) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            let pts = frame.timestamp().or(frame.pts()).unwrap_or(0).max(0);
+            let timestamp_ns = playlist::ticks_to_ns(pts, time_base) as u64;
+            if self.selector.select(self.index, timestamp_ns) {
+                let path = self.directory.join(format!(
+                    "frame_{:06}.{}",
+                    self.index,
+                    self.encoder.format.extension()
+                ));
+                std::fs::write(&path, self.encoder.encode(frame)?)
+                    .map_err(|e| format!("Could not write {:?}: {}", path, e))?;
+                self.written += 1;
+            }
+            self.index += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every() {
+        assert_eq!("1s".parse(), Ok(Every::Interval(1_000_000_000)));
+        assert_eq!("500ms".parse(), Ok(Every::Interval(500_000_000)));
+        assert_eq!("1.5".parse(), Ok(Every::Interval(1_500_000_000)));
+        assert_eq!("2m".parse(), Ok(Every::Interval(120_000_000_000)));
+        assert_eq!("10f".parse(), Ok(Every::Frames(10)));
+        assert!("0f".parse::<Every>().is_err());
+        assert!("-1s".parse::<Every>().is_err());
+
+        let mut selector = Selector {
+            every: Every::Interval(1_000_000_000),
+            next_ns: 0,
+        };
+        let selected: Vec<u64> = [0, 400, 800, 1_200, 1_600, 2_000, 5_100]
+            .into_iter()
+            .enumerate()
+            .filter(|&(index, ms)| selector.select(index as u64, ms * 1_000_000))
+            .map(|(_, ms)| ms)
+            .collect();
+        assert_eq!(selected, [0, 1_200, 2_000, 5_100]);
+    }
+}
@@ -0,0 +1,222 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use ffmpeg_next as ffmpeg;
+
+use crate::summary::json_string;
+
+/// How a failed conversion is reported, with --error-format
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorFormat {
+    /// The message, as it is
+    #[default]
+    Text,
+    /// A JSON object on stderr
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            other => Err(format!(
+                "Unsupported error format {:?}, expected text or json",
+                other
+            )),
+        }
+    }
+}
+
+/// An error raised by the conversion itself, with a code to tell it from
+/// others by and what to do about it
+pub struct Failure {
+    pub code: &'static str,
+    pub message: String,
+    pub remediation: Option<&'static str>,
+}
+
+/// What to do about an I/O error
+const IO_REMEDIATION: &str =
+    "Check that the input exists, that the output can be written and that there is free space";
+
+impl Failure {
+    pub fn new(code: &'static str, message: String, remediation: &'static str) -> Self {
+        Self {
+            code,
+            message,
+            remediation: Some(remediation),
+        }
+    }
+
+    /// An option that is invalid or can't be combined with the others
+    pub fn usage(message: String) -> Self {
+        Self::new("usage", message, "Check the options with --help")
+    }
+
+    /// A file given by the options that can't be read or written
+    pub fn io(message: String) -> Self {
+        Self::new("io", message, IO_REMEDIATION)
+    }
+
+    /// A file given by the options, like --calibration, that can't be made
+    /// out
+    pub fn invalid_file(message: String) -> Self {
+        Self::new(
+            "invalid-file",
+            message,
+            "Check the file against the format the README gives for it",
+        )
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Printed like the string errors when main returns one
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// Video packets read from the input so far
+static PACKETS: AtomicU64 = AtomicU64::new(0);
+/// Timestamp of the last video packet read, i64::MIN before the first
+static TIMESTAMP_NS: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// Counts a video packet read from the input, for the error to point at
+pub fn read_video_packet(timestamp_ns: Option<i64>) {
+    PACKETS.fetch_add(1, Ordering::Relaxed);
+    if let Some(timestamp_ns) = timestamp_ns {
+        TIMESTAMP_NS.store(timestamp_ns, Ordering::Relaxed);
+    }
+}
+
+/// The code and the remediation of any error
+fn classify(error: &(dyn Error + 'static)) -> (&'static str, Option<&'static str>) {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        return (failure.code, failure.remediation);
+    }
+    if error.is::<std::io::Error>() {
+        return ("io", Some(IO_REMEDIATION));
+    }
+    if error.is::<clap::Error>() {
+        return ("usage", Some("Check the options with --help"));
+    }
+    if error.is::<mcap::McapError>() {
+        return ("mcap", None);
+    }
+    match error.downcast_ref::<ffmpeg::Error>() {
+        Some(ffmpeg::Error::InvalidData) => (
+            "corrupt-input",
+            Some("Check that the file is complete, or convert what can be read with --lenient"),
+        ),
+        Some(
+            ffmpeg::Error::DecoderNotFound
+            | ffmpeg::Error::DemuxerNotFound
+            | ffmpeg::Error::StreamNotFound,
+        ) => (
+            "unsupported-input",
+            Some("Check that the input has a video stream in a format FFmpeg was built with"),
+        ),
+        Some(_) => ("ffmpeg", None),
+        None => ("other", None),
+    }
+}
+
+/// The --error-format of arguments that clap rejected, and so has to be
+/// looked for among them by hand
+pub fn format_of_args(args: &[OsString]) -> ErrorFormat {
+    let json = args
+        .windows(2)
+        .any(|pair| pair[0] == "--error-format" && pair[1] == "json")
+        || args.iter().any(|arg| arg == "--error-format=json");
+    if json {
+        ErrorFormat::Json
+    } else {
+        ErrorFormat::Text
+    }
+}
+
+/// Prints the error as a JSON object on stderr and exits with status 1
+/// under --error-format json, or leaves it to be printed as text
+pub fn report(error: &(dyn Error + 'static), format: ErrorFormat) {
+    if format == ErrorFormat::Json {
+        eprintln!("{}", to_json(error));
+        std::process::exit(1);
+    }
+}
+
+/// The error as a JSON object, with the packet the conversion failed at
+pub fn to_json(error: &(dyn Error + 'static)) -> String {
+    let (code, remediation) = classify(error);
+    let packets = PACKETS.load(Ordering::Relaxed);
+    let timestamp_ns = TIMESTAMP_NS.load(Ordering::Relaxed);
+    format!(
+        "{{\"code\":{},\"message\":{},\"packet_index\":{},\"timestamp\":{},\"remediation\":{}}}",
+        json_string(code),
+        json_string(&error.to_string()),
+        packets
+            .checked_sub(1)
+            .map_or("null".to_string(), |index| index.to_string()),
+        Some(timestamp_ns)
+            .filter(|&t| t != i64::MIN)
+            .map_or("null".to_string(), |t| t.to_string()),
+        remediation.map_or("null".to_string(), json_string)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let failure: Box<dyn Error> = Failure::new(
+            "b-frames",
+            "This video contains B-frames".to_string(),
+            "Re-encode it",
+        )
+        .into();
+        assert_eq!(format!("{:?}", failure), "\"This video contains B-frames\"");
+        // Nothing read yet in this test
+        assert_eq!(
+            to_json(failure.as_ref()),
+            "{\"code\":\"b-frames\",\"message\":\"This video contains B-frames\",\
+            \"packet_index\":null,\"timestamp\":null,\"remediation\":\"Re-encode it\"}"
+        );
+
+        let io: Box<dyn Error> = std::io::Error::other("disk full").into();
+        assert!(to_json(io.as_ref()).starts_with("{\"code\":\"io\",\"message\":\"disk full\""));
+        let string: Box<dyn Error> = "--foo can't be used with --bar".into();
+        assert_eq!(classify(string.as_ref()), ("other", None));
+        let usage: Box<dyn Error> = Failure::usage("--foo can't be used with --bar".into()).into();
+        assert_eq!(classify(usage.as_ref()).0, "usage");
+        assert_eq!(classify(&ffmpeg::Error::InvalidData).0, "corrupt-input");
+        assert_eq!("json".parse::<ErrorFormat>(), Ok(ErrorFormat::Json));
+
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            format_of_args(&args(&["in.mp4", "--error-format", "json"])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            format_of_args(&args(&["--error-format=json", "--bogus"])),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            format_of_args(&args(&["--topic", "json"])),
+            ErrorFormat::Text
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+
+use ffmpeg_next as ffmpeg;
+
+/// A libavfilter graph with a single video input and output
+pub struct FrameFilter {
+    graph: ffmpeg::filter::Graph,
+    input_time_base: ffmpeg::Rational,
+}
+
+impl FrameFilter {
+    pub fn new(
+        spec: &str,
+        decoder: &ffmpeg::decoder::Video,
+        time_base: ffmpeg::Rational,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pix_fmt = decoder
+            .format()
+            .descriptor()
+            .ok_or("Unknown pixel format")?
+            .name();
+        let mut aspect_ratio = decoder.aspect_ratio();
+        if aspect_ratio.numerator() == 0 {
+            aspect_ratio = ffmpeg::Rational::new(1, 1);
+        }
+        let args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect={}/{}",
+            decoder.width(),
+            decoder.height(),
+            pix_fmt,
+            time_base.numerator(),
+            time_base.denominator(),
+            aspect_ratio.numerator(),
+            aspect_ratio.denominator()
+        );
+
+        let mut graph = ffmpeg::filter::Graph::new();
+        graph.add(
+            &ffmpeg::filter::find("buffer").ok_or("Filter buffer not found")?,
+            "in",
+            &args,
+        )?;
+        graph.add(
+            &ffmpeg::filter::find("buffersink").ok_or("Filter buffersink not found")?,
+            "out",
+            "",
+        )?;
+        graph
+            .output("in", 0)?
+            .input("out", 0)?
+            .parse(spec)
+            .map_err(|e| format!("Invalid filter graph {:?}: {}", spec, e))?;
+        graph.validate()?;
+
+        Ok(Self {
+            graph,
+            input_time_base: time_base,
+        })
+    }
+
+    pub fn push(&mut self, frame: &ffmpeg::frame::Video) -> Result<(), ffmpeg::Error> {
+        self.context("in").source().add(frame)
+    }
+
+    /// Receives the next filtered frame with its PTS in the input time base
+    pub fn pull(&mut self, frame: &mut ffmpeg::frame::Video) -> Result<(), ffmpeg::Error> {
+        let mut sink = self.context("out");
+        let mut sink = sink.sink();
+        sink.frame(frame)?;
+        let output_time_base = sink.time_base();
+        let pts = frame
+            .pts()
+            .map(|pts| rescale(pts, output_time_base, self.input_time_base));
+        frame.set_pts(pts);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ffmpeg::Error> {
+        self.context("in").source().flush()
+    }
+
+    fn context(&mut self, name: &str) -> ffmpeg::filter::Context {
+        // Both contexts are added in new()
+        self.graph.get(name).unwrap()
+    }
+}
+
+pub fn rescale(value: i64, from: ffmpeg::Rational, to: ffmpeg::Rational) -> i64 {
+    let numerator = value as i128 * from.numerator() as i128 * to.denominator() as i128;
+    let denominator = from.denominator() as i128 * to.numerator() as i128;
+    if denominator == 0 {
+        return value;
+    }
+    (numerator / denominator) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rescale() {
+        let ms = ffmpeg::Rational::new(1, 1000);
+        let mpeg = ffmpeg::Rational::new(1, 90000);
+        assert_eq!(rescale(1000, ms, mpeg), 90000);
+        assert_eq!(rescale(90000, mpeg, ms), 1000);
+        assert_eq!(rescale(5, ms, ms), 5);
+        assert_eq!(rescale(5, ms, ffmpeg::Rational::new(0, 1)), 5);
+    }
+}
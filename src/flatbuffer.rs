@@ -0,0 +1,356 @@
+/// How the video messages are serialized, with --message-encoding
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MessageEncoding {
+    #[default]
+    Protobuf,
+    Flatbuffer,
+}
+
+impl MessageEncoding {
+    /// The MCAP message and schema encoding
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageEncoding::Protobuf => "protobuf",
+            MessageEncoding::Flatbuffer => "flatbuffer",
+        }
+    }
+}
+
+impl std::str::FromStr for MessageEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "protobuf" => Ok(MessageEncoding::Protobuf),
+            "flatbuffer" => Ok(MessageEncoding::Flatbuffer),
+            other => Err(format!(
+                "Unsupported message encoding {:?}, expected protobuf or flatbuffer",
+                other
+            )),
+        }
+    }
+}
+
+/// A field of a table, by its ID in the schema
+enum Value<'a> {
+    Bool(bool),
+    Byte(u8),
+    UShort(u16),
+    Int(i32),
+    UInt(u32),
+    /// A struct inline in the table, aligned to four bytes
+    Struct(Vec<u8>),
+    String(&'a str),
+    Bytes(&'a [u8]),
+    Table(Table<'a>),
+    Tables(Vec<Table<'a>>),
+}
+
+impl Value<'_> {
+    /// Size and alignment inline in the table
+    fn layout(&self) -> (usize, usize) {
+        match self {
+            Value::Bool(_) | Value::Byte(_) => (1, 1),
+            Value::UShort(_) => (2, 2),
+            Value::Int(_) | Value::UInt(_) => (4, 4),
+            Value::Struct(bytes) => (bytes.len(), 4),
+            // Offsets to what follows the table
+            _ => (4, 4),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Table<'a> {
+    fields: Vec<(u16, Value<'a>)>,
+}
+
+impl<'a> Table<'a> {
+    fn with(mut self, id: u16, value: Value<'a>) -> Self {
+        self.fields.push((id, value));
+        self
+    }
+}
+
+/// Writes a buffer front to back, with everything a table refers to after
+/// it, so that every offset points forward as it must. The vtable of a table
+/// goes right before it.
+struct Builder {
+    buffer: Vec<u8>,
+}
+
+impl Builder {
+    fn pad_to(&mut self, align: usize) {
+        let padded = self.buffer.len().next_multiple_of(align);
+        self.buffer.resize(padded, 0);
+    }
+
+    /// Points the offset at `at` to `target`
+    fn set_offset(&mut self, at: usize, target: usize) {
+        self.buffer[at..at + 4].copy_from_slice(&((target - at) as u32).to_le_bytes());
+    }
+
+    /// Writes a vector of `len` elements and returns its position
+    fn vector(&mut self, len: usize, elements: &[u8]) -> usize {
+        self.pad_to(4);
+        let position = self.buffer.len();
+        self.buffer.extend_from_slice(&(len as u32).to_le_bytes());
+        self.buffer.extend_from_slice(elements);
+        position
+    }
+
+    fn string(&mut self, value: &str) -> usize {
+        let position = self.vector(value.len(), value.as_bytes());
+        self.buffer.push(0);
+        position
+    }
+
+    /// Writes a table and what it refers to, and returns its position
+    fn table(&mut self, table: Table) -> usize {
+        // Largest first, so fields are aligned with the least padding
+        let mut fields = table.fields;
+        fields.sort_by_key(|(_, value)| std::cmp::Reverse(value.layout().1));
+        let mut table_size: usize = 4;
+        let mut offsets = Vec::with_capacity(fields.len());
+        for (_, value) in &fields {
+            let (size, align) = value.layout();
+            table_size = table_size.next_multiple_of(align);
+            offsets.push(table_size);
+            table_size += size;
+        }
+
+        let slots = fields
+            .iter()
+            .map(|(id, _)| *id as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let mut vtable = vec![0u16; 2 + slots];
+        vtable[0] = (4 + 2 * slots) as u16;
+        vtable[1] = table_size as u16;
+        for ((id, _), offset) in fields.iter().zip(&offsets) {
+            vtable[2 + *id as usize] = *offset as u16;
+        }
+        self.pad_to(2);
+        let vtable_position = self.buffer.len();
+        for entry in vtable {
+            self.buffer.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        self.pad_to(4);
+        let position = self.buffer.len();
+        self.buffer.resize(position + table_size, 0);
+        let vtable_offset = (position - vtable_position) as i32;
+        self.buffer[position..position + 4].copy_from_slice(&vtable_offset.to_le_bytes());
+        let mut children = Vec::new();
+        for ((_, value), offset) in fields.into_iter().zip(offsets) {
+            let at = position + offset;
+            let inline = match &value {
+                Value::Bool(value) => vec![*value as u8],
+                Value::Byte(value) => vec![*value],
+                Value::UShort(value) => value.to_le_bytes().to_vec(),
+                Value::Int(value) => value.to_le_bytes().to_vec(),
+                Value::UInt(value) => value.to_le_bytes().to_vec(),
+                Value::Struct(bytes) => bytes.clone(),
+                _ => {
+                    children.push((at, value));
+                    continue;
+                }
+            };
+            self.buffer[at..at + inline.len()].copy_from_slice(&inline);
+        }
+
+        for (at, value) in children {
+            let target = match value {
+                Value::String(value) => self.string(value),
+                Value::Bytes(value) => self.vector(value.len(), value),
+                Value::Table(table) => self.table(table),
+                Value::Tables(tables) => {
+                    let vector = self.vector(tables.len(), &vec![0; 4 * tables.len()]);
+                    for (i, table) in tables.into_iter().enumerate() {
+                        let target = self.table(table);
+                        self.set_offset(vector + 4 + 4 * i, target);
+                    }
+                    vector
+                }
+                _ => unreachable!("inline values are written with the table"),
+            };
+            self.set_offset(at, target);
+        }
+        position
+    }
+
+    /// Finishes a buffer with `root` as its root table
+    fn finish(mut self, root: Table, identifier: Option<&[u8; 4]>) -> Vec<u8> {
+        self.buffer.extend_from_slice(&[0; 4]);
+        if let Some(identifier) = identifier {
+            self.buffer.extend_from_slice(identifier);
+        }
+        let root = self.table(root);
+        self.set_offset(0, root);
+        self.buffer
+    }
+}
+
+/// foxglove.Time
+fn time(timestamp_ns: u64) -> Vec<u8> {
+    let mut time = Vec::with_capacity(8);
+    time.extend_from_slice(&((timestamp_ns / 1_000_000_000) as u32).to_le_bytes());
+    time.extend_from_slice(&((timestamp_ns % 1_000_000_000) as u32).to_le_bytes());
+    time
+}
+
+/// Serializes a foxglove.CompressedVideo into `buffer`, replacing what was
+/// in it
+pub fn encode_compressed_video(
+    buffer: &mut Vec<u8>,
+    timestamp_ns: u64,
+    frame_id: &str,
+    data: &[u8],
+    format: &str,
+) {
+    buffer.clear();
+    buffer.reserve(64 + frame_id.len() + data.len() + format.len());
+    let builder = Builder {
+        buffer: std::mem::take(buffer),
+    };
+    let message = Table::default()
+        .with(0, Value::Struct(time(timestamp_ns)))
+        .with(1, Value::String(frame_id))
+        .with(2, Value::Bytes(data))
+        .with(3, Value::String(format));
+    *buffer = builder.finish(message, None);
+}
+
+// reflection.BaseType
+const BASE_TYPE_UBYTE: u8 = 4;
+const BASE_TYPE_UINT: u8 = 8;
+const BASE_TYPE_STRING: u8 = 13;
+const BASE_TYPE_VECTOR: u8 = 14;
+const BASE_TYPE_OBJ: u8 = 15;
+
+/// A reflection.Field, with the offset of its vtable slot for tables or its
+/// byte offset for structs
+fn field<'a>(name: &'a str, id: u16, offset: u16, field_type: Table<'a>) -> Table<'a> {
+    Table::default()
+        .with(0, Value::String(name))
+        .with(1, Value::Table(field_type))
+        .with(2, Value::UShort(id))
+        .with(3, Value::UShort(offset))
+}
+
+fn base_type<'a>(base_type: u8) -> Table<'a> {
+    Table::default().with(0, Value::Byte(base_type))
+}
+
+fn compressed_video_object<'a>() -> Table<'a> {
+    let slot = |id: u16| 4 + 2 * id;
+    // Sorted by name, as the schema is searched by it
+    let fields = vec![
+        field(
+            "data",
+            2,
+            slot(2),
+            base_type(BASE_TYPE_VECTOR)
+                .with(1, Value::Byte(BASE_TYPE_UBYTE))
+                .with(5, Value::UInt(1)),
+        ),
+        field("format", 3, slot(3), base_type(BASE_TYPE_STRING)),
+        field("frame_id", 1, slot(1), base_type(BASE_TYPE_STRING)),
+        field(
+            "timestamp",
+            0,
+            slot(0),
+            // foxglove.Time, the second object
+            base_type(BASE_TYPE_OBJ)
+                .with(2, Value::Int(1))
+                .with(4, Value::UInt(8)),
+        ),
+    ];
+    Table::default()
+        .with(0, Value::String("foxglove.CompressedVideo"))
+        .with(1, Value::Tables(fields))
+        .with(3, Value::Int(1))
+}
+
+/// The binary schema (.bfbs) of foxglove.CompressedVideo, for the channel
+pub fn compressed_video_schema() -> Vec<u8> {
+    let time = Table::default()
+        .with(0, Value::String("foxglove.Time"))
+        .with(
+            1,
+            Value::Tables(vec![
+                field("nsec", 1, 4, base_type(BASE_TYPE_UINT)),
+                field("sec", 0, 0, base_type(BASE_TYPE_UINT)),
+            ]),
+        )
+        .with(2, Value::Bool(true))
+        .with(3, Value::Int(4))
+        .with(4, Value::Int(8));
+    let schema = Table::default()
+        .with(0, Value::Tables(vec![compressed_video_object(), time]))
+        .with(1, Value::Tables(Vec::new()))
+        .with(4, Value::Table(compressed_video_object()));
+    Builder { buffer: Vec::new() }.finish(schema, Some(b"BFBS"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(buffer: &[u8], at: usize) -> usize {
+        u32::from_le_bytes(buffer[at..at + 4].try_into().unwrap()) as usize
+    }
+
+    /// Where field `id` of the table at `table` is
+    fn field_position(buffer: &[u8], table: usize, id: usize) -> Option<usize> {
+        let vtable_offset = i32::from_le_bytes(buffer[table..table + 4].try_into().unwrap());
+        let vtable = (table as i64 - vtable_offset as i64) as usize;
+        let vtable_size = u16::from_le_bytes([buffer[vtable], buffer[vtable + 1]]) as usize;
+        if 4 + 2 * id >= vtable_size {
+            return None;
+        }
+        let at = vtable + 4 + 2 * id;
+        let offset = u16::from_le_bytes([buffer[at], buffer[at + 1]]) as usize;
+        (offset != 0).then_some(table + offset)
+    }
+
+    fn vector(buffer: &[u8], table: usize, id: usize) -> &[u8] {
+        let at = field_position(buffer, table, id).unwrap();
+        let vector = at + read_u32(buffer, at);
+        &buffer[vector + 4..vector + 4 + read_u32(buffer, vector)]
+    }
+
+    #[test]
+    fn test_compressed_video() {
+        let mut buffer = vec![1, 2, 3];
+        encode_compressed_video(
+            &mut buffer,
+            1_500_000_000,
+            "cam",
+            &[0, 0, 0, 1, 0x65],
+            "h264",
+        );
+        let root = read_u32(&buffer, 0);
+        let timestamp = field_position(&buffer, root, 0).unwrap();
+        assert_eq!(timestamp % 4, 0);
+        assert_eq!(read_u32(&buffer, timestamp), 1);
+        assert_eq!(read_u32(&buffer, timestamp + 4), 500_000_000);
+        assert_eq!(vector(&buffer, root, 1), b"cam");
+        assert_eq!(vector(&buffer, root, 2), [0, 0, 0, 1, 0x65]);
+        assert_eq!(vector(&buffer, root, 3), b"h264");
+        // Strings are null terminated
+        let format = field_position(&buffer, root, 3).unwrap();
+        assert_eq!(buffer[format + read_u32(&buffer, format) + 8], 0);
+        assert_eq!(field_position(&buffer, root, 4), None);
+
+        let schema = compressed_video_schema();
+        assert_eq!(&schema[4..8], b"BFBS");
+        let root = read_u32(&schema, 0);
+        let objects = field_position(&schema, root, 0).unwrap();
+        let objects = objects + read_u32(&schema, objects);
+        assert_eq!(read_u32(&schema, objects), 2);
+        let root_table = field_position(&schema, root, 4).unwrap();
+        let root_table = root_table + read_u32(&schema, root_table);
+        assert_eq!(vector(&schema, root_table, 0), b"foxglove.CompressedVideo");
+    }
+}
@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Largest request body read, for job submissions
+const MAX_BODY: usize = 1 << 20;
+/// How long a client has to send its request and read the response, so a
+/// stalled one doesn't hold up the others
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Self {
+        Self::new(status, "application/json", body)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404, "text/plain", String::new())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
+
+/// The content type and body of a GET for a path, or None for a 404
+pub type Get = dyn Fn(&str) -> Option<(&'static str, String)> + Send;
+
+fn read_request(stream: &TcpStream) -> std::io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; content_length.min(MAX_BODY)];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+fn respond(mut stream: TcpStream, routes: &dyn Fn(&Request) -> Response) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let response = routes(&read_request(&stream)?);
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason(response.status),
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Listens on `address`. `what` names the endpoint in errors.
+pub fn listen(address: &str, what: &str) -> Result<TcpListener, Box<dyn Error>> {
+    Ok(TcpListener::bind(address)
+        .map_err(|e| format!("Could not listen on {} for {}: {}", address, what, e))?)
+}
+
+/// Answers the requests to `listener` with `routes`, one at a time, each
+/// given up on after a few seconds without progress
+pub fn handle(listener: TcpListener, routes: &dyn Fn(&Request) -> Response) {
+    for stream in listener.incoming().flatten() {
+        if let Err(e) = respond(stream, routes) {
+            debug!("Request failed: {}", e);
+        }
+    }
+}
+
+/// Answers GET requests on `address` with `routes`, from a thread that runs
+/// until the process exits
+pub fn serve(address: &str, what: &str, routes: Box<Get>) -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = listen(address, what)?;
+    let local_address = listener.local_addr()?;
+    std::thread::spawn(move || {
+        handle(listener, &|request| match request.method.as_str() {
+            "GET" => routes(&request.path).map_or_else(Response::not_found, |(kind, body)| {
+                Response::new(200, kind, body)
+            }),
+            _ => Response::new(405, "text/plain", String::new()),
+        })
+    });
+    Ok(local_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve() {
+        let address = serve(
+            "127.0.0.1:0",
+            "test",
+            Box::new(|path| (path == "/hello").then(|| ("text/plain", "hi".to_string()))),
+        )
+        .unwrap();
+        let response = get(address, "/hello");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\nhi"));
+        assert!(get(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_request_body() {
+        let listener = listen("127.0.0.1:0", "test").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            handle(listener, &|request| {
+                Response::json(
+                    201,
+                    format!("{} {} {}", request.method, request.path, request.body),
+                )
+            })
+        });
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST /jobs HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{{}}"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.ends_with("\r\n\r\nPOST /jobs {}"));
+    }
+}
@@ -0,0 +1,184 @@
+use std::error::Error;
+use std::ptr;
+
+use ffmpeg_next as ffmpeg;
+
+use ffmpeg::ffi;
+
+use crate::codec::CodecType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HardwareEncoder {
+    VideoToolbox,
+    Nvenc,
+    Vaapi,
+    Qsv,
+}
+
+impl std::str::FromStr for HardwareEncoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "videotoolbox" => Ok(HardwareEncoder::VideoToolbox),
+            "nvenc" => Ok(HardwareEncoder::Nvenc),
+            "vaapi" => Ok(HardwareEncoder::Vaapi),
+            "qsv" => Ok(HardwareEncoder::Qsv),
+            other => Err(format!(
+                "Unsupported encoder {:?}, expected videotoolbox, nvenc, vaapi or qsv",
+                other
+            )),
+        }
+    }
+}
+
+impl HardwareEncoder {
+    /// Name of the FFmpeg encoder for the given codec, e.g. `hevc_nvenc`
+    pub fn encoder_name(&self, codec: CodecType) -> String {
+        let codec = match codec {
+            CodecType::H264 => "h264",
+            CodecType::H265 => "hevc",
+            CodecType::Av1 => "av1",
+        };
+        let suffix = match self {
+            HardwareEncoder::VideoToolbox => "videotoolbox",
+            HardwareEncoder::Nvenc => "nvenc",
+            HardwareEncoder::Vaapi => "vaapi",
+            HardwareEncoder::Qsv => "qsv",
+        };
+        format!("{}_{}", codec, suffix)
+    }
+
+    /// Whether frames have to be uploaded to the GPU before encoding
+    pub fn needs_upload(&self) -> bool {
+        matches!(self, HardwareEncoder::Vaapi)
+    }
+
+    /// The closest equivalent of a constant rate factor. VideoToolbox has
+    /// none, so only a bitrate can be used to control its quality.
+    pub fn quality_option(&self) -> Option<&'static str> {
+        match self {
+            HardwareEncoder::VideoToolbox => None,
+            HardwareEncoder::Nvenc => Some("cq"),
+            HardwareEncoder::Vaapi => Some("qp"),
+            HardwareEncoder::Qsv => Some("global_quality"),
+        }
+    }
+}
+
+/// A VAAPI device with a pool of NV12 frames that software frames are
+/// copied into for the encoder
+pub struct HwUpload {
+    device: *mut ffi::AVBufferRef,
+    frames: *mut ffi::AVBufferRef,
+}
+
+impl HwUpload {
+    /// Format the frames need to be in before uploading
+    pub const SW_FORMAT: ffmpeg::format::Pixel = ffmpeg::format::Pixel::NV12;
+
+    pub fn new(width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        let mut upload = Self {
+            device: ptr::null_mut(),
+            frames: ptr::null_mut(),
+        };
+        unsafe {
+            let ret = ffi::av_hwdevice_ctx_create(
+                &mut upload.device,
+                ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+                ptr::null(),
+                ptr::null_mut(),
+                0,
+            );
+            if ret < 0 {
+                return Err(
+                    format!("Could not open VAAPI device: {}", ffmpeg::Error::from(ret)).into(),
+                );
+            }
+
+            upload.frames = ffi::av_hwframe_ctx_alloc(upload.device);
+            if upload.frames.is_null() {
+                return Err("Could not allocate VAAPI frames".into());
+            }
+            let context = (*upload.frames).data as *mut ffi::AVHWFramesContext;
+            (*context).format = ffi::AVPixelFormat::AV_PIX_FMT_VAAPI;
+            (*context).sw_format = Self::SW_FORMAT.into();
+            (*context).width = width as i32;
+            (*context).height = height as i32;
+            (*context).initial_pool_size = 20;
+            let ret = ffi::av_hwframe_ctx_init(upload.frames);
+            if ret < 0 {
+                return Err(format!(
+                    "Could not initialize VAAPI frames: {}",
+                    ffmpeg::Error::from(ret)
+                )
+                .into());
+            }
+        }
+        Ok(upload)
+    }
+
+    /// Attaches the frame pool to an encoder that has not been opened yet
+    pub fn attach(&self, encoder: &mut ffmpeg::encoder::video::Video) {
+        unsafe {
+            (*encoder.as_mut_ptr()).hw_frames_ctx = ffi::av_buffer_ref(self.frames);
+        }
+    }
+
+    /// Copies a frame in `SW_FORMAT` to the GPU, keeping its PTS and type
+    pub fn upload(
+        &self,
+        frame: &ffmpeg::frame::Video,
+    ) -> Result<ffmpeg::frame::Video, ffmpeg::Error> {
+        let mut uploaded = ffmpeg::frame::Video::empty();
+        let check = |ret: i32| match ret {
+            ret if ret < 0 => Err(ffmpeg::Error::from(ret)),
+            _ => Ok(()),
+        };
+        unsafe {
+            check(ffi::av_hwframe_get_buffer(
+                self.frames,
+                uploaded.as_mut_ptr(),
+                0,
+            ))?;
+            check(ffi::av_hwframe_transfer_data(
+                uploaded.as_mut_ptr(),
+                frame.as_ptr(),
+                0,
+            ))?;
+            check(ffi::av_frame_copy_props(
+                uploaded.as_mut_ptr(),
+                frame.as_ptr(),
+            ))?;
+        }
+        Ok(uploaded)
+    }
+}
+
+impl Drop for HwUpload {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::av_buffer_unref(&mut self.frames);
+            ffi::av_buffer_unref(&mut self.device);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoder_name() {
+        assert_eq!("nvenc".parse(), Ok(HardwareEncoder::Nvenc));
+        assert!("cuda".parse::<HardwareEncoder>().is_err());
+        assert_eq!(
+            HardwareEncoder::Nvenc.encoder_name(CodecType::H264),
+            "h264_nvenc"
+        );
+        assert_eq!(
+            HardwareEncoder::VideoToolbox.encoder_name(CodecType::H265),
+            "hevc_videotoolbox"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::error::Error;
+
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+
+/// What the video is written as, with --output-messages
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputMessages {
+    /// The bitstream as it is, in foxglove.CompressedVideo messages
+    #[default]
+    CompressedVideo,
+    /// Every frame decoded and encoded as a foxglove.CompressedImage
+    CompressedImage,
+}
+
+impl std::str::FromStr for OutputMessages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "compressed-video" => Ok(OutputMessages::CompressedVideo),
+            "compressed-image" => Ok(OutputMessages::CompressedImage),
+            other => Err(format!(
+                "Unsupported output messages {:?}, expected compressed-video or compressed-image",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+            "png" => Ok(ImageFormat::Png),
+            "webp" => Ok(ImageFormat::Webp),
+            other => Err(format!(
+                "Unsupported image format {:?}, expected jpeg, png or webp",
+                other
+            )),
+        }
+    }
+}
+
+impl ImageFormat {
+    /// The CompressedImage format
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// Extension of the image files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    fn encoder_name(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "mjpeg",
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "libwebp",
+        }
+    }
+
+    fn pixel_format(&self) -> ffmpeg::format::Pixel {
+        match self {
+            // Full range, as JPEG viewers expect
+            ImageFormat::Jpeg => ffmpeg::format::Pixel::YUVJ420P,
+            ImageFormat::Png => ffmpeg::format::Pixel::RGB24,
+            ImageFormat::Webp => ffmpeg::format::Pixel::YUV420P,
+        }
+    }
+}
+
+/// FF_QP2LAMBDA, the scale of the quality of the encoders to quantizers
+const QP2LAMBDA: i32 = 118;
+
+/// The MJPEG quantizer for a quality from 1 to 100, from 31 down to 2
+fn jpeg_qscale(quality: u32) -> i32 {
+    let quality = quality.clamp(1, 100) as i32;
+    2 + (100 - quality) * 29 / 99
+}
+
+/// Encodes decoded frames as standalone images
+pub struct ImageEncoder {
+    pub format: ImageFormat,
+    /// From 1 to 100, ignored for PNG which is lossless
+    quality: u32,
+    /// Without the encoder version, which JPEG otherwise has in a comment
+    bitexact: bool,
+    scaler: Option<ffmpeg::software::scaling::Context>,
+    scaled: ffmpeg::frame::Video,
+    /// Opened with the first frame, for its size, and again whenever the
+    /// frames change format or size
+    encoder: Option<ffmpeg::encoder::Video>,
+    /// Pixel format and size of the frames the encoder was opened for
+    source: Option<(ffmpeg::format::Pixel, u32, u32)>,
+    packet: ffmpeg::Packet,
+}
+
+impl ImageEncoder {
+    pub fn new(format: ImageFormat, quality: u32, bitexact: bool) -> Self {
+        Self {
+            format,
+            quality,
+            bitexact,
+            scaler: None,
+            scaled: ffmpeg::frame::Video::empty(),
+            encoder: None,
+            source: None,
+            packet: ffmpeg::Packet::empty(),
+        }
+    }
+
+    fn open(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+    ) -> Result<ffmpeg::encoder::Video, Box<dyn Error>> {
+        let name = self.format.encoder_name();
+        let codec = ffmpeg::encoder::find_by_name(name)
+            .ok_or_else(|| format!("Encoder {} not found", name))?;
+        let format = self.format.pixel_format();
+        if frame.format() != format {
+            self.scaler = Some(ffmpeg::software::scaling::Context::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                format,
+                frame.width(),
+                frame.height(),
+                ffmpeg::software::scaling::Flags::BICUBIC,
+            )?);
+        }
+
+        let mut encoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_format(format);
+        // Each image stands alone, so the time base doesn't matter
+        encoder.set_time_base((1, 1000));
+        let mut flags = ffmpeg::codec::Flags::empty();
+        if self.bitexact {
+            flags |= ffmpeg::codec::Flags::BITEXACT;
+        }
+        let mut options = ffmpeg::Dictionary::new();
+        match self.format {
+            ImageFormat::Jpeg => {
+                flags |= ffmpeg::codec::Flags::QSCALE;
+                encoder.set_global_quality(jpeg_qscale(self.quality) * QP2LAMBDA);
+            }
+            ImageFormat::Webp => options.set("quality", &self.quality.to_string()),
+            ImageFormat::Png => {}
+        }
+        encoder.set_flags(flags);
+        Ok(encoder.open_with(options)?)
+    }
+
+    /// Encodes a frame as an image, straight from the decoder's buffer
+    /// unless it has to be converted to the pixel format of the image
+    pub fn encode(&mut self, frame: &ffmpeg::frame::Video) -> Result<Bytes, Box<dyn Error>> {
+        let source = (frame.format(), frame.width(), frame.height());
+        if self.source != Some(source) {
+            self.scaler = None;
+            self.encoder = Some(self.open(frame)?);
+            self.source = Some(source);
+        }
+        let frame = match &mut self.scaler {
+            Some(scaler) => {
+                scaler.run(frame, &mut self.scaled)?;
+                &self.scaled
+            }
+            None => frame,
+        };
+        if let Some(encoder) = &mut self.encoder {
+            encoder.send_frame(frame)?;
+            encoder.receive_packet(&mut self.packet)?;
+        }
+        Ok(Bytes::copy_from_slice(
+            self.packet.data().unwrap_or_default(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_format() {
+        assert_eq!("jpg".parse::<ImageFormat>(), Ok(ImageFormat::Jpeg));
+        assert_eq!("webp".parse::<ImageFormat>().unwrap().name(), "webp");
+        assert!("gif".parse::<ImageFormat>().is_err());
+        assert_eq!(
+            "compressed-image".parse::<OutputMessages>(),
+            Ok(OutputMessages::CompressedImage)
+        );
+        assert_eq!(jpeg_qscale(100), 2);
+        assert_eq!(jpeg_qscale(1), 31);
+        assert_eq!(jpeg_qscale(90), 4);
+    }
+}
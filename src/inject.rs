@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use mcap::records::{MessageHeader, Record};
+use mcap::sans_io::read::{LinearReader, LinearReaderOptions, ReadAction};
+use mcap::{Attachment, Channel, Schema, WriteOptions, Writer};
+
+use crate::duration;
+
+/// What was written to the combined log
+#[derive(Debug, Default, PartialEq)]
+pub struct Merged {
+    pub log_messages: u64,
+    pub video_messages: u64,
+}
+
+/// The video to inject, with how much to shift it by from where it would be
+/// placed, as in `cam1.mp4=+3.2s`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InjectInput {
+    pub path: PathBuf,
+    pub offset_ns: i64,
+}
+
+impl std::str::FromStr for InjectInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('=') {
+            Some((path, offset)) if offset.starts_with(['+', '-']) => Ok(InjectInput {
+                path: PathBuf::from(path),
+                offset_ns: duration::parse_offset(offset).ok_or_else(|| {
+                    format!(
+                        "Invalid offset {:?} for {:?}, expected a signed duration like +3.2s or \
+                        -250ms",
+                        offset, path
+                    )
+                })?,
+            }),
+            _ => Ok(InjectInput {
+                path: PathBuf::from(s),
+                offset_ns: 0,
+            }),
+        }
+    }
+}
+
+/// The records of an MCAP, read a buffer at a time so neither it nor the
+/// log it's merged with has to fit in memory. Chunks are read into.
+pub struct Records<R> {
+    read: R,
+    reader: LinearReader,
+}
+
+impl<R: Read> Records<R> {
+    pub fn new(read: R) -> Self {
+        Self {
+            read,
+            reader: LinearReader::new(),
+        }
+    }
+
+    /// The records from the start of the summary section on
+    pub fn summary(read: R) -> Self {
+        Self {
+            read,
+            reader: LinearReader::new_with_options(
+                LinearReaderOptions::default().with_skip_start_magic(true),
+            ),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Records<R> {
+    type Item = Result<Record<'static>, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.next_action()? {
+                Ok(ReadAction::NeedMore(need)) => match self.read.read(self.reader.insert(need)) {
+                    Ok(read) => self.reader.set_written(read),
+                    Err(e) => return Some(Err(e.into())),
+                },
+                Ok(ReadAction::GetRecord { data, opcode }) => {
+                    return Some(
+                        mcap::parse_record(opcode, data)
+                            .map(Record::into_owned)
+                            .map_err(Into::into),
+                    )
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+/// Opens an MCAP to read its records
+pub fn open(path: &Path) -> Result<BufReader<File>, Box<dyn Error>> {
+    let file = File::open(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    Ok(BufReader::new(file))
+}
+
+/// The sequence numbers to continue each topic of `log` from, one after
+/// the highest of its messages
+pub fn next_sequences<R: Read>(log: R) -> Result<HashMap<String, u32>, Box<dyn Error>> {
+    let mut topics = HashMap::new();
+    let mut next: HashMap<String, u32> = HashMap::new();
+    for record in Records::new(log) {
+        match record? {
+            Record::Channel(channel) => {
+                topics.insert(channel.id, channel.topic);
+            }
+            Record::Message { header, .. } => {
+                let topic = topics.get(&header.channel_id).ok_or_else(|| {
+                    format!("A message is on unknown channel {}", header.channel_id)
+                })?;
+                let sequence = next.entry(topic.clone()).or_default();
+                *sequence = (*sequence).max(header.sequence.saturating_add(1));
+            }
+            Record::DataEnd(_) => break,
+            _ => {}
+        }
+    }
+    Ok(next)
+}
+
+/// A channel of the output, by topic, with the input it was added from
+struct Topic {
+    id: u16,
+    is_video: bool,
+    message_encoding: String,
+    schema_name: Option<String>,
+}
+
+/// One of the MCAPs being merged, with its channels added to the output
+struct Input<R> {
+    records: Records<R>,
+    offset_ns: i64,
+    schemas: HashMap<u16, Arc<Schema<'static>>>,
+    /// Output channel IDs by input channel ID
+    channels: HashMap<u16, u16>,
+    /// The next sequence number of the output channels renumbered to follow
+    /// on from the messages of the log
+    sequences: HashMap<u16, u32>,
+    /// The next message, with its log time moved by the offset
+    next: Option<(MessageHeader, Vec<u8>)>,
+}
+
+impl<R: Read> Input<R> {
+    fn new(records: Records<R>, offset_ns: i64) -> Self {
+        Self {
+            records,
+            offset_ns,
+            schemas: HashMap::new(),
+            channels: HashMap::new(),
+            sequences: HashMap::new(),
+            next: None,
+        }
+    }
+
+    /// Reads up to the next message, writing the channels, metadata and
+    /// attachments before it. A topic in both inputs is one channel, which
+    /// the video messages on it are numbered from `sequence_starts` in.
+    fn advance<W: Write + Seek>(
+        &mut self,
+        writer: &mut Writer<'static, W>,
+        topics: &mut HashMap<String, Topic>,
+        sequence_starts: &HashMap<String, u32>,
+        is_video: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        self.next = None;
+        for record in self.records.by_ref() {
+            match record? {
+                Record::Schema { header, data } => {
+                    let schema = Schema {
+                        name: header.name,
+                        encoding: header.encoding,
+                        data,
+                    };
+                    self.schemas.insert(header.id, Arc::new(schema));
+                }
+                // The summary repeats what came before
+                Record::Channel(channel) if self.channels.contains_key(&channel.id) => {}
+                Record::Channel(channel) => {
+                    let schema = self.schemas.get(&channel.schema_id).cloned();
+                    let schema_name = schema.as_ref().map(|schema| schema.name.clone());
+                    let id = match topics.get(&channel.topic) {
+                        Some(topic) if topic.is_video != is_video => {
+                            if topic.message_encoding != channel.message_encoding
+                                || topic.schema_name != schema_name
+                            {
+                                return Err(format!(
+                                    "The log already has a {} channel of another message type",
+                                    channel.topic
+                                )
+                                .into());
+                            }
+                            topic.id
+                        }
+                        _ => {
+                            let id = writer.add_channel(&Channel {
+                                topic: channel.topic.clone(),
+                                schema,
+                                message_encoding: channel.message_encoding.clone(),
+                                metadata: channel.metadata,
+                            })?;
+                            topics.entry(channel.topic.clone()).or_insert(Topic {
+                                id,
+                                is_video,
+                                message_encoding: channel.message_encoding,
+                                schema_name,
+                            });
+                            id
+                        }
+                    };
+                    if is_video {
+                        if let Some(&start) = sequence_starts.get(&channel.topic) {
+                            self.sequences.insert(id, start);
+                        }
+                    }
+                    self.channels.insert(channel.id, id);
+                }
+                Record::Message { header, data } => {
+                    let channel_id = *self.channels.get(&header.channel_id).ok_or_else(|| {
+                        format!("A message is on unknown channel {}", header.channel_id)
+                    })?;
+                    let sequence = match self.sequences.get_mut(&channel_id) {
+                        Some(next) => {
+                            let sequence = *next;
+                            *next = sequence.wrapping_add(1);
+                            sequence
+                        }
+                        None => header.sequence,
+                    };
+                    let header = MessageHeader {
+                        channel_id,
+                        sequence,
+                        log_time: header.log_time.saturating_add_signed(self.offset_ns),
+                        publish_time: header.publish_time.saturating_add_signed(self.offset_ns),
+                    };
+                    self.next = Some((header, data.into_owned()));
+                    return Ok(());
+                }
+                Record::Metadata(metadata) => writer.write_metadata(&metadata)?,
+                Record::Attachment { header, data } => writer.attach(&Attachment {
+                    log_time: header.log_time,
+                    create_time: header.create_time,
+                    name: header.name,
+                    media_type: header.media_type,
+                    data,
+                })?,
+                // The summary after it is left to the writer to redo
+                Record::DataEnd(_) => break,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the messages of `log` and `video` with the video moved by
+/// `video_offset_ns`, interleaved by log time. Neither has to be in log
+/// time order, since readers go by the time index of the chunks, but the
+/// messages of each are kept in their order, a log message first on a tie.
+/// The channels, even those without messages, metadata and attachments of
+/// both are kept, and the profile of the log. A topic the log already has
+/// is merged into its channel, with the video messages on it numbered from
+/// the sequence in `sequence_starts`, or as they are without one.
+pub fn merge<R: Read, W: Write + Seek>(
+    log: R,
+    video: R,
+    video_offset_ns: i64,
+    sequence_starts: &HashMap<String, u32>,
+    out: W,
+) -> Result<Merged, Box<dyn Error>> {
+    let mut log_records = Records::new(log);
+    let profile = match log_records.next().transpose()? {
+        Some(Record::Header(header)) => header.profile,
+        _ => return Err("The log is not an MCAP".into()),
+    };
+    let mut writer = WriteOptions::new().profile(profile).create(out)?;
+    let mut topics = HashMap::new();
+    let mut log = Input::new(log_records, 0);
+    let mut video = Input::new(Records::new(video), video_offset_ns);
+    log.advance(&mut writer, &mut topics, sequence_starts, false)?;
+    video.advance(&mut writer, &mut topics, sequence_starts, true)?;
+
+    let mut merged = Merged::default();
+    loop {
+        let take_video = match (&log.next, &video.next) {
+            (Some((log_header, _)), Some((video_header, _))) => {
+                video_header.log_time < log_header.log_time
+            }
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (None, None) => break,
+        };
+        let (input, is_video) = if take_video {
+            merged.video_messages += 1;
+            (&mut video, true)
+        } else {
+            merged.log_messages += 1;
+            (&mut log, false)
+        };
+        if let Some((header, data)) = &input.next {
+            writer.write_to_known_channel(header, data)?;
+        }
+        input.advance(&mut writer, &mut topics, sequence_starts, is_video)?;
+    }
+    writer.finish()?;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use mcap::{Message, MessageStream, Summary};
+
+    use super::*;
+    use crate::timesync;
+
+    fn channel(topic: &str, message_encoding: &str) -> Arc<Channel<'static>> {
+        Arc::new(Channel {
+            topic: topic.to_string(),
+            schema: None,
+            message_encoding: message_encoding.to_string(),
+            metadata: BTreeMap::new(),
+        })
+    }
+
+    fn mcap(topic: &str, times: &[u64]) -> Vec<u8> {
+        mcap_encoded(topic, "json", times)
+    }
+
+    fn mcap_encoded(topic: &str, message_encoding: &str, times: &[u64]) -> Vec<u8> {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut writer = WriteOptions::new()
+            .profile("ros2")
+            .create(&mut buffer)
+            .unwrap();
+        // A channel nothing was logged on
+        writer
+            .add_channel(&channel(&format!("{}/status", topic), message_encoding))
+            .unwrap();
+        let channel = channel(topic, message_encoding);
+        for (sequence, &time) in times.iter().enumerate() {
+            writer
+                .write(&Message {
+                    channel: channel.clone(),
+                    sequence: sequence as u32,
+                    log_time: time,
+                    publish_time: time,
+                    data: Cow::Borrowed(b"{}"),
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn test_inject_input() {
+        let input: InjectInput = "cam1.mp4=+3.2s".parse().unwrap();
+        assert_eq!(input.path, PathBuf::from("cam1.mp4"));
+        assert_eq!(input.offset_ns, 3_200_000_000);
+        assert_eq!(
+            "a=b.mp4=-250ms".parse::<InjectInput>().unwrap().offset_ns,
+            -250_000_000
+        );
+        assert_eq!(
+            "a=b.mp4".parse::<InjectInput>().unwrap(),
+            InjectInput {
+                path: PathBuf::from("a=b.mp4"),
+                offset_ns: 0,
+            }
+        );
+        assert!("cam1.mp4=+3.2".parse::<InjectInput>().is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        // Out of log time order, as when several recorders are combined
+        let log = mcap("/odom", &[1000, 3000, 2000]);
+        let video = mcap("/camera/image", &[0, 1500]);
+        assert_eq!(
+            timesync::mcap_time_range(Cursor::new(&log)).unwrap(),
+            Some((1000, 3000))
+        );
+
+        let mut out = Cursor::new(Vec::new());
+        let merged = merge(
+            log.as_slice(),
+            video.as_slice(),
+            1000,
+            &HashMap::new(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(
+            merged,
+            Merged {
+                log_messages: 3,
+                video_messages: 2
+            }
+        );
+        let out = out.into_inner();
+        let messages: Vec<_> = MessageStream::new(&out)
+            .unwrap()
+            .map(|message| {
+                let message = message.unwrap();
+                (message.channel.topic.clone(), message.log_time)
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("/odom".to_string(), 1000),
+                ("/camera/image".to_string(), 1000),
+                ("/camera/image".to_string(), 2500),
+                ("/odom".to_string(), 3000),
+                ("/odom".to_string(), 2000),
+            ]
+        );
+        let summary = Summary::read(&out).unwrap().unwrap();
+        let mut topics: Vec<_> = summary
+            .channels
+            .values()
+            .map(|channel| channel.topic.as_str())
+            .collect();
+        topics.sort_unstable();
+        assert_eq!(
+            topics,
+            [
+                "/camera/image",
+                "/camera/image/status",
+                "/odom",
+                "/odom/status"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_existing_topic() {
+        let log = mcap("/camera/image", &[1000, 2000]);
+        let video = mcap("/camera/image", &[0, 500]);
+        let sequence_starts = next_sequences(log.as_slice()).unwrap();
+        assert_eq!(
+            sequence_starts,
+            HashMap::from([("/camera/image".to_string(), 2)])
+        );
+
+        let sequences = |sequence_starts: &HashMap<String, u32>| {
+            let mut out = Cursor::new(Vec::new());
+            merge(
+                log.as_slice(),
+                video.as_slice(),
+                1500,
+                sequence_starts,
+                &mut out,
+            )
+            .unwrap();
+            let out = out.into_inner();
+            let summary = Summary::read(&out).unwrap().unwrap();
+            assert_eq!(summary.channels.len(), 2);
+            MessageStream::new(&out)
+                .unwrap()
+                .map(|message| {
+                    let message = message.unwrap();
+                    (message.log_time, message.sequence)
+                })
+                .collect::<Vec<_>>()
+        };
+        // Continued from the last message of the log, a log message first on
+        // a tie
+        assert_eq!(
+            sequences(&sequence_starts),
+            [(1000, 0), (1500, 2), (2000, 1), (2000, 3)]
+        );
+        // As they were converted, with --sequence-start
+        assert_eq!(
+            sequences(&HashMap::new()),
+            [(1000, 0), (1500, 0), (2000, 1), (2000, 1)]
+        );
+
+        let other = mcap_encoded("/camera/image", "cdr", &[0]);
+        let out = Cursor::new(Vec::new());
+        assert!(merge(log.as_slice(), other.as_slice(), 0, &sequence_starts, out).is_err());
+    }
+}
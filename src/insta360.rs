@@ -0,0 +1,47 @@
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::stream_info;
+
+pub fn is_insv(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("insv"))
+}
+
+/// The streams of the two fisheye lenses of an Insta360 .insv recording,
+/// which has one video stream of the same size per lens
+pub fn lens_streams(path: &Path, input: &ffmpeg::format::context::Input) -> Option<[usize; 2]> {
+    if !is_insv(path) {
+        return None;
+    }
+    let lenses: Vec<_> = input
+        .streams()
+        .filter(|stream| stream.parameters().medium() == ffmpeg::media::Type::Video)
+        .map(|stream| {
+            (
+                stream.index(),
+                stream_info::dimensions(&stream.parameters()),
+            )
+        })
+        .collect();
+    match lenses[..] {
+        [(first, size), (second, other_size)] if size == other_size => Some([first, second]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_insv() {
+        assert!(is_insv(Path::new(
+            "DCIM/Camera01/VID_20240501_101500_00_012.insv"
+        )));
+        assert!(is_insv(Path::new("VID.INSV")));
+        assert!(!is_insv(Path::new("VID_20240501_101500_00_012.mp4")));
+        assert!(!is_insv(Path::new("insv")));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{Seek, Write};
+
+use mcap::{records::MessageHeader, Writer};
+
+/// Encodes a message timed from the first frame, given its log time
+type Encode = Box<dyn FnOnce(u64) -> Vec<u8>>;
+
+/// Messages from outside the video, like the calibration or sidecar
+/// telemetry, written along with the frames so the log times of the file
+/// stay in order for readers of it from start to end
+#[derive(Default)]
+pub struct Interleaver {
+    /// By log time, then in the order they were added
+    pending: BTreeMap<(u64, usize), (u16, Vec<u8>)>,
+    /// Offset from the first frame, channel and encoder of the messages
+    /// timed from it, until its log time is known
+    from_start: Vec<(u64, u16, Encode)>,
+    start_ns: Option<u64>,
+    sequences: BTreeMap<u16, u32>,
+    added: usize,
+}
+
+impl Interleaver {
+    pub fn add(&mut self, channel_id: u16, log_time: u64, encoded: Vec<u8>) {
+        self.pending
+            .insert((log_time, self.added), (channel_id, encoded));
+        self.added += 1;
+    }
+
+    /// Adds a message at `offset_ns` after the first frame
+    pub fn add_from_start(
+        &mut self,
+        channel_id: u16,
+        offset_ns: u64,
+        encode: impl FnOnce(u64) -> Vec<u8> + 'static,
+    ) {
+        match self.start_ns {
+            Some(start_ns) => {
+                let log_time = start_ns.saturating_add(offset_ns);
+                self.add(channel_id, log_time, encode(log_time));
+            }
+            None => self
+                .from_start
+                .push((offset_ns, channel_id, Box::new(encode))),
+        }
+    }
+
+    /// Sets the log time of the first frame, once
+    pub fn start(&mut self, start_ns: u64) {
+        if self.start_ns.is_some() {
+            return;
+        }
+        self.start_ns = Some(start_ns);
+        for (offset_ns, channel_id, encode) in std::mem::take(&mut self.from_start) {
+            let log_time = start_ns.saturating_add(offset_ns);
+            self.add(channel_id, log_time, encode(log_time));
+        }
+    }
+
+    /// Writes the messages up to `until_ns`
+    pub fn write_until<W: Write + Seek>(
+        &mut self,
+        writer: &mut Writer<W>,
+        until_ns: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > until_ns {
+                break;
+            }
+            let ((log_time, _), (channel_id, encoded)) = entry.remove_entry();
+            let sequence = self.sequences.entry(channel_id).or_default();
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id,
+                    sequence: *sequence,
+                    log_time,
+                    publish_time: log_time,
+                },
+                &encoded,
+            )?;
+            *sequence += 1;
+        }
+        Ok(())
+    }
+
+    /// Number of messages written to a channel
+    pub fn messages(&self, channel_id: u16) -> u32 {
+        self.sequences.get(&channel_id).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{minimal, subtitles};
+
+    #[test]
+    fn test_interleave() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = mcap::WriteOptions::new()
+            .use_chunks(false)
+            .create(&mut file)
+            .unwrap();
+        let mut channel = |topic: &str| {
+            writer
+                .add_channel(&mcap::Channel {
+                    topic: topic.to_string(),
+                    schema: None,
+                    message_encoding: "json".to_string(),
+                    metadata: BTreeMap::new(),
+                })
+                .unwrap()
+        };
+        let (video, calibration, log) =
+            (channel("/video"), channel("/calibration"), channel("/log"));
+
+        let mut interleaver = Interleaver::default();
+        interleaver.add(log, 250, b"late".to_vec());
+        interleaver.add(log, 50, b"early".to_vec());
+        interleaver.add_from_start(calibration, 0, |log_time| log_time.to_string().into_bytes());
+        for (sequence, log_time) in [100, 200, 300].into_iter().enumerate() {
+            interleaver.start(log_time);
+            interleaver.write_until(&mut writer, log_time).unwrap();
+            writer
+                .write_to_known_channel(
+                    &MessageHeader {
+                        channel_id: video,
+                        sequence: sequence as u32,
+                        log_time,
+                        publish_time: log_time,
+                    },
+                    b"frame",
+                )
+                .unwrap();
+        }
+        interleaver.add(log, 400, b"last".to_vec());
+        interleaver.write_until(&mut writer, u64::MAX).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        assert_eq!(interleaver.messages(log), 3);
+
+        let data = file.into_inner();
+        let messages: Vec<_> = mcap::MessageStream::new(&data)
+            .unwrap()
+            .map(|message| {
+                let message = message.unwrap();
+                (message.log_time, message.data.to_vec())
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                (50, b"early".to_vec()),
+                (100, b"100".to_vec()),
+                (100, b"frame".to_vec()),
+                (200, b"frame".to_vec()),
+                (250, b"late".to_vec()),
+                (300, b"frame".to_vec()),
+                (400, b"last".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_minimal_srt_in_order() {
+        let cues = subtitles::parse_srt(
+            "1\n00:00:00,150 --> 00:00:01,000\nfirst\n\n\
+             2\n00:00:00,100 --> 00:00:00,900\n<i>overlapping</i>\n\n\
+             3\n00:00:02,500 --> 00:00:03,000\nafter the last frame\n",
+        );
+        assert_eq!(cues.len(), 3);
+
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = minimal::write_options().create(&mut file).unwrap();
+        let mut channel = |topic: &str| {
+            writer
+                .add_channel(&mcap::Channel {
+                    topic: topic.to_string(),
+                    schema: None,
+                    message_encoding: "json".to_string(),
+                    metadata: BTreeMap::new(),
+                })
+                .unwrap()
+        };
+        let (video, srt) = (channel("/video"), channel("subtitles"));
+
+        let mut interleaver = Interleaver::default();
+        for cue in &cues {
+            let text = subtitles::strip_tags(&cue.text);
+            interleaver.add(srt, cue.start_ns, text.into_bytes());
+        }
+        for (sequence, log_time) in (0..2_000_000_000).step_by(100_000_000).enumerate() {
+            interleaver.start(log_time);
+            interleaver.write_until(&mut writer, log_time).unwrap();
+            writer
+                .write_to_known_channel(
+                    &MessageHeader {
+                        channel_id: video,
+                        sequence: sequence as u32,
+                        log_time,
+                        publish_time: log_time,
+                    },
+                    b"frame",
+                )
+                .unwrap();
+        }
+        interleaver.write_until(&mut writer, u64::MAX).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let length = minimal::strip_summary(&mut file).unwrap();
+        let mut data = file.into_inner();
+        data.truncate(length as usize);
+
+        let log_times: Vec<_> = mcap::MessageStream::new(&data)
+            .unwrap()
+            .map(|message| message.unwrap().log_time)
+            .collect();
+        assert_eq!(log_times.len(), 23);
+        assert!(log_times.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(interleaver.messages(srt), 3);
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::io::{BufRead, BufReader};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::http::{self, Request, Response};
+use crate::json::{self, Value};
+use crate::summary::json_string;
+
+/// Warnings kept per job
+const JOB_WARNINGS: usize = 20;
+/// Finished jobs kept for their clients to look up, the oldest are dropped
+const FINISHED_JOBS: usize = 100;
+/// Options a job can't pass: those reading or writing files other than its
+/// input and output or opening ports, and those the server sets itself
+const REJECTED_OPTIONS: &[&str] = &[
+    "--at-file",
+    "--calibration",
+    "--capture",
+    "--error-format",
+    "--lrv",
+    "--metrics-addr",
+    "--progress",
+    "--schema",
+    "--srt",
+    "--stats-json",
+    "--status-addr",
+    "--summary-file",
+    "--sync-to",
+    "--telemetry-srt",
+    "--timestamps",
+    "--transform-file",
+    "--vf",
+];
+/// Arguments that would make the job run another subcommand or end the
+/// options, if they reached the command line in the wrong place
+const REJECTED_ARGUMENTS: &[&str] = &["--", "serve", "inject", "selftest", "generate", "extract"];
+
+/// A conversion submitted to `mp42mcap serve`
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobRequest {
+    /// Path or URL of the input
+    pub input: String,
+    /// Path of the MCAP on the server
+    pub output: String,
+    /// Options of the conversion, as on the command line
+    pub options: Vec<String>,
+}
+
+/// A job from `{"input": ..., "output": ..., "options": [...]}`
+pub fn parse_job(body: &str) -> Result<JobRequest, String> {
+    let job = json::parse(body)?;
+    let Value::Object(fields) = &job else {
+        return Err("expected an object".to_string());
+    };
+    if let Some((key, _)) = fields
+        .iter()
+        .find(|(key, _)| !matches!(key.as_str(), "input" | "output" | "options"))
+    {
+        return Err(format!("unexpected field {:?}", key));
+    }
+    let string = |key: &str| match job.get(key) {
+        Some(Value::String(value)) => Ok(value.clone()),
+        Some(_) => Err(format!("{} must be a string", key)),
+        None => Err(format!("the job has no {}", key)),
+    };
+    let options = match job.get("options") {
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(option) => Ok(option.clone()),
+                _ => Err("options must be strings".to_string()),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err("options must be an array".to_string()),
+        None => Vec::new(),
+    };
+    Ok(JobRequest {
+        input: string("input")?,
+        output: string("output")?,
+        options,
+    })
+}
+
+fn is_url(input: &str) -> bool {
+    input.starts_with("http://") || input.starts_with("https://")
+}
+
+/// A path of a job, which has to be relative and stay inside the root of
+/// the server, a canonical path, even after following symlinks. Returns the
+/// path with the symlinks of its existing part resolved.
+fn job_path(root: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    let outside = || format!("{:?} is not a relative path inside the server's root", path);
+    if path.is_empty()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside());
+    }
+    // An output doesn't exist yet, so resolve the deepest directory that does
+    for existing in relative.ancestors() {
+        if let Ok(resolved) = root.join(existing).canonicalize() {
+            if !resolved.starts_with(root) {
+                return Err(outside());
+            }
+            let rest = relative.strip_prefix(existing).unwrap_or(relative);
+            return Ok(resolved.join(rest));
+        }
+    }
+    Ok(root.join(relative))
+}
+
+impl JobRequest {
+    /// Checks that the job only reads its input, an HTTP(S) URL or a file
+    /// under `root`, and only writes its output under it
+    fn check(&self, root: &Path) -> Result<(), String> {
+        if !is_url(&self.input) {
+            job_path(root, &self.input)?;
+        }
+        job_path(root, &self.output)?;
+        for option in &self.options {
+            if REJECTED_ARGUMENTS.contains(&option.as_str()) {
+                return Err(format!("{} can't be given to a job", option));
+            }
+            let name = option
+                .split_once('=')
+                .map_or(option.as_str(), |(name, _)| name);
+            if REJECTED_OPTIONS.contains(&name) {
+                return Err(format!("{} can't be given to a job", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl State {
+    fn name(&self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Done => "done",
+            State::Failed => "failed",
+            State::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self, State::Done | State::Failed | State::Cancelled)
+    }
+}
+
+struct Job {
+    request: JobRequest,
+    state: State,
+    /// The last `--progress json` line of the conversion
+    progress: Option<String>,
+    /// The `--error-format json` object of a failed conversion
+    error: Option<String>,
+    warnings: VecDeque<String>,
+    child: Option<Child>,
+}
+
+impl Job {
+    fn to_json(&self, id: u64) -> String {
+        format!(
+            "{{\"id\":{},\"state\":\"{}\",\"input\":{},\"output\":{},\"progress\":{},\
+            \"error\":{},\"warnings\":[{}]}}",
+            id,
+            self.state.name(),
+            json_string(&self.request.input),
+            json_string(&self.request.output),
+            self.progress.as_deref().unwrap_or("null"),
+            self.error.as_deref().unwrap_or("null"),
+            self.warnings
+                .iter()
+                .map(|warning| json_string(warning))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: BTreeMap<u64, Job>,
+    queue: VecDeque<u64>,
+    next_id: u64,
+}
+
+impl Jobs {
+    /// Drops the oldest finished jobs past FINISHED_JOBS
+    fn evict_finished(&mut self) {
+        let finished: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.state.is_finished())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &finished[..finished.len().saturating_sub(FINISHED_JOBS)] {
+            self.jobs.remove(id);
+        }
+    }
+}
+
+struct Server {
+    /// Directory the paths of the jobs are in
+    root: PathBuf,
+    jobs: Mutex<Jobs>,
+    /// Signalled when a job is queued
+    queued: Condvar,
+}
+
+impl Server {
+    fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            jobs: Mutex::default(),
+            queued: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Jobs> {
+        self.jobs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn submit(&self, request: JobRequest) -> String {
+        let mut jobs = self.lock();
+        jobs.evict_finished();
+        jobs.next_id += 1;
+        let id = jobs.next_id;
+        let job = Job {
+            request,
+            state: State::Queued,
+            progress: None,
+            error: None,
+            warnings: VecDeque::new(),
+            child: None,
+        };
+        let json = job.to_json(id);
+        jobs.jobs.insert(id, job);
+        jobs.queue.push_back(id);
+        self.queued.notify_one();
+        json
+    }
+
+    fn cancel(&self, id: u64) -> Response {
+        let mut jobs = self.lock();
+        jobs.queue.retain(|&queued| queued != id);
+        let Some(job) = jobs.jobs.get_mut(&id) else {
+            return Response::not_found();
+        };
+        if job.state.is_finished() {
+            return Response::json(409, job.to_json(id));
+        }
+        if let Some(child) = &mut job.child {
+            let _ = child.kill();
+        }
+        job.state = State::Cancelled;
+        Response::json(200, job.to_json(id))
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let id = request
+            .path
+            .strip_prefix("/jobs/")
+            .map(|id| id.parse::<u64>().ok());
+        match (request.method.as_str(), request.path.as_str(), id) {
+            ("POST", "/jobs", _) => {
+                match parse_job(&request.body).and_then(|job| job.check(&self.root).map(|()| job)) {
+                    Ok(job) => Response::json(201, self.submit(job)),
+                    Err(e) => Response::json(400, format!("{{\"error\":{}}}", json_string(&e))),
+                }
+            }
+            ("GET", "/jobs", _) => {
+                let jobs = self.lock();
+                let list: Vec<String> =
+                    jobs.jobs.iter().map(|(&id, job)| job.to_json(id)).collect();
+                Response::json(200, format!("[{}]", list.join(",")))
+            }
+            ("GET", _, Some(Some(id))) => {
+                let jobs = self.lock();
+                jobs.jobs.get(&id).map_or_else(Response::not_found, |job| {
+                    Response::json(200, job.to_json(id))
+                })
+            }
+            ("DELETE", _, Some(Some(id))) => self.cancel(id),
+            ("GET" | "POST" | "DELETE", _, _) => Response::not_found(),
+            _ => Response::new(405, "text/plain", String::new()),
+        }
+    }
+
+    /// Starts the conversion of a job, as a child process that reports its
+    /// progress and errors as JSON on stderr
+    fn start(&self, id: u64, program: &PathBuf) -> Option<std::process::ChildStderr> {
+        let mut jobs = self.lock();
+        let job = jobs.jobs.get_mut(&id)?;
+        if job.state != State::Queued {
+            return None;
+        }
+        // Resolved again, as the symlinks could have changed since the job was
+        // checked
+        let paths = match is_url(&job.request.input) {
+            true => Ok(PathBuf::from(&job.request.input)),
+            false => job_path(&self.root, &job.request.input),
+        }
+        .and_then(|input| Ok((input, job_path(&self.root, &job.request.output)?)));
+        let (input, output) = match paths {
+            Ok(paths) => paths,
+            Err(e) => {
+                job.state = State::Failed;
+                job.error = Some(format!(
+                    "{{\"code\":\"usage\",\"message\":{}}}",
+                    json_string(&e)
+                ));
+                return None;
+            }
+        };
+        // The options of the server come first, so the first argument is
+        // never a subcommand
+        let spawned = Command::new(program)
+            .args(["--progress", "json", "--error-format", "json"])
+            .args(&job.request.options)
+            .arg("--")
+            .arg(input)
+            .arg(output)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        match spawned {
+            Ok(mut child) => {
+                let stderr = child.stderr.take();
+                job.child = Some(child);
+                job.state = State::Running;
+                stderr
+            }
+            Err(e) => {
+                job.state = State::Failed;
+                job.error = Some(format!(
+                    "{{\"code\":\"io\",\"message\":{}}}",
+                    json_string(&format!("Could not start the conversion: {}", e))
+                ));
+                None
+            }
+        }
+    }
+
+    /// Follows a running job until its conversion exits
+    fn follow(&self, id: u64, stderr: std::process::ChildStderr) {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            let mut jobs = self.lock();
+            let Some(job) = jobs.jobs.get_mut(&id) else {
+                return;
+            };
+            if line.starts_with("{\"timestamp\"") {
+                job.progress = Some(line);
+            } else if line.starts_with("{\"code\"") {
+                job.error = Some(line);
+            } else if !line.trim().is_empty() {
+                if job.warnings.len() == JOB_WARNINGS {
+                    job.warnings.pop_front();
+                }
+                job.warnings.push_back(line);
+            }
+        }
+        let mut jobs = self.lock();
+        let Some(job) = jobs.jobs.get_mut(&id) else {
+            return;
+        };
+        let status = job.child.take().map(|mut child| child.wait());
+        match (job.state, status) {
+            (State::Cancelled, _) => {
+                if let Ok(output) = job_path(&self.root, &job.request.output) {
+                    let _ = std::fs::remove_file(output);
+                }
+            }
+            (_, Some(Ok(status))) if status.success() => job.state = State::Done,
+            _ => job.state = State::Failed,
+        }
+    }
+
+    fn work(&self, program: &PathBuf) {
+        loop {
+            let id = {
+                let mut jobs = self.lock();
+                loop {
+                    if let Some(id) = jobs.queue.pop_front() {
+                        break id;
+                    }
+                    jobs = self.queued.wait(jobs).unwrap_or_else(|e| e.into_inner());
+                }
+            };
+            if let Some(stderr) = self.start(id, program) {
+                self.follow(id, stderr);
+            }
+        }
+    }
+}
+
+/// Runs conversion jobs submitted over HTTP on `address`, at most `workers`
+/// at a time, until the process is stopped. The paths of the jobs are in
+/// `root`.
+pub fn serve(address: &str, workers: usize, root: &Path) -> Result<(), Box<dyn Error>> {
+    let program = std::env::current_exe()?;
+    let listener = http::listen(address, "jobs")?;
+    info!(
+        "Accepting conversion jobs on http://{}/jobs with {} workers",
+        listener.local_addr()?,
+        workers
+    );
+    let root = root
+        .canonicalize()
+        .map_err(|e| format!("Could not open the root {:?}: {}", root, e))?;
+    let server = Arc::new(Server::new(&root));
+    for _ in 0..workers.max(1) {
+        let server = server.clone();
+        let program = program.clone();
+        std::thread::spawn(move || server.work(&program));
+    }
+    http::handle(listener, &move |request| server.route(request));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_job() {
+        assert_eq!(
+            parse_job(
+                r#"{"input": "https://example.com/drive.mp4", "output": "/data/drive.mcap",
+                    "options": ["--transcode", "h264", "--topic", "/cam\"era"]}"#
+            ),
+            Ok(JobRequest {
+                input: "https://example.com/drive.mp4".to_string(),
+                output: "/data/drive.mcap".to_string(),
+                options: vec![
+                    "--transcode".to_string(),
+                    "h264".to_string(),
+                    "--topic".to_string(),
+                    "/cam\"era".to_string()
+                ],
+            })
+        );
+        assert_eq!(
+            parse_job(r#"{"input":"a.mp4","output":"a.mcap"}"#).map(|job| job.options),
+            Ok(Vec::new())
+        );
+        assert!(parse_job(r#"{"input":"a.mp4"}"#).is_err());
+        assert!(parse_job(r#"{"input":"a.mp4","output":"a.mcap","threads":4}"#).is_err());
+        assert!(parse_job("[]").is_err());
+    }
+
+    #[test]
+    fn test_check_job() {
+        let job = |input: &str, output: &str, options: &[&str]| JobRequest {
+            input: input.to_string(),
+            output: output.to_string(),
+            options: options.iter().map(|option| option.to_string()).collect(),
+        };
+        let root = Path::new("/data");
+        assert!(job("in/a.mp4", "./a.mcap", &["--topic", "/camera"])
+            .check(root)
+            .is_ok());
+        assert!(job("https://example.com/a.mp4", "a.mcap", &[])
+            .check(root)
+            .is_ok());
+        assert!(job("/etc/a.mp4", "a.mcap", &[]).check(root).is_err());
+        assert!(job("a.mp4", "../a.mcap", &[]).check(root).is_err());
+        assert!(job("a.mp4", "", &[]).check(root).is_err());
+        assert_eq!(
+            job("a.mp4", "a.mcap", &["--progress", "bar"]).check(root),
+            Err("--progress can't be given to a job".to_string())
+        );
+        assert!(job("a.mp4", "a.mcap", &["--sync-to=/etc/log.mcap"])
+            .check(root)
+            .is_err());
+        // Another subcommand on unchecked paths, or a filtergraph reading files
+        assert!(job(
+            "a.mp4",
+            "a.mcap",
+            &["inject", "/etc/x.mcap", "a.mp4", "-o", "/anywhere"]
+        )
+        .check(root)
+        .is_err());
+        assert!(job("a.mp4", "a.mcap", &["generate", "/anywhere.mcap"])
+            .check(root)
+            .is_err());
+        assert!(
+            job("a.mp4", "a.mcap", &["--", "/etc/a.mp4", "/anywhere.mcap"])
+                .check(root)
+                .is_err()
+        );
+        assert_eq!(
+            job("a.mp4", "a.mcap", &["--vf=movie=/etc/passwd"]).check(root),
+            Err("--vf can't be given to a job".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_job_path_symlinks() {
+        let dir = std::env::temp_dir().join(format!("mp42mcap-jobs-{}", std::process::id()));
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        std::fs::create_dir_all(root.join("in")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        let root = root.canonicalize().unwrap();
+
+        assert_eq!(
+            job_path(&root, "in/new/a.mcap"),
+            Ok(root.join("in/new/a.mcap"))
+        );
+        assert!(job_path(&root, "link/a.mcap").is_err());
+        assert!(job_path(&root, "link/new/a.mcap").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_evict_finished() {
+        let server = Server::new(Path::new(""));
+        let job = r#"{"input":"a.mp4","output":"a.mcap"}"#;
+        for _ in 0..FINISHED_JOBS + 2 {
+            let id = server.lock().next_id + 1;
+            server.submit(parse_job(job).unwrap());
+            server.cancel(id);
+        }
+        let jobs = server.lock();
+        assert_eq!(jobs.jobs.len(), FINISHED_JOBS + 1);
+        assert!(!jobs.jobs.contains_key(&1));
+    }
+
+    #[test]
+    fn test_routes() {
+        let server = Server::new(Path::new(""));
+        let request = |method: &str, path: &str, body: &str| Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.to_string(),
+        };
+        let created = server.route(&request(
+            "POST",
+            "/jobs",
+            r#"{"input":"a.mp4","output":"a.mcap"}"#,
+        ));
+        assert_eq!(created.status, 201);
+        assert_eq!(
+            created.body,
+            "{\"id\":1,\"state\":\"queued\",\"input\":\"a.mp4\",\"output\":\"a.mcap\",\
+            \"progress\":null,\"error\":null,\"warnings\":[]}"
+        );
+        assert_eq!(server.route(&request("POST", "/jobs", "{")).status, 400);
+        let escaping = r#"{"input":"a.mp4","output":"../a.mcap"}"#;
+        assert_eq!(
+            server.route(&request("POST", "/jobs", escaping)).status,
+            400
+        );
+        assert_eq!(server.route(&request("GET", "/jobs/1", "")).status, 200);
+        assert_eq!(server.route(&request("GET", "/jobs/2", "")).status, 404);
+
+        // A queued job is taken off the queue
+        let cancelled = server.route(&request("DELETE", "/jobs/1", ""));
+        assert!(cancelled.body.contains("\"state\":\"cancelled\""));
+        assert!(server.lock().queue.is_empty());
+        assert_eq!(server.route(&request("DELETE", "/jobs/1", "")).status, 409);
+        assert!(server
+            .route(&request("GET", "/jobs", ""))
+            .body
+            .starts_with("[{\"id\":1,"));
+    }
+}
@@ -0,0 +1,194 @@
+/// A JSON value. Numbers keep their text, so integers parse without the
+/// loss of going through f64.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The value of a field of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// The number parsed as `T`, with `what` naming it in the error
+    pub fn number<T: std::str::FromStr>(&self, what: &str) -> Result<T, String> {
+        match self {
+            Value::Number(number) => number
+                .parse()
+                .map_err(|_| format!("invalid {} {}", what, number)),
+            other => Err(format!("expected a number for {}, found {:?}", what, other)),
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            other => Err(format!("expected {:?}, found {:?}", expected, other)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next().ok_or("unterminated string")? {
+                '"' => return Ok(string),
+                '\\' => match self.chars.next().ok_or("unterminated string")? {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    'b' => string.push('\u{8}'),
+                    'f' => string.push('\u{c}'),
+                    'u' => {
+                        let code: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                        let code = u32::from_str_radix(&code, 16)
+                            .map_err(|_| format!("invalid escape \\u{}", code))?;
+                        string.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                    }
+                    c => string.push(c),
+                },
+                c => string.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> String {
+        let mut number = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            number.push(c);
+        }
+        number
+    }
+
+    fn word(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for expected in word.chars() {
+            if self.chars.next() != Some(expected) {
+                return Err(format!("expected {}", word));
+            }
+        }
+        Ok(value)
+    }
+
+    /// The items of an array or object, after its opening bracket
+    fn items<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.next_if_eq(&close).is_some() {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(c) if c == close => return Ok(items),
+                other => return Err(format!("expected ',' or {:?}, found {:?}", close, other)),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.chars.next();
+                Ok(Value::Array(self.items(']', Self::value)?))
+            }
+            Some('{') => {
+                self.chars.next();
+                Ok(Value::Object(self.items('}', |parser| {
+                    let key = parser.string()?;
+                    parser.expect(':')?;
+                    Ok((key, parser.value()?))
+                })?))
+            }
+            Some('t') => self.word("true", Value::Bool(true)),
+            Some('f') => self.word("false", Value::Bool(false)),
+            Some('n') => self.word("null", Value::Null),
+            Some(c) if *c == '-' || c.is_ascii_digit() => Ok(Value::Number(self.number())),
+            other => Err(format!("expected a value, found {:?}", other)),
+        }
+    }
+}
+
+/// Parses a JSON document, which has to be a single value
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    match parser.chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {:?} after the value", c)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let value = parse(
+            r#" {"label": "x\"y", "frame": 3, "stamp": -1.5e3, "ok": true,
+                "list": [null, false, "é"], "empty": {}} "#,
+        )
+        .unwrap();
+        assert_eq!(value.get("label"), Some(&Value::String("x\"y".to_string())));
+        assert_eq!(value.get("frame").unwrap().number::<i64>("frame"), Ok(3));
+        assert_eq!(
+            value.get("stamp").unwrap().number::<f64>("stamp"),
+            Ok(-1500.0)
+        );
+        assert!(value.get("stamp").unwrap().number::<u64>("stamp").is_err());
+        assert_eq!(value.get("ok"), Some(&Value::Bool(true)));
+        assert_eq!(
+            value.get("list"),
+            Some(&Value::Array(vec![
+                Value::Null,
+                Value::Bool(false),
+                Value::String("é".to_string())
+            ]))
+        );
+        assert_eq!(value.get("empty"), Some(&Value::Object(Vec::new())));
+        assert_eq!(value.get("missing"), None);
+
+        assert!(parse(r#"{"a": 1"#).is_err());
+        assert!(parse(r#"["a" "b"]"#).is_err());
+        assert!(parse("[1] 2").is_err());
+        assert!(parse("nul").is_err());
+        assert!(parse("").is_err());
+    }
+}
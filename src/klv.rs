@@ -0,0 +1,368 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::foxglove::{Pose, Vector3};
+use crate::telemetry::{pose_from_ned_angles, quaternion_from_euler};
+
+/// Universal key of the MISB ST 0601 UAS Datalink Local Set
+const UAS_DATALINK_KEY: [u8; 16] = [
+    0x06, 0x0E, 0x2B, 0x34, 0x02, 0x0B, 0x01, 0x01, 0x0E, 0x01, 0x03, 0x01, 0x01, 0x00, 0x00, 0x00,
+];
+
+// Local set tags
+const TAG_CHECKSUM: u32 = 1;
+const TAG_PLATFORM_HEADING: u32 = 5;
+const TAG_PLATFORM_PITCH: u32 = 6;
+const TAG_PLATFORM_ROLL: u32 = 7;
+const TAG_SENSOR_LATITUDE: u32 = 13;
+const TAG_SENSOR_LONGITUDE: u32 = 14;
+const TAG_SENSOR_ALTITUDE: u32 = 15;
+const TAG_SENSOR_AZIMUTH: u32 = 18;
+const TAG_SENSOR_ELEVATION: u32 = 19;
+const TAG_SENSOR_ROLL: u32 = 20;
+const TAG_FRAME_CENTER_LATITUDE: u32 = 23;
+const TAG_FRAME_CENTER_LONGITUDE: u32 = 24;
+const TAG_FRAME_CENTER_ELEVATION: u32 = 25;
+/// First of the latitude and longitude offsets of the four footprint corners
+const TAG_CORNER_OFFSETS: u32 = 26;
+
+/// Whether the stream is a KLV metadata track
+pub fn is_klv(parameters: &ffmpeg::codec::Parameters) -> bool {
+    parameters.id() == ffmpeg::codec::Id::SMPTE_KLV
+}
+
+/// Reads a BER length, returning it and its size in bytes
+fn read_ber_length(data: &[u8]) -> Option<(usize, usize)> {
+    let first = *data.first()?;
+    if first & 0x80 == 0 {
+        return Some((first as usize, 1));
+    }
+    let size = (first & 0x7F) as usize;
+    if size == 0 || size > 8 {
+        return None;
+    }
+    let bytes = data.get(1..1 + size)?;
+    // Lengths past the address space are rejected rather than wrapped
+    let length = bytes.iter().try_fold(0usize, |acc, &b| {
+        acc.checked_mul(256)?.checked_add(b as usize)
+    })?;
+    Some((length, 1 + size))
+}
+
+/// Reads a BER-OID encoded tag, returning it and its size in bytes
+fn read_ber_oid(data: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for (i, &byte) in data.iter().take(4).enumerate() {
+        value = (value << 7) | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// A KLV packet with a 16 byte universal key
+pub struct Packet<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    /// The whole packet, key and length included
+    pub bytes: &'a [u8],
+}
+
+impl Packet<'_> {
+    pub fn is_uas_datalink(&self) -> bool {
+        self.key == UAS_DATALINK_KEY
+    }
+}
+
+/// Splits the data of a KLV sample into its packets
+pub fn split_packets(data: &[u8]) -> Result<Vec<Packet<'_>>, String> {
+    let mut packets = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = data.get(pos..pos + 16).ok_or("Truncated KLV key")?;
+        let (length, length_size) =
+            read_ber_length(&data[pos + 16..]).ok_or("Invalid KLV length")?;
+        let start = pos + 16 + length_size;
+        let end = start.checked_add(length).ok_or("Invalid KLV length")?;
+        let value = data.get(start..end).ok_or("Truncated KLV value")?;
+        packets.push(Packet {
+            key,
+            value,
+            bytes: &data[pos..end],
+        });
+        pos = end;
+    }
+    Ok(packets)
+}
+
+/// The georeferencing fields of a UAS Datalink Local Set, in degrees and
+/// meters
+#[derive(Debug, Default, PartialEq)]
+pub struct UasDatalink {
+    pub platform_heading: Option<f64>,
+    pub platform_pitch: Option<f64>,
+    pub platform_roll: Option<f64>,
+    pub sensor_latitude: Option<f64>,
+    pub sensor_longitude: Option<f64>,
+    pub sensor_altitude: Option<f64>,
+    /// Sensor pointing relative to the platform
+    pub sensor_azimuth: Option<f64>,
+    pub sensor_elevation: Option<f64>,
+    pub sensor_roll: Option<f64>,
+    pub frame_center_latitude: Option<f64>,
+    pub frame_center_longitude: Option<f64>,
+    pub frame_center_elevation: Option<f64>,
+    /// Latitude and longitude offsets of the footprint corners from the
+    /// frame center
+    pub corner_offsets: [(Option<f64>, Option<f64>); 4],
+}
+
+/// Maps an unsigned integer onto `[min, max]`
+fn map_unsigned<const N: usize>(bytes: [u8; N], min: f64, max: f64) -> f64 {
+    let value = bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    let range = (1u64 << (N * 8)) - 1;
+    min + value as f64 * (max - min) / range as f64
+}
+
+/// Maps a signed integer onto `[-max, max]`, the most negative value
+/// meaning out of range
+fn map_signed<const N: usize>(bytes: [u8; N], max: f64) -> Option<f64> {
+    let value = bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    let shift = 64 - N * 8;
+    let value = ((value << shift) as i64) >> shift;
+    let range = (1i64 << (N * 8 - 1)) - 1;
+    (value != -range - 1).then(|| value as f64 * max / range as f64)
+}
+
+/// Checksum over the packet up to the checksum value
+fn checksum(bytes: &[u8]) -> u16 {
+    bytes.iter().enumerate().fold(0u16, |sum, (i, &byte)| {
+        sum.wrapping_add((byte as u16) << (8 * ((i + 1) % 2)))
+    })
+}
+
+impl UasDatalink {
+    /// Decodes a UAS Datalink Local Set, checking its checksum. Items with
+    /// an unexpected length are ignored.
+    pub fn parse(packet: &Packet) -> Result<Self, String> {
+        let mut datalink = Self::default();
+        let mut pos = 0;
+        while pos < packet.value.len() {
+            let (tag, tag_size) =
+                read_ber_oid(&packet.value[pos..]).ok_or("Invalid local set tag")?;
+            let (length, length_size) = read_ber_length(&packet.value[pos + tag_size..])
+                .ok_or("Invalid local set length")?;
+            let start = pos + tag_size + length_size;
+            let end = start
+                .checked_add(length)
+                .ok_or_else(|| format!("Invalid length of local set item {}", tag))?;
+            let value = packet
+                .value
+                .get(start..end)
+                .ok_or_else(|| format!("Truncated local set item {}", tag))?;
+            pos = end;
+
+            let two = <[u8; 2]>::try_from(value).ok();
+            let four = <[u8; 4]>::try_from(value).ok();
+            match tag {
+                TAG_CHECKSUM => {
+                    let expected = u16::from_be_bytes(two.ok_or("Invalid checksum length")?);
+                    let offset = packet.bytes.len() - packet.value.len() + start;
+                    let actual = checksum(&packet.bytes[..offset]);
+                    if actual != expected {
+                        return Err(format!(
+                            "KLV checksum mismatch, expected {:#06x} but got {:#06x}",
+                            expected, actual
+                        ));
+                    }
+                }
+                TAG_PLATFORM_HEADING => {
+                    datalink.platform_heading = two.map(|v| map_unsigned(v, 0.0, 360.0))
+                }
+                TAG_PLATFORM_PITCH => {
+                    datalink.platform_pitch = two.and_then(|v| map_signed(v, 20.0))
+                }
+                TAG_PLATFORM_ROLL => datalink.platform_roll = two.and_then(|v| map_signed(v, 50.0)),
+                TAG_SENSOR_LATITUDE => {
+                    datalink.sensor_latitude = four.and_then(|v| map_signed(v, 90.0))
+                }
+                TAG_SENSOR_LONGITUDE => {
+                    datalink.sensor_longitude = four.and_then(|v| map_signed(v, 180.0))
+                }
+                TAG_SENSOR_ALTITUDE => {
+                    datalink.sensor_altitude = two.map(|v| map_unsigned(v, -900.0, 19000.0))
+                }
+                TAG_SENSOR_AZIMUTH => {
+                    datalink.sensor_azimuth = four.map(|v| map_unsigned(v, 0.0, 360.0))
+                }
+                TAG_SENSOR_ELEVATION => {
+                    datalink.sensor_elevation = four.and_then(|v| map_signed(v, 180.0))
+                }
+                TAG_SENSOR_ROLL => datalink.sensor_roll = four.map(|v| map_unsigned(v, 0.0, 360.0)),
+                TAG_FRAME_CENTER_LATITUDE => {
+                    datalink.frame_center_latitude = four.and_then(|v| map_signed(v, 90.0))
+                }
+                TAG_FRAME_CENTER_LONGITUDE => {
+                    datalink.frame_center_longitude = four.and_then(|v| map_signed(v, 180.0))
+                }
+                TAG_FRAME_CENTER_ELEVATION => {
+                    datalink.frame_center_elevation = two.map(|v| map_unsigned(v, -900.0, 19000.0))
+                }
+                tag if (TAG_CORNER_OFFSETS..TAG_CORNER_OFFSETS + 8).contains(&tag) => {
+                    let index = (tag - TAG_CORNER_OFFSETS) as usize;
+                    let offset = two.and_then(|v| map_signed(v, 0.075));
+                    let corner = &mut datalink.corner_offsets[index / 2];
+                    if index.is_multiple_of(2) {
+                        corner.0 = offset;
+                    } else {
+                        corner.1 = offset;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(datalink)
+    }
+
+    /// Latitude, longitude and altitude of the sensor
+    pub fn sensor_position(&self) -> Option<(f64, f64, f64)> {
+        Some((
+            self.sensor_latitude?,
+            self.sensor_longitude?,
+            self.sensor_altitude.unwrap_or(0.0),
+        ))
+    }
+
+    /// Latitude, longitude and elevation of the center of the image on the
+    /// ground
+    pub fn frame_center(&self) -> Option<(f64, f64, f64)> {
+        Some((
+            self.frame_center_latitude?,
+            self.frame_center_longitude?,
+            self.frame_center_elevation.unwrap_or(0.0),
+        ))
+    }
+
+    /// The platform attitude in an east-north-up frame
+    pub fn platform_pose(&self) -> Option<Pose> {
+        Some(pose_from_ned_angles(
+            self.platform_heading?,
+            self.platform_pitch.unwrap_or(0.0),
+            self.platform_roll.unwrap_or(0.0),
+        ))
+    }
+
+    /// The sensor pointing in the forward-left-up frame of the platform.
+    /// Azimuth is clockwise from the nose and elevation is up.
+    pub fn sensor_pose(&self) -> Option<Pose> {
+        Some(Pose {
+            position: Some(Vector3::default()),
+            orientation: Some(quaternion_from_euler(
+                -self.sensor_azimuth?.to_radians(),
+                -self.sensor_elevation.unwrap_or(0.0).to_radians(),
+                self.sensor_roll.unwrap_or(0.0).to_radians(),
+            )),
+        })
+    }
+
+    /// The ground footprint of the image as a GeoJSON polygon
+    pub fn footprint_geojson(&self) -> Option<String> {
+        let (latitude, longitude, _) = self.frame_center()?;
+        let mut corners = Vec::with_capacity(5);
+        for (latitude_offset, longitude_offset) in self.corner_offsets {
+            corners.push(format!(
+                "[{},{}]",
+                longitude + longitude_offset?,
+                latitude + latitude_offset?
+            ));
+        }
+        corners.push(corners[0].clone());
+        Some(format!(
+            "{{\"type\":\"Feature\",\"geometry\":{{\"type\":\"Polygon\",\"coordinates\":[[{}]]}},\"properties\":{{}}}}",
+            corners.join(",")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a UAS Datalink packet with a valid checksum from local set items
+    fn datalink_packet(items: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut value = Vec::new();
+        for (tag, item) in items {
+            value.push(*tag);
+            value.push(item.len() as u8);
+            value.extend_from_slice(item);
+        }
+        value.extend_from_slice(&[TAG_CHECKSUM as u8, 2]);
+        let mut packet = UAS_DATALINK_KEY.to_vec();
+        packet.push(value.len() as u8 + 2);
+        packet.extend_from_slice(&value);
+        let sum = checksum(&packet);
+        packet.extend_from_slice(&sum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_ber() {
+        assert_eq!(read_ber_length(&[0x05]), Some((5, 1)));
+        assert_eq!(read_ber_length(&[0x82, 0x01, 0x00]), Some((256, 3)));
+        assert_eq!(read_ber_length(&[0x82, 0x01]), None);
+        assert_eq!(read_ber_oid(&[0x81, 0x01]), Some((129, 2)));
+
+        // A length that would run past the end of the address space
+        let mut huge = UAS_DATALINK_KEY.to_vec();
+        huge.extend_from_slice(&[0x88, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(split_packets(&huge).is_err());
+    }
+
+    #[test]
+    fn test_parse_uas_datalink() {
+        let data = datalink_packet(&[
+            // Example values from MISB ST 0601
+            (5, &[0x71, 0xC2]),
+            (13, &[0x55, 0x95, 0xB6, 0x6D]),
+            (14, &[0x5B, 0x53, 0x60, 0xC4]),
+            (15, &[0xC2, 0x21]),
+            (6, &[0x80, 0x00]),
+            (99, &[1, 2, 3]),
+        ]);
+        let packets = split_packets(&data).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].is_uas_datalink());
+
+        let datalink = UasDatalink::parse(&packets[0]).unwrap();
+        assert!((datalink.platform_heading.unwrap() - 159.9744).abs() < 1e-3);
+        assert_eq!(datalink.platform_pitch, None);
+        let (latitude, longitude, altitude) = datalink.sensor_position().unwrap();
+        assert!((latitude - 60.176822966978335).abs() < 1e-6);
+        assert!((longitude - 128.42675904204452).abs() < 1e-6);
+        assert!((altitude - 14190.72).abs() < 0.01);
+
+        let mut corrupted = data.clone();
+        corrupted[20] ^= 0xFF;
+        let packets = split_packets(&corrupted).unwrap();
+        assert!(UasDatalink::parse(&packets[0]).is_err());
+
+        assert!(split_packets(&data[..20]).is_err());
+    }
+
+    #[test]
+    fn test_footprint_geojson() {
+        let mut datalink = UasDatalink {
+            frame_center_latitude: Some(10.0),
+            frame_center_longitude: Some(20.0),
+            corner_offsets: [(Some(0.5), Some(-0.5)); 4],
+            ..Default::default()
+        };
+        assert_eq!(
+            datalink.footprint_geojson().unwrap(),
+            "{\"type\":\"Feature\",\"geometry\":{\"type\":\"Polygon\",\"coordinates\":\
+            [[[19.5,10.5],[19.5,10.5],[19.5,10.5],[19.5,10.5],[19.5,10.5]]]},\"properties\":{}}"
+        );
+        datalink.corner_offsets[2].1 = None;
+        assert_eq!(datalink.footprint_geojson(), None);
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+
+/// Whether the input is a live UDP or RTP stream, or the SDP file of one
+pub fn is_live(input: &Path) -> bool {
+    let input = input.to_string_lossy();
+    ["udp://", "rtp://"]
+        .iter()
+        .any(|scheme| input.starts_with(scheme))
+        || input.to_ascii_lowercase().ends_with(".sdp")
+}
+
+/// Demuxer and protocol options for a live stream, to ride out loss and
+/// bursts on the network
+pub fn input_options(input: &Path) -> Vec<(&'static str, String)> {
+    let mut options = [
+        // Drop what the demuxer knows is damaged rather than decode it
+        ("fflags", "+discardcorrupt"),
+        // A larger socket buffer, and a full one drops packets instead of
+        // failing
+        ("buffer_size", "8388608"),
+        ("fifo_size", "1000000"),
+        ("overrun_nonfatal", "1"),
+        // Waits for RTP packets that arrive out of order
+        ("reorder_queue_size", "500"),
+    ]
+    .map(|(key, value)| (key, value.to_string()))
+    .to_vec();
+    if input
+        .to_string_lossy()
+        .to_ascii_lowercase()
+        .ends_with(".sdp")
+    {
+        // The SDP names the UDP ports the demuxer listens on
+        options.push(("protocol_whitelist", "file,udp,rtp,crypto,data".to_string()));
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_live_inputs() {
+        assert!(is_live(Path::new("udp://239.0.0.1:5004")));
+        assert!(is_live(Path::new("rtp://@:5004")));
+        assert!(is_live(Path::new("camera.SDP")));
+        assert!(!is_live(Path::new("video.mp4")));
+        assert!(input_options(Path::new("camera.sdp"))
+            .iter()
+            .any(|(key, _)| *key == "protocol_whitelist"));
+        assert!(!input_options(Path::new("udp://239.0.0.1:5004"))
+            .iter()
+            .any(|(key, _)| *key == "protocol_whitelist"));
+    }
+}
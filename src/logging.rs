@@ -0,0 +1,123 @@
+// Console output filtered by `-q` and `-v`. Informational messages go to
+// stdout, everything else to stderr, with the progress bar cleared around
+// them.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    /// Problems with the input or the conversion, shown even with `-q`
+    Warn,
+    /// What was converted and written
+    Info,
+    /// Every deviation as it happens, and the choices made, with `-v`
+    Debug,
+    /// Per frame and NAL unit detail, with `-vv`
+    Trace,
+}
+
+impl Level {
+    pub fn from_flags(quiet: bool, verbose: u8) -> Self {
+        match (quiet, verbose) {
+            (true, _) => Level::Warn,
+            (false, 0) => Level::Info,
+            (false, 1) => Level::Debug,
+            (false, _) => Level::Trace,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Warnings kept for the status endpoint
+const RECENT_WARNINGS: usize = 20;
+
+static WARNINGS: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Keeps a warning among the most recent ones
+pub fn remember(warning: String) {
+    let mut warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    if warnings.len() == RECENT_WARNINGS {
+        warnings.pop_front();
+    }
+    warnings.push_back(warning);
+}
+
+/// The most recent warnings, oldest first
+pub fn recent_warnings() -> Vec<String> {
+    let warnings = WARNINGS.lock().unwrap_or_else(|e| e.into_inner());
+    warnings.iter().cloned().collect()
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        let warning = format!($($arg)*);
+        if $crate::logging::enabled($crate::logging::Level::Warn) {
+            $crate::progress::suspend(|| eprintln!("{}", warning));
+        }
+        $crate::logging::remember(warning);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Info) {
+            let line = format!($($arg)*);
+            $crate::progress::suspend(|| println!("{}", line));
+        }
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Debug) {
+            let line = format!($($arg)*);
+            $crate::progress::suspend(|| eprintln!("{}", line));
+        }
+    };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::logging::enabled($crate::logging::Level::Trace) {
+            let line = format!($($arg)*);
+            $crate::progress::suspend(|| eprintln!("{}", line));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_from_flags() {
+        assert_eq!(Level::from_flags(true, 2), Level::Warn);
+        assert_eq!(Level::from_flags(false, 0), Level::Info);
+        assert_eq!(Level::from_flags(false, 1), Level::Debug);
+        assert_eq!(Level::from_flags(false, 3), Level::Trace);
+        assert!(Level::Warn < Level::Info && Level::Debug < Level::Trace);
+    }
+
+    #[test]
+    fn test_recent_warnings() {
+        for i in 0..RECENT_WARNINGS + 2 {
+            remember(format!("warning {}", i));
+        }
+        // Other tests may warn at the same time
+        let warnings = recent_warnings();
+        assert_eq!(warnings.len(), RECENT_WARNINGS);
+        assert!(!warnings.contains(&"warning 0".to_string()));
+        assert!(warnings.contains(&format!("warning {}", RECENT_WARNINGS + 1)));
+    }
+}
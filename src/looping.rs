@@ -0,0 +1,40 @@
+/// Decides when to stop reading the input, once the video is `duration_ns`
+/// long, and counts the passes over it with --loop
+pub struct Looper {
+    duration_ns: Option<u64>,
+    first_ns: Option<i64>,
+    /// Times the input was read from the start again
+    pub passes: usize,
+}
+
+impl Looper {
+    pub fn new(duration_ns: Option<u64>) -> Self {
+        Self {
+            duration_ns,
+            first_ns: None,
+            passes: 0,
+        }
+    }
+
+    /// Takes the timestamp of the next video packet, in nanoseconds on the
+    /// continuous timeline, and returns whether it's past the duration
+    pub fn reached(&mut self, timestamp_ns: i64) -> bool {
+        let first_ns = *self.first_ns.get_or_insert(timestamp_ns);
+        self.duration_ns
+            .is_some_and(|duration_ns| timestamp_ns.saturating_sub(first_ns) >= duration_ns as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_looper() {
+        let mut looper = Looper::new(Some(1_000_000_000));
+        assert!(!looper.reached(500_000_000));
+        assert!(!looper.reached(1_499_999_999));
+        assert!(looper.reached(1_500_000_000));
+        assert!(!Looper::new(None).reached(i64::MAX));
+    }
+}
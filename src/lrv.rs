@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+/// Where GoPro cameras record the low-resolution video of `input`: next to
+/// it with an .LRV extension, and on newer models with the GX or GH prefix
+/// of the name replaced by GL
+fn candidates(input: &Path) -> Vec<PathBuf> {
+    let Some(stem) = input.file_stem().and_then(|stem| stem.to_str()) else {
+        return Vec::new();
+    };
+    if input
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("lrv"))
+    {
+        return Vec::new();
+    }
+    let mut stems = vec![stem.to_string()];
+    if let Some(rest) = ["GX", "GH", "gx", "gh"]
+        .iter()
+        .find_map(|prefix| stem.strip_prefix(prefix))
+    {
+        let prefix = if stem.starts_with('g') { "gl" } else { "GL" };
+        stems.push(format!("{}{}", prefix, rest));
+    }
+    stems
+        .iter()
+        .flat_map(|stem| ["LRV", "lrv"].map(|extension| format!("{}.{}", stem, extension)))
+        .map(|name| input.with_file_name(name))
+        .collect()
+}
+
+/// The GoPro low-resolution video recorded with `input`, if there is one
+pub fn companion(input: &Path) -> Option<PathBuf> {
+    candidates(input).into_iter().find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(
+            candidates(Path::new("DCIM/100GOPRO/GX010123.MP4")),
+            [
+                "DCIM/100GOPRO/GX010123.LRV",
+                "DCIM/100GOPRO/GX010123.lrv",
+                "DCIM/100GOPRO/GL010123.LRV",
+                "DCIM/100GOPRO/GL010123.lrv",
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            candidates(Path::new("gopr0042.mp4")),
+            ["gopr0042.LRV", "gopr0042.lrv"].map(PathBuf::from)
+        );
+        assert!(candidates(Path::new("GL010123.LRV")).is_empty());
+    }
+}
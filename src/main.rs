@@ -1,25 +1,157 @@
 use std::{
     borrow::Cow,
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
+    ffi::OsString,
     fs::File,
-    io::{BufWriter, Write},
-    path::PathBuf,
+    io::BufWriter,
+    path::{Path, PathBuf},
     time::Instant,
 };
 
+use bytes::Bytes;
 use clap::Parser;
 use ffmpeg_next as ffmpeg;
-use mcap::{records::MessageHeader, Channel, Schema, Writer};
+use mcap::{
+    records::{MessageHeader, Metadata},
+    Channel, Schema, WriteOptions, Writer,
+};
 use prost::Message;
 
 pub mod foxglove {
     include!(concat!(env!("OUT_DIR"), "/foxglove.rs"));
 }
-use foxglove::CompressedVideo;
+pub mod mp42mcap {
+    include!(concat!(env!("OUT_DIR"), "/mp42mcap.rs"));
+}
+use foxglove::{
+    CameraCalibration, CompressedImage, CompressedVideo, FrameTransforms, LocationFix, PoseInFrame,
+};
+
+// First, for its macros to be in scope in the other modules
+#[macro_use]
+mod logging;
+use logging::Level;
+
+mod av1;
+mod bag;
+use bag::OutputFormat;
+mod capture;
+use capture::CaptureOptions;
+mod bench;
+use bench::{Bench, Stage};
+mod calibration;
+mod camm;
+mod chapters;
+use chapters::ChapterMode;
+mod crc;
 
 mod codec;
-use codec::VideoConverter;
+use codec::{CodecType, ConverterOptions, NalFilter, ParameterSetMode, VideoConverter};
+
+mod derived;
+mod duration;
+use derived::DerivedStream;
+mod duplicates;
+use duplicates::DuplicateDetector;
+mod editlist;
+mod filter;
+mod flatbuffer;
+use flatbuffer::MessageEncoding;
+mod hash;
+mod http;
+use hash::HashAlgorithm;
+
+mod hwaccel;
+mod image;
+use image::{ImageEncoder, ImageFormat, OutputMessages};
+
+mod extract;
+mod failure;
+use extract::Every;
+use failure::{ErrorFormat, Failure};
+mod inject;
+use inject::InjectInput;
+mod insta360;
+mod interleave;
+use interleave::Interleaver;
+mod jobs;
+mod json;
+mod klv;
+mod live;
+mod looping;
+use looping::Looper;
+mod lrv;
+mod mebx;
+mod memory;
+use memory::MemoryBudget;
+mod metrics;
+mod minimal;
+mod mp4box;
+mod nal;
+mod onvif;
+mod passthrough;
+use passthrough::PassthroughStream;
+mod pattern;
+use pattern::Pattern;
+mod playlist;
+mod preview;
+use preview::PreviewOptions;
+mod progress;
+use progress::{Progress, ProgressMode};
+mod qr;
+mod quality;
+use hwaccel::HardwareEncoder;
+
+mod rosbag2;
+mod sampling;
+use sampling::Sampler;
+mod scene;
+use scene::SceneDetector;
+
+mod schema;
+use schema::{CustomSchema, FieldMapping};
+
+mod segments;
+mod selftest;
+
+mod sps;
+mod status;
+mod stereo;
+use stereo::StereoLayout;
+mod stats;
+use stats::{BitrateWindows, FrameGaps};
+mod stream_info;
+mod subtitles;
+mod summary;
+use summary::ConversionSummary;
+mod telemetry;
+mod throttle;
+use telemetry::TelemetrySample;
+use throttle::RateLimit;
+mod timesource;
+mod timestamps;
+use timesource::LogTimeSource;
+mod timesync;
+mod topic;
+use topic::NameFrom;
+
+mod tracks;
+use tracks::{LogOutput, MebxOutput, Tracks};
+
+mod transcode;
+use transcode::{parse_bitrate, Scale, ToneMap, TranscodeOptions};
+
+mod transforms;
+use transforms::StaticTransform;
+
+mod validation;
+use validation::Strictness;
+
+mod verify;
+use verify::DecodeVerifier;
+
+mod yaml;
 
 /// Convert MP4 files to MCAP format
 #[derive(Parser)]
@@ -29,109 +161,2888 @@ use codec::VideoConverter;
 #[command(about = "Converts MP4 videos to MCAP", long_about = None)]
 #[command(arg_required_else_help = true)]
 struct Cli {
-    /// Input MP4 file
+    /// Only print warnings and errors, and the reports asked for
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also print every deviation as it's found and the choices made, or with -vv, detail
+    /// on every frame
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Input MP4 file, an HLS (.m3u8) or DASH (.mpd) playlist or URL, a udp:// or rtp://
+    /// stream or its SDP file, or the camera device with --capture
     #[arg(value_name = "INPUT")]
     input: PathBuf,
 
+    /// Record from the camera INPUT, like /dev/video0 or an AVFoundation device index,
+    /// encoding it to H.264 until Ctrl-C
+    #[arg(long, conflicts_with_all = ["jobs", "qr_time_sync"])]
+    capture: bool,
+
+    /// Device demuxer for --capture [default: v4l2, or avfoundation on macOS]
+    #[arg(long, value_name = "FORMAT", requires = "capture")]
+    capture_format: Option<String>,
+
+    /// Size to ask the camera for, as WIDTHxHEIGHT
+    #[arg(long, value_name = "SIZE", requires = "capture")]
+    capture_size: Option<String>,
+
+    /// Frame rate to ask the camera for
+    #[arg(long, value_name = "FPS", requires = "capture")]
+    capture_fps: Option<u32>,
+
+    /// Set by mp42mcap generate, for which INPUT is the lavfi graph that draws it
+    #[arg(skip)]
+    pattern: Option<Pattern>,
+
     /// Output MCAP file
     #[arg(value_name = "OUTPUT")]
     output: PathBuf,
 
-    /// Topic name for the video messages
+    /// Write an MCAP file, or a ROS 1 bag or a rosbag2 directory with only the video as
+    /// sensor_msgs/CompressedImage messages
+    #[arg(long, value_name = "FORMAT", default_value = "mcap")]
+    format: OutputFormat,
+
+    /// Topic name for the video messages, which can contain {stem} for the file name of the
+    /// input, {stream} for the index and {handler} for the handler name of the video track
     #[arg(long, default_value = "video")]
     topic: String,
 
+    /// Name the video topic after the track, from its handler name or udta name, when it has
+    /// one other than the default of the muxer
+    #[arg(long, value_name = "SOURCE", conflicts_with = "topic")]
+    topic_from: Option<NameFrom>,
+
+    /// Serialize the video messages as protobuf, or as flatbuffer with the FlatBuffers
+    /// CompressedVideo schema
+    #[arg(long, value_name = "ENCODING", default_value = "protobuf")]
+    message_encoding: MessageEncoding,
+
+    /// Write the video as compressed-video messages, or as compressed-image with every frame
+    /// decoded and encoded as an image
+    #[arg(long, value_name = "MESSAGES", default_value = "compressed-video")]
+    output_messages: OutputMessages,
+
+    /// Format of the images with --output-messages compressed-image: jpeg, png or webp
+    #[arg(long, value_name = "FORMAT", default_value = "jpeg")]
+    image_format: ImageFormat,
+
+    /// Quality of JPEG and WebP images, from 1 to 100
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u32).range(1..=100)
+    )]
+    quality: u32,
+
+    /// Also write a downscaled, low frame rate H.264 copy of the video to this topic, for
+    /// reviewers on slow connections
+    #[arg(long, value_name = "TOPIC")]
+    preview_topic: Option<String>,
+
+    /// Height of the preview in pixels, if the video is taller
+    #[arg(
+        long,
+        value_name = "PIXELS",
+        default_value_t = 480,
+        requires = "preview_topic"
+    )]
+    preview_height: u32,
+
+    /// Frames per second of the preview
+    #[arg(
+        long,
+        value_name = "FPS",
+        default_value_t = 2,
+        requires = "preview_topic",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    preview_fps: u32,
+
+    /// Bitrate of the preview, e.g. 250k
+    #[arg(
+        long,
+        value_parser = parse_bitrate,
+        default_value = "250k",
+        requires = "preview_topic"
+    )]
+    preview_bitrate: usize,
+
+    /// GoPro low-resolution video (.LRV) to pass through to <TOPIC>/preview, instead of the one
+    /// the camera recorded next to the input
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["preview_topic", "no_lrv", "loop_input", "at", "at_file"]
+    )]
+    lrv: Option<PathBuf>,
+
+    /// Leave out the GoPro low-resolution video recorded next to the input
+    #[arg(long)]
+    no_lrv: bool,
+
+    /// Keep an Insta360 dual-fisheye recording as one video on <TOPIC>, the first lens, instead of
+    /// writing each lens to <TOPIC>/lens0 and <TOPIC>/lens1
+    #[arg(long)]
+    no_lens_split: bool,
+
+    /// Split side-by-side (sbs) stereo frames into their halves, re-encoded to <TOPIC>/left
+    /// and <TOPIC>/right
+    #[arg(long, value_name = "LAYOUT")]
+    stereo_split: Option<StereoLayout>,
+
+    /// .proto file with the message type to write the video as, instead of
+    /// foxglove.CompressedVideo
+    #[arg(long, value_name = "FILE", requires = "schema_name")]
+    schema: Option<PathBuf>,
+
+    /// Fully qualified name of the message type in --schema, e.g. acme.Video
+    #[arg(long, value_name = "NAME", requires = "schema")]
+    schema_name: Option<String>,
+
+    /// Set a field of the --schema message to timestamp, frame_id, data, format, sequence,
+    /// keyframe or a constant, as FIELD=SOURCE. Can be repeated
+    #[arg(long, value_name = "FIELD=SOURCE", requires = "schema")]
+    field_map: Vec<FieldMapping>,
+
     /// Frame ID for the video messages
     #[arg(long, default_value = "video")]
     frame_id: String,
+
+    /// Take the frame ID of the video messages from the track, like --topic-from
+    #[arg(long, value_name = "SOURCE", conflicts_with = "frame_id")]
+    frame_id_from: Option<NameFrom>,
+
+    /// Sequence number of the first message written to the video channel. By default 0, or with
+    /// inject the one after the last message of the log on the topic
+    #[arg(long, value_name = "N")]
+    sequence_start: Option<u32>,
+
+    /// DJI SRT sidecar file to write location and gimbal pose channels from
+    #[arg(long, value_name = "FILE")]
+    telemetry_srt: Option<PathBuf>,
+
+    /// ROS camera_info or OpenCV calibration YAML file to write as a
+    /// CameraCalibration message on <TOPIC>/calibration
+    #[arg(long, value_name = "FILE")]
+    calibration: Option<PathBuf>,
+
+    /// Static transform to write to the tf_static topic, as
+    /// parent:child:x,y,z,qx,qy,qz,qw. Can be repeated
+    #[arg(long, value_name = "TRANSFORM")]
+    transform: Vec<StaticTransform>,
+
+    /// YAML file with a list of static transforms, each with parent, child,
+    /// translation and rotation
+    #[arg(long, value_name = "FILE")]
+    transform_file: Option<PathBuf>,
+
+    /// SRT subtitle file to write as log messages, timed like the frames of the video
+    #[arg(long, value_name = "FILE")]
+    srt: Option<PathBuf>,
+
+    /// How to write the chapters of the input: metadata records, log messages on a
+    /// chapters topic, or none
+    #[arg(long, value_name = "MODE", default_value = "metadata")]
+    chapters: ChapterMode,
+
+    /// Fail on any anomaly in the input stream
+    #[arg(long, conflicts_with = "lenient")]
+    strict: bool,
+
+    /// Repair anomalies in the input stream where possible and report them
+    #[arg(long)]
+    lenient: bool,
+
+    /// Pass SEI NAL units through, optionally only those with these payload types
+    #[arg(
+        long,
+        value_name = "TYPES",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ','
+    )]
+    keep_sei: Option<Vec<u32>>,
+
+    /// Drop access unit delimiter and filler data NAL units, which carry no picture data
+    #[arg(long)]
+    strip_aud_filler: bool,
+
+    /// Write the size, keyframe flag, NAL unit types and timestamps of every frame to
+    /// <TOPIC>/frame_stats
+    #[arg(long)]
+    frame_stats: bool,
+
+    /// Write the bitrate of every second of video to <TOPIC>/bitrate
+    #[arg(long)]
+    emit_bitrate: bool,
+
+    /// Write a message per keyframe with its frame index to <TOPIC>/keyframes
+    #[arg(long)]
+    emit_keyframes: bool,
+
+    /// Write a hash of every frame's data to <TOPIC>/frame_hashes, with xxhash or sha256
+    #[arg(long, value_name = "ALGORITHM")]
+    frame_hashes: Option<HashAlgorithm>,
+
+    /// Write the cuts in the video to <TOPIC>/scene_changes, where the luma histograms of
+    /// consecutive frames differ by at least THRESHOLD (0 to 1)
+    #[arg(
+        long,
+        value_name = "THRESHOLD",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0.4",
+        value_parser = scene::parse_threshold
+    )]
+    scene_changes: Option<f64>,
+
+    /// Scan the first FRAMES decoded frames for a QR code showing the Unix time, and
+    /// shift all timestamps so that frame is logged at that time
+    #[arg(
+        long,
+        value_name = "FRAMES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "300"
+    )]
+    qr_time_sync: Option<usize>,
+
+    /// Offset the video to start at the first message of this MCAP, to pair it with a log
+    #[arg(long, value_name = "FILE", conflicts_with = "qr_time_sync")]
+    sync_to: Option<PathBuf>,
+
+    /// With --sync-to, start the video at this log time in nanoseconds instead
+    #[arg(long, value_name = "NANOSECONDS", requires = "sync_to")]
+    sync_anchor: Option<u64>,
+
+    /// Take the timestamp of every frame from a CSV or JSON file mapping the frame index, or
+    /// the PTS, to a Unix time in nanoseconds, instead of the container's timing
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["qr_time_sync", "sync_to", "capture"]
+    )]
+    timestamps: Option<PathBuf>,
+
+    /// Where the log times of the video come from: pts, dts, wall, creation-time, timecode,
+    /// sei or sidecar [default: sidecar with --timestamps, otherwise pts]
+    #[arg(long, value_name = "SOURCE")]
+    log_time_source: Option<LogTimeSource>,
+
+    /// Play the segments of an MP4 edit list back to back, instead of keeping the pauses
+    /// between them
+    #[arg(long)]
+    collapse_edit_gaps: bool,
+
+    /// Stop once the video is this long, like 10min, 90s or 500ms
+    #[arg(long, value_name = "DURATION", value_parser = duration::parse_duration)]
+    duration: Option<u64>,
+
+    /// Read the input again from the start, with the timestamps carrying on, until the
+    /// video is --duration long, to make long test data from a short clip
+    #[arg(
+        long = "loop",
+        requires = "duration",
+        conflicts_with_all = ["capture", "timestamps", "jobs"]
+    )]
+    loop_input: bool,
+
+    /// Only write the frame nearest to this time from the start of the video, like 12.5s,
+    /// re-encoded as a keyframe. Can be given more than once
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = duration::parse_time,
+        conflicts_with_all = ["capture", "jobs", "loop_input"]
+    )]
+    at: Vec<u64>,
+
+    /// Like --at, for each time listed in this file, one per line
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["capture", "jobs", "loop_input"])]
+    at_file: Option<PathBuf>,
+
+    /// Skip frames that repeat the one before: byte-identical ones, or when re-encoding,
+    /// ones that decode to the same picture
+    #[arg(long)]
+    drop_duplicate_frames: bool,
+
+    /// Also write every gap in the video, where frames are further apart than the nominal
+    /// frame interval, as a warning to <TOPIC>/diagnostics
+    #[arg(long)]
+    log_frame_gaps: bool,
+
+    /// Decode the frames exactly as they are written with a second decoder, and fail if
+    /// any of them doesn't decode cleanly
+    #[arg(long)]
+    verify_decode: bool,
+
+    /// Print NAL unit, GOP and frame size statistics after converting
+    #[arg(long)]
+    stats: bool,
+
+    /// How to report progress: auto for a bar on a terminal and dots otherwise, or json for
+    /// a JSON object per line on stderr
+    #[arg(long, value_name = "MODE", default_value = "auto")]
+    progress: ProgressMode,
+
+    /// How to report a failed conversion: text for the message, or json for a JSON object on
+    /// stderr with an error code, the packet it failed at and what to do about it
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    error_format: ErrorFormat,
+
+    /// Serve Prometheus metrics of the conversion, like the frames converted and bytes written,
+    /// at http://ADDR/metrics, e.g. 0.0.0.0:9464
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+
+    /// Serve the progress, input, output and recent warnings of the conversion as JSON at
+    /// http://ADDR/status, e.g. 8080 for 127.0.0.1:8080 or 0.0.0.0:8080 for every interface
+    #[arg(long, value_name = "ADDR")]
+    status_addr: Option<String>,
+
+    /// Print the time spent demuxing, converting, decoding, encoding and writing, and the
+    /// frames and megabytes per second
+    #[arg(long)]
+    bench: bool,
+
+    /// Write the statistics as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    stats_json: Option<PathBuf>,
+
+    /// Print a JSON summary of the conversion to stdout when done
+    #[arg(long)]
+    summary_json: bool,
+
+    /// Write the JSON summary of the conversion to this file
+    #[arg(long, value_name = "FILE")]
+    summary_file: Option<PathBuf>,
+
+    /// Longest wait in seconds between attempts to reconnect to an http(s) input that dropped,
+    /// which resume where it stopped, or 0 to fail at once
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    network_retry_max: u32,
+
+    /// Read the input and write the output at no more than this many megabytes per second each,
+    /// to leave the disk to other work
+    #[arg(long, value_name = "MB/S", value_parser = throttle::parse_rate)]
+    limit_rate: Option<f64>,
+
+    /// Run at this lower CPU priority, from 0 to 19 as with nice(1), which on Linux lowers the
+    /// I/O priority too
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(i32).range(0..=19)
+    )]
+    nice: Option<i32>,
+
+    /// Number of decoder threads, 0 for one per CPU core
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    threads: usize,
+
+    /// When re-encoding, convert segments between keyframes on this many threads at once,
+    /// 0 for one per CPU core
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        conflicts_with_all = [
+            "scene_changes",
+            "drop_duplicate_frames",
+            "log_frame_gaps",
+            "quality_metrics",
+            "burn_frame_index",
+            "preview_topic",
+            "stereo_split",
+        ]
+    )]
+    jobs: usize,
+
+    /// Keep the memory used for buffering within about this size, e.g. 512M, with smaller
+    /// MCAP chunks written out eagerly and fewer threads
+    #[arg(long, value_name = "SIZE")]
+    max_memory: Option<MemoryBudget>,
+
+    /// Also fill in the CRC of the MCAP data section, which is otherwise left at zero, once the
+    /// file is written. Chunk and summary CRCs are always written
+    #[arg(long)]
+    data_section_crc: bool,
+
+    /// Write the leanest MCAP, for readers on small devices that only read it from start to end:
+    /// messages without chunks, message indexes or a summary
+    #[arg(long, conflicts_with = "max_memory")]
+    minimal: bool,
+
+    /// Make the output the same, byte for byte, every time the same input is converted with the
+    /// same options and build: encoders leave their version out, and times that come from the
+    /// clock are refused
+    #[arg(long, conflicts_with = "capture")]
+    reproducible: bool,
+
+    /// Where to write the parameter sets: every-keyframe, or first-only for a leaner stream
+    /// that can only be decoded from the start. By default every keyframe, or once a second
+    /// when every frame is one
+    #[arg(long, value_name = "MODE")]
+    parameter_sets: Option<ParameterSetMode>,
+
+    /// Warn when keyframes are further apart than this many seconds, 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 10.0)]
+    max_keyframe_interval: f64,
+
+    /// Re-encode with a keyframe at least every this many seconds and closed GOPs
+    #[arg(long, value_name = "SECONDS")]
+    force_keyframe_interval: Option<f64>,
+
+    /// Re-encode the video with this codec (h264, h265 or av1)
+    #[arg(long, value_name = "CODEC")]
+    transcode: Option<CodecType>,
+
+    /// Constant rate factor when re-encoding, lower is higher quality
+    #[arg(long, conflicts_with = "bitrate")]
+    crf: Option<u32>,
+
+    /// Target bitrate when re-encoding, e.g. 800k or 4M
+    #[arg(long, value_parser = parse_bitrate)]
+    bitrate: Option<usize>,
+
+    /// Encoder preset when re-encoding, e.g. veryfast or slow
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Encoder profile when re-encoding, e.g. high or main10
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Scale when re-encoding, to WxH (e.g. 1280x720) or by a percentage (e.g. 50%)
+    #[arg(long)]
+    scale: Option<Scale>,
+
+    /// Re-encode to bake the display matrix rotation into the frames, if it isn't 0°
+    #[arg(long)]
+    apply_rotation: bool,
+
+    /// Re-encode with interlaced frames deinterlaced
+    #[arg(long)]
+    deinterlace: bool,
+
+    /// Re-encode with HDR (PQ or HLG) tone mapped to this range (sdr)
+    #[arg(long, value_name = "RANGE")]
+    tonemap: Option<ToneMap>,
+
+    /// Re-encode with a hardware encoder (videotoolbox, nvenc, vaapi or qsv),
+    /// falling back to libx264/libx265 if it is unavailable
+    #[arg(long, conflicts_with = "reproducible")]
+    encoder: Option<HardwareEncoder>,
+
+    /// Re-encode with the frames passed through this FFmpeg filtergraph, e.g. crop=1280:720:0:0
+    #[arg(long, value_name = "FILTERGRAPH")]
+    vf: Option<String>,
+
+    /// Re-encode with the log time of each frame drawn onto it
+    #[arg(long)]
+    burn_timestamps: bool,
+
+    /// Also draw the frame index, with --burn-timestamps
+    #[arg(long, requires = "burn_timestamps")]
+    burn_frame_index: bool,
+
+    /// Re-encode as 8-bit 4:2:0, e.g. Main10 H.265 to Main
+    #[arg(long = "force-8bit")]
+    force_8bit: bool,
+
+    /// When re-encoding, decode the encoded frames again and report their PSNR and SSIM
+    /// against the frames sent to the encoder
+    #[arg(long)]
+    quality_metrics: bool,
+
+    /// Re-encode to H.264 if the source codec cannot be passed through, e.g. ProRes
+    #[arg(long)]
+    auto_transcode: bool,
+}
+
+impl Cli {
+    /// The options for the video bitstream, which images can't be written
+    /// with
+    fn bitstream_options(&self) -> Vec<&'static str> {
+        [
+            (self.transcode_options().is_some(), "re-encoding"),
+            (self.apply_rotation, "--apply-rotation"),
+            (self.format != OutputFormat::Mcap, "--format"),
+            (
+                self.message_encoding != MessageEncoding::Protobuf,
+                "--message-encoding",
+            ),
+            (self.schema.is_some(), "--schema"),
+            (self.frame_stats, "--frame-stats"),
+            (self.verify_decode, "--verify-decode"),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect()
+    }
+
+    /// The options that write channels only an MCAP file has, which a bag
+    /// can't be written with
+    fn mcap_only_options(&self) -> Vec<&'static str> {
+        [
+            (self.telemetry_srt.is_some(), "--telemetry-srt"),
+            (self.calibration.is_some(), "--calibration"),
+            (!self.transform.is_empty(), "--transform"),
+            (self.transform_file.is_some(), "--transform-file"),
+            (self.srt.is_some(), "--srt"),
+            (self.frame_stats, "--frame-stats"),
+            (self.emit_bitrate, "--emit-bitrate"),
+            (self.emit_keyframes, "--emit-keyframes"),
+            (self.frame_hashes.is_some(), "--frame-hashes"),
+            (self.scene_changes.is_some(), "--scene-changes"),
+            (self.log_frame_gaps, "--log-frame-gaps"),
+            (
+                self.message_encoding != MessageEncoding::Protobuf,
+                "--message-encoding",
+            ),
+            (self.schema.is_some(), "--schema"),
+            (self.preview_topic.is_some(), "--preview-topic"),
+            (self.lrv.is_some(), "--lrv"),
+            (self.stereo_split.is_some(), "--stereo-split"),
+            (self.capture, "--capture"),
+            (self.data_section_crc, "--data-section-crc"),
+            (self.minimal, "--minimal"),
+            (self.limit_rate.is_some(), "--limit-rate"),
+            (self.duration.is_some(), "--duration"),
+            (self.samples_frames(), "--at"),
+            (self.chapters == ChapterMode::Log, "--chapters log"),
+            (self.drop_duplicate_frames, "--drop-duplicate-frames"),
+            (self.verify_decode, "--verify-decode"),
+            (self.quality_metrics, "--quality-metrics"),
+            (self.jobs != 1, "--jobs"),
+            (self.sequence_start.is_some(), "--sequence-start"),
+            (self.progress == ProgressMode::Json, "--progress json"),
+            (self.bench, "--bench"),
+            (self.stats_json.is_some(), "--stats-json"),
+            (self.summary_json, "--summary-json"),
+            (self.summary_file.is_some(), "--summary-file"),
+            (
+                self.log_time_source
+                    .is_some_and(|source| source.retimes_packets()),
+                "--log-time-source dts or wall",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(set, option)| set.then_some(option))
+        .collect()
+    }
+
+    /// The source of the log times, checked against the options that
+    /// offset them or give them by other means
+    fn log_time_source(&self) -> Result<LogTimeSource, String> {
+        let source = match (self.log_time_source, &self.timestamps) {
+            (None, Some(_)) | (Some(LogTimeSource::Sidecar), Some(_)) => LogTimeSource::Sidecar,
+            (Some(LogTimeSource::Sidecar), None) => {
+                return Err("--log-time-source sidecar needs --timestamps".to_string())
+            }
+            (Some(source), Some(_)) => {
+                return Err(format!(
+                    "--timestamps can't be used with --log-time-source {}",
+                    source.name()
+                ))
+            }
+            (source, None) => source.unwrap_or(LogTimeSource::Pts),
+        };
+        if source.is_absolute() {
+            let offsets = [
+                (self.qr_time_sync.is_some(), "--qr-time-sync"),
+                (self.sync_to.is_some(), "--sync-to"),
+            ];
+            if let Some((_, option)) = offsets.iter().find(|(set, _)| *set) {
+                return Err(format!(
+                    "{} can't be used with --log-time-source {}, which gives Unix times",
+                    option,
+                    source.name()
+                ));
+            }
+        }
+        if source == LogTimeSource::Wall && !(self.capture || live::is_live(&self.input)) {
+            return Err(
+                "--log-time-source wall is only for live inputs and --capture, as a file is read \
+                faster than it was recorded"
+                    .to_string(),
+            );
+        }
+        if source == LogTimeSource::Wall && self.reproducible {
+            return Err(
+                "--reproducible can't be used with --log-time-source wall, which takes the \
+                time of the conversion"
+                    .to_string(),
+            );
+        }
+        if source.retimes_packets() && self.loop_input {
+            return Err(format!(
+                "--loop can't be used with --log-time-source {}",
+                source.name()
+            ));
+        }
+        if source.retimes_packets() && self.samples_frames() {
+            return Err(format!(
+                "--at can't be used with --log-time-source {}",
+                source.name()
+            ));
+        }
+        if source.retimes_packets() && self.jobs != 1 {
+            return Err(format!(
+                "--jobs can't be used with --log-time-source {}",
+                source.name()
+            ));
+        }
+        Ok(source)
+    }
+
+    fn capture_options(&self) -> Option<CaptureOptions> {
+        if self.pattern.is_some() {
+            return Some(CaptureOptions {
+                device_format: "lavfi".to_string(),
+                size: None,
+                fps: None,
+            });
+        }
+        self.capture.then(|| CaptureOptions {
+            device_format: self
+                .capture_format
+                .clone()
+                .unwrap_or_else(|| capture::default_device_format().to_string()),
+            size: self.capture_size.clone(),
+            fps: self.capture_fps,
+        })
+    }
+
+    /// Any encoder setting implies re-encoding, with the source codec unless
+    /// `--transcode` picks another
+    fn transcode_options(&self) -> Option<TranscodeOptions> {
+        let transcode = self.transcode.is_some()
+            || self.force_keyframe_interval.is_some()
+            || self.crf.is_some()
+            || self.bitrate.is_some()
+            || self.preset.is_some()
+            || self.profile.is_some()
+            || self.scale.is_some()
+            || self.deinterlace
+            || self.tonemap.is_some()
+            || self.encoder.is_some()
+            || self.vf.is_some()
+            || self.burn_timestamps
+            || self.force_8bit
+            || self.samples_frames();
+        transcode.then(|| TranscodeOptions {
+            codec: self.transcode,
+            keyframe_interval: self.force_keyframe_interval,
+            crf: self.crf,
+            bitrate: self.bitrate,
+            preset: self.preset.clone(),
+            profile: self.profile.clone(),
+            scale: self.scale,
+            apply_rotation: self.apply_rotation,
+            deinterlace: self.deinterlace,
+            tonemap: self.tonemap,
+            encoder: self.encoder,
+            filter: self.vf.clone(),
+            burn_timestamps: self.burn_timestamps,
+            burn_frame_index: self.burn_frame_index,
+            force_8bit: self.force_8bit,
+            bitexact: self.reproducible,
+            all_keyframes: self.samples_frames(),
+        })
+    }
+
+    /// Only the frames at the times of --at and --at-file are written
+    fn samples_frames(&self) -> bool {
+        !self.at.is_empty() || self.at_file.is_some()
+    }
+}
+
+/// Add the video of an MP4 to an existing MCAP log
+#[derive(Parser)]
+#[command(bin_name = concat!(env!("CARGO_PKG_NAME"), " inject"))]
+#[command(about = "Merges the video of an MP4 into an existing MCAP log", long_about = None)]
+#[command(arg_required_else_help = true)]
+struct InjectCli {
+    /// MCAP log to add the video to, rewritten with it
+    #[arg(value_name = "LOG")]
+    log: PathBuf,
+
+    /// Input MP4 file, optionally with a known delay to shift it by from where it's placed,
+    /// like camera.mp4=+3.2s or camera.mp4=-250ms
+    #[arg(value_name = "INPUT")]
+    input: InjectInput,
+
+    /// Write the combined log here instead of rewriting LOG
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Unix time of the first frame in nanoseconds. By default the video starts with the
+    /// log, unless --qr-time-sync places it
+    #[arg(long, value_name = "NANOSECONDS")]
+    video_start: Option<u64>,
+
+    /// Options for converting the video, like --topic /camera/image
+    #[arg(
+        value_name = "OPTIONS",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    options: Vec<OsString>,
+}
+
+/// Write frames of an MP4 as images
+#[derive(Parser)]
+#[command(bin_name = concat!(env!("CARGO_PKG_NAME"), " extract"))]
+#[command(about = "Writes frames of an MP4 as JPEG, PNG or WebP images", long_about = None)]
+#[command(arg_required_else_help = true)]
+struct ExtractCli {
+    /// Input MP4 file
+    #[arg(value_name = "INPUT")]
+    input: PathBuf,
+
+    /// Directory to write the images to, named after their frame index
+    #[arg(long, value_name = "DIR")]
+    out: PathBuf,
+
+    /// How often to write a frame: a duration like 1s or 500ms, or a frame count like 10f
+    #[arg(long, value_name = "INTERVAL", default_value = "1f")]
+    every: Every,
+
+    /// Format of the images: jpeg, png or webp
+    #[arg(long, value_name = "FORMAT", default_value = "jpeg")]
+    image_format: ImageFormat,
+
+    /// Quality of JPEG and WebP images, from 1 to 100
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 90,
+        value_parser = clap::value_parser!(u32).range(1..=100)
+    )]
+    quality: u32,
+
+    /// How to report a failure: text for the message, or json for a JSON object on stderr
+    /// with an error code and what to do about it
+    #[arg(long, value_name = "FORMAT", default_value = "text")]
+    error_format: ErrorFormat,
+}
+
+/// Write an MCAP of a synthetic test pattern
+#[derive(Parser)]
+#[command(bin_name = concat!(env!("CARGO_PKG_NAME"), " generate"))]
+#[command(about = "Writes an MCAP of a test pattern, the same every time", long_about = None)]
+#[command(arg_required_else_help = true)]
+struct GenerateCli {
+    /// Output MCAP file
+    #[arg(value_name = "OUTPUT")]
+    output: PathBuf,
+
+    /// Picture to draw: smpte, smptehd, or testsrc for moving gradients with a frame counter
+    #[arg(long, default_value = "smpte")]
+    pattern: Pattern,
+
+    /// Size of the frames, as WIDTHxHEIGHT
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "1280x720",
+        value_parser = pattern::parse_resolution
+    )]
+    resolution: (u32, u32),
+
+    /// Frames per second
+    #[arg(
+        long,
+        value_name = "FPS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u32).range(1..=240)
+    )]
+    fps: u32,
+
+    /// Length of the video, like 60s or 10min
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "10s",
+        value_parser = duration::parse_duration
+    )]
+    duration: u64,
+
+    /// Options for converting the pattern, like --topic /camera/image or --transcode h265
+    #[arg(
+        value_name = "OPTIONS",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    options: Vec<OsString>,
+}
+
+/// Check that the FFmpeg of the build can encode, convert and decode video
+#[derive(Parser)]
+#[command(bin_name = concat!(env!("CARGO_PKG_NAME"), " selftest"))]
+#[command(
+    about = "Converts small H.264 and H.265 MP4s it encodes itself and checks the MCAPs",
+    long_about = None
+)]
+struct SelftestCli {
+    /// Keep the MP4s and MCAPs written, in a directory that is printed
+    #[arg(long)]
+    keep: bool,
+}
+
+/// Run conversion jobs submitted over HTTP
+#[derive(Parser)]
+#[command(bin_name = concat!(env!("CARGO_PKG_NAME"), " serve"))]
+#[command(about = "Runs conversion jobs submitted over HTTP", long_about = None)]
+struct ServeCli {
+    /// Address to accept jobs on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Conversions run at the same time, the others wait in a queue
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    workers: usize,
+
+    /// Directory the input and output paths of the jobs are relative to, which they
+    /// can't leave
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    root: PathBuf,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+    match std::env::args_os().nth(1) {
+        Some(arg) if arg == "serve" => {
+            let cli = ServeCli::parse_from(std::env::args_os().skip(1));
+            return jobs::serve(&cli.listen, cli.workers, &cli.root);
+        }
+        Some(arg) if arg == "inject" => {
+            let cli = InjectCli::parse_from(std::env::args_os().skip(1));
+            // Given with the options of the conversion
+            let error_format = failure::format_of_args(&cli.options);
+            return inject(cli).inspect_err(|e| failure::report(e.as_ref(), error_format));
+        }
+        Some(arg) if arg == "selftest" => {
+            return selftest(SelftestCli::parse_from(std::env::args_os().skip(1)))
+        }
+        Some(arg) if arg == "generate" => {
+            return generate(GenerateCli::parse_from(std::env::args_os().skip(1)))
+        }
+        Some(arg) if arg == "extract" => {
+            let cli = ExtractCli::parse_from(std::env::args_os().skip(1));
+            ffmpeg::init()?;
+            let start_time = Instant::now();
+            let images = extract::extract(
+                &cli.input,
+                cli.every,
+                &cli.out,
+                cli.image_format,
+                cli.quality,
+            )
+            .inspect_err(|e| failure::report(e.as_ref(), cli.error_format))?;
+            info!(
+                "Wrote {} images to {:?} in {:.3} seconds",
+                images,
+                cli.out,
+                start_time.elapsed().as_secs_f64()
+            );
+            return Ok(());
+        }
+        _ => {}
+    }
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        // Unless it's --help or --version
+        if e.use_stderr() {
+            let args: Vec<OsString> = std::env::args_os().collect();
+            failure::report(&e, failure::format_of_args(&args));
+        }
+        e.exit()
+    });
+    let error_format = cli.error_format;
+    convert(cli).inspect_err(|e| failure::report(e.as_ref(), error_format))
+}
+
+/// Converts the video to an MCAP next to the log and merges the two
+fn inject(inject: InjectCli) -> Result<(), Box<dyn Error>> {
+    let output = inject.output.clone().unwrap_or_else(|| inject.log.clone());
+    let video_path = output.with_extension("video.mcap.tmp");
+    let mut args = vec![OsString::from(env!("CARGO_PKG_NAME"))];
+    args.extend(inject.options.iter().cloned());
+    args.push(inject.input.path.clone().into());
+    args.push(video_path.clone().into());
+    let cli = Cli::try_parse_from(args)?;
+    if cli.format != OutputFormat::Mcap {
+        return Err(Failure::usage(
+            "inject merges into an MCAP log, so the video can't be converted with --format"
+                .to_string(),
+        )
+        .into());
+    }
+    let synchronized = cli.qr_time_sync.is_some() || cli.sync_to.is_some();
+    let data_section_crc = cli.data_section_crc;
+    let sequence_start = cli.sequence_start;
+    let result = convert(cli).and_then(|()| {
+        let video_start =
+            timesync::mcap_time_range(inject::open(&video_path)?)?.map_or(0, |(start, _)| start);
+        let offset_ns = match inject.video_start {
+            Some(start_ns) => start_ns as i64 - video_start as i64,
+            None if synchronized => 0,
+            None => {
+                let log_start = timesync::mcap_time_range(inject::open(&inject.log)?)?
+                    .map_or(video_start, |(start, _)| start);
+                log_start as i64 - video_start as i64
+            }
+        } + inject.input.offset_ns;
+        // Written aside and moved into place, so a failure leaves the log as it was
+        let merged_path = output.with_extension("mcap.tmp");
+        // A topic the log already has goes on from its last message, so a
+        // gap in the sequence still means a dropped frame
+        let sequence_starts = match sequence_start {
+            Some(_) => HashMap::new(),
+            None => inject::next_sequences(inject::open(&inject.log)?)?,
+        };
+        let merged = inject::merge(
+            inject::open(&inject.log)?,
+            inject::open(&video_path)?,
+            offset_ns,
+            &sequence_starts,
+            BufWriter::new(File::create(&merged_path)?),
+        )
+        .and_then(|merged| {
+            if data_section_crc {
+                write_data_section_crc(&merged_path)?;
+            }
+            Ok(merged)
+        })
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&merged_path);
+        })?;
+        std::fs::rename(&merged_path, &output)?;
+        info!(
+            "Merged {} video messages with the {} messages of {:?} into {:?}",
+            merged.video_messages, merged.log_messages, inject.log, output
+        );
+        Ok(())
+    });
+    let _ = std::fs::remove_file(&video_path);
+    result
+}
+
+/// Converts the pattern from FFmpeg's lavfi sources, as if it were a camera
+/// that starts at zero, with the encoder output kept the same between runs
+fn generate(generate: GenerateCli) -> Result<(), Box<dyn Error>> {
+    let graph = generate
+        .pattern
+        .lavfi_graph(generate.resolution, generate.fps, generate.duration);
+    let mut args = vec![OsString::from(env!("CARGO_PKG_NAME"))];
+    args.extend(generate.options.iter().cloned());
+    args.push(graph.into());
+    args.push(generate.output.into());
+    let mut cli = Cli::try_parse_from(args)?;
+    let unsupported = [
+        (cli.capture, "--capture"),
+        (cli.timestamps.is_some(), "--timestamps"),
+        (cli.qr_time_sync.is_some(), "--qr-time-sync"),
+        (cli.sync_to.is_some(), "--sync-to"),
+        (cli.jobs != 1, "--jobs"),
+        (cli.loop_input, "--loop"),
+        (!cli.at.is_empty(), "--at"),
+        (cli.at_file.is_some(), "--at-file"),
+        // Hardware encoders aren't bit exact
+        (cli.encoder.is_some(), "--encoder"),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(set, _)| *set) {
+        return Err(format!("{} can't be used with mp42mcap generate", option).into());
+    }
+    cli.pattern = Some(generate.pattern);
+    cli.reproducible = true;
+    convert(cli)
+}
+
+/// Encodes a fixture in each codec, converts it like any other input and
+/// checks the MCAP, printing a line per codec
+fn selftest(selftest: SelftestCli) -> Result<(), Box<dyn Error>> {
+    ffmpeg::init()?;
+    let dir = std::env::temp_dir().join(format!("mp42mcap-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut failed = Vec::new();
+    let mut report = |what: String, result: Result<String, Box<dyn Error>>| match result {
+        Ok(checked) => println!("ok    {}: {}", what, checked),
+        Err(e) => {
+            println!("FAIL  {}: {}", what, e);
+            failed.push(what);
+        }
+    };
+    for codec in [CodecType::H264, CodecType::H265] {
+        let name = codec.format_str();
+        let input = dir.join(format!("{}.mp4", name));
+        let output = dir.join(format!("{}.mcap", name));
+        let result = selftest::write_fixture(&input, codec)
+            .and_then(|()| {
+                let args: [OsString; 4] = [
+                    env!("CARGO_PKG_NAME").into(),
+                    "--quiet".into(),
+                    input.clone().into(),
+                    output.clone().into(),
+                ];
+                convert(Cli::try_parse_from(args)?)
+            })
+            .and_then(|()| selftest::check_output(&std::fs::read(&output)?, codec));
+        report(name.to_string(), result);
+
+        // The same pattern, generated with the decoder on one thread and
+        // then on every core, has to come out the same
+        let result = [1, 0]
+            .into_iter()
+            .map(|threads| {
+                let output = dir.join(format!("{}-generated-{}.mcap", name, threads));
+                let args: [OsString; 12] = [
+                    "generate".into(),
+                    "--resolution".into(),
+                    "320x240".into(),
+                    "--duration".into(),
+                    "2s".into(),
+                    output.clone().into(),
+                    "--quiet".into(),
+                    "--transcode".into(),
+                    name.into(),
+                    "--threads".into(),
+                    threads.to_string().into(),
+                    "--force-keyframe-interval=1".into(),
+                ];
+                generate(GenerateCli::try_parse_from(args)?)?;
+                Ok(std::fs::read(&output)?)
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()
+            .and_then(|runs| {
+                if runs[0] != runs[1] {
+                    return Err("the two files differ".into());
+                }
+                Ok("generated the same file twice".to_string())
+            });
+        report(format!("{} --reproducible", name), result);
+    }
+    if selftest.keep {
+        println!("The files are in {:?}", dir);
+    } else {
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    if !failed.is_empty() {
+        return Err(format!("The self-test failed for {}", failed.join(" and ")).into());
+    }
+    Ok(())
+}
+
+/// Fills in the data section CRC of the finished MCAP at `path`
+fn write_data_section_crc(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let crc = crc::write_data_section_crc(&mut file)?;
+    debug!("Wrote the data section CRC {:08x}", crc);
+    Ok(())
+}
+
+fn convert(mut cli: Cli) -> Result<(), Box<dyn Error>> {
+    logging::set_level(Level::from_flags(cli.quiet, cli.verbose));
+    // Before FFmpeg starts its threads, which inherit the priority
+    if let Some(niceness) = cli.nice {
+        throttle::set_nice(niceness)?;
+    }
     let start_time = Instant::now();
-    println!("Converting {:?} to {:?}", cli.input, cli.output);
+    if cli.format != OutputFormat::Mcap {
+        let options = cli.mcap_only_options();
+        if !options.is_empty() {
+            return Err(Failure::usage(format!(
+                "{} can't be used with --format {}",
+                options.join(", "),
+                cli.format.name()
+            ))
+            .into());
+        }
+    }
+    if cli.output_messages == OutputMessages::CompressedImage {
+        let options = cli.bitstream_options();
+        if !options.is_empty() {
+            return Err(Failure::usage(format!(
+                "{} can't be used with --output-messages compressed-image",
+                options.join(", ")
+            ))
+            .into());
+        }
+    }
+    let log_time_source = cli.log_time_source().map_err(Failure::usage)?;
+    if let Some(address) = &cli.metrics_addr {
+        metrics::serve(address, cli.output.clone())?;
+    }
+    if let Some(address) = &cli.status_addr {
+        status::serve(address, cli.input.clone(), cli.output.clone())?;
+    }
+    info!("Converting {:?} to {:?}", cli.input, cli.output);
+
+    let telemetry = match &cli.telemetry_srt {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| Failure::io(format!("Could not read {:?}: {}", path, e)))?;
+            let samples = telemetry::parse_srt(&text);
+            if samples.is_empty() {
+                return Err(
+                    Failure::invalid_file(format!("No telemetry found in {:?}", path)).into(),
+                );
+            }
+            samples
+        }
+        None => Vec::new(),
+    };
+
+    let subtitles = match &cli.srt {
+        Some(path) => std::fs::read_to_string(path)
+            .map(|text| subtitles::parse_srt(&text))
+            .map_err(|e| Failure::io(format!("Could not read {:?}: {}", path, e)))?,
+        None => Vec::new(),
+    };
+
+    let calibration = match &cli.calibration {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| Failure::io(format!("Could not read {:?}: {}", path, e)))?;
+            Some(calibration::parse_yaml(&text).map_err(|e| {
+                Failure::invalid_file(format!("Invalid calibration in {:?}: {}", path, e))
+            })?)
+        }
+        None => None,
+    };
+
+    let custom_schema = match (&cli.schema, &cli.schema_name) {
+        (Some(path), Some(name)) => {
+            if cli.message_encoding != MessageEncoding::Protobuf {
+                return Err(Failure::usage(
+                    "--schema can only be used with protobuf messages".to_string(),
+                )
+                .into());
+            }
+            Some(CustomSchema::load(path, name, &cli.field_map)?)
+        }
+        _ => None,
+    };
+
+    let mut static_transforms = cli.transform.clone();
+    if let Some(path) = &cli.transform_file {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Failure::io(format!("Could not read {:?}: {}", path, e)))?;
+        static_transforms.extend(transforms::parse_yaml(&text).map_err(|e| {
+            Failure::invalid_file(format!("Invalid transforms in {:?}: {}", path, e))
+        })?);
+    }
 
     ffmpeg::init()?;
+    let live = live::is_live(&cli.input);
+    if live && cli.reproducible {
+        return Err(
+            Failure::usage("--reproducible can't be used with a live input".to_string()).into(),
+        );
+    }
+    if live && cli.loop_input {
+        return Err(Failure::usage("--loop can't be used with a live input".to_string()).into());
+    }
+    if live && cli.samples_frames() {
+        return Err(Failure::usage("--at can't be used with a live input".to_string()).into());
+    }
+    if playlist::is_url(&cli.input) || live {
+        ffmpeg::format::network::init();
+    }
+
+    let mut time_offset_ns = match cli.qr_time_sync {
+        Some(frames) => {
+            let sync = timesync::scan(&cli.input, frames)?.ok_or_else(|| {
+                format!(
+                    "No QR code with a time found in the first {} frames",
+                    frames
+                )
+            })?;
+            info!(
+                "Synchronized to the QR code time {}ns shown at {:.3}s",
+                sync.epoch_ns,
+                sync.frame_ns as f64 / 1e9
+            );
+            Some(sync.offset_ns())
+        }
+        None => None,
+    };
 
-    let (mut converter, mut input) = VideoConverter::new(&cli.input)?;
-    let video_stream_index = input
-        .streams()
-        .best(ffmpeg::media::Type::Video)
+    let frame_timestamps = match &cli.timestamps {
+        Some(path) => {
+            let timestamps = timestamps::FrameTimestamps::load(path)?;
+            let frame_pts = timestamps::frame_pts(&cli.input, None)?;
+            let timestamps = timestamps.by_pts(&frame_pts);
+            let missing = frame_pts
+                .iter()
+                .filter(|pts| !timestamps.contains_key(pts))
+                .count();
+            if missing > 0 {
+                warn!(
+                    "{} of {} frames have no timestamp in {:?}, keeping their distance to the \
+                    nearest frame listed",
+                    missing,
+                    frame_pts.len(),
+                    path
+                );
+            }
+            if timestamps.is_empty() {
+                return Err(Failure::invalid_file(format!(
+                    "No frame of the video is listed in {:?}",
+                    path
+                ))
+                .into());
+            }
+            Some(timestamps)
+        }
+        None => None,
+    };
+
+    if cli.quality_metrics {
+        let hint = if ffmpeg::filter::find("libvmaf").is_some() {
+            "run `ffmpeg -lavfi libvmaf` on the decoded output for it"
+        } else {
+            "this FFmpeg build has no libvmaf for it either"
+        };
+        warn!(
+            "--quality-metrics reports PSNR and SSIM, not VMAF; {}",
+            hint
+        );
+    }
+
+    let options = ConverterOptions {
+        strictness: Strictness::from_flags(cli.strict, cli.lenient),
+        max_keyframe_interval: cli.max_keyframe_interval,
+        // Cameras and test patterns give raw or MJPEG frames, which are
+        // encoded as they come in
+        transcode: cli.transcode_options().or_else(|| {
+            (cli.capture || cli.pattern.is_some()).then(|| TranscodeOptions {
+                codec: Some(CodecType::H264),
+                preset: Some("veryfast".to_string()),
+                ..Default::default()
+            })
+        }),
+        auto_transcode: cli.auto_transcode,
+        apply_rotation: cli.apply_rotation,
+        nal_filter: NalFilter {
+            keep_sei: cli.keep_sei.clone(),
+            strip_aud_filler: cli.strip_aud_filler,
+            stripped: 0,
+            upper_layers: 0,
+        },
+        parameter_sets: cli.parameter_sets,
+        quality_metrics: cli.quality_metrics,
+        threads: cli.threads,
+        max_memory: cli.max_memory,
+        capture: cli.capture_options(),
+        frame_timestamps,
+        collapse_edit_gaps: cli.collapse_edit_gaps,
+        input_options: if live {
+            live::input_options(&cli.input)
+        } else if playlist::is_http(&cli.input) {
+            playlist::reconnect_options(cli.network_retry_max)
+        } else {
+            Vec::new()
+        },
+    };
+    if let Some(pattern) = cli.pattern {
+        info!(
+            "Drawing the {} pattern with {:?}",
+            pattern.name(),
+            cli.input
+        );
+    } else if cli.capture {
+        capture::stop_on_interrupt()?;
+        info!("Recording from {:?}, press Ctrl-C to stop", cli.input);
+    } else if live {
+        capture::stop_on_interrupt()?;
+        info!("Receiving {:?}, press Ctrl-C to stop", cli.input);
+    }
+    let (mut converter, mut input) = VideoConverter::new(&cli.input, None, &options)?;
+    // The DTS of the input are only known for the frames passed through
+    if log_time_source == LogTimeSource::Dts
+        && (converter.is_transcoding() || cli.output_messages == OutputMessages::CompressedImage)
+    {
+        return Err(
+            "--log-time-source dts can't be used when the video is re-encoded or written as \
+            images, as the frames are no longer those of the input"
+                .into(),
+        );
+    }
+    let video_stream_index = stream_info::best_video(&input)?.index();
+    let video_stream = input
+        .stream(video_stream_index)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let topic_values = topic::TopicValues::from_stream(&cli.input, &video_stream);
+    // Expanded again for the channels of other streams and files
+    let mut topic_template = cli.topic.clone();
+    cli.topic = topic::expand(&cli.topic, &topic_values)?;
+    if let Some(path) = &cli.sync_to {
+        let (log_start, log_end) = timesync::mcap_time_range(inject::open(path)?)?
+            .ok_or_else(|| format!("{:?} has no messages to sync to", path))?;
+        let start_ns = cli.sync_anchor.unwrap_or(log_start);
+        if !(log_start..=log_end).contains(&start_ns) {
+            warn!(
+                "--sync-anchor {} is outside the log, which runs from {} to {}",
+                start_ns, log_start, log_end
+            );
+        }
+        let offset_ns = start_ns as i64 - timesync::video_start_ns(&video_stream) as i64;
+        info!(
+            "Synchronized to {:?}, starting the video at {}ns",
+            path, start_ns
+        );
+        time_offset_ns = Some(offset_ns);
+    }
+    if let Some(offset_ns) =
+        timesource::anchor_offset_ns(log_time_source, &cli.input, &input, &video_stream)?
+    {
+        time_offset_ns = Some(offset_ns);
+    }
+    if log_time_source != LogTimeSource::Pts {
+        info!("Log times from {}", log_time_source.name());
+    }
+    if let Some(from) = cli.topic_from {
+        match topic_values.name(from) {
+            Some(name) => {
+                cli.topic = name;
+                topic_template = cli.topic.clone();
+            }
+            None => warn!("The video track has no name, writing it to {}", cli.topic),
+        }
+    }
+    if let Some(from) = cli.frame_id_from {
+        match topic_values.name(from) {
+            Some(name) => cli.frame_id = name,
+            None => warn!(
+                "The video track has no name, using frame ID {}",
+                cli.frame_id
+            ),
+        }
+    }
+    let source_codec_id = input
+        .stream(video_stream_index)
         .ok_or(ffmpeg::Error::StreamNotFound)?
-        .index();
+        .parameters()
+        .id();
+    if converter.is_transcoding() && CodecType::from_ffmpeg_id(source_codec_id).is_err() {
+        info!(
+            "Source codec {:?} cannot be passed through, transcoding to {}",
+            source_codec_id,
+            converter.format_str()
+        );
+    }
+
+    if cli.format != OutputFormat::Mcap {
+        let convert = match cli.format {
+            OutputFormat::Rosbag2 => rosbag2::convert,
+            _ => bag::convert,
+        };
+        let frames = convert(
+            &mut converter,
+            &mut input,
+            video_stream_index,
+            &cli.output,
+            &cli.topic,
+            &cli.frame_id,
+            time_offset_ns,
+        )?;
+        info!(
+            "Wrote {} frames to the {} in {:.3} seconds",
+            frames,
+            cli.format.name(),
+            start_time.elapsed().as_secs_f64()
+        );
+        if cli.stats {
+            for line in converter.stats().summary(converter.output_codec()) {
+                println!("{}", line);
+            }
+        }
+        return Ok(());
+    }
+
+    // Both lenses start with the first frame written, which isn't the start
+    // of the recording with --loop or --at
+    let insta360 = insta360::is_insv(&cli.input) && !cli.loop_input && !cli.samples_frames();
+    let base_topic = cli.topic.clone();
+    let base_frame_id = cli.frame_id.clone();
+    let other_lens = match insta360::lens_streams(&cli.input, &input) {
+        Some(lenses) if insta360 && !cli.no_lens_split => {
+            // The lenses are numbered in the order of their streams
+            let lens = usize::from(lenses[1] == video_stream_index);
+            info!(
+                "Writing the lenses of the dual-fisheye recording to {}/lens0 and {}/lens1",
+                base_topic, base_topic
+            );
+            cli.topic = format!("{}/lens{}", base_topic, lens);
+            cli.frame_id = format!("{}_lens{}", base_frame_id, lens);
+            Some((1 - lens, lenses[1 - lens]))
+        }
+        _ => None,
+    };
+
+    let mut jobs = segments::job_count(cli.jobs);
+    if let Some(budget) = &cli.max_memory {
+        let (width, height) = stream_info::dimensions(
+            &input
+                .stream(video_stream_index)
+                .ok_or(ffmpeg::Error::StreamNotFound)?
+                .parameters(),
+        );
+        jobs = budget.jobs(jobs, memory::frame_bytes(width, height));
+    }
+    let segments = if jobs > 1 && converter.is_transcoding() {
+        let keyframes = segments::keyframes(&cli.input, video_stream_index)?;
+        let segments = segments::plan(&keyframes, jobs * segments::SEGMENTS_PER_JOB);
+        info!(
+            "Converting {} segments on {} threads",
+            segments.len(),
+            jobs.min(segments.len())
+        );
+        for segment in &segments {
+            debug!("Segment from PTS {:?} to {:?}", segment.start, segment.end);
+        }
+        Some(segments)
+    } else {
+        if jobs > 1 {
+            info!("Passing the video through, which --jobs does not split up");
+        }
+        None
+    };
 
-    let mut writer = Writer::new(BufWriter::new(File::create(&cli.output)?))?;
-    let channel_id = setup_mcap_channel(&mut writer, &cli.topic)?;
+    // Moved with the frames, now that the offset is known
+    let mut chapters = chapters::read_chapters(&input);
+    for chapter in &mut chapters {
+        chapter.start_ns = timesync::shift_ns(chapter.start_ns, time_offset_ns);
+        chapter.end_ns = timesync::shift_ns(chapter.end_ns, time_offset_ns);
+    }
+    let container_metadata = stream_info::container_metadata(&input);
 
-    let mut sequence: u32 = 0;
+    let file = BufWriter::new(File::create(&cli.output)?);
+    let mut writer = match &cli.max_memory {
+        _ if cli.minimal => minimal::write_options().create(file)?,
+        Some(budget) => WriteOptions::new()
+            .chunk_size(Some(budget.chunk_size()))
+            .create(file)?,
+        None => Writer::new(file)?,
+    };
+    if !container_metadata.is_empty() {
+        writer.write_metadata(&Metadata {
+            name: "container".to_string(),
+            metadata: container_metadata,
+        })?;
+    }
+    let channel_id = match (&custom_schema, cli.message_encoding) {
+        _ if cli.output_messages == OutputMessages::CompressedImage => setup_mcap_channel(
+            &mut writer,
+            &cli.topic,
+            "foxglove.CompressedImage",
+            converter.channel_metadata().clone(),
+        )?,
+        (Some(custom_schema), _) => {
+            let schema = Schema {
+                name: custom_schema.name.clone(),
+                encoding: MessageEncoding::Protobuf.name().to_string(),
+                data: Cow::Owned(custom_schema.descriptor.clone()),
+            };
+            let channel = Channel {
+                topic: cli.topic.clone(),
+                message_encoding: MessageEncoding::Protobuf.name().to_string(),
+                schema: Some(schema.into()),
+                metadata: converter.channel_metadata().clone(),
+            };
+            writer.add_channel(&channel)?.into()
+        }
+        (None, MessageEncoding::Protobuf) => setup_mcap_channel(
+            &mut writer,
+            &cli.topic,
+            "foxglove.CompressedVideo",
+            converter.channel_metadata().clone(),
+        )?,
+        (None, MessageEncoding::Flatbuffer) => {
+            let schema = Schema {
+                name: "foxglove.CompressedVideo".to_string(),
+                encoding: MessageEncoding::Flatbuffer.name().to_string(),
+                data: Cow::Owned(flatbuffer::compressed_video_schema()),
+            };
+            let channel = Channel {
+                topic: cli.topic.clone(),
+                message_encoding: MessageEncoding::Flatbuffer.name().to_string(),
+                schema: Some(schema.into()),
+                metadata: converter.channel_metadata().clone(),
+            };
+            writer.add_channel(&channel)?.into()
+        }
+    };
+    let stats_channel_id = if cli.frame_stats {
+        let channel_id = setup_mcap_channel(
+            &mut writer,
+            &format!("{}/frame_stats", cli.topic),
+            "mp42mcap.FrameStats",
+            BTreeMap::new(),
+        )?;
+        Some(channel_id.try_into()?)
+    } else {
+        None
+    };
+    let bitrate = if cli.emit_bitrate {
+        let channel_id = setup_mcap_channel(
+            &mut writer,
+            &format!("{}/bitrate", cli.topic),
+            "mp42mcap.Bitrate",
+            BTreeMap::new(),
+        )?;
+        Some((channel_id.try_into()?, BitrateWindows::default()))
+    } else {
+        None
+    };
+    let keyframes_channel_id = if cli.emit_keyframes {
+        let channel_id = setup_mcap_channel(
+            &mut writer,
+            &format!("{}/keyframes", cli.topic),
+            "mp42mcap.Keyframe",
+            BTreeMap::new(),
+        )?;
+        Some(channel_id.try_into()?)
+    } else {
+        None
+    };
+    let hashes = match cli.frame_hashes {
+        Some(algorithm) => {
+            let channel_id = setup_mcap_channel(
+                &mut writer,
+                &format!("{}/frame_hashes", cli.topic),
+                "mp42mcap.FrameHash",
+                BTreeMap::from([("algorithm".to_string(), algorithm.name().to_string())]),
+            )?;
+            Some((channel_id.try_into()?, algorithm))
+        }
+        None => None,
+    };
+    let scenes = match cli.scene_changes {
+        Some(threshold) => {
+            let channel_id = setup_mcap_channel(
+                &mut writer,
+                &format!("{}/scene_changes", cli.topic),
+                "mp42mcap.SceneChange",
+                BTreeMap::new(),
+            )?;
+            Some((channel_id.try_into()?, SceneDetector::new(threshold), 0))
+        }
+        None => None,
+    };
+    let frame_interval_ns = stream_info::frame_interval_ns(
+        &input
+            .stream(video_stream_index)
+            .ok_or(ffmpeg::Error::StreamNotFound)?,
+    );
+    let sampler = if cli.samples_frames() {
+        let mut times = cli.at.clone();
+        if let Some(path) = &cli.at_file {
+            times.extend(sampling::read_times(path)?);
+        }
+        let time_base = input
+            .stream(video_stream_index)
+            .ok_or(ffmpeg::Error::StreamNotFound)?
+            .time_base();
+        // Packets are shifted before decoding, by --qr-time-sync and --sync-to
+        let ticks = time_offset_ns.map_or(0, |ns| timesync::offset_ticks(time_base, ns));
+        let frame_pts: Vec<i64> = timestamps::frame_pts(&cli.input, Some(video_stream_index))?
+            .into_iter()
+            .map(|pts| pts + ticks)
+            .collect();
+        let sampler = Sampler::new(&frame_pts, &times, time_base);
+        info!(
+            "Writing the {} frames nearest to the {} times given",
+            sampler.len(),
+            times.len()
+        );
+        Some(sampler)
+    } else {
+        None
+    };
+    let duration_ns = stream_info::duration_ns(
+        &input,
+        &input
+            .stream(video_stream_index)
+            .ok_or(ffmpeg::Error::StreamNotFound)?,
+    );
+    let mut derived = Vec::new();
+    if let Some(topic) = &cli.preview_topic {
+        let preview = PreviewOptions {
+            height: cli.preview_height,
+            fps: cli.preview_fps,
+            bitrate: cli.preview_bitrate,
+        };
+        let (width, height) = stream_info::dimensions(
+            &input
+                .stream(video_stream_index)
+                .ok_or(ffmpeg::Error::StreamNotFound)?
+                .parameters(),
+        );
+        derived.push(DerivedStream::new(
+            &mut writer,
+            &converter,
+            topic,
+            &cli.frame_id,
+            &TranscodeOptions {
+                bitexact: cli.reproducible,
+                ..preview.transcode_options(width, height)
+            },
+            &preview.filters(),
+            Some(preview.frame_rate()),
+        )?);
+    }
+    if let Some(layout) = cli.stereo_split {
+        // Encoded like the video would be when re-encoding
+        let options = cli.transcode_options().unwrap_or_default();
+        for (view, crop) in layout.views() {
+            derived.push(DerivedStream::new(
+                &mut writer,
+                &converter,
+                &format!("{}/{}", cli.topic, view),
+                &format!("{}_{}", cli.frame_id, view),
+                &options,
+                &[crop.to_string()],
+                None,
+            )?);
+        }
+    }
+    let lrv_path = match &cli.lrv {
+        Some(path) => Some(path.clone()),
+        // Not with a preview of its own, or when the frames written don't
+        // start where the recording does
+        None if cli.no_lrv
+            || cli.preview_topic.is_some()
+            || cli.loop_input
+            || cli.samples_frames() =>
+        {
+            None
+        }
+        None => lrv::companion(&cli.input),
+    };
+    let mut passthrough = Vec::new();
+    if let Some(path) = &lrv_path {
+        let lrv_options = ConverterOptions {
+            transcode: None,
+            auto_transcode: false,
+            apply_rotation: false,
+            quality_metrics: false,
+            capture: None,
+            frame_timestamps: None,
+            input_options: Vec::new(),
+            ..options.clone()
+        };
+        // One found next to the input is skipped if it can't be read, while
+        // one given with --lrv has to be
+        let detected = cli.lrv.is_none();
+        match PassthroughStream::new(
+            &mut writer,
+            path,
+            None,
+            &format!("{}/preview", topic_template),
+            &cli.frame_id,
+            &lrv_options,
+            detected,
+        ) {
+            Ok(stream) => {
+                info!("Passing {:?} through as the preview", path);
+                passthrough.push(stream);
+            }
+            Err(e) if detected => warn!("Skipping the preview {:?}: {}", path, e),
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some((lens, stream_index)) = other_lens {
+        passthrough.push(PassthroughStream::new(
+            &mut writer,
+            &cli.input,
+            Some(stream_index),
+            &format!("{}/lens{}", topic_template, lens),
+            &format!("{}_lens{}", base_frame_id, lens),
+            &ConverterOptions {
+                transcode: None,
+                auto_transcode: false,
+                apply_rotation: false,
+                quality_metrics: false,
+                frame_timestamps: None,
+                ..options.clone()
+            },
+            false,
+        )?);
+    }
+    let video_dimensions = stream_info::dimensions(
+        &input
+            .stream(video_stream_index)
+            .ok_or(ffmpeg::Error::StreamNotFound)?
+            .parameters(),
+    );
+    let mut interleaver = Interleaver::default();
+    // Without a calibration file, the SPS still gives image panels the size
+    let sps_info = converter.sps_info();
+    let calibration =
+        calibration.or_else(|| sps_info.as_ref().map(|_| CameraCalibration::default()));
+    if let Some(mut calibration) = calibration {
+        calibration.frame_id = cli.frame_id.clone();
+        if calibration.width == 0 || calibration.height == 0 {
+            (calibration.width, calibration.height) = sps_info
+                .as_ref()
+                .map_or(video_dimensions, |info| (info.width, info.height));
+        }
+        let mut metadata = BTreeMap::new();
+        if let Some((width, height)) = sps_info
+            .as_ref()
+            .and_then(|info| info.sample_aspect_ratio)
+            .filter(|(width, height)| width != height)
+        {
+            metadata.insert(
+                "sample_aspect_ratio".to_string(),
+                format!("{}:{}", width, height),
+            );
+        }
+        let channel_id = setup_mcap_channel(
+            &mut writer,
+            &format!("{}/calibration", cli.topic),
+            "foxglove.CameraCalibration",
+            metadata,
+        )?;
+        // Latched at the first frame, ahead of it in the file
+        interleaver.add_from_start(channel_id.try_into()?, 0, move |log_time| {
+            calibration.timestamp = Some(to_timestamp(log_time));
+            calibration.encode_to_vec()
+        });
+    }
+    if !static_transforms.is_empty() {
+        let channel_id = setup_mcap_channel(
+            &mut writer,
+            "tf_static",
+            "foxglove.FrameTransforms",
+            BTreeMap::new(),
+        )?;
+        let transforms: Vec<_> = static_transforms
+            .iter()
+            .map(StaticTransform::to_message)
+            .collect();
+        interleaver.add_from_start(channel_id.try_into()?, 0, move |log_time| {
+            FrameTransforms {
+                transforms: transforms
+                    .into_iter()
+                    .map(|transform| foxglove::FrameTransform {
+                        timestamp: Some(to_timestamp(log_time)),
+                        ..transform
+                    })
+                    .collect(),
+            }
+            .encode_to_vec()
+        });
+    }
+    // Telemetry, subtitles and chapters are written along with the frames,
+    // so the log times stay in order for readers without an index
+    let telemetry_channels = if telemetry.is_empty() {
+        None
+    } else {
+        Some(add_telemetry(
+            &mut writer,
+            &mut interleaver,
+            &telemetry,
+            &cli.frame_id,
+        )?)
+    };
+    chapters::write_chapters(&mut writer, &mut interleaver, &chapters, cli.chapters)?;
+    let srt_channel = match &cli.srt {
+        Some(path) if !subtitles.is_empty() => {
+            let channel_id: u16 =
+                setup_mcap_channel(&mut writer, "subtitles", "foxglove.Log", BTreeMap::new())?
+                    .try_into()?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            // On the timeline of the input, like the frames and the subtitle
+            // tracks, rather than from the first frame written
+            for cue in &subtitles {
+                let text = subtitles::strip_tags(&cue.text);
+                let log_time = timesync::shift_ns(cue.start_ns, time_offset_ns);
+                interleaver.add(
+                    channel_id,
+                    log_time,
+                    tracks::encode_log(foxglove::log::Level::Info, &name, log_time, &text),
+                );
+            }
+            Some(channel_id)
+        }
+        _ => None,
+    };
+    let mut output = VideoOutput {
+        channel_id: channel_id.try_into()?,
+        scenes,
+        hashes,
+        stats_channel_id,
+        bitrate,
+        keyframes: keyframes_channel_id.map(|channel_id| (channel_id, 0)),
+        frames: 0,
+        duplicates: cli.drop_duplicate_frames.then(DuplicateDetector::default),
+        log_dts: log_time_source == LogTimeSource::Dts,
+        verifier: if cli.verify_decode {
+            Some(DecodeVerifier::new(converter.output_codec())?)
+        } else {
+            None
+        },
+        gaps: frame_interval_ns.map(FrameGaps::new),
+        log_gaps: cli.log_frame_gaps,
+        diagnostics: LogOutput::new(&format!("{}/diagnostics", cli.topic)),
+        black_frames: 0,
+        in_black_run: false,
+        corrupt_frames: 0,
+        frame_id: cli.frame_id.clone(),
+        message_encoding: cli.message_encoding,
+        custom_schema,
+        images: (cli.output_messages == OutputMessages::CompressedImage)
+            .then(|| ImageEncoder::new(cli.image_format, cli.quality, cli.reproducible)),
+        derived,
+        passthrough,
+        interleaver,
+        flush_at_keyframes: cli.max_memory.is_some(),
+        sequence: cli.sequence_start.unwrap_or(0),
+        buffer: Vec::new(),
+        bench: Bench::default(),
+        progress: Progress::new(
+            cli.progress,
+            match cli.duration {
+                Some(duration) if cli.loop_input => Some(duration),
+                Some(duration) => Some(duration_ns.map_or(duration, |ns| ns.min(duration))),
+                None => duration_ns,
+            },
+            cli.output.clone(),
+        ),
+        first_timestamp_ns: None,
+        last_timestamp_ns: None,
+        keyframes_written: 0,
+        sampler,
+        read_limit: cli.limit_rate.map(RateLimit::new),
+        write_limit: cli.limit_rate.map(RateLimit::new),
+    };
+
+    let mut tracks = Tracks::new(&input, &cli.frame_id, video_dimensions);
+
+    let mut passes = Passes {
+        video_stream_index,
+        video_start_ns: input
+            .stream(video_stream_index)
+            .map_or(0, |stream| timesync::video_start_ns(&stream)),
+        // The segments of a playlist, a live stream that restarts and the
+        // passes of --loop are stitched into one continuous recording
+        stitcher: (playlist::is_playlist(&cli.input) || live || cli.loop_input).then(|| {
+            playlist::Stitcher::new(
+                input
+                    .stream(video_stream_index)
+                    .and_then(|stream| stream_info::frame_interval_ns(&stream)),
+            )
+        }),
+        looper: Looper::new(cli.duration),
+        log_time_source,
+        time_offset_ns,
+        live,
+        segmented: segments.is_some(),
+        max_pending_frame_bytes: cli
+            .max_memory
+            .map(|budget| budget.max_pending_frame_bytes()),
+    };
+    while let Some(video_packets) = convert_pass(
+        &mut input,
+        &mut converter,
+        &mut writer,
+        &mut output,
+        &mut tracks,
+        &mut passes,
+    )? {
+        if !cli.loop_input || video_packets == 0 {
+            break;
+        }
+        // Again from the first keyframe, continuing the timeline
+        input.seek(0, ..)?;
+        converter.rewrite_parameter_sets();
+        passes.looper.passes += 1;
+    }
     let mut frame = ffmpeg::frame::Video::empty();
-    let first_frame = true;
+    if passes.looper.passes > 0 {
+        info!("Read the input {} times over", passes.looper.passes + 1);
+    } else if let Some(stitcher) = passes.stitcher.filter(|s| s.discontinuities > 0) {
+        info!(
+            "Stitched {} timestamp discontinuities between segments",
+            stitcher.discontinuities
+        );
+    }
+    if let Some(segments) = &segments {
+        let mut worker_options = options.clone();
+        // The workers keep the cores busy, each decoding on its own thread
+        // unless told otherwise
+        worker_options.threads = cli.threads.max(1);
+        let start = Instant::now();
+        let writing =
+            output.bench.elapsed(Stage::Serialize) + output.bench.elapsed(Stage::McapWrite);
+        segments::convert(
+            &cli.input,
+            &worker_options,
+            video_stream_index,
+            segments,
+            jobs,
+            time_offset_ns,
+            |frame| {
+                let packet = frame.to_packet();
+                converter.record_encoded(frame.data.len(), frame.keyframe);
+                let timestamp_ns = converter.get_timestamp(packet.pts().unwrap_or(0));
+                output.write_frame(
+                    &mut writer,
+                    &mut converter,
+                    &packet,
+                    timestamp_ns,
+                    frame.data,
+                )
+            },
+        )?;
+        // Waiting for the workers, which decode and encode in parallel
+        let written = output.bench.elapsed(Stage::Serialize)
+            + output.bench.elapsed(Stage::McapWrite)
+            - writing;
+        output
+            .bench
+            .add(Stage::Encode, start.elapsed().saturating_sub(written));
+    } else {
+        converter.send_eof()?;
+        if !converter.is_transcoding() {
+            flush_decoder(&mut converter, &mut frame, &mut writer, &mut output)?;
+        }
+    }
+    if converter.is_transcoding() && segments.is_none() {
+        transcode_frames(&mut converter, &mut frame, &mut writer, &mut output)?;
+        converter.send_encoder_eof()?;
+        drain_encoder(&mut converter, &mut writer, &mut output)?;
+    }
+    for stream in &mut output.derived {
+        stream.finish(&mut writer, &converter)?;
+    }
+    output.progress.finish(output.frames)?;
+    let quality = converter.finish_quality()?;
+    let verification = output.verifier.take().map(DecodeVerifier::finish);
+    output.finish_bitrate(&mut writer)?;
+    converter.finish()?;
+    let start_ns = output.first_timestamp_ns.unwrap_or(0);
+    for stream in &mut output.passthrough {
+        stream.write_until(&mut writer, start_ns, u64::MAX)?;
+    }
+    output.interleaver.start(start_ns);
+    output.interleaver.write_until(&mut writer, u64::MAX)?;
+    if let Some(duplicates) = &output.duplicates {
+        info!("Dropped {} duplicate frames", duplicates.dropped());
+    }
+    if let Some(gaps) = &output.gaps {
+        for line in gaps.summary() {
+            info!("{}", line);
+        }
+    }
+    if output.black_frames > 0 {
+        info!("Detected {} black frames", output.black_frames);
+    }
+    if output.corrupt_frames > 0 {
+        info!("Detected {} corrupt frames", output.corrupt_frames);
+    }
+    writer.finish()?;
+    drop(writer);
+    if cli.minimal {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&cli.output)?;
+        let length = minimal::strip_summary(&mut file)?;
+        file.set_len(length)?;
+    }
+    if cli.data_section_crc {
+        write_data_section_crc(&cli.output)?;
+    }
 
-    let packet_iter = input.packets();
-    for (stream, packet) in packet_iter {
-        if stream.index() != video_stream_index {
-            continue;
+    info!(
+        "\nCompleted in {:.3} seconds",
+        start_time.elapsed().as_secs_f64()
+    );
+
+    if let Some((locations, poses)) = telemetry_channels {
+        info!(
+            "Wrote {} location fixes and {} gimbal poses from the telemetry",
+            output.interleaver.messages(locations),
+            output.interleaver.messages(poses)
+        );
+    }
+
+    if !static_transforms.is_empty() {
+        info!("Wrote {} static transforms", static_transforms.len());
+    }
+    for stream in &output.derived {
+        info!("Wrote {} frames to {}", stream.messages(), stream.topic);
+    }
+    for stream in &output.passthrough {
+        info!("Wrote {} frames to {}", stream.messages(), stream.topic);
+    }
+    if let Some(camm) = &tracks.camm {
+        info!("Wrote {} messages from the CAMM track", camm.messages());
+    }
+    if let Some(klv) = &tracks.klv {
+        info!("Wrote {} messages from the KLV track", klv.messages());
+    }
+    if !chapters.is_empty() && cli.chapters != ChapterMode::None {
+        info!("Wrote {} chapters", chapters.len());
+    }
+    let subtitle_messages = tracks.log.messages()
+        + srt_channel.map_or(0, |channel_id| {
+            output.interleaver.messages(channel_id) as usize
+        });
+    if subtitle_messages > 0 {
+        info!("Wrote {} subtitles as log messages", subtitle_messages);
+    }
+    if let Some(onvif) = &tracks.onvif {
+        info!("Wrote {} messages from the ONVIF track", onvif.messages());
+    }
+    if !tracks.mebx.is_empty() {
+        info!(
+            "Wrote {} messages from {} timed metadata tracks",
+            tracks.mebx.iter().map(MebxOutput::messages).sum::<usize>(),
+            tracks.mebx.len()
+        );
+    }
+
+    if converter.stripped_nal_units() > 0 {
+        info!(
+            "Dropped {} AUD/filler NAL units",
+            converter.stripped_nal_units()
+        );
+    }
+
+    match quality {
+        Some(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        None if cli.quality_metrics => {
+            println!("No quality metrics, as the video was passed through without re-encoding")
         }
+        None => {}
+    }
 
-        let timestamp_ns = converter.get_timestamp(packet.pts().unwrap_or(0));
-        converter.process_packet(&packet, first_frame)?;
-        converter.send_packet(&packet)?;
+    if let Some((sent, failures)) = &verification {
+        for line in verify::summary(*sent, failures) {
+            println!("{}", line);
+        }
+    }
 
-        match converter.receive_frame(&mut frame) {
-            Ok(_) => {
-                if converter.update_progress(timestamp_ns) {
-                    print!(".");
-                    std::io::stdout().flush()?;
-                }
+    if cli.stats {
+        for line in converter.stats().summary(converter.output_codec()) {
+            println!("{}", line);
+        }
+    }
+    if cli.bench {
+        for line in output.bench.summary(start_time.elapsed()) {
+            println!("{}", line);
+        }
+    }
+    if let Some(path) = &cli.stats_json {
+        std::fs::write(path, converter.stats().to_json(converter.output_codec()))?;
+    }
+
+    let validator = converter.validator();
+    if !validator.is_clean() {
+        warn!("Deviations from a well-formed input:");
+        for line in validator.summary() {
+            warn!("  {}", line);
+        }
+    }
+
+    if cli.summary_json || cli.summary_file.is_some() {
+        let validator = converter.validator();
+        let summary = ConversionSummary {
+            input: cli.input.clone(),
+            output: cli.output.clone(),
+            duration_ns: output
+                .last_timestamp_ns
+                .zip(output.first_timestamp_ns)
+                .map_or(0, |(last, first)| last - first),
+            frames: output.frames,
+            keyframes: output.keyframes_written,
+            dropped_frames: (output
+                .duplicates
+                .as_ref()
+                .map_or(0, DuplicateDetector::dropped)
+                + converter.skipped_leading()) as u64,
+            fixed_timestamps: ["missing PTS", "missing DTS", "non-monotonic timestamp"]
+                .iter()
+                .map(|kind| validator.count(kind) as u64)
+                .sum(),
+            output_bytes: std::fs::metadata(&cli.output)?.len(),
+            elapsed_seconds: start_time.elapsed().as_secs_f64(),
+            log_time_source: log_time_source.name(),
+        }
+        .to_json();
+        if cli.summary_json {
+            println!("{}", summary);
+        }
+        if let Some(path) = &cli.summary_file {
+            std::fs::write(path, summary)?;
+        }
+    }
 
-                converter.check_timestamp(timestamp_ns)?;
+    if let Some((_, failures)) = verification.filter(|(_, failures)| !failures.is_empty()) {
+        return Err(Failure::new(
+            "verify-decode",
+            format!(
+                "{} written frames failed to decode, see above (--verify-decode)",
+                failures.len()
+            ),
+            "Convert with --transcode to write frames of its own encoder",
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+struct VideoOutput {
+    channel_id: u16,
+    /// Channel of the per-frame diagnostics, with --frame-stats
+    stats_channel_id: Option<u16>,
+    /// Channel and running windows of the bitrate, with --emit-bitrate
+    bitrate: Option<(u16, BitrateWindows)>,
+    /// Channel and count of the keyframe markers, with --emit-keyframes
+    keyframes: Option<(u16, u32)>,
+    /// Channel and algorithm of the frame hashes, with --frame-hashes
+    hashes: Option<(u16, HashAlgorithm)>,
+    /// Channel, detector and count of the scene changes, with --scene-changes
+    scenes: Option<(u16, SceneDetector, u32)>,
+    /// Number of frames written so far
+    frames: u64,
+    /// Detector of repeated frames to drop, with --drop-duplicate-frames
+    duplicates: Option<DuplicateDetector>,
+    /// Whether the frames are logged at the DTS of their packets, with
+    /// --log-time-source dts
+    log_dts: bool,
+    /// Decoder of the written frames, with --verify-decode
+    verifier: Option<DecodeVerifier>,
+    /// Gaps between frames, if the stream has a nominal frame rate
+    gaps: Option<FrameGaps>,
+    log_gaps: bool,
+    /// Warnings about black and corrupt frames on <TOPIC>/diagnostics
+    diagnostics: LogOutput,
+    black_frames: usize,
+    /// Whether the last decoded frame was black
+    in_black_run: bool,
+    corrupt_frames: usize,
+    frame_id: String,
+    message_encoding: MessageEncoding,
+    /// Message type the video is written as instead, with --schema
+    custom_schema: Option<CustomSchema>,
+    /// Encoder of the frames as images, with --output-messages compressed-image
+    images: Option<ImageEncoder>,
+    /// Copies of the video on topics of their own, with --preview-topic and
+    /// --stereo-split
+    derived: Vec<DerivedStream>,
+    /// Other video passed through to topics of their own and written along
+    /// with the frames, the GoPro preview and the other lens of an Insta360
+    passthrough: Vec<PassthroughStream>,
+    /// Messages from outside the video, written ahead of the frames that
+    /// follow them
+    interleaver: Interleaver,
+    /// Close the MCAP chunk after every keyframe, with --max-memory
+    flush_at_keyframes: bool,
+    sequence: u32,
+    /// Encoded CompressedVideo message of the frame being written
+    buffer: Vec<u8>,
+    /// Time spent in each stage, printed with --bench
+    bench: Bench,
+    progress: Progress,
+    /// Log time of the first frame, which sidecar telemetry is aligned to
+    first_timestamp_ns: Option<u64>,
+    last_timestamp_ns: Option<u64>,
+    keyframes_written: u64,
+    /// The frames to write, with --at
+    sampler: Option<Sampler>,
+    /// Pacing of the packets read and the frames written, with --limit-rate
+    read_limit: Option<RateLimit>,
+    write_limit: Option<RateLimit>,
+}
+
+impl VideoOutput {
+    /// The timestamp a frame batched from `packet` is logged at
+    fn frame_timestamp(&self, converter: &VideoConverter, packet: &ffmpeg::Packet) -> u64 {
+        let ts = if self.log_dts {
+            packet.dts().or(packet.pts())
+        } else {
+            packet.pts()
+        };
+        converter.get_timestamp(ts.unwrap_or(0))
+    }
+
+    fn write_frame(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &mut VideoConverter,
+        packet: &ffmpeg::Packet,
+        timestamp_ns: u64,
+        data: Bytes,
+    ) -> Result<(), Box<dyn Error>> {
+        trace!(
+            "Frame {} at {}ns: {} bytes{}",
+            self.frames,
+            timestamp_ns,
+            data.len(),
+            if packet.is_key() { ", keyframe" } else { "" }
+        );
+        if let Some(limit) = &mut self.write_limit {
+            limit.pace(data.len());
+        }
+        let dot = converter.update_progress(timestamp_ns);
+        self.progress.update(timestamp_ns, self.frames + 1, dot)?;
+        metrics::add_frame();
+
+        let timestamp_ns = converter.check_timestamp(timestamp_ns)?;
+        if self.first_timestamp_ns.is_none() {
+            self.first_timestamp_ns = Some(timestamp_ns);
+            self.interleaver.start(timestamp_ns);
+        }
+        self.interleaver.write_until(writer, timestamp_ns)?;
+        self.last_timestamp_ns = Some(timestamp_ns);
+        self.keyframes_written += packet.is_key() as u64;
+
+        if let Some(stats_channel_id) = self.stats_channel_id {
+            let codec = converter.output_codec();
+            let nal_types = match codec {
+                CodecType::Av1 => Vec::new(),
+                _ => nal::split_annex_b(&data)
+                    .iter()
+                    .filter_map(|nal| codec.nal_type(*nal.first()?))
+                    .map(u32::from)
+                    .collect(),
+            };
+            let pts = packet.pts().or(packet.dts()).unwrap_or(0);
+            let dts = packet.dts().unwrap_or(pts);
+            let stats = mp42mcap::FrameStats {
+                timestamp: Some(to_timestamp(timestamp_ns)),
+                size: data.len() as u32,
+                keyframe: packet.is_key(),
+                nal_types,
+                pts,
+                dts,
+                decode_latency_ns: playlist::ticks_to_ns(
+                    pts.saturating_sub(dts),
+                    converter.time_base(),
+                )
+                .max(0) as u64,
+            };
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id: stats_channel_id,
+                    sequence: self.sequence,
+                    log_time: timestamp_ns,
+                    publish_time: timestamp_ns,
+                },
+                &stats.encode_to_vec(),
+            )?;
+        }
 
+        if let Some((_, windows)) = &mut self.bitrate {
+            for (start_ns, bytes) in windows.add(timestamp_ns, data.len()) {
+                self.write_bitrate(writer, start_ns, bytes)?;
+            }
+        }
+
+        if let Some((channel_id, count)) = self.keyframes.as_mut().filter(|_| packet.is_key()) {
+            let message = mp42mcap::Keyframe {
+                timestamp: Some(to_timestamp(timestamp_ns)),
+                frame_index: self.frames,
+            };
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id: *channel_id,
+                    sequence: *count,
+                    log_time: timestamp_ns,
+                    publish_time: timestamp_ns,
+                },
+                &message.encode_to_vec(),
+            )?;
+            *count += 1;
+        }
+
+        if let Some((channel_id, algorithm)) = self.hashes {
+            let message = mp42mcap::FrameHash {
+                timestamp: Some(to_timestamp(timestamp_ns)),
+                sequence: self.sequence,
+                algorithm: algorithm.name().to_string(),
+                hash: hash::to_hex(&algorithm.digest(&data)),
+            };
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id,
+                    sequence: self.sequence,
+                    log_time: timestamp_ns,
+                    publish_time: timestamp_ns,
+                },
+                &message.encode_to_vec(),
+            )?;
+        }
+
+        if let Some(verifier) = &mut self.verifier {
+            verifier.add(&data, packet.is_key());
+        }
+
+        // Reused for every frame, so the only allocation per frame is the
+        // one the data was assembled in
+        self.buffer.clear();
+        match (&self.images, &self.custom_schema, self.message_encoding) {
+            (Some(images), _, _) => {
+                let message = CompressedImage {
+                    timestamp: Some(to_timestamp(timestamp_ns)),
+                    frame_id: self.frame_id.clone(),
+                    data,
+                    format: images.format.name().to_string(),
+                };
+                self.bench
+                    .time(Stage::Serialize, || message.encode(&mut self.buffer))?;
+            }
+            (None, Some(schema), _) => {
+                let frame = schema::Frame {
+                    timestamp_ns,
+                    frame_id: &self.frame_id,
+                    data: &data,
+                    format: converter.format_str(),
+                    sequence: self.sequence,
+                    keyframe: packet.is_key(),
+                };
+                self.bench
+                    .time(Stage::Serialize, || schema.encode(&frame, &mut self.buffer));
+            }
+            (None, None, MessageEncoding::Protobuf) => {
                 let message = CompressedVideo {
-                    frame_id: cli.frame_id.clone(),
-                    timestamp: Some(prost_types::Timestamp {
-                        seconds: (timestamp_ns / 1_000_000_000) as i64,
-                        nanos: (timestamp_ns % 1_000_000_000) as i32,
-                    }),
-                    data: converter.take_frame_data(),
+                    frame_id: self.frame_id.clone(),
+                    timestamp: Some(to_timestamp(timestamp_ns)),
+                    data,
                     format: converter.format_str().to_string(),
                 };
+                self.bench
+                    .time(Stage::Serialize, || message.encode(&mut self.buffer))?;
+            }
+            (None, None, MessageEncoding::Flatbuffer) => self.bench.time(Stage::Serialize, || {
+                flatbuffer::encode_compressed_video(
+                    &mut self.buffer,
+                    timestamp_ns,
+                    &self.frame_id,
+                    &data,
+                    converter.format_str(),
+                )
+            }),
+        }
+        let header = MessageHeader {
+            channel_id: self.channel_id,
+            sequence: self.sequence,
+            log_time: timestamp_ns,
+            publish_time: timestamp_ns,
+        };
+        self.bench.time(Stage::McapWrite, || {
+            writer.write_to_known_channel(&header, &self.buffer)
+        })?;
+        self.bench.add_frame(self.buffer.len());
+        if self.flush_at_keyframes && packet.is_key() {
+            writer.flush()?;
+        }
 
-                let encoded = message.encode_to_vec();
-                writer.write_to_known_channel(
-                    &MessageHeader {
-                        channel_id: channel_id.try_into().unwrap(),
-                        sequence,
-                        log_time: timestamp_ns,
-                        publish_time: timestamp_ns,
-                    },
-                    &encoded,
-                )?;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.frames += 1;
 
-                sequence = sequence.wrapping_add(1);
+        // Up to this frame, so the log times of the file stay in order
+        let start_ns = self.first_timestamp_ns.unwrap_or(timestamp_ns);
+        for stream in &mut self.passthrough {
+            stream.write_until(writer, start_ns, timestamp_ns)?;
+        }
+        Ok(())
+    }
+
+    fn write_bitrate(
+        &self,
+        writer: &mut Writer<BufWriter<File>>,
+        start_ns: u64,
+        bytes: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some((channel_id, _)) = &self.bitrate else {
+            return Ok(());
+        };
+        let window =
+            (start_ns - self.first_timestamp_ns.unwrap_or(start_ns)) / BitrateWindows::WINDOW_NS;
+        let message = mp42mcap::Bitrate {
+            timestamp: Some(to_timestamp(start_ns)),
+            bytes,
+            bits_per_second: (bytes * 8) as f64 * 1e9 / BitrateWindows::WINDOW_NS as f64,
+        };
+        writer.write_to_known_channel(
+            &MessageHeader {
+                channel_id: *channel_id,
+                sequence: window as u32,
+                log_time: start_ns,
+                publish_time: start_ns,
+            },
+            &message.encode_to_vec(),
+        )?;
+        Ok(())
+    }
+
+    /// Encodes a decoded frame into the copies of the video
+    fn write_derived(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &VideoConverter,
+        frame: &mut ffmpeg::frame::Video,
+    ) -> Result<(), Box<dyn Error>> {
+        for stream in &mut self.derived {
+            self.bench.time(Stage::Encode, || {
+                stream.send_frame(writer, converter, frame)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Checks a decoded frame for dropouts and scene changes
+    fn analyze_frame(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        converter: &mut VideoConverter,
+        frame: &ffmpeg::frame::Video,
+    ) -> Result<(), Box<dyn Error>> {
+        let pts = frame.timestamp().or(frame.pts()).unwrap_or(0);
+        let timestamp_ns = converter.get_timestamp(pts);
+        let seconds = timestamp_ns as f64 / 1e9;
+
+        if let Some(gap) = self.gaps.as_mut().and_then(|gaps| gaps.add(timestamp_ns)) {
+            if self.log_gaps {
+                let text = format!(
+                    "No frames for {:.3}s after {:.3}s, {} missing",
+                    gap.duration_ns as f64 / 1e9,
+                    gap.start_ns as f64 / 1e9,
+                    gap.missing
+                );
+                self.diagnostics
+                    .warn(writer, "frame_gap", gap.start_ns, &text)?;
+            }
+        }
+
+        if scene::is_corrupt(frame) {
+            self.corrupt_frames += 1;
+            let text = format!("Decoder concealed errors in the frame at {:.3}s", seconds);
+            self.diagnostics
+                .warn(writer, "corrupt_frame", timestamp_ns, &text)?;
+        }
+
+        let Some(histogram) = scene::luma_histogram(frame) else {
+            return Ok(());
+        };
+        let black = scene::is_black(&histogram);
+        if black {
+            self.black_frames += 1;
+            // Once per run of black frames rather than for each of them
+            if !self.in_black_run {
+                let text = format!("Black frames start at {:.3}s", seconds);
+                self.diagnostics
+                    .warn(writer, "black_frame", timestamp_ns, &text)?;
+            }
+        }
+        self.in_black_run = black;
+
+        let Some((channel_id, detector, count)) = &mut self.scenes else {
+            return Ok(());
+        };
+        let Some(score) = detector.process(&histogram) else {
+            return Ok(());
+        };
+        let message = mp42mcap::SceneChange {
+            timestamp: Some(to_timestamp(timestamp_ns)),
+            score,
+        };
+        writer.write_to_known_channel(
+            &MessageHeader {
+                channel_id: *channel_id,
+                sequence: *count,
+                log_time: timestamp_ns,
+                publish_time: timestamp_ns,
+            },
+            &message.encode_to_vec(),
+        )?;
+        *count += 1;
+        Ok(())
+    }
+
+    /// Writes the last, partial bitrate window
+    fn finish_bitrate(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some((start_ns, bytes)) = self.bitrate.as_mut().and_then(|(_, w)| w.finish()) {
+            self.write_bitrate(writer, start_ns, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// The passes over the input, one unless it's read again with --loop
+struct Passes {
+    video_stream_index: usize,
+    /// Start of the video stream, which the progress is counted from
+    video_start_ns: u64,
+    stitcher: Option<playlist::Stitcher>,
+    looper: Looper,
+    log_time_source: LogTimeSource,
+    time_offset_ns: Option<i64>,
+    /// Corrupt packets of a live stream are skipped rather than failing
+    live: bool,
+    /// The video is decoded and encoded by the segment workers instead
+    segmented: bool,
+    /// Frame data held back for the decoder past which it's written as is,
+    /// from --max-memory
+    max_pending_frame_bytes: Option<usize>,
+}
+
+/// Reads the input once from where it's at, moving each packet by the edit
+/// list pauses, the stitched timeline and the offset before writing it.
+/// Returns the number of video packets read at the end of the input, or
+/// None if --duration was reached or the conversion interrupted.
+fn convert_pass(
+    input: &mut ffmpeg::format::context::Input,
+    converter: &mut VideoConverter,
+    writer: &mut Writer<BufWriter<File>>,
+    output: &mut VideoOutput,
+    tracks: &mut Tracks,
+    passes: &mut Passes,
+) -> Result<Option<usize>, Box<dyn Error>> {
+    let mut video_packets = 0;
+    let mut frame = ffmpeg::frame::Video::empty();
+    let mut packet_iter = input.packets();
+    while let Some((stream, mut packet)) = output.bench.time(Stage::Demux, || packet_iter.next()) {
+        if capture::interrupted() {
+            return Ok(None);
+        }
+        output.progress.add_input(packet.size());
+        metrics::add_input(packet.size());
+        if let Some(limit) = &mut output.read_limit {
+            limit.pace(packet.size());
+        }
+        let video = stream.index() == passes.video_stream_index;
+        if video {
+            converter.apply_edit_gaps(&mut packet);
+        }
+        if video {
+            failure::read_video_packet(
+                packet
+                    .pts()
+                    .or(packet.dts())
+                    .map(|ts| playlist::ticks_to_ns(ts, stream.time_base())),
+            );
+        }
+        if let Some(stitcher) = &mut passes.stitcher {
+            if let Some(dts) = packet.dts().or(packet.pts()) {
+                let dts_ns = playlist::ticks_to_ns(dts, stream.time_base());
+                stitcher.add(packet.stream(), dts_ns, video);
+            }
+            stitcher.shift(&mut packet, stream.time_base());
+        }
+        if video {
+            video_packets += 1;
+            if let Some(ts) = packet.dts().or(packet.pts()) {
+                let position_ns = playlist::ticks_to_ns(ts, stream.time_base());
+                if passes.looper.reached(position_ns) {
+                    return Ok(None);
+                }
+                output
+                    .progress
+                    .add_video((position_ns.max(0) as u64).saturating_sub(passes.video_start_ns));
+            }
+        }
+        if passes.log_time_source.retimes_packets() && video {
+            timesource::retime_packet(passes.log_time_source, &mut packet, stream.time_base());
+        }
+        if let Some(offset_ns) = passes.time_offset_ns {
+            timesync::shift_packet(&mut packet, stream.time_base(), offset_ns);
+        }
+        if tracks.write_packet(writer, converter.validator_mut(), stream.index(), &packet)? {
+            continue;
+        }
+        if !video {
+            continue;
+        }
+
+        // Where the packet's data starts, for dropping it alone if it's corrupt
+        let queued = converter.pending_frame_bytes();
+        let Some(pts) = output
+            .bench
+            .time(Stage::AnnexB, || converter.process_packet(&packet))?
+        else {
+            continue;
+        };
+        // The segments are decoded and encoded from their own demuxers
+        if passes.segmented {
+            continue;
+        }
+        match output
+            .bench
+            .time(Stage::Decode, || converter.send_packet(&packet))
+        {
+            Ok(()) => {}
+            Err(ffmpeg::Error::InvalidData) if passes.live => {
+                skip_corrupt_packet(converter, pts, queued);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        if converter.is_transcoding() {
+            transcode_frames(converter, &mut frame, writer, output)?;
+            continue;
+        }
+
+        match output
+            .bench
+            .time(Stage::Decode, || converter.receive_frame(&mut frame))
+        {
+            Ok(_) => write_decoded_frame(converter, &mut frame, writer, output)?,
+            // Rather than hold back ever more data for a decoder that keeps
+            // failing, write it at the packet it reached the limit at
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            }) if output.images.is_none()
+                && passes
+                    .max_pending_frame_bytes
+                    .is_some_and(|max| converter.pending_frame_bytes() > max) =>
+            {
+                let (frame_packet, data) = converter.take_frame_data();
+                let timestamp_ns = output.frame_timestamp(converter, &frame_packet);
+                output.write_frame(writer, converter, &frame_packet, timestamp_ns, data)?;
             }
             Err(ffmpeg::Error::Other {
                 errno: ffmpeg::error::EAGAIN,
             }) => continue,
+            Err(ffmpeg::Error::InvalidData) if passes.live => {
+                skip_corrupt_packet(converter, pts, queued);
+            }
             Err(e) => return Err(e.into()),
         }
     }
-    writer.finish()?;
-    converter.send_eof()?;
+    Ok(Some(video_packets))
+}
 
-    println!(
-        "\nCompleted in {:.3} seconds",
-        start_time.elapsed().as_secs_f64()
-    );
+/// Drops a packet of a live stream the decoder can't make out, like one
+/// missing data lost on the network, and its data waiting to be written
+/// after the first `queued` bytes. The decoder picks up again from the
+/// packets after it.
+fn skip_corrupt_packet(converter: &mut VideoConverter, pts: i64, queued: usize) {
+    converter.truncate_frame_data(queued);
+    // In case they went out with the dropped data
+    converter.rewrite_parameter_sets();
+    info!("Skipped an undecodable packet at PTS={}", pts);
+}
 
+/// Writes a frame the decoder of the passed through video put out, as the
+/// data of the packets batched into it or, with --output-messages
+/// compressed-image, as an image
+fn write_decoded_frame(
+    converter: &mut VideoConverter,
+    frame: &mut ffmpeg::frame::Video,
+    writer: &mut Writer<BufWriter<File>>,
+    output: &mut VideoOutput,
+) -> Result<(), Box<dyn Error>> {
+    output.analyze_frame(writer, converter, frame)?;
+    output.write_derived(writer, converter, frame)?;
+    // Written with the first of the packets batched into it
+    let (mut frame_packet, mut data) = converter.take_frame_data();
+    if let Some(images) = &mut output.images {
+        // The frames come out in display order, so each image has the
+        // timestamp of its own frame rather than of the packet just sent
+        frame_packet = ffmpeg::Packet::empty();
+        frame_packet.set_pts(frame.timestamp().or(frame.pts()));
+        frame_packet.set_dts(frame_packet.pts());
+        frame_packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        data = output.bench.time(Stage::Encode, || images.encode(frame))?;
+    } else if data.is_empty() {
+        // Its data went out with a frame the decoder put out before it
+        return Ok(());
+    }
+    if let Some(duplicates) = &mut output.duplicates {
+        // Images stand alone, while a frame of the video can only go if no
+        // other frame references it
+        let droppable = output.images.is_some()
+            || (!frame_packet.is_key()
+                && duplicates::is_non_reference(&data, converter.output_codec()));
+        if duplicates.is_duplicate_data(&data, droppable) {
+            return Ok(());
+        }
+    }
+    let timestamp_ns = output.frame_timestamp(converter, &frame_packet);
+    output.write_frame(writer, converter, &frame_packet, timestamp_ns, data)
+}
+
+/// Writes the frames the decoder of the passed through video still holds at
+/// the end of the input, and the data of any packets it made nothing of
+fn flush_decoder(
+    converter: &mut VideoConverter,
+    frame: &mut ffmpeg::frame::Video,
+    writer: &mut Writer<BufWriter<File>>,
+    output: &mut VideoOutput,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match output
+            .bench
+            .time(Stage::Decode, || converter.receive_frame(frame))
+        {
+            Ok(_) => write_decoded_frame(converter, frame, writer, output)?,
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if output.images.is_none() && converter.pending_frame_bytes() > 0 {
+        let (frame_packet, data) = converter.take_frame_data();
+        let timestamp_ns = output.frame_timestamp(converter, &frame_packet);
+        output.write_frame(writer, converter, &frame_packet, timestamp_ns, data)?;
+    }
     Ok(())
 }
 
+/// Feeds every frame the decoder has ready to the encoder
+fn transcode_frames(
+    converter: &mut VideoConverter,
+    frame: &mut ffmpeg::frame::Video,
+    writer: &mut Writer<BufWriter<File>>,
+    output: &mut VideoOutput,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match output
+            .bench
+            .time(Stage::Decode, || converter.receive_frame(frame))
+        {
+            Ok(_) => {
+                let timestamp = frame.timestamp().unwrap_or(0);
+                if output.sampler.as_ref().is_some_and(|s| !s.keep(timestamp)) {
+                    continue;
+                }
+                output.analyze_frame(writer, converter, frame)?;
+                output.write_derived(writer, converter, frame)?;
+                if let Some(duplicates) = &mut output.duplicates {
+                    if duplicates.is_duplicate_frame(frame) {
+                        continue;
+                    }
+                }
+                output
+                    .bench
+                    .time(Stage::Encode, || converter.encode_frame(frame))?;
+                drain_encoder(converter, writer, output)?;
+            }
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Writes every packet the encoder has ready
+fn drain_encoder(
+    converter: &mut VideoConverter,
+    writer: &mut Writer<BufWriter<File>>,
+    output: &mut VideoOutput,
+) -> Result<(), Box<dyn Error>> {
+    let mut packet = ffmpeg::Packet::empty();
+    loop {
+        match output
+            .bench
+            .time(Stage::Encode, || converter.receive_encoded(&mut packet))
+        {
+            Ok(_) => {
+                let timestamp_ns = converter.get_timestamp(packet.pts().unwrap_or(0));
+                let data = packet
+                    .data()
+                    .map(Bytes::copy_from_slice)
+                    .unwrap_or_default();
+                output.write_frame(writer, converter, &packet, timestamp_ns, data)?;
+            }
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Adds the location fixes and gimbal poses of a DJI SRT file to
+/// `interleaver`, timed from the first video frame. Returns the channels of
+/// the two.
+fn add_telemetry(
+    writer: &mut Writer<BufWriter<File>>,
+    interleaver: &mut Interleaver,
+    samples: &[TelemetrySample],
+    frame_id: &str,
+) -> Result<(u16, u16), Box<dyn Error>> {
+    let location_channel: u16 =
+        setup_mcap_channel(writer, "location", "foxglove.LocationFix", BTreeMap::new())?
+            .try_into()?;
+    let gimbal_channel: u16 =
+        setup_mcap_channel(writer, "gimbal", "foxglove.PoseInFrame", BTreeMap::new())?
+            .try_into()?;
+
+    for sample in samples {
+        if let Some((latitude, longitude, altitude)) = sample.position() {
+            let frame_id = frame_id.to_string();
+            interleaver.add_from_start(location_channel, sample.offset_ns, move |log_time| {
+                LocationFix {
+                    timestamp: Some(to_timestamp(log_time)),
+                    frame_id,
+                    latitude,
+                    longitude,
+                    altitude,
+                    ..Default::default()
+                }
+                .encode_to_vec()
+            });
+        }
+        if let Some(pose) = sample.gimbal_pose() {
+            let frame_id = frame_id.to_string();
+            interleaver.add_from_start(gimbal_channel, sample.offset_ns, move |log_time| {
+                PoseInFrame {
+                    timestamp: Some(to_timestamp(log_time)),
+                    frame_id,
+                    pose: Some(pose),
+                }
+                .encode_to_vec()
+            });
+        }
+    }
+    Ok((location_channel, gimbal_channel))
+}
+
+fn to_timestamp(timestamp_ns: u64) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: (timestamp_ns / 1_000_000_000) as i64,
+        nanos: (timestamp_ns % 1_000_000_000) as i32,
+    }
+}
+
 fn setup_mcap_channel(
     writer: &mut Writer<BufWriter<File>>,
     topic: &str,
+    schema_name: &str,
+    metadata: BTreeMap<String, String>,
 ) -> Result<u64, Box<dyn Error>> {
     let schema = Schema {
-        name: String::from("foxglove.CompressedVideo"),
+        name: schema_name.to_string(),
         encoding: String::from("protobuf"),
         data: Cow::Owned(
             include_bytes!(concat!(env!("OUT_DIR"), "/foxglove_descriptor.bin")).to_vec(),
@@ -141,7 +3052,7 @@ fn setup_mcap_channel(
         topic: topic.to_string(),
         message_encoding: String::from("protobuf"),
         schema: Some(schema.into()),
-        metadata: BTreeMap::default(),
+        metadata,
     };
     Ok(writer.add_channel(&channel)?.into())
 }
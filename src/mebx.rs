@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::mp4box::{boxes, child};
+
+/// Sample entry of a QuickTime timed metadata track
+const MEBX_TAG: u32 = u32::from_le_bytes(*b"mebx");
+
+// Well-known data types of the QuickTime metadata format
+const TYPE_UTF8: u32 = 1;
+const TYPE_SIGNED_INT: u32 = 21;
+const TYPE_UNSIGNED_INT: u32 = 22;
+const TYPE_FLOAT32: u32 = 23;
+const TYPE_FLOAT64: u32 = 24;
+
+/// Whether the stream is a timed metadata track
+pub fn is_mebx(parameters: &ffmpeg::codec::Parameters) -> bool {
+    unsafe { (*parameters.as_ptr()).codec_tag == MEBX_TAG }
+}
+
+/// A key of the key table of a timed metadata track
+#[derive(Debug, PartialEq)]
+pub struct Key {
+    /// Key value, e.g. `com.apple.quicktime.video-orientation`
+    pub name: String,
+    /// Well-known data type of the values, if declared
+    pub data_type: Option<u32>,
+}
+
+/// A decoded item value
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Number(f64),
+}
+
+/// The payload of the 'keys' box of a sample entry, which may start at the
+/// entry's own box header, at its reserved fields and data reference index,
+/// or at its child boxes
+fn keys_box(data: &[u8]) -> Option<&[u8]> {
+    let children = boxes(data);
+    if let Some(keys) = child(&children, b"keys") {
+        return Some(keys);
+    }
+    if let Some(entry) = child(&children, b"mebx") {
+        return keys_box(entry);
+    }
+    // After the 6 reserved bytes and the data reference index
+    child(&boxes(data.get(8..)?), b"keys")
+}
+
+/// Reads the key table ('keys' box) out of the sample description, mapping
+/// the local key IDs that samples refer to onto their keys
+pub fn parse_keys(sample_description: &[u8]) -> BTreeMap<u32, Key> {
+    let mut keys = BTreeMap::new();
+    let Some(table) = keys_box(sample_description) else {
+        return keys;
+    };
+
+    for (local_id, entry) in boxes(table) {
+        let mut name = None;
+        let mut data_type = None;
+        for (box_type, payload) in boxes(entry) {
+            match &box_type {
+                // Namespace, then the key value
+                b"keyd" if payload.len() > 4 => {
+                    name = Some(String::from_utf8_lossy(&payload[4..]).into_owned())
+                }
+                // Namespace 0 for the well-known types, then the type
+                b"dtyp" if payload.len() == 8 && payload[..4] == [0; 4] => {
+                    data_type = Some(u32::from_be_bytes(payload[4..8].try_into().unwrap()))
+                }
+                _ => {}
+            }
+        }
+        if let Some(name) = name {
+            keys.insert(u32::from_be_bytes(local_id), Key { name, data_type });
+        }
+    }
+    keys
+}
+
+/// The topic of each key under `mebx/`, its name, or the name and the local
+/// key ID where a name is there more than once
+pub fn topics(keys: &BTreeMap<u32, Key>) -> BTreeMap<u32, String> {
+    keys.iter()
+        .map(|(local_id, key)| {
+            let shared = keys.values().filter(|other| other.name == key.name).count() > 1;
+            let topic = if shared {
+                format!("{}/{}", key.name, local_id)
+            } else {
+                key.name.clone()
+            };
+            (*local_id, topic)
+        })
+        .collect()
+}
+
+/// Splits a sample into its items as (local key ID, value)
+pub fn parse_sample(data: &[u8]) -> Vec<(u32, &[u8])> {
+    boxes(data)
+        .into_iter()
+        .map(|(local_id, value)| (u32::from_be_bytes(local_id), value))
+        .collect()
+}
+
+/// Decodes a value of one of the well-known string and number types
+pub fn decode_value(data_type: u32, data: &[u8]) -> Option<Value> {
+    let be_bytes = |data: &[u8]| data.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    match (data_type, data.len()) {
+        (TYPE_UTF8, _) => Some(Value::Text(String::from_utf8_lossy(data).into_owned())),
+        (TYPE_SIGNED_INT, len @ (1 | 2 | 4 | 8)) => {
+            let shift = 64 - len * 8;
+            Some(Value::Number(
+                ((be_bytes(data) << shift) as i64 >> shift) as f64,
+            ))
+        }
+        (TYPE_UNSIGNED_INT, 1 | 2 | 4 | 8) => Some(Value::Number(be_bytes(data) as f64)),
+        (TYPE_FLOAT32, 4) => Some(Value::Number(
+            f32::from_be_bytes(data.try_into().unwrap()) as f64
+        )),
+        (TYPE_FLOAT64, 8) => Some(Value::Number(f64::from_be_bytes(data.try_into().unwrap()))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let keyd = make_box(b"keyd", b"mdtacom.apple.quicktime.video-orientation");
+        let dtyp = make_box(b"dtyp", &[0, 0, 0, 0, 0, 0, 0, 22]);
+        let entry = make_box(&1u32.to_be_bytes(), &[keyd, dtyp].concat());
+        let untyped = make_box(
+            &2u32.to_be_bytes(),
+            &make_box(b"keyd", b"mdtacom.apple.quicktime.detected-face"),
+        );
+        let table = make_box(b"keys", &[entry, untyped].concat());
+        let mut description = vec![0; 8];
+        description.extend(&table);
+
+        let keys = parse_keys(&description);
+        assert_eq!(
+            keys[&1],
+            Key {
+                name: "com.apple.quicktime.video-orientation".to_string(),
+                data_type: Some(TYPE_UNSIGNED_INT),
+            }
+        );
+        assert_eq!(keys[&2].data_type, None);
+        assert!(parse_keys(&[0; 16]).is_empty());
+        assert!(parse_keys(&[]).is_empty());
+
+        // The whole sample entry, header included
+        let entry = make_box(b"mebx", &description);
+        assert_eq!(parse_keys(&entry), keys);
+        // A "keys" in the data of another box isn't taken for the table
+        let mut misleading = vec![0; 8];
+        misleading.extend(make_box(b"free", b"\0\0\0\x10keys\0\0\0\0"));
+        misleading.extend(&table);
+        assert_eq!(parse_keys(&misleading), keys);
+    }
+
+    #[test]
+    fn test_topics() {
+        let key = |name: &str| Key {
+            name: name.to_string(),
+            data_type: None,
+        };
+        let keys = BTreeMap::from([(1, key("a")), (2, key("b")), (3, key("a"))]);
+        assert_eq!(
+            topics(&keys),
+            BTreeMap::from([
+                (1, "a/1".to_string()),
+                (2, "b".to_string()),
+                (3, "a/3".to_string())
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_sample() {
+        let sample = [
+            make_box(&1u32.to_be_bytes(), &[0, 6]),
+            make_box(&3u32.to_be_bytes(), b"hi"),
+        ]
+        .concat();
+        let items = parse_sample(&sample);
+        assert_eq!(items, [(1, &[0, 6][..]), (3, &b"hi"[..])]);
+
+        assert_eq!(
+            decode_value(TYPE_UNSIGNED_INT, items[0].1),
+            Some(Value::Number(6.0))
+        );
+        assert_eq!(
+            decode_value(TYPE_SIGNED_INT, &[0xFF, 0xFE]),
+            Some(Value::Number(-2.0))
+        );
+        assert_eq!(
+            decode_value(TYPE_UTF8, items[1].1),
+            Some(Value::Text("hi".to_string()))
+        );
+        assert_eq!(decode_value(TYPE_FLOAT32, &[0; 3]), None);
+    }
+}
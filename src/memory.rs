@@ -0,0 +1,101 @@
+/// Uncompressed size of a decoded frame, for 4:2:0 at up to 16 bits per
+/// sample
+pub fn frame_bytes(width: u32, height: u32) -> u64 {
+    width as u64 * height as u64 * 3
+}
+
+/// Decoded frames a worker of `--jobs` holds at once, between the reference
+/// pictures of its decoder and the lookahead of its encoder
+const FRAMES_PER_WORKER: u64 = 24;
+
+/// Splits the memory allowed by `--max-memory` between the buffers of the
+/// conversion
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryBudget {
+    pub bytes: u64,
+}
+
+impl MemoryBudget {
+    /// Target size of the MCAP chunks, at most the writer's default
+    pub fn chunk_size(&self) -> u64 {
+        (self.bytes / 32).clamp(64 * 1024, 768 * 1024)
+    }
+
+    /// Largest amount of converted packet data held back while the decoder
+    /// hasn't returned their frame yet
+    pub fn max_pending_frame_bytes(&self) -> usize {
+        (self.bytes / 8) as usize
+    }
+
+    /// Decoder threads, each holding a frame of its own, within a quarter of
+    /// the budget. `requested` is `--threads`, where 0 is one per core.
+    pub fn decoder_threads(&self, requested: usize, frame_bytes: u64) -> usize {
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+        let affordable = (self.bytes / 4 / frame_bytes.max(1)).max(1) as usize;
+        match requested {
+            0 => cores.min(affordable),
+            requested => requested.min(affordable),
+        }
+    }
+
+    /// Workers for `--jobs` within half of the budget
+    pub fn jobs(&self, requested: usize, frame_bytes: u64) -> usize {
+        let affordable = (self.bytes / 2 / (frame_bytes.max(1) * FRAMES_PER_WORKER)).max(1);
+        requested.min(affordable as usize)
+    }
+}
+
+impl std::str::FromStr for MemoryBudget {
+    type Err = String;
+
+    /// Parses a number of bytes with an optional K, M or G suffix, in
+    /// powers of 1024
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid memory size {:?}, expected e.g. 512M or 2G", s);
+        let (digits, multiplier) = match s.char_indices().last() {
+            Some((index, 'k' | 'K')) => (&s[..index], 1u64 << 10),
+            Some((index, 'm' | 'M')) => (&s[..index], 1 << 20),
+            Some((index, 'g' | 'G')) => (&s[..index], 1 << 30),
+            _ => (s, 1),
+        };
+        let value: f64 = digits.parse().map_err(|_| invalid())?;
+        // NaN and infinity parse too, and would saturate to 0 or u64::MAX
+        let bytes = value * multiplier as f64;
+        if !(bytes.is_finite() && bytes >= 1.0 && bytes < u64::MAX as f64) {
+            return Err(invalid());
+        }
+        Ok(Self {
+            bytes: bytes as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget: MemoryBudget = "512M".parse().unwrap();
+        assert_eq!(budget.bytes, 512 << 20);
+        assert_eq!("1.5k".parse::<MemoryBudget>().unwrap().bytes, 1536);
+        assert!("lots".parse::<MemoryBudget>().is_err());
+        assert!("0M".parse::<MemoryBudget>().is_err());
+        assert!("-1G".parse::<MemoryBudget>().is_err());
+        assert!("NaN".parse::<MemoryBudget>().is_err());
+        assert!("infG".parse::<MemoryBudget>().is_err());
+        assert!("0.5".parse::<MemoryBudget>().is_err());
+        assert!("1e30G".parse::<MemoryBudget>().is_err());
+
+        assert_eq!(budget.chunk_size(), 768 * 1024);
+        assert_eq!(MemoryBudget { bytes: 1 << 20 }.chunk_size(), 64 * 1024);
+        assert_eq!(budget.max_pending_frame_bytes(), 64 << 20);
+
+        // A quarter of 512 MiB fits five 4K frames, half of it one worker
+        let frame = frame_bytes(3840, 2160);
+        assert_eq!(budget.decoder_threads(16, frame), 5);
+        assert_eq!(budget.decoder_threads(2, frame), 2);
+        assert_eq!(budget.jobs(8, frame), 1);
+        assert_eq!(budget.jobs(8, frame_bytes(640, 480)), 8);
+    }
+}
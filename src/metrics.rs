@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::http;
+
+/// Frames written so far
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+/// Size of the packets read from the input so far
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+
+pub fn add_frame() {
+    FRAMES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add_input(bytes: usize) {
+    BYTES_IN.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+struct Snapshot {
+    frames: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    elapsed: Duration,
+}
+
+/// The metrics in the Prometheus text format
+fn render(snapshot: &Snapshot) -> String {
+    let elapsed = snapshot.elapsed.as_secs_f64();
+    let rate = if elapsed > 0.0 {
+        snapshot.frames as f64 / elapsed
+    } else {
+        0.0
+    };
+    let metrics = [
+        (
+            "mp42mcap_frames_total",
+            "counter",
+            "Frames written to the output",
+            snapshot.frames.to_string(),
+        ),
+        (
+            "mp42mcap_input_bytes_total",
+            "counter",
+            "Bytes of packets read from the input",
+            snapshot.bytes_in.to_string(),
+        ),
+        (
+            "mp42mcap_output_bytes",
+            "gauge",
+            "Size of the output file",
+            snapshot.bytes_out.to_string(),
+        ),
+        (
+            "mp42mcap_frames_per_second",
+            "gauge",
+            "Frames written per second since the conversion started",
+            format!("{:.3}", rate),
+        ),
+        (
+            "mp42mcap_elapsed_seconds",
+            "gauge",
+            "Time since the conversion started",
+            format!("{:.3}", elapsed),
+        ),
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+            name, help, name, kind, name, value
+        ));
+    }
+    text
+}
+
+/// Serves the metrics at `/metrics` on `address`, from a thread that runs
+/// until the process exits
+pub fn serve(address: &str, output: PathBuf) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let address = http::serve(
+        address,
+        "metrics",
+        Box::new(move |path| {
+            (path == "/metrics").then(|| {
+                let body = render(&Snapshot {
+                    frames: FRAMES.load(Ordering::Relaxed),
+                    bytes_in: BYTES_IN.load(Ordering::Relaxed),
+                    bytes_out: std::fs::metadata(&output).map_or(0, |m| m.len()),
+                    elapsed: start.elapsed(),
+                });
+                ("text/plain; version=0.0.4", body)
+            })
+        }),
+    )?;
+    info!("Serving metrics on http://{}/metrics", address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let text = render(&Snapshot {
+            frames: 300,
+            bytes_in: 2_000_000,
+            bytes_out: 1_900_000,
+            elapsed: Duration::from_secs(4),
+        });
+        assert!(text.starts_with(
+            "# HELP mp42mcap_frames_total Frames written to the output\n\
+            # TYPE mp42mcap_frames_total counter\n\
+            mp42mcap_frames_total 300\n"
+        ));
+        assert!(text.contains("\nmp42mcap_frames_per_second 75.000\n"));
+        assert!(text.contains("\nmp42mcap_output_bytes 1900000\n"));
+        assert!(text.ends_with("mp42mcap_elapsed_seconds 4.000\n"));
+    }
+}
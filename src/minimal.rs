@@ -0,0 +1,85 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use mcap::WriteOptions;
+
+use crate::crc::{self, DATA_END_SIZE, OP_FOOTER};
+
+const MAGIC: &[u8] = b"\x89MCAP0\r\n";
+
+/// Messages written straight to the data section, without chunks or their
+/// indexes, with --minimal
+pub fn write_options() -> WriteOptions {
+    WriteOptions::new().use_chunks(false)
+}
+
+/// Drops the summary of a finished MCAP, with a footer that says it has
+/// none. Returns the new length of the file, which the caller truncates it
+/// to.
+pub fn strip_summary<F: Read + Write + Seek>(file: &mut F) -> Result<u64, Box<dyn Error>> {
+    let footer_position = crc::data_end_position(file)? + DATA_END_SIZE;
+    let mut footer = vec![OP_FOOTER];
+    footer.extend_from_slice(&20u64.to_le_bytes());
+    // No summary start, summary offset start or summary CRC
+    footer.extend_from_slice(&[0; 20]);
+    footer.extend_from_slice(MAGIC);
+    file.seek(SeekFrom::Start(footer_position))?;
+    file.write_all(&footer)?;
+    file.flush()?;
+    Ok(footer_position + footer.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    use mcap::records::Record;
+
+    use super::*;
+
+    #[test]
+    fn test_strip_summary() {
+        let mut file = Cursor::new(Vec::new());
+        let mut writer = write_options().create(&mut file).unwrap();
+        let channel = writer
+            .add_channel(&mcap::Channel {
+                topic: "/video".to_string(),
+                schema: None,
+                message_encoding: "json".to_string(),
+                metadata: BTreeMap::new(),
+            })
+            .unwrap();
+        writer
+            .write_to_known_channel(
+                &mcap::records::MessageHeader {
+                    channel_id: channel,
+                    sequence: 0,
+                    log_time: 1,
+                    publish_time: 1,
+                },
+                b"{}",
+            )
+            .unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let length = strip_summary(&mut file).unwrap();
+        let mut data = file.into_inner();
+        data.truncate(length as usize);
+        assert!(data.starts_with(MAGIC) && data.ends_with(MAGIC));
+        let records = mcap::read::LinearReader::new(&data)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(!records.iter().any(|record| matches!(
+            record,
+            Record::Chunk { .. } | Record::MessageIndex(_) | Record::Statistics(_)
+        )));
+        assert!(
+            matches!(records.last(), Some(Record::Footer(footer)) if footer.summary_start == 0)
+        );
+        let messages = mcap::MessageStream::new(&data).unwrap().count();
+        assert_eq!(messages, 1);
+    }
+}
@@ -0,0 +1,100 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Splits data into its boxes as (type, payload), stopping at the first
+/// truncated one
+pub fn boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        if size < 8 || size > data.len() - pos {
+            break;
+        }
+        boxes.push((
+            data[pos + 4..pos + 8].try_into().unwrap(),
+            &data[pos + 8..pos + size],
+        ));
+        pos += size;
+    }
+    boxes
+}
+
+/// The payload of the first box of a kind
+pub fn child<'a>(boxes: &[([u8; 4], &'a [u8])], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes
+        .iter()
+        .find(|(box_type, _)| box_type == kind)
+        .map(|(_, payload)| *payload)
+}
+
+/// The version of a full box and its fields
+pub fn full_box(payload: &[u8]) -> Option<(u8, &[u8])> {
+    Some((*payload.first()?, payload.get(4..)?))
+}
+
+pub fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+pub fn be_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+/// Reads the moov box of an MP4 or MOV file, without the rest
+pub fn read_moov(file: &mut File) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let length = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + 8 <= length {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header[..8])?;
+        let (size, header_size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (length - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (u64::from_be_bytes(header[8..].try_into().unwrap()), 16)
+            }
+            size => (size as u64, 8),
+        };
+        if size < header_size || size > length - pos {
+            return Ok(None);
+        }
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0; (size - header_size) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn test_boxes() {
+        let data = [make_box(b"ftyp", b"isom"), make_box(b"free", &[])].concat();
+        let boxes = boxes(&data);
+        assert_eq!(boxes, [(*b"ftyp", &b"isom"[..]), (*b"free", &[][..])]);
+        assert_eq!(child(&boxes, b"free"), Some(&[][..]));
+        assert_eq!(child(&boxes, b"moov"), None);
+
+        // A box claiming more than there is ends the list
+        let mut truncated = make_box(b"moov", &[0; 8]);
+        truncated.truncate(12);
+        assert!(super::boxes(&truncated).is_empty());
+        assert_eq!(full_box(&[1, 0, 0, 0, 7]), Some((1, &[7][..])));
+        assert_eq!(be_u32(&[0, 0, 1], 0), None);
+    }
+}
@@ -0,0 +1,132 @@
+/// Whether the data starts with an Annex B start code
+pub fn has_start_code(data: &[u8]) -> bool {
+    data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1])
+}
+
+/// Splits Annex B data into its NAL units, without the start codes
+pub fn split_annex_b(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push((i, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut nals = Vec::with_capacity(starts.len());
+    for (index, &(_, start)) in starts.iter().enumerate() {
+        let mut end = starts
+            .get(index + 1)
+            .map_or(data.len(), |&(prefix, _)| prefix);
+        // Zero bytes before a start code belong to it, not to the NAL unit
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nals.push(&data[start..end]);
+        }
+    }
+    nals
+}
+
+/// Removes the emulation prevention bytes from a NAL unit payload
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Payload types of the messages in the RBSP of an SEI NAL unit, stopping at
+/// the trailing bits or the first truncated message
+pub fn sei_payload_types(rbsp: &[u8]) -> Vec<u32> {
+    sei_messages(rbsp)
+        .into_iter()
+        .map(|(payload_type, _)| payload_type)
+        .collect()
+}
+
+/// The payload type and payload of the messages in the RBSP of an SEI NAL
+/// unit, stopping like sei_payload_types
+pub fn sei_messages(rbsp: &[u8]) -> Vec<(u32, &[u8])> {
+    // Both the type and the size are coded as a run of 0xFF bytes plus a
+    // final byte, all summed up
+    fn read_value(rbsp: &[u8], pos: &mut usize) -> Option<u32> {
+        let mut value = 0;
+        loop {
+            let byte = *rbsp.get(*pos)?;
+            *pos += 1;
+            value += byte as u32;
+            if byte != 0xFF {
+                return Some(value);
+            }
+        }
+    }
+
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    while pos < rbsp.len() && rbsp[pos] != 0x80 {
+        let Some(payload_type) = read_value(rbsp, &mut pos) else {
+            break;
+        };
+        let Some(size) = read_value(rbsp, &mut pos) else {
+            break;
+        };
+        if pos + size as usize > rbsp.len() {
+            break;
+        }
+        messages.push((payload_type, &rbsp[pos..pos + size as usize]));
+        pos += size as usize;
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_annex_b() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 0, 1, 0x65, 0, 0, 3,
+        ];
+        assert!(has_start_code(&data));
+        assert!(has_start_code(&data[1..]));
+        assert!(!has_start_code(&data[4..]));
+        assert_eq!(
+            split_annex_b(&data),
+            [&[0x67, 0x42][..], &[0x68, 0xCE], &[0x65, 0, 0, 3]]
+        );
+        assert!(split_annex_b(&[0x65, 0x01]).is_empty());
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(
+            unescape(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x03]),
+            [0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03]
+        );
+        assert_eq!(unescape(&[0x01, 0x03, 0x00]), [0x01, 0x03, 0x00]);
+    }
+
+    #[test]
+    fn test_sei_payload_types() {
+        // user_data_unregistered with 2 bytes, then a 300 byte type 137
+        let mut rbsp = vec![5, 2, 0xAA, 0xBB, 137, 0xFF, 45];
+        rbsp.extend_from_slice(&[0; 300]);
+        rbsp.push(0x80);
+        assert_eq!(sei_payload_types(&rbsp), [5, 137]);
+
+        assert_eq!(sei_payload_types(&[0xFF, 0x05, 10, 1]), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,267 @@
+use ffmpeg_next as ffmpeg;
+
+/// Sample entry of an XML metadata track, which ONVIF cameras record their
+/// analytics in
+const METX_TAG: u32 = u32::from_le_bytes(*b"metx");
+
+/// Whether the stream is an XML metadata track
+pub fn is_onvif(parameters: &ffmpeg::codec::Parameters) -> bool {
+    unsafe { (*parameters.as_ptr()).codec_tag == METX_TAG }
+}
+
+/// A bounding box in ONVIF's normalized coordinates, from -1 to 1 with y up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+impl BoundingBox {
+    /// The top-left and bottom-right corners in pixels of an image
+    pub fn to_pixels(self, width: u32, height: u32) -> ((f64, f64), (f64, f64)) {
+        let x = |v: f64| (v + 1.0) / 2.0 * width as f64;
+        let y = |v: f64| (1.0 - v) / 2.0 * height as f64;
+        ((x(self.left), y(self.top)), (x(self.right), y(self.bottom)))
+    }
+}
+
+/// An object of a scene description
+#[derive(Debug, Default, PartialEq)]
+pub struct OnvifObject {
+    pub id: String,
+    pub bounding_box: Option<BoundingBox>,
+    /// The most likely class, e.g. `Human` or `Vehicle`
+    pub class: Option<String>,
+    pub likelihood: Option<f64>,
+}
+
+enum Token<'a> {
+    Open {
+        name: &'a str,
+        attributes: &'a str,
+        self_closing: bool,
+    },
+    Close,
+    Text(&'a str),
+}
+
+/// Splits XML into its tags and text, skipping declarations and comments
+fn tokens(xml: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let text = rest[..start].trim();
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+        rest = &rest[start..];
+        let end = if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 2)
+        } else {
+            rest.find('>')
+        };
+        let Some(end) = end else {
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with(['?', '!']) {
+            continue;
+        }
+        if tag.starts_with('/') {
+            tokens.push(Token::Close);
+            continue;
+        }
+        let self_closing = tag.ends_with('/');
+        let tag = tag.trim_end_matches('/');
+        let (name, attributes) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        // Namespace prefixes vary between cameras, e.g. `tt:` or `onvif:`
+        let name = name.rsplit(':').next().unwrap_or(name);
+        tokens.push(Token::Open {
+            name,
+            attributes,
+            self_closing,
+        });
+    }
+    tokens
+}
+
+/// The value of an attribute of a tag
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = attributes;
+    while let Some((key, value)) = rest.split_once('=') {
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+        let (value, after) = value.strip_prefix(quote)?.split_once(quote)?;
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = after;
+    }
+    None
+}
+
+/// Parses the objects of the frames in an ONVIF metadata stream document
+pub fn parse_objects(xml: &str) -> Vec<OnvifObject> {
+    let mut objects: Vec<OnvifObject> = Vec::new();
+    // Class candidates of the current object, as (class, likelihood)
+    let mut candidates: Vec<(String, Option<f64>)> = Vec::new();
+    let mut stack: Vec<&str> = Vec::new();
+    let mut in_object = false;
+
+    let finish_object = |objects: &mut Vec<OnvifObject>, candidates: &mut Vec<_>| {
+        let best = candidates.drain(..).max_by(|a: &(String, Option<f64>), b| {
+            a.1.unwrap_or(0.0).total_cmp(&b.1.unwrap_or(0.0))
+        });
+        if let (Some(object), Some((class, likelihood))) = (objects.last_mut(), best) {
+            object.class = Some(class);
+            object.likelihood = likelihood;
+        }
+    };
+
+    for token in tokens(xml) {
+        match token {
+            Token::Open {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                match name {
+                    "Object" => {
+                        in_object = true;
+                        objects.push(OnvifObject {
+                            id: attribute(attributes, "ObjectId")
+                                .unwrap_or_default()
+                                .to_string(),
+                            ..Default::default()
+                        });
+                    }
+                    "BoundingBox" if in_object => {
+                        let value = |name| attribute(attributes, name)?.parse().ok();
+                        if let (Some(left), Some(top), Some(right), Some(bottom)) =
+                            (value("left"), value("top"), value("right"), value("bottom"))
+                        {
+                            if let Some(object) = objects.last_mut() {
+                                object.bounding_box = Some(BoundingBox {
+                                    left,
+                                    top,
+                                    right,
+                                    bottom,
+                                });
+                            }
+                        }
+                    }
+                    "Type" if in_object => {
+                        let likelihood = attribute(attributes, "Likelihood")
+                            .and_then(|value| value.parse().ok());
+                        candidates.push((String::new(), likelihood));
+                    }
+                    _ => {}
+                }
+                if !self_closing {
+                    stack.push(name);
+                } else if name == "Object" {
+                    in_object = false;
+                }
+            }
+            Token::Close => {
+                if stack.pop() == Some("Object") {
+                    in_object = false;
+                    finish_object(&mut objects, &mut candidates);
+                }
+            }
+            Token::Text(text) if in_object => match stack.last() {
+                Some(&"Type") => {
+                    if let Some(candidate) = candidates.last_mut() {
+                        candidate.0 = text.to_string();
+                    }
+                }
+                // Newer cameras put the likelihood of a ClassCandidate next
+                // to its type instead of on it
+                Some(&"Likelihood") => {
+                    if let Some(candidate) = candidates.last_mut() {
+                        candidate.1 = text.parse().ok();
+                    }
+                }
+                _ => {}
+            },
+            Token::Text(_) => {}
+        }
+    }
+    objects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_objects() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <tt:MetadataStream xmlns:tt="http://www.onvif.org/ver10/schema">
+              <tt:VideoAnalytics>
+                <tt:Frame UtcTime="2024-01-01T00:00:00.000Z">
+                  <tt:Object ObjectId="12">
+                    <tt:Appearance>
+                      <tt:Shape>
+                        <tt:BoundingBox left="-0.5" top="0.5" right="0.5" bottom="-0.5"/>
+                      </tt:Shape>
+                      <tt:Class>
+                        <tt:Type Likelihood="0.3">Vehicle</tt:Type>
+                        <tt:Type Likelihood="0.9">Human</tt:Type>
+                      </tt:Class>
+                    </tt:Appearance>
+                  </tt:Object>
+                  <!-- <tt:Object ObjectId="99"/> -->
+                  <tt:Object ObjectId='13'>
+                    <tt:Appearance>
+                      <tt:Class>
+                        <tt:ClassCandidate>
+                          <tt:Type>Face</tt:Type>
+                          <tt:Likelihood>0.75</tt:Likelihood>
+                        </tt:ClassCandidate>
+                      </tt:Class>
+                    </tt:Appearance>
+                  </tt:Object>
+                </tt:Frame>
+              </tt:VideoAnalytics>
+            </tt:MetadataStream>"#;
+        let bounding_box = BoundingBox {
+            left: -0.5,
+            top: 0.5,
+            right: 0.5,
+            bottom: -0.5,
+        };
+        assert_eq!(
+            parse_objects(xml),
+            [
+                OnvifObject {
+                    id: "12".to_string(),
+                    bounding_box: Some(bounding_box),
+                    class: Some("Human".to_string()),
+                    likelihood: Some(0.9),
+                },
+                OnvifObject {
+                    id: "13".to_string(),
+                    bounding_box: None,
+                    class: Some("Face".to_string()),
+                    likelihood: Some(0.75),
+                },
+            ]
+        );
+        assert_eq!(
+            bounding_box.to_pixels(640, 480),
+            ((160.0, 120.0), (480.0, 360.0))
+        );
+
+        // A value that isn't quoted, even with a multibyte first character,
+        // ends the attributes rather than panicking
+        assert_eq!(attribute(r#"a="1" b='2'"#, "b"), Some("2"));
+        assert_eq!(attribute("a=é1 b=\"2\"", "b"), None);
+        assert_eq!(attribute("a=\"é\"", "a"), Some("é"));
+        assert_eq!(attribute("a=\"1", "a"), None);
+    }
+}
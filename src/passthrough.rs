@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+use mcap::{records::MessageHeader, Writer};
+use prost::Message;
+
+use crate::codec::{ConverterOptions, VideoConverter};
+use crate::foxglove::CompressedVideo;
+use crate::stream_info;
+use crate::topic::{self, TopicValues};
+use crate::{setup_mcap_channel, to_timestamp};
+
+/// A video stream of another file, or another of the input, passed through
+/// to a topic of its own, like a GoPro preview or a lens of a 360 camera
+pub struct PassthroughStream {
+    pub topic: String,
+    converter: VideoConverter,
+    input: ffmpeg::format::context::Input,
+    stream_index: usize,
+    channel_id: u16,
+    sequence: u32,
+    frame_id: String,
+    /// Timestamp of the first frame, which is moved to the start of the
+    /// main video
+    first_ns: Option<u64>,
+    /// A packet read past the time written up to, for the next call
+    next: Option<ffmpeg::Packet>,
+    /// Whether a failure only ends this stream, with a warning, rather
+    /// than the conversion
+    optional: bool,
+    failed: bool,
+}
+
+impl PassthroughStream {
+    /// Opens the video and adds its channel, so it fails before the
+    /// conversion of the main video rather than after it. The best video
+    /// stream is taken unless `stream_index` is given. The placeholders of
+    /// `topic` are expanded for that stream.
+    pub fn new(
+        writer: &mut Writer<BufWriter<File>>,
+        path: &Path,
+        stream_index: Option<usize>,
+        topic: &str,
+        frame_id: &str,
+        options: &ConverterOptions,
+        optional: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (converter, input) = VideoConverter::new(path, stream_index, options)
+            .map_err(|e| format!("Could not open {:?}: {}", path, e))?;
+        let stream_index = match stream_index {
+            Some(stream_index) => stream_index,
+            None => stream_info::best_video(&input)?.index(),
+        };
+        let stream = input
+            .stream(stream_index)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let topic = topic::expand(topic, &TopicValues::from_stream(path, &stream))?;
+        let channel_id = setup_mcap_channel(
+            writer,
+            &topic,
+            "foxglove.CompressedVideo",
+            converter.channel_metadata().clone(),
+        )?;
+        Ok(Self {
+            topic,
+            converter,
+            input,
+            stream_index,
+            channel_id: channel_id.try_into()?,
+            sequence: 0,
+            frame_id: frame_id.to_string(),
+            first_ns: None,
+            next: None,
+            optional,
+            failed: false,
+        })
+    }
+
+    /// Where a frame at `timestamp_ns` is written
+    fn log_time(&self, start_ns: u64, timestamp_ns: u64) -> u64 {
+        start_ns + timestamp_ns.saturating_sub(self.first_ns.unwrap_or(timestamp_ns))
+    }
+
+    /// Reads the next packet of the stream, `None` at the end of the file
+    fn read_packet(&mut self) -> Result<Option<ffmpeg::Packet>, Box<dyn Error>> {
+        if let Some(packet) = self.next.take() {
+            return Ok(Some(packet));
+        }
+        loop {
+            let mut packet = ffmpeg::Packet::empty();
+            match packet.read(&mut self.input) {
+                Ok(()) if packet.stream() == self.stream_index => {
+                    self.converter.apply_edit_gaps(&mut packet);
+                    return Ok(Some(packet));
+                }
+                Ok(()) => {}
+                Err(ffmpeg::Error::Eof) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Writes the frames up to `until_ns`, moved so the first is at
+    /// `start_ns` like that of the main video, as both are recorded at once.
+    /// Called as the main video is written, so the log times stay in order.
+    pub fn write_until(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        start_ns: u64,
+        until_ns: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.failed {
+            return Ok(());
+        }
+        match self.write_frames(writer, start_ns, until_ns) {
+            Err(e) if self.optional => {
+                warn!(
+                    "Stopped writing {} after {} messages: {}",
+                    self.topic, self.sequence, e
+                );
+                self.failed = true;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn write_frames(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        start_ns: u64,
+        until_ns: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        while let Some(packet) = self.read_packet()? {
+            let pts = packet.pts().or(packet.dts()).unwrap_or(0);
+            if self.log_time(start_ns, self.converter.get_timestamp(pts)) > until_ns {
+                self.next = Some(packet);
+                return Ok(());
+            }
+            let Some(pts) = self.converter.process_packet(&packet)? else {
+                continue;
+            };
+            // Not decoded, so each packet is a frame of its own
+            let (_, data) = self.converter.take_frame_data();
+            if data.is_empty() {
+                continue;
+            }
+            let timestamp_ns = self.converter.get_timestamp(pts);
+            self.first_ns.get_or_insert(timestamp_ns);
+            let log_time = self.log_time(start_ns, timestamp_ns);
+            let message = CompressedVideo {
+                timestamp: Some(to_timestamp(log_time)),
+                frame_id: self.frame_id.clone(),
+                data,
+                format: self.converter.format_str().to_string(),
+            };
+            writer.write_to_known_channel(
+                &MessageHeader {
+                    channel_id: self.channel_id,
+                    sequence: self.sequence,
+                    log_time,
+                    publish_time: log_time,
+                },
+                &message.encode_to_vec(),
+            )?;
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    pub fn messages(&self) -> u32 {
+        self.sequence
+    }
+}
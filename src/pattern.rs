@@ -0,0 +1,94 @@
+/// A synthetic picture for `mp42mcap generate`, drawn by an FFmpeg source
+/// filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    /// SMPTE color bars
+    Smpte,
+    /// SMPTE RP 219 HD color bars
+    SmpteHd,
+    /// Moving gradients with a frame counter, to see dropped frames
+    Testsrc,
+}
+
+impl std::str::FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smpte" => Ok(Pattern::Smpte),
+            "smptehd" => Ok(Pattern::SmpteHd),
+            "testsrc" => Ok(Pattern::Testsrc),
+            other => Err(format!(
+                "Unsupported pattern {:?}, expected smpte, smptehd or testsrc",
+                other
+            )),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pattern::Smpte => "smpte",
+            Pattern::SmpteHd => "smptehd",
+            Pattern::Testsrc => "testsrc",
+        }
+    }
+
+    fn source_filter(&self) -> &'static str {
+        match self {
+            Pattern::Smpte => "smptebars",
+            Pattern::SmpteHd => "smptehdbars",
+            Pattern::Testsrc => "testsrc2",
+        }
+    }
+
+    /// The lavfi input that draws `duration_ns` of the pattern
+    pub fn lavfi_graph(&self, (width, height): (u32, u32), fps: u32, duration_ns: u64) -> String {
+        format!(
+            "{}=size={}x{}:rate={}:duration={:.3}",
+            self.source_filter(),
+            width,
+            height,
+            fps,
+            duration_ns as f64 / 1e9
+        )
+    }
+}
+
+/// WIDTHxHEIGHT, both even for 4:2:0 chroma, with --resolution
+pub fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || {
+        format!(
+            "Invalid resolution {:?}, expected an even WIDTHxHEIGHT like 1280x720",
+            value
+        )
+    };
+    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(width), Ok(height))
+            if width > 0 && height > 0 && width.is_multiple_of(2) && height.is_multiple_of(2) =>
+        {
+            Ok((width, height))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lavfi_graph() {
+        assert_eq!(
+            Pattern::Smpte.lavfi_graph((1280, 720), 30, 60_000_000_000),
+            "smptebars=size=1280x720:rate=30:duration=60.000"
+        );
+        assert_eq!("testsrc".parse(), Ok(Pattern::Testsrc));
+        assert!("bars".parse::<Pattern>().is_err());
+        assert_eq!(parse_resolution("1920x1080"), Ok((1920, 1080)));
+        assert!(parse_resolution("1281x720").is_err());
+        assert!(parse_resolution("720p").is_err());
+    }
+}
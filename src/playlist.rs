@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::timesync;
+
+/// Largest forward jump between video packets that's taken as a gap in the
+/// recording rather than a new segment with timestamps of its own
+const MAX_GAP_NS: i64 = 10_000_000_000;
+
+/// Schemes of the network protocols FFmpeg reads inputs from
+const URL_SCHEMES: &[&str] = &[
+    "http", "https", "rtsp", "rtsps", "rtmp", "rtmps", "srt", "udp", "rtp", "tcp",
+];
+
+/// The scheme of a URL, lowercased
+fn scheme(input: &Path) -> Option<String> {
+    let input = input.to_string_lossy();
+    let (scheme, _) = input.split_once("://")?;
+    Some(scheme.to_ascii_lowercase())
+}
+
+/// Whether the input is a URL for FFmpeg's network protocols
+pub fn is_url(input: &Path) -> bool {
+    scheme(input).is_some_and(|scheme| URL_SCHEMES.contains(&scheme.as_str()))
+}
+
+/// Whether the input is an HTTP(S) URL, which reconnect_options apply to
+pub fn is_http(input: &Path) -> bool {
+    scheme(input).is_some_and(|scheme| scheme == "http" || scheme == "https")
+}
+
+/// Options of FFmpeg's HTTP protocol that reconnect after a network error
+/// or a server error instead of failing, resuming with a range request at
+/// the byte it stopped at. The wait between attempts doubles up to
+/// `retry_max_seconds`, and 0 turns reconnecting off.
+pub fn reconnect_options(retry_max_seconds: u32) -> Vec<(&'static str, String)> {
+    if retry_max_seconds == 0 {
+        return Vec::new();
+    }
+    vec![
+        ("reconnect", "1".to_string()),
+        ("reconnect_streamed", "1".to_string()),
+        ("reconnect_on_network_error", "1".to_string()),
+        ("reconnect_on_http_error", "429,5xx".to_string()),
+        ("reconnect_delay_max", retry_max_seconds.to_string()),
+        // A connection that stalls fails after 30 s, and is retried
+        ("rw_timeout", "30000000".to_string()),
+    ]
+}
+
+/// Whether the input is an HLS or DASH playlist of segments, by its
+/// extension, ignoring the query of a URL
+pub fn is_playlist(input: &Path) -> bool {
+    let input = input.to_string_lossy();
+    let path = input.split(['?', '#']).next().unwrap_or_default();
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    ["m3u8", "mpd"]
+        .iter()
+        .any(|playlist| extension.eq_ignore_ascii_case(playlist))
+}
+
+/// Keeps the timestamps of the segments of a playlist continuous. Segments
+/// after a discontinuity, or cut from other recordings, can restart their
+/// timestamps, so the packets after a jump back in any stream, or too far
+/// forward in the video, are shifted to follow the last packet of that
+/// stream.
+pub struct Stitcher {
+    frame_ns: i64,
+    /// Shifted DTS of the last packet of each stream
+    last_ns: HashMap<usize, i64>,
+    /// What the packets are shifted by since the last discontinuity
+    pub offset_ns: i64,
+    pub discontinuities: usize,
+}
+
+impl Stitcher {
+    pub fn new(frame_interval_ns: Option<u64>) -> Self {
+        Self {
+            frame_ns: frame_interval_ns.map_or(33_333_333, |ns| ns as i64),
+            last_ns: HashMap::new(),
+            offset_ns: 0,
+            discontinuities: 0,
+        }
+    }
+
+    /// Takes the DTS of the next packet of a stream, in nanoseconds, updating
+    /// the offset if it doesn't follow the last one. Whichever stream of a
+    /// segment comes first sets the offset for all of them.
+    pub fn add(&mut self, stream_index: usize, dts_ns: i64, video: bool) {
+        let shifted = dts_ns + self.offset_ns;
+        if let Some(&last_ns) = self.last_ns.get(&stream_index) {
+            // Sparse streams like subtitles can go quiet for longer than a gap
+            let jumped_forward = video && shifted > last_ns + MAX_GAP_NS;
+            if shifted <= last_ns - self.frame_ns || jumped_forward {
+                debug!(
+                    "Timestamps jump by {:.3}s between segments, continuing from the last packet",
+                    (shifted - last_ns) as f64 / 1e9
+                );
+                self.offset_ns = last_ns + self.frame_ns - dts_ns;
+                self.discontinuities += 1;
+            }
+        }
+        self.last_ns.insert(stream_index, dts_ns + self.offset_ns);
+    }
+
+    /// Shifts a packet of any stream by the current offset
+    pub fn shift(&self, packet: &mut ffmpeg::Packet, time_base: ffmpeg::Rational) {
+        if self.offset_ns != 0 {
+            timesync::shift_packet(packet, time_base, self.offset_ns);
+        }
+    }
+}
+
+/// A timestamp in `time_base` in nanoseconds
+pub fn ticks_to_ns(ticks: i64, time_base: ffmpeg::Rational) -> i64 {
+    (ticks as i128 * time_base.numerator() as i128 * 1_000_000_000
+        / time_base.denominator().max(1) as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_playlist() {
+        assert!(is_playlist(Path::new(
+            "https://cdn.example.com/dvr/index.m3u8?token=1"
+        )));
+        assert!(is_playlist(Path::new("export/manifest.MPD")));
+        assert!(!is_playlist(Path::new("video.mp4")));
+        assert!(is_url(Path::new("https://cdn.example.com/index.m3u8")));
+        assert!(is_url(Path::new("RTSP://192.168.1.10:554/stream1")));
+        assert!(is_url(Path::new("srt://relay:9000?mode=caller")));
+        assert!(!is_url(Path::new("index.m3u8")));
+        assert!(!is_url(Path::new("file://video.mp4")));
+        assert!(is_http(Path::new("http://cdn.example.com/index.mpd")));
+        assert!(!is_http(Path::new("rtmp://live.example.com/app/key")));
+    }
+
+    #[test]
+    fn test_reconnect_options() {
+        assert!(reconnect_options(0).is_empty());
+        let options = reconnect_options(60);
+        assert!(options.contains(&("reconnect_on_network_error", "1".to_string())));
+        assert!(options.contains(&("reconnect_delay_max", "60".to_string())));
+    }
+
+    #[test]
+    fn test_stitcher() {
+        let mut stitcher = Stitcher::new(Some(40_000_000));
+        stitcher.add(0, 1_000_000_000, true);
+        stitcher.add(0, 1_040_000_000, true);
+        assert_eq!(stitcher.offset_ns, 0);
+        // The next segment starts over from zero
+        stitcher.add(0, 0, true);
+        assert_eq!(stitcher.offset_ns, 1_080_000_000);
+        stitcher.add(0, 40_000_000, true);
+        assert_eq!(stitcher.offset_ns, 1_080_000_000);
+        // A short gap is kept
+        stitcher.add(0, 2_000_000_000, true);
+        assert_eq!(stitcher.offset_ns, 1_080_000_000);
+        assert_eq!(stitcher.discontinuities, 1);
+
+        // The audio of the next segment comes before its video, and shifts it
+        let mut stitcher = Stitcher::new(Some(40_000_000));
+        stitcher.add(0, 1_000_000_000, true);
+        stitcher.add(1, 1_010_000_000, false);
+        stitcher.add(1, 0, false);
+        assert_eq!(stitcher.offset_ns, 1_050_000_000);
+        stitcher.add(0, 0, true);
+        assert_eq!(stitcher.discontinuities, 1);
+        // A subtitle a minute later is no discontinuity
+        stitcher.add(2, 0, false);
+        stitcher.add(2, 60_000_000_000, false);
+        assert_eq!(stitcher.discontinuities, 1);
+    }
+}
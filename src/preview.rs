@@ -0,0 +1,69 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::CodecType;
+use crate::transcode::{Scale, TranscodeOptions};
+
+/// How the preview stream is encoded, with --preview-topic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewOptions {
+    /// Lines of the preview, if the source has more
+    pub height: u32,
+    pub fps: u32,
+    /// Bits per second
+    pub bitrate: usize,
+}
+
+impl PreviewOptions {
+    /// The size of the preview with the aspect ratio of the source, never
+    /// larger than it
+    fn size(&self, width: u32, height: u32) -> (u32, u32) {
+        if height <= self.height || height == 0 {
+            return (width & !1, height & !1);
+        }
+        let scaled = (width as u64 * self.height as u64 / height as u64) as u32;
+        ((scaled & !1).max(2), self.height & !1)
+    }
+
+    /// The encoder settings for a video of `width` by `height`
+    pub fn transcode_options(&self, width: u32, height: u32) -> TranscodeOptions {
+        let (width, height) = self.size(width, height);
+        TranscodeOptions {
+            codec: Some(CodecType::H264),
+            // A keyframe every second to scrub to
+            keyframe_interval: Some(1.0),
+            bitrate: Some(self.bitrate),
+            preset: Some("veryfast".to_string()),
+            scale: Some(Scale::Size(width, height)),
+            force_8bit: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn filters(&self) -> Vec<String> {
+        vec![format!("fps={}", self.fps)]
+    }
+
+    pub fn frame_rate(&self) -> ffmpeg::Rational {
+        ffmpeg::Rational::new(self.fps as i32, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview_size() {
+        let options = PreviewOptions {
+            height: 480,
+            fps: 2,
+            bitrate: 250_000,
+        };
+        assert_eq!(options.size(1920, 1080), (852, 480));
+        assert_eq!(options.size(3840, 2160), (852, 480));
+        // Portrait video keeps its shape
+        assert_eq!(options.size(1080, 1920), (270, 480));
+        // Never upscaled
+        assert_eq!(options.size(640, 360), (640, 360));
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::logging::{self, Level};
+use crate::status;
+
+/// Time between updates of the frame count and output size on the bar
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+/// Time between JSON progress lines
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How progress is reported, with --progress
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProgressMode {
+    /// A bar on a terminal, dots otherwise
+    #[default]
+    Auto,
+    /// A JSON object per line on stderr
+    Json,
+}
+
+impl std::str::FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ProgressMode::Auto),
+            "json" => Ok(ProgressMode::Json),
+            other => Err(format!(
+                "Unsupported progress mode {:?}, expected auto or json",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Bar,
+    Dots,
+    Json,
+    /// Nothing, with -q
+    Off,
+}
+
+/// Time left for the rest of the video at the pace so far, once any of
+/// it is converted
+pub fn eta(fraction: f64, elapsed: Duration) -> Option<Duration> {
+    (fraction > 0.0).then(|| elapsed.mul_f64((1.0 - fraction) / fraction))
+}
+
+/// The bar's frame count and output size
+fn format_counts(frames: u64, bytes: u64) -> String {
+    format!("{} frames, {:.1} MB", frames, bytes as f64 / 1e6)
+}
+
+/// The bar in use, which log lines are printed around
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Runs `f`, which prints a log line, with the bar cleared from the terminal
+/// and redrawn after it
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+    let bar = BAR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+/// A bar on stdout, measured in nanoseconds of video when the length of the
+/// video is known, or a spinner otherwise
+fn make_bar(duration_ns: Option<u64>) -> ProgressBar {
+    let target = ProgressDrawTarget::stdout();
+    let (bar, template) = match duration_ns.filter(|&d| d > 0) {
+        Some(duration_ns) => (
+            ProgressBar::with_draw_target(Some(duration_ns), target),
+            "[{bar:30}] {percent:>3}% {elapsed_precise} elapsed, ETA {eta_precise}, {msg}",
+        ),
+        None => (
+            ProgressBar::with_draw_target(None, target),
+            "{spinner} {elapsed_precise} elapsed, {msg}",
+        ),
+    };
+    let style = ProgressStyle::with_template(template)
+        .expect("progress template is valid")
+        .progress_chars("=> ");
+    bar.set_style(style);
+    bar
+}
+
+/// A progress line for dashboards
+fn format_json(
+    timestamp_ns: u64,
+    frames: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    fraction: Option<f64>,
+    elapsed: Duration,
+) -> String {
+    let fraction = fraction.map(|fraction| fraction.clamp(0.0, 1.0));
+    format!(
+        "{{\"timestamp\":{},\"frames\":{},\"bytes_in\":{},\"bytes_out\":{},\"percent\":{},\
+        \"elapsed_seconds\":{:.1},\"eta_seconds\":{}}}",
+        timestamp_ns,
+        frames,
+        bytes_in,
+        bytes_out,
+        fraction.map_or("null".to_string(), |fraction| format!(
+            "{:.2}",
+            fraction * 100.0
+        )),
+        elapsed.as_secs_f64(),
+        fraction.and_then(|fraction| eta(fraction, elapsed)).map_or(
+            "null".to_string(),
+            |eta| format!("{:.1}", eta.as_secs_f64())
+        )
+    )
+}
+
+/// A progress bar on a terminal, a dot per second of video when stdout is
+/// redirected, or JSON lines on stderr
+pub struct Progress {
+    start: Instant,
+    style: Style,
+    /// The bar, with Style::Bar
+    bar: Option<ProgressBar>,
+    /// Whether a dot was printed, and so the line needs ending
+    dotted: bool,
+    /// Length of the video, from the stream or the container
+    duration_ns: Option<u64>,
+    /// How far into the video the input has been read, which the fraction
+    /// done is taken from when known, as the log times may not follow it
+    position_ns: Option<u64>,
+    first_timestamp_ns: Option<u64>,
+    last_timestamp_ns: u64,
+    last_redraw: Option<Instant>,
+    /// Size of the packets read from the input so far
+    bytes_in: u64,
+    /// Output file, whose size is shown
+    output: PathBuf,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode, duration_ns: Option<u64>, output: PathBuf) -> Self {
+        let style = match mode {
+            ProgressMode::Json => Style::Json,
+            ProgressMode::Auto if !logging::enabled(Level::Info) => Style::Off,
+            ProgressMode::Auto if std::io::stdout().is_terminal() => Style::Bar,
+            ProgressMode::Auto => Style::Dots,
+        };
+        let bar = (style == Style::Bar).then(|| make_bar(duration_ns));
+        *BAR.lock().unwrap_or_else(|e| e.into_inner()) = bar.clone();
+        Self {
+            start: Instant::now(),
+            style,
+            bar,
+            dotted: false,
+            duration_ns,
+            position_ns: None,
+            first_timestamp_ns: None,
+            last_timestamp_ns: 0,
+            last_redraw: None,
+            bytes_in: 0,
+            output,
+        }
+    }
+
+    /// Counts a packet read from the input
+    pub fn add_input(&mut self, bytes: usize) {
+        self.bytes_in += bytes as u64;
+    }
+
+    /// Counts a video packet read from the input, `position_ns` from the
+    /// start of the video
+    pub fn add_video(&mut self, position_ns: u64) {
+        self.position_ns = Some(self.position_ns.unwrap_or(0).max(position_ns));
+    }
+
+    fn fraction(&self, timestamp_ns: u64) -> Option<f64> {
+        let first_timestamp_ns = self.first_timestamp_ns.unwrap_or(timestamp_ns);
+        let position_ns = self
+            .position_ns
+            .unwrap_or(timestamp_ns.saturating_sub(first_timestamp_ns));
+        self.duration_ns
+            .filter(|&d| d > 0)
+            .map(|duration_ns| position_ns as f64 / duration_ns as f64)
+    }
+
+    /// Reports a frame written at `timestamp_ns`. `dot` is whether a second
+    /// of video has passed since the last dot, for plain output.
+    pub fn update(&mut self, timestamp_ns: u64, frames: u64, dot: bool) -> std::io::Result<()> {
+        self.first_timestamp_ns.get_or_insert(timestamp_ns);
+        self.last_timestamp_ns = timestamp_ns;
+        let fraction = self.fraction(timestamp_ns);
+        self.publish(fraction, frames, false);
+        let interval = match self.style {
+            Style::Dots => {
+                if dot {
+                    self.dotted = true;
+                    print!(".");
+                    std::io::stdout().flush()?;
+                }
+                return Ok(());
+            }
+            Style::Bar => REDRAW_INTERVAL,
+            Style::Json => JSON_INTERVAL,
+            Style::Off => return Ok(()),
+        };
+        if let (Some(bar), Some(fraction)) = (&self.bar, fraction) {
+            bar.set_position((fraction.clamp(0.0, 1.0) * bar.length().unwrap_or(0) as f64) as u64);
+        }
+        if self
+            .last_redraw
+            .is_some_and(|last| last.elapsed() < interval)
+        {
+            return Ok(());
+        }
+        self.last_redraw = Some(Instant::now());
+        self.draw(fraction, frames)
+    }
+
+    /// Reports the conversion as complete once all frames are written, and
+    /// ends the line of the bar or the dots
+    pub fn finish(&mut self, frames: u64) -> std::io::Result<()> {
+        self.publish(self.duration_ns.map(|_| 1.0), frames, true);
+        match self.style {
+            Style::Dots if self.dotted => {
+                self.dotted = false;
+                println!();
+                Ok(())
+            }
+            Style::Dots | Style::Off => Ok(()),
+            Style::Bar | Style::Json => self.draw(self.duration_ns.map(|_| 1.0), frames),
+        }?;
+        if let Some(bar) = self.bar.take() {
+            if let Some(length) = bar.length() {
+                bar.set_position(length);
+            }
+            bar.finish();
+            BAR.lock().unwrap_or_else(|e| e.into_inner()).take();
+        }
+        Ok(())
+    }
+
+    /// Hands the progress to the status endpoint
+    fn publish(&self, fraction: Option<f64>, frames: u64, done: bool) {
+        status::publish(status::Snapshot {
+            timestamp_ns: self.last_timestamp_ns,
+            frames,
+            bytes_in: self.bytes_in,
+            fraction,
+            elapsed: self.start.elapsed(),
+            done,
+        });
+    }
+
+    fn draw(&self, fraction: Option<f64>, frames: u64) -> std::io::Result<()> {
+        let bytes_out = std::fs::metadata(&self.output).map_or(0, |m| m.len());
+        if let Some(bar) = &self.bar {
+            bar.set_message(format_counts(frames, bytes_out));
+            return Ok(());
+        }
+        let line = format_json(
+            self.last_timestamp_ns,
+            frames,
+            self.bytes_in,
+            bytes_out,
+            fraction,
+            self.start.elapsed(),
+        );
+        writeln!(std::io::stderr(), "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        assert_eq!(format_counts(120, 2_500_000), "120 frames, 2.5 MB");
+        assert_eq!(
+            eta(0.25, Duration::from_secs(30)),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(eta(0.0, Duration::ZERO), None);
+
+        assert_eq!(
+            format_json(
+                1_500_000_000,
+                45,
+                1_000_000,
+                900_000,
+                Some(0.125),
+                Duration::from_secs(10)
+            ),
+            "{\"timestamp\":1500000000,\"frames\":45,\"bytes_in\":1000000,\
+            \"bytes_out\":900000,\"percent\":12.50,\"elapsed_seconds\":10.0,\"eta_seconds\":70.0}"
+        );
+        assert!(format_json(0, 0, 0, 0, None, Duration::ZERO)
+            .ends_with("\"percent\":null,\"elapsed_seconds\":0.0,\"eta_seconds\":null}"));
+    }
+
+    #[test]
+    fn test_fraction() {
+        let mut progress = Progress::new(ProgressMode::Json, Some(10_000_000_000), PathBuf::new());
+        progress.first_timestamp_ns = Some(1_000_000_000);
+        assert_eq!(progress.fraction(3_500_000_000), Some(0.25));
+        // The input read so far, once known, whatever the log times
+        progress.add_video(5_000_000_000);
+        progress.add_video(4_000_000_000);
+        assert_eq!(progress.fraction(1_000_000_000), Some(0.5));
+        assert_eq!(
+            Progress::new(ProgressMode::Json, None, PathBuf::new()).fraction(0),
+            None
+        );
+    }
+}
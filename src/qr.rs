@@ -0,0 +1,796 @@
+/// Dark modules of a binarized image
+struct BitImage {
+    bits: Vec<bool>,
+    width: usize,
+    height: usize,
+}
+
+impl BitImage {
+    fn get(&self, x: i64, y: i64) -> Option<bool> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        Some(self.bits[y as usize * self.width + x as usize])
+    }
+
+    /// Whether the pixel under a point is dark, false outside the image
+    fn sample(&self, x: f64, y: f64) -> bool {
+        self.get(x.floor() as i64, y.floor() as i64)
+            .unwrap_or(false)
+    }
+}
+
+const BLOCK: usize = 8;
+/// Blocks with less contrast than this are taken to be a single color
+const MIN_CONTRAST: u32 = 24;
+
+/// Thresholds a plane of 8-bit samples by the local average of each 8x8
+/// block, so a code on a bright screen in a dark room still stands out
+fn binarize(plane: &[u8], stride: usize, width: usize, height: usize) -> Option<BitImage> {
+    if width < BLOCK || height < BLOCK || plane.len() < stride * (height - 1) + width {
+        return None;
+    }
+    let blocks_x = width.div_ceil(BLOCK);
+    let blocks_y = height.div_ceil(BLOCK);
+    let rows = |by: usize| by * BLOCK..((by + 1) * BLOCK).min(height);
+    let columns = |bx: usize| bx * BLOCK..((bx + 1) * BLOCK).min(width);
+
+    let mut black_points = vec![0u32; blocks_x * blocks_y];
+    for by in 0..blocks_y {
+        for bx in 0..blocks_x {
+            let (mut min, mut max, mut sum, mut count) = (u32::MAX, 0, 0, 0);
+            for y in rows(by) {
+                for &sample in &plane[y * stride..][columns(bx)] {
+                    let sample = sample as u32;
+                    min = min.min(sample);
+                    max = max.max(sample);
+                    sum += sample;
+                    count += 1;
+                }
+            }
+            let mut average = sum / count;
+            if max - min <= MIN_CONTRAST {
+                // A flat block is light unless its neighbours put it inside a
+                // dark area, like the center of a finder pattern
+                average = min / 2;
+                if bx > 0 && by > 0 {
+                    let neighbours = (black_points[(by - 1) * blocks_x + bx]
+                        + 2 * black_points[by * blocks_x + bx - 1]
+                        + black_points[(by - 1) * blocks_x + bx - 1])
+                        / 4;
+                    if min < neighbours {
+                        average = neighbours;
+                    }
+                }
+            }
+            black_points[by * blocks_x + bx] = average;
+        }
+    }
+
+    let mut bits = vec![false; width * height];
+    for by in 0..blocks_y {
+        for bx in 0..blocks_x {
+            let (mut sum, mut count) = (0, 0);
+            for ny in by.saturating_sub(2)..(by + 3).min(blocks_y) {
+                for nx in bx.saturating_sub(2)..(bx + 3).min(blocks_x) {
+                    sum += black_points[ny * blocks_x + nx];
+                    count += 1;
+                }
+            }
+            let threshold = sum / count;
+            for y in rows(by) {
+                for x in columns(bx) {
+                    bits[y * width + x] = plane[y * stride + x] as u32 <= threshold;
+                }
+            }
+        }
+    }
+    Some(BitImage {
+        bits,
+        width,
+        height,
+    })
+}
+
+/// Whether runs of pixels have the 1:1:3:1:1 proportions of a finder pattern
+fn is_finder_ratio(counts: &[usize; 5]) -> bool {
+    let total = counts.iter().sum::<usize>() as f64;
+    if total < 7.0 {
+        return false;
+    }
+    let module = total / 7.0;
+    let tolerance = module / 2.0;
+    counts
+        .iter()
+        .zip([1.0, 1.0, 3.0, 1.0, 1.0])
+        .all(|(&count, modules)| (count as f64 - modules * module).abs() < modules * tolerance)
+}
+
+/// Measures the finder pattern through a dark pixel along a row or column,
+/// returning the center along that line and the width of the pattern
+fn cross_check(
+    image: &BitImage,
+    x: i64,
+    y: i64,
+    vertical: bool,
+    max_run: usize,
+) -> Option<(f64, usize)> {
+    let at = |i: i64| {
+        if vertical {
+            image.get(x, i)
+        } else {
+            image.get(i, y)
+        }
+    };
+    let position = if vertical { y } else { x };
+    let mut counts = [0usize; 5];
+
+    let mut i = position;
+    while at(i) == Some(true) {
+        counts[2] += 1;
+        i -= 1;
+    }
+    let start = i + 1;
+    for (run, dark) in [(1, false), (0, true)] {
+        while at(i) == Some(dark) && counts[run] <= max_run {
+            counts[run] += 1;
+            i -= 1;
+        }
+    }
+    let mut i = position + 1;
+    while at(i) == Some(true) {
+        counts[2] += 1;
+        i += 1;
+    }
+    let end = i;
+    for (run, dark) in [(3, false), (4, true)] {
+        while at(i) == Some(dark) && counts[run] <= max_run {
+            counts[run] += 1;
+            i += 1;
+        }
+    }
+
+    is_finder_ratio(&counts).then(|| ((start + end) as f64 / 2.0, counts.iter().sum()))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Finder {
+    x: f64,
+    y: f64,
+    module: f64,
+    /// Number of rows the pattern was found on
+    count: usize,
+}
+
+/// Finds the centers of the finder patterns by scanning every row for runs
+/// in their proportions and confirming them along the column
+fn find_finders(image: &BitImage) -> Vec<Finder> {
+    let mut finders: Vec<Finder> = Vec::new();
+    let mut runs: Vec<(bool, usize, usize)> = Vec::new();
+    for y in 0..image.height {
+        // Runs of (dark, start, length) along the row
+        runs.clear();
+        for x in 0..image.width {
+            let dark = image.bits[y * image.width + x];
+            match runs.last_mut() {
+                Some((run_dark, _, length)) if *run_dark == dark => *length += 1,
+                _ => runs.push((dark, x, 1)),
+            }
+        }
+
+        for window in runs.windows(5).filter(|window| window[0].0) {
+            let counts = [0, 1, 2, 3, 4].map(|i| window[i].2);
+            if !is_finder_ratio(&counts) {
+                continue;
+            }
+            let total: usize = counts.iter().sum();
+            let center_x = window[2].1 as f64 + window[2].2 as f64 / 2.0;
+            let Some((center_y, height)) =
+                cross_check(image, center_x as i64, y as i64, true, total)
+            else {
+                continue;
+            };
+            let Some((center_x, width)) =
+                cross_check(image, center_x as i64, center_y as i64, false, total)
+            else {
+                continue;
+            };
+            let module = (width + height) as f64 / 14.0;
+            match finders.iter_mut().find(|finder| {
+                (finder.x - center_x).abs() <= module
+                    && (finder.y - center_y).abs() <= module
+                    && (finder.module - module).abs() <= module / 2.0
+            }) {
+                Some(finder) => {
+                    let weight = finder.count as f64;
+                    finder.x = (finder.x * weight + center_x) / (weight + 1.0);
+                    finder.y = (finder.y * weight + center_y) / (weight + 1.0);
+                    finder.module = (finder.module * weight + module) / (weight + 1.0);
+                    finder.count += 1;
+                }
+                None => finders.push(Finder {
+                    x: center_x,
+                    y: center_y,
+                    module,
+                    count: 1,
+                }),
+            }
+        }
+    }
+    finders
+}
+
+/// The three finder patterns that best form the corners of a square, as the
+/// top left, top right and bottom left ones
+fn select_finders(finders: &[Finder]) -> Option<[Finder; 3]> {
+    let mut candidates: Vec<Finder> = finders.iter().filter(|f| f.count >= 2).copied().collect();
+    candidates.sort_by_key(|finder| std::cmp::Reverse(finder.count));
+    candidates.truncate(10);
+    let distance = |a: &Finder, b: &Finder| (a.x - b.x).hypot(a.y - b.y);
+
+    let mut best: Option<([Finder; 3], f64)> = None;
+    for i in 0..candidates.len() {
+        for j in i + 1..candidates.len() {
+            for k in j + 1..candidates.len() {
+                let [a, b, c] = [candidates[i], candidates[j], candidates[k]];
+                let (min_module, max_module) = [a.module, b.module, c.module]
+                    .iter()
+                    .fold((f64::MAX, 0f64), |(min, max), &m| (min.min(m), max.max(m)));
+                if max_module > min_module * 1.4 {
+                    continue;
+                }
+                // The corner is the one opposite the longest side
+                let (corner, p, q) = [(a, b, c), (b, a, c), (c, a, b)]
+                    .into_iter()
+                    .max_by(|x, y| distance(&x.1, &x.2).total_cmp(&distance(&y.1, &y.2)))?;
+                let (leg_p, leg_q) = (distance(&corner, &p), distance(&corner, &q));
+                let hypotenuse = distance(&p, &q);
+                if leg_p.min(leg_q) < 10.0 * max_module {
+                    continue;
+                }
+                let skew = (leg_p - leg_q).abs() / leg_p.max(leg_q);
+                let squareness =
+                    (hypotenuse.powi(2) - leg_p.powi(2) - leg_q.powi(2)).abs() / hypotenuse.powi(2);
+                if skew > 0.2 || squareness > 0.2 {
+                    continue;
+                }
+                // Clockwise from the corner, as the y axis points down
+                let cross =
+                    (p.x - corner.x) * (q.y - corner.y) - (p.y - corner.y) * (q.x - corner.x);
+                let ordered = if cross > 0.0 {
+                    [corner, p, q]
+                } else {
+                    [corner, q, p]
+                };
+                let score = skew + squareness;
+                if best.as_ref().is_none_or(|(_, best)| score < *best) {
+                    best = Some((ordered, score));
+                }
+            }
+        }
+    }
+    best.map(|(finders, _)| finders)
+}
+
+/// Modules of a symbol, true for dark
+struct Matrix {
+    bits: Vec<bool>,
+    size: usize,
+}
+
+impl Matrix {
+    fn new(size: usize) -> Self {
+        Self {
+            bits: vec![false; size * size],
+            size,
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.bits[y * self.size + x]
+    }
+
+    fn set_region(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        for y in y..y + height {
+            for x in x..x + width {
+                self.bits[y * self.size + x] = true;
+            }
+        }
+    }
+}
+
+/// Reads the modules of a symbol of the given size, mapping module
+/// coordinates to the image by the finder pattern centers
+fn sample(
+    image: &BitImage,
+    [top_left, top_right, bottom_left]: &[Finder; 3],
+    size: usize,
+) -> Matrix {
+    let span = (size - 7) as f64;
+    let u = (
+        (top_right.x - top_left.x) / span,
+        (top_right.y - top_left.y) / span,
+    );
+    let v = (
+        (bottom_left.x - top_left.x) / span,
+        (bottom_left.y - top_left.y) / span,
+    );
+    let mut matrix = Matrix::new(size);
+    for y in 0..size {
+        for x in 0..size {
+            // Finder centers are 3.5 modules in from the edges
+            let (mx, my) = (x as f64 - 3.0, y as f64 - 3.0);
+            matrix.bits[y * size + x] = image.sample(
+                top_left.x + mx * u.0 + my * v.0,
+                top_left.y + mx * u.1 + my * v.1,
+            );
+        }
+    }
+    matrix
+}
+
+/// The 15-bit BCH code of 5 bits of format information, masked as in the
+/// symbol
+fn format_code(data: u32) -> u32 {
+    let mut remainder = data << 10;
+    for bit in (10..15).rev() {
+        if remainder & (1 << bit) != 0 {
+            remainder ^= 0x537 << (bit - 10);
+        }
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+/// The error correction level index (L, M, Q, H) and data mask of a symbol,
+/// from whichever copy of the format information is closest to a valid code
+fn read_format(matrix: &Matrix) -> Option<(usize, u32)> {
+    let size = matrix.size;
+    let read = |positions: &mut dyn Iterator<Item = (usize, usize)>| {
+        positions.fold(0u32, |bits, (x, y)| (bits << 1) | matrix.get(x, y) as u32)
+    };
+    let first = read(
+        &mut (0..6)
+            .map(|x| (x, 8))
+            .chain([(7, 8), (8, 8), (8, 7)])
+            .chain((0..6).rev().map(|y| (8, y))),
+    );
+    let second = read(
+        &mut (size - 7..size)
+            .rev()
+            .map(|y| (8, y))
+            .chain((size - 8..size).map(|x| (x, 8))),
+    );
+
+    let (distance, data) = (0..32)
+        .map(|data| {
+            let code = format_code(data);
+            let distance = (code ^ first)
+                .count_ones()
+                .min((code ^ second).count_ones());
+            (distance, data)
+        })
+        .min()?;
+    if distance > 3 {
+        return None;
+    }
+    // The level bits are M, L, H, Q in order
+    let level = [1, 0, 3, 2][(data >> 3) as usize];
+    Some((level, data & 7))
+}
+
+fn is_masked(mask: u32, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (y / 2 + x / 3).is_multiple_of(2),
+        5 => (x * y) % 2 + (x * y) % 3 == 0,
+        6 => ((x * y) % 2 + (x * y) % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + (x * y) % 3).is_multiple_of(2),
+    }
+}
+
+/// Highest supported version, which holds 34 bytes even at level H, plenty
+/// for a timestamp. Up to it, the blocks of a symbol are all the same length
+/// and there is no version information.
+const MAX_VERSION: usize = 4;
+
+/// Centers of the alignment patterns along each axis for versions 1 to 4
+const ALIGNMENT: [&[usize]; MAX_VERSION] = [&[], &[6, 18], &[6, 22], &[6, 26]];
+
+/// Error correction codewords per block, the number of blocks and the data
+/// codewords per block, for levels L, M, Q and H of versions 1 to 4
+const BLOCKS: [[(usize, usize, usize); 4]; MAX_VERSION] = [
+    [(7, 1, 19), (10, 1, 16), (13, 1, 13), (17, 1, 9)],
+    [(10, 1, 34), (16, 1, 28), (22, 1, 22), (28, 1, 16)],
+    [(15, 1, 55), (26, 1, 44), (18, 2, 17), (22, 2, 13)],
+    [(20, 1, 80), (18, 2, 32), (26, 2, 24), (16, 4, 9)],
+];
+
+/// Modules that don't hold data: finder, timing and alignment patterns and
+/// format information
+fn function_patterns(version: usize) -> Matrix {
+    let size = 17 + 4 * version;
+    let mut matrix = Matrix::new(size);
+    matrix.set_region(0, 0, 9, 9);
+    matrix.set_region(size - 8, 0, 8, 9);
+    matrix.set_region(0, size - 8, 9, 8);
+    // The one alignment pattern of versions 2 to 4 is at the bottom right,
+    // where it doesn't overlap a finder pattern
+    if let Some(&center) = ALIGNMENT[version - 1].last() {
+        matrix.set_region(center - 2, center - 2, 5, 5);
+    }
+    matrix.set_region(6, 9, 1, size - 17);
+    matrix.set_region(9, 6, size - 17, 1);
+    matrix
+}
+
+/// Reads the codewords in the zigzag order of pairs of columns from the
+/// bottom right corner, undoing the data mask
+fn read_codewords(matrix: &Matrix, version: usize, mask: u32) -> Vec<u8> {
+    let size = matrix.size;
+    let function = function_patterns(version);
+    let mut codewords = Vec::new();
+    let (mut byte, mut bits) = (0u8, 0);
+    let mut upward = true;
+    let mut column = size - 1;
+    while column > 0 {
+        // Skip the vertical timing pattern
+        if column == 6 {
+            column -= 1;
+        }
+        for i in 0..size {
+            let y = if upward { size - 1 - i } else { i };
+            for x in [column, column - 1] {
+                if function.get(x, y) {
+                    continue;
+                }
+                byte = (byte << 1) | (matrix.get(x, y) ^ is_masked(mask, x, y)) as u8;
+                bits += 1;
+                if bits == 8 {
+                    codewords.push(byte);
+                    (byte, bits) = (0, 0);
+                }
+            }
+        }
+        upward = !upward;
+        column = column.saturating_sub(2);
+    }
+    codewords
+}
+
+/// Log and antilog tables of GF(256) with the QR code polynomial
+struct Galois {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+impl Galois {
+    fn new() -> Self {
+        let mut field = Self {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+        let mut x = 1u16;
+        for i in 0..255 {
+            field.exp[i] = x as u8;
+            field.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11D;
+            }
+        }
+        for i in 255..512 {
+            field.exp[i] = field.exp[i - 255];
+        }
+        field
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+    }
+
+    fn div(&self, a: u8, b: u8) -> u8 {
+        if a == 0 {
+            return 0;
+        }
+        self.exp[self.log[a as usize] as usize + 255 - self.log[b as usize] as usize]
+    }
+
+    /// Evaluates a polynomial with its coefficients from the lowest degree
+    fn eval(&self, poly: &[u8], x: u8) -> u8 {
+        poly.iter().rev().fold(0, |acc, &c| self.mul(acc, x) ^ c)
+    }
+}
+
+/// Corrects a block of data and error correction codewords in place,
+/// returning false if it has more errors than can be corrected
+fn correct(field: &Galois, block: &mut [u8], ec_len: usize) -> bool {
+    // The codewords are the coefficients from the highest degree
+    let syndromes: Vec<u8> = (0..ec_len)
+        .map(|i| {
+            block
+                .iter()
+                .fold(0, |acc, &c| field.mul(acc, field.exp[i]) ^ c)
+        })
+        .collect();
+    if syndromes.iter().all(|&s| s == 0) {
+        return true;
+    }
+
+    // Berlekamp-Massey for the error locator polynomial
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let (mut errors, mut shift, mut last_discrepancy) = (0, 1, 1u8);
+    for n in 0..ec_len {
+        let mut discrepancy = syndromes[n];
+        for i in 1..=errors.min(locator.len() - 1) {
+            discrepancy ^= field.mul(locator[i], syndromes[n - i]);
+        }
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let scale = field.div(discrepancy, last_discrepancy);
+        let before = locator.clone();
+        locator.resize(locator.len().max(previous.len() + shift), 0);
+        for (i, &c) in previous.iter().enumerate() {
+            locator[i + shift] ^= field.mul(scale, c);
+        }
+        if 2 * errors <= n {
+            errors = n + 1 - errors;
+            previous = before;
+            last_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+    if 2 * errors > ec_len {
+        return false;
+    }
+
+    // The error evaluator, from the syndromes times the locator
+    let mut evaluator = vec![0u8; ec_len];
+    for (i, &s) in syndromes.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate().take(ec_len - i) {
+            evaluator[i + j] ^= field.mul(s, l);
+        }
+    }
+    // Formal derivative, where only the odd powers survive
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &c)| if i % 2 == 1 { c } else { 0 })
+        .collect();
+
+    let n = block.len();
+    let mut found = 0;
+    for (k, codeword) in block.iter_mut().enumerate() {
+        let power = (n - 1 - k) % 255;
+        let location = field.exp[power];
+        let inverse = field.exp[(255 - power) % 255];
+        if field.eval(&locator, inverse) != 0 {
+            continue;
+        }
+        let denominator = field.eval(&derivative, inverse);
+        if denominator == 0 {
+            return false;
+        }
+        let magnitude = field.div(field.eval(&evaluator, inverse), denominator);
+        *codeword ^= field.mul(location, magnitude);
+        found += 1;
+    }
+    found == errors
+}
+
+/// De-interleaves the codewords into their blocks, corrects them and returns
+/// the data codewords
+fn correct_blocks(codewords: &[u8], version: usize, level: usize) -> Option<Vec<u8>> {
+    let (ec_len, count, len) = BLOCKS[version - 1][level];
+    let codewords = codewords.get(..count * (len + ec_len))?;
+    let mut blocks = vec![Vec::with_capacity(len + ec_len); count];
+    for (i, &codeword) in codewords.iter().enumerate() {
+        blocks[i % count].push(codeword);
+    }
+
+    let field = Galois::new();
+    let mut data = Vec::new();
+    for block in &mut blocks {
+        if !correct(&field, block, ec_len) {
+            return None;
+        }
+        data.extend_from_slice(&block[..len]);
+    }
+    Some(data)
+}
+
+struct BitStream<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitStream<'_> {
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        if self.position + bits > self.data.len() * 8 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+}
+
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+/// Decodes the numeric, alphanumeric and byte segments of the data,
+/// with the lengths of their character counts in versions 1 to 9
+fn decode_segments(data: &[u8]) -> Option<String> {
+    let mut stream = BitStream { data, position: 0 };
+    let mut bytes = Vec::new();
+    while stream.remaining() >= 4 {
+        match stream.read(4)? {
+            0 => break,
+            1 => {
+                let mut count = stream.read(10)?;
+                while count > 0 {
+                    let digits = count.min(3);
+                    let value = stream.read([0, 4, 7, 10][digits as usize])?;
+                    let text = format!("{:0width$}", value, width = digits as usize);
+                    bytes.extend_from_slice(text.as_bytes());
+                    count -= digits;
+                }
+            }
+            2 => {
+                let mut count = stream.read(9)?;
+                while count > 0 {
+                    if count >= 2 {
+                        let value = stream.read(11)? as usize;
+                        bytes.push(*ALPHANUMERIC.get(value / 45)?);
+                        bytes.push(ALPHANUMERIC[value % 45]);
+                        count -= 2;
+                    } else {
+                        bytes.push(*ALPHANUMERIC.get(stream.read(6)? as usize)?);
+                        count -= 1;
+                    }
+                }
+            }
+            4 => {
+                let count = stream.read(8)?;
+                for _ in 0..count {
+                    bytes.push(stream.read(8)? as u8);
+                }
+            }
+            // The character set designator is ignored, as the payload is
+            // expected to be ASCII
+            7 => {
+                if stream.read(1)? == 1 {
+                    let bits = if stream.read(1)? == 1 { 14 } else { 7 };
+                    stream.read(bits)?;
+                } else {
+                    stream.read(7)?;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Decodes the text of a QR code in a plane of 8-bit luma samples. The
+/// symbol is sampled by its finder patterns alone, so it has to face the
+/// camera without much perspective distortion, and be version 4 or lower.
+pub fn decode(plane: &[u8], stride: usize, width: usize, height: usize) -> Option<String> {
+    let image = binarize(plane, stride, width, height)?;
+    let finders = select_finders(&find_finders(&image))?;
+    let [top_left, top_right, bottom_left] = &finders;
+    let module = (top_left.module + top_right.module + bottom_left.module) / 3.0;
+    let span = ((top_left.x - top_right.x).hypot(top_left.y - top_right.y)
+        + (top_left.x - bottom_left.x).hypot(top_left.y - bottom_left.y))
+        / 2.0;
+    let estimate = (span / module).round() as usize + 7;
+    // Sizes are 4n + 17, so round to the nearest and try its neighbours if
+    // the module size was off
+    let version = (estimate.saturating_sub(15) / 4).max(1);
+    [version, version + 1, version - 1]
+        .into_iter()
+        .filter(|version| (1..=MAX_VERSION).contains(version))
+        .find_map(|version| {
+            let matrix = sample(&image, &finders, 17 + 4 * version);
+            let (level, mask) = read_format(&matrix)?;
+            let codewords = read_codewords(&matrix, version, mask);
+            let data = correct_blocks(&codewords, version, level)?;
+            decode_segments(&data)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_correct() {
+        // "HELLO WORLD" as version 1-M with three codewords damaged
+        let codewords = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17, 196, 35, 39,
+            119, 235, 215, 231, 226, 93, 23,
+        ];
+        let mut block = codewords;
+        block[0] ^= 0xFF;
+        block[7] ^= 0x01;
+        block[20] ^= 0x5A;
+        let field = Galois::new();
+        assert!(correct(&field, &mut block, 10));
+        assert_eq!(block, codewords);
+        assert_eq!(decode_segments(&block[..16]).unwrap(), "HELLO WORLD");
+
+        for codeword in &mut block[..6] {
+            *codeword ^= 0x11;
+        }
+        assert!(!correct(&field, &mut block, 10));
+    }
+
+    #[test]
+    fn test_decode() {
+        // Version 1-M with mask 3, holding 1697040000123 in numeric mode
+        let symbol = [
+            "#######.##....#######",
+            "#.....#.#.##..#.....#",
+            "#.###.#..###..#.###.#",
+            "#.###.#.##....#.###.#",
+            "#.###.#.......#.###.#",
+            "#.....#..#....#.....#",
+            "#######.#.#.#.#######",
+            "........#............",
+            "#.##.###..#...#..#.##",
+            "..##....#...#..#...#.",
+            "....#.####..#####.#..",
+            ".#.###......##.....##",
+            "#....##.####.#..#.#.#",
+            "........###..#...#..#",
+            "#######.#.#..##.#....",
+            "#.....#.##.#####..###",
+            "#.###.#...##..####..#",
+            "#.###.#.#....#..#..#.",
+            "#.###.#.#..#.#.#..#..",
+            "#.....#...###.####.##",
+            "#######.#..##...#..#.",
+        ];
+        // Drawn at 5 pixels per module on a grey background with a quiet
+        // zone, upside down
+        let (scale, margin, width) = (5, 20, 160);
+        let mut plane = vec![200u8; width * width];
+        for (y, row) in symbol.iter().enumerate() {
+            for (x, module) in row.bytes().enumerate() {
+                let value = if module == b'#' { 30 } else { 230 };
+                for py in 0..scale {
+                    for px in 0..scale {
+                        let (ix, iy) = (
+                            margin + (20 - x) * scale + px,
+                            margin + (20 - y) * scale + py,
+                        );
+                        plane[iy * width + ix] = value;
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            decode(&plane, width, width, width).as_deref(),
+            Some("1697040000123")
+        );
+        assert_eq!(decode(&[200; 64 * 64], 64, 64, 64), None);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::VecDeque;
+use std::error::Error;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::stream_info;
+
+/// Luma samples of a frame
+#[derive(Debug, Clone, PartialEq)]
+struct Plane {
+    samples: Vec<u16>,
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+}
+
+impl Plane {
+    fn from_frame(frame: &ffmpeg::frame::Video) -> Option<Self> {
+        let bit_depth = stream_info::bit_depth(frame.format())?;
+        let width = frame.plane_width(0) as usize;
+        let height = frame.plane_height(0) as usize;
+        let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+        let samples = frame
+            .data(0)
+            .chunks(frame.stride(0))
+            .take(height)
+            .flat_map(|row| {
+                row.chunks_exact(bytes_per_sample)
+                    .take(width)
+                    .map(|sample| match sample {
+                        [low, high] => u16::from_le_bytes([*low, *high]),
+                        _ => sample[0] as u16,
+                    })
+            })
+            .collect();
+        Some(Self {
+            samples,
+            width,
+            height,
+            bit_depth,
+        })
+    }
+
+    fn max_value(&self) -> f64 {
+        ((1u32 << self.bit_depth) - 1) as f64
+    }
+
+    fn is_comparable(&self, other: &Plane) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.bit_depth == other.bit_depth
+            && self.samples.len() == self.width * self.height
+            && other.samples.len() == other.width * other.height
+    }
+}
+
+/// Mean squared error between two planes, relative to the largest sample
+/// value so frames of any bit depth can be combined
+fn normalized_mse(a: &Plane, b: &Plane) -> f64 {
+    let sum: f64 = a
+        .samples
+        .iter()
+        .zip(&b.samples)
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    sum / a.samples.len() as f64 / a.max_value().powi(2)
+}
+
+/// PSNR in dB for a normalized MSE, infinite for identical frames
+fn psnr(mse: f64) -> f64 {
+    -10.0 * mse.log10()
+}
+
+/// Size of the SSIM windows and the distance between them
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+
+/// Mean SSIM over overlapping 8x8 windows
+fn ssim(a: &Plane, b: &Plane) -> f64 {
+    let c1 = (0.01 * a.max_value()).powi(2);
+    let c2 = (0.03 * a.max_value()).powi(2);
+    let n = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+    let (mut total, mut windows) = (0.0, 0);
+    for y in (0..=a.height.saturating_sub(SSIM_WINDOW)).step_by(SSIM_STEP) {
+        for x in (0..=a.width.saturating_sub(SSIM_WINDOW)).step_by(SSIM_STEP) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for row in y..(y + SSIM_WINDOW).min(a.height) {
+                for column in x..(x + SSIM_WINDOW).min(a.width) {
+                    let i = row * a.width + column;
+                    let (va, vb) = (a.samples[i] as f64, b.samples[i] as f64);
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let variance_a = sum_aa / n - mean_a * mean_a;
+            let variance_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (variance_a + variance_b + c2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// Decodes the encoded stream again and compares the luma of every frame
+/// with the frame that went into the encoder
+pub struct QualityMeter {
+    decoder: ffmpeg::decoder::Video,
+    decoded: ffmpeg::frame::Video,
+    /// Frames sent to the encoder by PTS, waiting for their decoded version
+    references: VecDeque<(i64, Plane)>,
+    frames: usize,
+    mse_sum: f64,
+    worst_psnr: f64,
+    ssim_sum: f64,
+    worst_ssim: f64,
+}
+
+impl QualityMeter {
+    pub fn new(codec_id: ffmpeg::codec::Id) -> Result<Self, Box<dyn Error>> {
+        let codec = ffmpeg::decoder::find(codec_id)
+            .ok_or_else(|| format!("No decoder for {:?} to measure the quality", codec_id))?;
+        let decoder = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .decoder()
+            .video()?;
+        Ok(Self {
+            decoder,
+            decoded: ffmpeg::frame::Video::empty(),
+            references: VecDeque::new(),
+            frames: 0,
+            mse_sum: 0.0,
+            worst_psnr: f64::INFINITY,
+            ssim_sum: 0.0,
+            worst_ssim: 1.0,
+        })
+    }
+
+    /// Keeps a frame as it is sent to the encoder
+    pub fn add_reference(&mut self, frame: &ffmpeg::frame::Video) {
+        if let Some(plane) = Plane::from_frame(frame) {
+            self.references.push_back((frame.pts().unwrap_or(0), plane));
+        }
+    }
+
+    /// Decodes an encoded packet and compares the frames it completes
+    pub fn add_encoded(&mut self, packet: &ffmpeg::Packet) -> Result<(), ffmpeg::Error> {
+        self.decoder.send_packet(packet)?;
+        self.drain()
+    }
+
+    /// Compares the frames still in the decoder, once the encoder is done
+    pub fn finish(&mut self) -> Result<(), ffmpeg::Error> {
+        self.decoder.send_eof()?;
+        self.drain()
+    }
+
+    fn drain(&mut self) -> Result<(), ffmpeg::Error> {
+        loop {
+            match self.decoder.receive_frame(&mut self.decoded) {
+                Ok(_) => self.compare(),
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn compare(&mut self) {
+        let pts = self.decoded.pts().unwrap_or(0);
+        // Frames the encoder dropped never come back
+        while self.references.front().is_some_and(|(r, _)| *r < pts) {
+            self.references.pop_front();
+        }
+        if self.references.front().is_none_or(|(r, _)| *r != pts) {
+            return;
+        }
+        let Some((_, reference)) = self.references.pop_front() else {
+            return;
+        };
+        let Some(decoded) = Plane::from_frame(&self.decoded) else {
+            return;
+        };
+        if !reference.is_comparable(&decoded) {
+            return;
+        }
+        let mse = normalized_mse(&reference, &decoded);
+        let ssim = ssim(&reference, &decoded);
+        self.frames += 1;
+        self.mse_sum += mse;
+        self.worst_psnr = self.worst_psnr.min(psnr(mse));
+        self.ssim_sum += ssim;
+        self.worst_ssim = self.worst_ssim.min(ssim);
+    }
+
+    /// PSNR from the mean squared error over all frames, and the mean SSIM,
+    /// each with the worst frame
+    pub fn summary(&self) -> Vec<String> {
+        if self.frames == 0 {
+            return vec!["No encoded frames could be compared with their source".to_string()];
+        }
+        vec![
+            format!(
+                "Compared {} encoded frames with their source (luma)",
+                self.frames
+            ),
+            format!(
+                "  PSNR: {:.2} dB, worst frame {:.2} dB",
+                psnr(self.mse_sum / self.frames as f64),
+                self.worst_psnr
+            ),
+            format!(
+                "  SSIM: {:.4}, worst frame {:.4}",
+                self.ssim_sum / self.frames as f64,
+                self.worst_ssim
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plane(width: usize, height: usize, sample: impl Fn(usize, usize) -> u16) -> Plane {
+        Plane {
+            samples: (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| sample(x, y))
+                .collect(),
+            width,
+            height,
+            bit_depth: 8,
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let source = plane(16, 16, |x, y| ((x * 16 + y * 3) % 256) as u16);
+        assert_eq!(normalized_mse(&source, &source), 0.0);
+        assert_eq!(psnr(0.0), f64::INFINITY);
+        assert!((ssim(&source, &source) - 1.0).abs() < 1e-9);
+
+        // Off by one everywhere, for an MSE of 1
+        let brighter = plane(16, 16, |x, y| source.samples[y * 16 + x] + 1);
+        assert!((psnr(normalized_mse(&source, &brighter)) - 48.13).abs() < 0.01);
+        assert!(ssim(&source, &brighter) > 0.99);
+
+        let flat = plane(16, 16, |_, _| 128);
+        assert!(ssim(&source, &flat) < 0.1);
+        assert!(!source.is_comparable(&plane(8, 16, |_, _| 0)));
+    }
+}
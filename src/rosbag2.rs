@@ -0,0 +1,260 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+use mcap::{records::MessageHeader, Channel, Schema, WriteOptions, Writer};
+
+use crate::bag::{self, FrameSink};
+use crate::codec::VideoConverter;
+
+pub const COMPRESSED_IMAGE_TYPE: &str = "sensor_msgs/msg/CompressedImage";
+/// The ros2msg definition of sensor_msgs/msg/CompressedImage, with the
+/// messages it uses
+pub const COMPRESSED_IMAGE_DEFINITION: &str = "\
+std_msgs/Header header
+string format
+uint8[] data
+
+================================================================================
+MSG: std_msgs/Header
+builtin_interfaces/Time stamp
+string frame_id
+
+================================================================================
+MSG: builtin_interfaces/Time
+int32 sec
+uint32 nanosec
+";
+
+/// Little-endian CDR, the encapsulation rosbag2 messages start with
+const CDR_LE: [u8; 4] = [0, 1, 0, 0];
+
+/// Writes CDR, aligning each value to its size from the end of the
+/// encapsulation header
+struct Cdr {
+    data: Vec<u8>,
+}
+
+impl Cdr {
+    fn new() -> Self {
+        Self {
+            data: CDR_LE.to_vec(),
+        }
+    }
+
+    fn align(&mut self, size: usize) {
+        while !(self.data.len() - CDR_LE.len()).is_multiple_of(size) {
+            self.data.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A string, with its terminating NUL counted in its length
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32 + 1);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.data.extend_from_slice(value);
+    }
+}
+
+/// A sensor_msgs/msg/CompressedImage serialized as CDR
+pub fn compressed_image(timestamp_ns: u64, frame_id: &str, format: &str, data: &[u8]) -> Vec<u8> {
+    let mut cdr = Cdr::new();
+    cdr.u32((timestamp_ns / 1_000_000_000) as u32);
+    cdr.u32((timestamp_ns % 1_000_000_000) as u32);
+    cdr.string(frame_id);
+    cdr.string(format);
+    cdr.bytes(data);
+    cdr.data
+}
+
+/// ROS 2 topics are absolute
+pub fn topic_name(topic: &str) -> String {
+    if topic.starts_with('/') {
+        topic.to_string()
+    } else {
+        format!("/{}", topic)
+    }
+}
+
+/// What metadata.yaml records of the bag
+struct BagInfo<'a> {
+    storage_file: &'a str,
+    topic: &'a str,
+    messages: u32,
+    start_ns: u64,
+    duration_ns: u64,
+}
+
+impl BagInfo<'_> {
+    /// The metadata.yaml `ros2 bag` reads the bag with, in version 5 of the
+    /// format which every distribution since Humble can read
+    fn yaml(&self) -> String {
+        format!(
+            "rosbag2_bagfile_information:
+  version: 5
+  storage_identifier: mcap
+  duration:
+    nanoseconds: {duration}
+  starting_time:
+    nanoseconds_since_epoch: {start}
+  message_count: {messages}
+  topics_with_message_count:
+    - topic_metadata:
+        name: {topic}
+        type: {ty}
+        serialization_format: cdr
+        offered_qos_profiles: \"\"
+      message_count: {messages}
+  compression_format: \"\"
+  compression_mode: \"\"
+  relative_file_paths:
+    - {file}
+  files:
+    - path: {file}
+      starting_time:
+        nanoseconds_since_epoch: {start}
+      duration:
+        nanoseconds: {duration}
+      message_count: {messages}
+",
+            duration = self.duration_ns,
+            start = self.start_ns,
+            messages = self.messages,
+            topic = self.topic,
+            ty = COMPRESSED_IMAGE_TYPE,
+            file = self.storage_file,
+        )
+    }
+}
+
+/// The video channel of the MCAP storage of a rosbag2
+struct VideoStorage<'a> {
+    writer: Writer<'a, BufWriter<File>>,
+    channel_id: u16,
+    frame_id: &'a str,
+    start_ns: Option<u64>,
+    end_ns: u64,
+}
+
+impl FrameSink for VideoStorage<'_> {
+    fn write_frame(
+        &mut self,
+        sequence: u32,
+        timestamp_ns: u64,
+        format: &str,
+        data: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        self.start_ns.get_or_insert(timestamp_ns);
+        self.end_ns = timestamp_ns;
+        let message = compressed_image(timestamp_ns, self.frame_id, format, data);
+        self.writer.write_to_known_channel(
+            &MessageHeader {
+                channel_id: self.channel_id,
+                sequence,
+                log_time: timestamp_ns,
+                publish_time: timestamp_ns,
+            },
+            &message,
+        )?;
+        Ok(())
+    }
+}
+
+/// Converts the video stream alone to a rosbag2 directory, with a
+/// CompressedImage per frame in its MCAP storage. Returns the number of
+/// frames written.
+pub fn convert(
+    converter: &mut VideoConverter,
+    input: &mut ffmpeg::format::context::Input,
+    video_stream_index: usize,
+    directory: &Path,
+    topic: &str,
+    frame_id: &str,
+    time_offset_ns: Option<i64>,
+) -> Result<u32, Box<dyn Error>> {
+    std::fs::create_dir(directory)
+        .map_err(|e| format!("Could not create the bag directory {:?}: {}", directory, e))?;
+    let name = directory
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let storage_file = format!("{}_0.mcap", name);
+    let file = BufWriter::new(File::create(directory.join(&storage_file))?);
+    let mut writer = WriteOptions::new().profile("ros2").create(file)?;
+    let topic = topic_name(topic);
+    let schema = Schema {
+        name: COMPRESSED_IMAGE_TYPE.to_string(),
+        encoding: "ros2msg".to_string(),
+        data: Cow::Borrowed(COMPRESSED_IMAGE_DEFINITION.as_bytes()),
+    };
+    let channel_id = writer.add_channel(&Channel {
+        topic: topic.clone(),
+        message_encoding: "cdr".to_string(),
+        schema: Some(schema.into()),
+        metadata: BTreeMap::from([("offered_qos_profiles".to_string(), String::new())]),
+    })?;
+    let mut sink = VideoStorage {
+        writer,
+        channel_id,
+        frame_id,
+        start_ns: None,
+        end_ns: 0,
+    };
+    let frames = bag::convert_video(
+        converter,
+        input,
+        video_stream_index,
+        &mut sink,
+        time_offset_ns,
+    )?;
+    sink.writer.finish()?;
+
+    let start_ns = sink.start_ns.unwrap_or(0);
+    let info = BagInfo {
+        storage_file: &storage_file,
+        topic: &topic,
+        messages: frames,
+        start_ns,
+        duration_ns: sink.end_ns.saturating_sub(start_ns),
+    };
+    std::fs::write(directory.join("metadata.yaml"), info.yaml())?;
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_image_cdr() {
+        let message = compressed_image(1_500_000_000, "cam", "h264", &[0, 0, 1, 0x65]);
+        assert_eq!(
+            message,
+            [
+                0, 1, 0, 0, // encapsulation
+                1, 0, 0, 0, 0x00, 0x65, 0xcd, 0x1d, // stamp, 1s and 5e8 ns
+                4, 0, 0, 0, b'c', b'a', b'm', 0, // frame_id
+                5, 0, 0, 0, b'h', b'2', b'6', b'4', 0, // format
+                0, 0, 0, // padding
+                4, 0, 0, 0, 0, 0, 1, 0x65, // data
+            ]
+        );
+        assert_eq!(topic_name("video"), "/video");
+        assert_eq!(topic_name("/camera/front"), "/camera/front");
+    }
+}
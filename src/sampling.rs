@@ -0,0 +1,102 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::duration;
+
+/// Reads the times of an --at-file, one per line, skipping blank lines and
+/// # comments
+pub fn read_times(path: &Path) -> Result<Vec<u64>, Box<dyn Error>> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    let mut times = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        times.push(
+            duration::parse_time(line)
+                .map_err(|e| format!("{:?} line {}: {}", path, index + 1, e))?,
+        );
+    }
+    Ok(times)
+}
+
+/// The frames nearest to a list of times, which are the only ones written
+pub struct Sampler {
+    pts: BTreeSet<i64>,
+}
+
+impl Sampler {
+    /// Picks, for every time from the first frame in `times_ns`, the frame in
+    /// `frame_pts` closest to it, the earlier one on a tie
+    pub fn new(frame_pts: &[i64], times_ns: &[u64], time_base: ffmpeg::Rational) -> Self {
+        let mut pts = BTreeSet::new();
+        let (Some(&first), Some(&last)) = (frame_pts.first(), frame_pts.last()) else {
+            return Self { pts };
+        };
+        for &time_ns in times_ns {
+            let target = first.saturating_add(
+                (time_ns as i128 * time_base.denominator() as i128
+                    / (time_base.numerator().max(1) as i128 * 1_000_000_000))
+                    as i64,
+            );
+            if target > last {
+                warn!(
+                    "{:.3}s is past the end of the video, taking its last frame",
+                    time_ns as f64 / 1e9
+                );
+            }
+            let after = frame_pts.partition_point(|&pts| pts < target);
+            let nearest = match (
+                after.checked_sub(1).map(|i| frame_pts[i]),
+                frame_pts.get(after),
+            ) {
+                (Some(before), Some(&after)) if target - before <= after - target => before,
+                (_, Some(&after)) => after,
+                (Some(before), None) => before,
+                (None, None) => continue,
+            };
+            pts.insert(nearest);
+        }
+        Self { pts }
+    }
+
+    pub fn keep(&self, pts: i64) -> bool {
+        self.pts.contains(&pts)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler() {
+        // 10 fps in a 1/1000 time base, starting at 500
+        let frame_pts: Vec<i64> = (0..20).map(|i| 500 + i * 100).collect();
+        let sampler = Sampler::new(
+            &frame_pts,
+            &[
+                0,
+                1_040_000_000,
+                1_050_000_000,
+                1_260_000_000,
+                60_000_000_000,
+            ],
+            ffmpeg::Rational::new(1, 1000),
+        );
+        assert_eq!(sampler.len(), 4);
+        for pts in [500, 1500, 1800, 2400] {
+            assert!(sampler.keep(pts));
+        }
+        assert!(!sampler.keep(1600));
+    }
+}
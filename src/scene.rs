@@ -0,0 +1,151 @@
+use ffmpeg_next as ffmpeg;
+
+use crate::stream_info;
+
+/// Number of luma histogram bins, coarse enough to ignore noise and small
+/// movements
+const BINS: usize = 64;
+
+/// Samples a histogram is built from at the most, on an even grid, as the
+/// bins are too coarse to need every sample of a large frame
+const MAX_SAMPLES: usize = 1 << 16;
+
+/// Normalized histogram of the luma samples of a frame
+pub type Histogram = [f64; BINS];
+
+/// Parses a --scene-changes threshold, from 0 to 1
+pub fn parse_threshold(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(threshold) if (0.0..=1.0).contains(&threshold) => Ok(threshold),
+        _ => Err(format!(
+            "Invalid threshold {:?}, expected a number from 0 to 1",
+            value
+        )),
+    }
+}
+
+/// Histogram of a plane of 8-bit samples, or of little-endian 16-bit samples
+/// with the given bit depth, from every `step`th sample of every `step`th row
+/// so that there are at most MAX_SAMPLES of them
+pub fn histogram(
+    plane: &[u8],
+    stride: usize,
+    width: usize,
+    height: usize,
+    bit_depth: u8,
+) -> Option<Histogram> {
+    let mut counts = [0u64; BINS];
+    let bytes_per_sample = if bit_depth > 8 { 2 } else { 1 };
+    let step = ((width * height / MAX_SAMPLES) as f64)
+        .sqrt()
+        .ceil()
+        .max(1.0) as usize;
+    for row in plane.chunks(stride).take(height).step_by(step) {
+        let row = row.get(..width * bytes_per_sample)?;
+        if bytes_per_sample == 1 {
+            for &sample in row.iter().step_by(step) {
+                counts[sample as usize * BINS / 256] += 1;
+            }
+        } else {
+            for sample in row.chunks_exact(2).step_by(step) {
+                let sample = u16::from_le_bytes([sample[0], sample[1]]) as usize;
+                counts[((sample * BINS) >> bit_depth).min(BINS - 1)] += 1;
+            }
+        }
+    }
+    let total = counts.iter().sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+    Some(counts.map(|count| count as f64 / total as f64))
+}
+
+/// Histogram of the luma plane of a decoded frame
+pub fn luma_histogram(frame: &ffmpeg::frame::Video) -> Option<Histogram> {
+    histogram(
+        frame.data(0),
+        frame.stride(0),
+        frame.plane_width(0) as usize,
+        frame.plane_height(0) as usize,
+        stream_info::bit_depth(frame.format())?,
+    )
+}
+
+/// Whether nearly all of a frame is black, allowing for noise and the
+/// limited range black level of 16
+pub fn is_black(histogram: &Histogram) -> bool {
+    histogram[..BINS / 8].iter().sum::<f64>() >= 0.98
+}
+
+/// Whether the decoder concealed errors in the frame
+pub fn is_corrupt(frame: &ffmpeg::frame::Video) -> bool {
+    frame.is_corrupt() || unsafe { (*frame.as_ptr()).decode_error_flags != 0 }
+}
+
+/// How different two frames look, from 0 for the same luma distribution to
+/// 1 for disjoint ones
+pub fn score(a: &Histogram, b: &Histogram) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f64>() / 2.0
+}
+
+/// Finds cuts by comparing the luma histogram of each decoded frame with the
+/// one before
+pub struct SceneDetector {
+    threshold: f64,
+    previous: Option<Histogram>,
+}
+
+impl SceneDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: None,
+        }
+    }
+
+    /// The score of the frame if it starts a new scene
+    pub fn process(&mut self, histogram: &Histogram) -> Option<f64> {
+        let previous = self.previous.replace(*histogram)?;
+        Some(score(&previous, histogram)).filter(|&score| score >= self.threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_score() {
+        // A 2x2 frame with padding at the end of each row
+        let dark = histogram(&[0, 1, 99, 20, 30, 99], 3, 2, 2, 8).unwrap();
+        assert_eq!(dark[0], 0.5);
+        assert_eq!(dark[5], 0.25);
+        let bright = histogram(&[255, 255, 255, 250], 2, 2, 2, 8).unwrap();
+        assert_eq!(score(&dark, &dark), 0.0);
+        assert_eq!(score(&dark, &bright), 1.0);
+        assert!(is_black(&histogram(&[16; 100], 10, 10, 10, 8).unwrap()));
+        assert!(!is_black(&bright));
+
+        // 10-bit samples of 0 and 1023
+        let samples = [0, 0, 0xFF, 0x03];
+        let deep = histogram(&samples, 4, 2, 1, 10).unwrap();
+        assert_eq!((deep[0], deep[BINS - 1]), (0.5, 0.5));
+        assert_eq!(histogram(&[0], 1, 2, 1, 8), None);
+
+        // Every other sample of every other row of a 1024x256 frame
+        let mut plane = vec![0; 1024 * 256];
+        for (i, row) in plane.chunks_mut(1024).enumerate() {
+            row.iter_mut()
+                .skip(i % 2)
+                .step_by(2)
+                .for_each(|sample| *sample = 255);
+        }
+        let sampled = histogram(&plane, 1024, 1024, 256, 8).unwrap();
+        assert_eq!(sampled[BINS - 1], 1.0);
+
+        assert_eq!(parse_threshold("0.4"), Ok(0.4));
+        assert!(parse_threshold("1.5").is_err());
+        assert!(parse_threshold("-0.1").is_err());
+        assert!(parse_threshold("NaN").is_err());
+    }
+}
@@ -0,0 +1,803 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use prost::Message;
+use prost_types::field_descriptor_proto::{Label, Type};
+use prost_types::{
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+    FileDescriptorProto, FileDescriptorSet,
+};
+
+const TIMESTAMP_FILE: &str = "google/protobuf/timestamp.proto";
+const TIMESTAMP_TYPE: &str = ".google.protobuf.Timestamp";
+
+/// A value of the frame being written that a field can be set to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Timestamp,
+    FrameId,
+    Data,
+    Format,
+    Sequence,
+    Keyframe,
+    /// The same value in every message, converted to the field's type
+    Constant(String),
+}
+
+impl Source {
+    fn parse(text: &str) -> Self {
+        match text {
+            "timestamp" => Source::Timestamp,
+            "frame_id" => Source::FrameId,
+            "data" => Source::Data,
+            "format" => Source::Format,
+            "sequence" => Source::Sequence,
+            "keyframe" => Source::Keyframe,
+            other => Source::Constant(other.to_string()),
+        }
+    }
+}
+
+/// A field of the custom schema to set, from --field-map `FIELD=SOURCE`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMapping {
+    pub field: String,
+    pub source: Source,
+}
+
+impl std::str::FromStr for FieldMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, source) = s
+            .split_once('=')
+            .filter(|(field, _)| !field.is_empty())
+            .ok_or_else(|| format!("Invalid field mapping {:?}, expected FIELD=SOURCE", s))?;
+        Ok(FieldMapping {
+            field: field.to_string(),
+            source: Source::parse(source),
+        })
+    }
+}
+
+/// A field as written in the .proto file
+#[derive(Debug)]
+struct FieldDef {
+    name: String,
+    number: i32,
+    label: Label,
+    type_name: String,
+}
+
+#[derive(Debug, Default)]
+struct EnumDef {
+    name: String,
+    values: Vec<(String, i32)>,
+}
+
+#[derive(Debug, Default)]
+struct MessageDef {
+    name: String,
+    fields: Vec<FieldDef>,
+    messages: Vec<MessageDef>,
+    enums: Vec<EnumDef>,
+}
+
+#[derive(Debug, Default)]
+struct ProtoFile {
+    syntax: Option<String>,
+    package: Option<String>,
+    imports: Vec<String>,
+    messages: Vec<MessageDef>,
+    enums: Vec<EnumDef>,
+}
+
+/// Words, numbers and quoted strings, with comments dropped
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '/' {
+            chars.next();
+            match chars.next() {
+                Some('/') => {
+                    chars.by_ref().find(|&c| c == '\n');
+                }
+                Some('*') => {
+                    let mut last = ' ';
+                    chars
+                        .by_ref()
+                        .find(|&c| std::mem::replace(&mut last, c) == '*' && c == '/');
+                }
+                _ => return Err("Unexpected '/'".to_string()),
+            }
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut token = String::from('"');
+            loop {
+                match chars.next() {
+                    Some(end) if end == c => break,
+                    Some('\\') => token.extend(chars.next()),
+                    Some(c) => token.push(c),
+                    None => return Err("Unterminated string".to_string()),
+                }
+            }
+            tokens.push(token);
+        } else if c.is_alphanumeric() || "_.-+".contains(c) {
+            let mut token = String::new();
+            while let Some(&c) = chars
+                .peek()
+                .filter(|c| c.is_alphanumeric() || "_.-+".contains(**c))
+            {
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            tokens.push(c.to_string());
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<String, String> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or("Unexpected end of file")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!("Expected {:?}, found {:?}", expected, token)),
+        }
+    }
+
+    /// Skips a statement like an option, up to its semicolon
+    fn skip_statement(&mut self) -> Result<(), String> {
+        while self.next()? != ";" {}
+        Ok(())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        token
+            .strip_prefix('"')
+            .map(str::to_string)
+            .ok_or_else(|| format!("Expected a string, found {:?}", token))
+    }
+
+    fn file(&mut self) -> Result<ProtoFile, String> {
+        let mut file = ProtoFile::default();
+        while self.position < self.tokens.len() {
+            match self.next()?.as_str() {
+                "syntax" => {
+                    self.expect("=")?;
+                    file.syntax = Some(self.string()?);
+                    self.expect(";")?;
+                }
+                "package" => {
+                    file.package = Some(self.next()?);
+                    self.expect(";")?;
+                }
+                "import" => {
+                    let mut import = self.string();
+                    if import.is_err() {
+                        // public or weak
+                        import = self.string();
+                    }
+                    file.imports.push(import?);
+                    self.expect(";")?;
+                }
+                "option" => self.skip_statement()?,
+                "message" => file.messages.push(self.message()?),
+                "enum" => file.enums.push(self.enumeration()?),
+                ";" => {}
+                other => return Err(format!("Unsupported definition {:?}", other)),
+            }
+        }
+        Ok(file)
+    }
+
+    fn message(&mut self) -> Result<MessageDef, String> {
+        let mut message = MessageDef {
+            name: self.next()?,
+            ..Default::default()
+        };
+        self.expect("{")?;
+        loop {
+            let token = self.next()?;
+            match token.as_str() {
+                "}" => return Ok(message),
+                "message" => message.messages.push(self.message()?),
+                "enum" => message.enums.push(self.enumeration()?),
+                "option" | "reserved" | "extensions" => self.skip_statement()?,
+                ";" => {}
+                "oneof" | "map" | "extend" | "group" => {
+                    return Err(format!("{} fields are not supported", token))
+                }
+                _ => {
+                    let (label, type_name) = match token.as_str() {
+                        "repeated" => (Label::Repeated, self.next()?),
+                        "required" => (Label::Required, self.next()?),
+                        "optional" => (Label::Optional, self.next()?),
+                        _ => (Label::Optional, token),
+                    };
+                    let name = self.next()?;
+                    self.expect("=")?;
+                    let number = self.next()?;
+                    let number = number
+                        .parse()
+                        .map_err(|_| format!("Invalid field number {:?} of {}", number, name))?;
+                    self.skip_statement()?;
+                    message.fields.push(FieldDef {
+                        name,
+                        number,
+                        label,
+                        type_name,
+                    });
+                }
+            }
+        }
+    }
+
+    fn enumeration(&mut self) -> Result<EnumDef, String> {
+        let mut definition = EnumDef {
+            name: self.next()?,
+            ..Default::default()
+        };
+        self.expect("{")?;
+        loop {
+            match self.next()?.as_str() {
+                "}" => return Ok(definition),
+                "option" | "reserved" => self.skip_statement()?,
+                ";" => {}
+                name => {
+                    let name = name.to_string();
+                    self.expect("=")?;
+                    let number = self.next()?;
+                    let number = number
+                        .parse()
+                        .map_err(|_| format!("Invalid value {:?} of {}", number, name))?;
+                    self.skip_statement()?;
+                    definition.values.push((name, number));
+                }
+            }
+        }
+    }
+}
+
+fn scalar_type(name: &str) -> Option<Type> {
+    Some(match name {
+        "double" => Type::Double,
+        "float" => Type::Float,
+        "int32" => Type::Int32,
+        "int64" => Type::Int64,
+        "uint32" => Type::Uint32,
+        "uint64" => Type::Uint64,
+        "sint32" => Type::Sint32,
+        "sint64" => Type::Sint64,
+        "fixed32" => Type::Fixed32,
+        "fixed64" => Type::Fixed64,
+        "sfixed32" => Type::Sfixed32,
+        "sfixed64" => Type::Sfixed64,
+        "bool" => Type::Bool,
+        "string" => Type::String,
+        "bytes" => Type::Bytes,
+        _ => return None,
+    })
+}
+
+impl ProtoFile {
+    /// Fully qualified names of the messages and enums defined, with a
+    /// leading dot
+    fn types(&self) -> BTreeMap<String, Type> {
+        fn add(types: &mut BTreeMap<String, Type>, scope: &str, message: &MessageDef) {
+            let name = format!("{}.{}", scope, message.name);
+            for definition in &message.enums {
+                types.insert(format!("{}.{}", name, definition.name), Type::Enum);
+            }
+            for nested in &message.messages {
+                add(types, &name, nested);
+            }
+            types.insert(name, Type::Message);
+        }
+        let scope = self
+            .package
+            .as_ref()
+            .map_or(String::new(), |p| format!(".{}", p));
+        let mut types = BTreeMap::from([(TIMESTAMP_TYPE.to_string(), Type::Message)]);
+        for definition in &self.enums {
+            types.insert(format!("{}.{}", scope, definition.name), Type::Enum);
+        }
+        for message in &self.messages {
+            add(&mut types, &scope, message);
+        }
+        types
+    }
+
+    /// The type of a field named `type_name` in the message `scope`,
+    /// looked up from the innermost scope out as protoc does
+    fn resolve(
+        types: &BTreeMap<String, Type>,
+        scope: &str,
+        type_name: &str,
+    ) -> Result<(Type, Option<String>), String> {
+        if let Some(scalar) = scalar_type(type_name) {
+            return Ok((scalar, None));
+        }
+        let mut scope = scope;
+        loop {
+            let name = if type_name.starts_with('.') {
+                type_name.to_string()
+            } else {
+                format!("{}.{}", scope, type_name)
+            };
+            if let Some(kind) = types.get(&name) {
+                return Ok((*kind, Some(name)));
+            }
+            match scope.rfind('.') {
+                Some(end) if !type_name.starts_with('.') => scope = &scope[..end],
+                _ => return Err(format!("Unknown type {:?}", type_name)),
+            }
+        }
+    }
+
+    fn descriptor(
+        types: &BTreeMap<String, Type>,
+        scope: &str,
+        message: &MessageDef,
+    ) -> Result<DescriptorProto, String> {
+        let name = format!("{}.{}", scope, message.name);
+        let mut field = Vec::new();
+        for definition in &message.fields {
+            let (kind, type_name) = Self::resolve(types, &name, &definition.type_name)?;
+            field.push(FieldDescriptorProto {
+                name: Some(definition.name.clone()),
+                number: Some(definition.number),
+                label: Some(definition.label as i32),
+                r#type: Some(kind as i32),
+                type_name,
+                ..Default::default()
+            });
+        }
+        Ok(DescriptorProto {
+            name: Some(message.name.clone()),
+            field,
+            nested_type: message
+                .messages
+                .iter()
+                .map(|nested| Self::descriptor(types, &name, nested))
+                .collect::<Result<_, _>>()?,
+            enum_type: message.enums.iter().map(EnumDef::descriptor).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// A descriptor set of the file and the well-known types it imports
+    fn descriptor_set(&self, name: &str) -> Result<FileDescriptorSet, String> {
+        let types = self.types();
+        let scope = self
+            .package
+            .as_ref()
+            .map_or(String::new(), |p| format!(".{}", p));
+        let mut file = Vec::new();
+        for import in &self.imports {
+            if import != TIMESTAMP_FILE {
+                return Err(format!(
+                    "Unsupported import {:?}, only {} can be imported",
+                    import, TIMESTAMP_FILE
+                ));
+            }
+            file.push(timestamp_file());
+        }
+        file.push(FileDescriptorProto {
+            name: Some(name.to_string()),
+            package: self.package.clone(),
+            dependency: self.imports.clone(),
+            message_type: self
+                .messages
+                .iter()
+                .map(|message| Self::descriptor(&types, &scope, message))
+                .collect::<Result<_, _>>()?,
+            enum_type: self.enums.iter().map(EnumDef::descriptor).collect(),
+            syntax: self.syntax.clone(),
+            ..Default::default()
+        });
+        Ok(FileDescriptorSet { file })
+    }
+
+    fn find(&self, full_name: &str) -> Option<(String, &MessageDef)> {
+        fn find<'a>(
+            scope: String,
+            messages: &'a [MessageDef],
+            full_name: &str,
+        ) -> Option<(String, &'a MessageDef)> {
+            messages.iter().find_map(|message| {
+                let name = format!("{}.{}", scope, message.name);
+                if name == full_name {
+                    Some((scope.clone(), message))
+                } else {
+                    find(name, &message.messages, full_name)
+                }
+            })
+        }
+        let scope = self
+            .package
+            .as_ref()
+            .map_or(String::new(), |p| format!(".{}", p));
+        find(scope, &self.messages, &format!(".{}", full_name))
+    }
+}
+
+impl EnumDef {
+    fn descriptor(&self) -> EnumDescriptorProto {
+        EnumDescriptorProto {
+            name: Some(self.name.clone()),
+            value: self
+                .values
+                .iter()
+                .map(|(name, number)| EnumValueDescriptorProto {
+                    name: Some(name.clone()),
+                    number: Some(*number),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+}
+
+fn timestamp_file() -> FileDescriptorProto {
+    let field = |name: &str, number, kind: Type| FieldDescriptorProto {
+        name: Some(name.to_string()),
+        number: Some(number),
+        label: Some(Label::Optional as i32),
+        r#type: Some(kind as i32),
+        ..Default::default()
+    };
+    FileDescriptorProto {
+        name: Some(TIMESTAMP_FILE.to_string()),
+        package: Some("google.protobuf".to_string()),
+        message_type: vec![DescriptorProto {
+            name: Some("Timestamp".to_string()),
+            field: vec![
+                field("seconds", 1, Type::Int64),
+                field("nanos", 2, Type::Int32),
+            ],
+            ..Default::default()
+        }],
+        syntax: Some("proto3".to_string()),
+        ..Default::default()
+    }
+}
+
+/// A constant converted to the type of its field
+#[derive(Debug, Clone, PartialEq)]
+enum Constant {
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+}
+
+/// What a field of the custom message is set to
+#[derive(Debug)]
+struct MappedField {
+    number: u32,
+    kind: Type,
+    source: Source,
+    constant: Option<Constant>,
+}
+
+/// The frame values a custom message is made of
+pub struct Frame<'a> {
+    pub timestamp_ns: u64,
+    pub frame_id: &'a str,
+    pub data: &'a [u8],
+    pub format: &'a str,
+    pub sequence: u32,
+    pub keyframe: bool,
+}
+
+/// A video message type from a .proto file, with --schema
+#[derive(Debug)]
+pub struct CustomSchema {
+    pub name: String,
+    /// The FileDescriptorSet for the channel
+    pub descriptor: Vec<u8>,
+    fields: Vec<MappedField>,
+}
+
+impl CustomSchema {
+    /// Loads `name` from the .proto file, with the fields named like a
+    /// CompressedVideo field set to its value unless mapped otherwise
+    pub fn load(
+        path: &Path,
+        name: &str,
+        mappings: &[FieldMapping],
+    ) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        Self::parse(&text, &file_name, name, mappings)
+            .map_err(|e| format!("Invalid schema {:?}: {}", path, e).into())
+    }
+
+    fn parse(
+        text: &str,
+        file_name: &str,
+        name: &str,
+        mappings: &[FieldMapping],
+    ) -> Result<Self, String> {
+        let file = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+        }
+        .file()?;
+        let descriptor = file.descriptor_set(file_name)?.encode_to_vec();
+        let (scope, message) = file
+            .find(name)
+            .ok_or_else(|| format!("No message {} in the schema", name))?;
+        let types = file.types();
+        let scope = format!("{}.{}", scope, message.name);
+
+        for mapping in mappings {
+            if !message
+                .fields
+                .iter()
+                .any(|field| field.name == mapping.field)
+            {
+                return Err(format!("{} has no field {}", name, mapping.field));
+            }
+        }
+        let mut fields = Vec::new();
+        for field in &message.fields {
+            let source = match mappings.iter().find(|m| m.field == field.name) {
+                Some(mapping) => mapping.source.clone(),
+                None => match Source::parse(&field.name) {
+                    Source::Constant(_) | Source::Sequence | Source::Keyframe => continue,
+                    source => source,
+                },
+            };
+            if field.label == Label::Repeated {
+                return Err(format!("Repeated field {} cannot be mapped", field.name));
+            }
+            let (kind, type_name) = ProtoFile::resolve(&types, &scope, &field.type_name)?;
+            let is_timestamp = type_name.as_deref() == Some(TIMESTAMP_TYPE);
+            let integer = matches!(
+                kind,
+                Type::Int32
+                    | Type::Int64
+                    | Type::Uint32
+                    | Type::Uint64
+                    | Type::Sint32
+                    | Type::Sint64
+                    | Type::Fixed32
+                    | Type::Fixed64
+                    | Type::Sfixed32
+                    | Type::Sfixed64
+            );
+            let valid = match &source {
+                // As a Timestamp, or nanoseconds
+                Source::Timestamp => {
+                    is_timestamp
+                        || matches!(
+                            kind,
+                            Type::Int64
+                                | Type::Uint64
+                                | Type::Fixed64
+                                | Type::Sfixed64
+                                | Type::Sint64
+                        )
+                }
+                Source::FrameId | Source::Format => kind == Type::String,
+                Source::Data => kind == Type::Bytes,
+                Source::Sequence => integer,
+                Source::Keyframe => kind == Type::Bool,
+                Source::Constant(_) => !matches!(kind, Type::Message),
+            };
+            if !valid {
+                return Err(format!(
+                    "Field {} of type {} cannot be set to {:?}",
+                    field.name, field.type_name, source
+                ));
+            }
+            let constant = match &source {
+                Source::Constant(text) => {
+                    Some(Self::constant(kind, integer, text).ok_or_else(|| {
+                        format!(
+                            "Invalid value {:?} for field {} of type {}",
+                            text, field.name, field.type_name
+                        )
+                    })?)
+                }
+                _ => None,
+            };
+            fields.push(MappedField {
+                number: field.number as u32,
+                kind,
+                source,
+                constant,
+            });
+        }
+        Ok(Self {
+            name: name.to_string(),
+            descriptor,
+            fields,
+        })
+    }
+
+    fn constant(kind: Type, integer: bool, text: &str) -> Option<Constant> {
+        Some(match kind {
+            Type::String | Type::Bytes => Constant::Text(text.to_string()),
+            Type::Bool => Constant::Bool(text.parse().ok()?),
+            Type::Double | Type::Float => Constant::Float(text.parse().ok()?),
+            Type::Uint32 | Type::Uint64 | Type::Fixed32 | Type::Fixed64 => {
+                Constant::UInt(text.parse().ok()?)
+            }
+            // Enums by number
+            _ if integer || kind == Type::Enum => Constant::Int(text.parse().ok()?),
+            _ => return None,
+        })
+    }
+
+    /// Serializes the message for a frame into `buffer`
+    pub fn encode(&self, frame: &Frame, buffer: &mut Vec<u8>) {
+        use prost::encoding;
+        for field in &self.fields {
+            let tag = field.number;
+            let int = match (&field.source, &field.constant) {
+                (Source::Timestamp, _) if field.kind == Type::Message => {
+                    let timestamp = prost_types::Timestamp {
+                        seconds: (frame.timestamp_ns / 1_000_000_000) as i64,
+                        nanos: (frame.timestamp_ns % 1_000_000_000) as i32,
+                    };
+                    encoding::message::encode(tag, &timestamp, buffer);
+                    continue;
+                }
+                (Source::Timestamp, _) => frame.timestamp_ns as i64,
+                (Source::FrameId, _) => {
+                    encoding::string::encode(tag, &frame.frame_id.to_string(), buffer);
+                    continue;
+                }
+                (Source::Format, _) => {
+                    encoding::string::encode(tag, &frame.format.to_string(), buffer);
+                    continue;
+                }
+                (Source::Data, _) => {
+                    encoding::encode_key(tag, encoding::WireType::LengthDelimited, buffer);
+                    encoding::encode_varint(frame.data.len() as u64, buffer);
+                    buffer.extend_from_slice(frame.data);
+                    continue;
+                }
+                (Source::Sequence, _) => frame.sequence as i64,
+                (Source::Keyframe, _) => frame.keyframe as i64,
+                (_, Some(Constant::Text(text))) => {
+                    encoding::encode_key(tag, encoding::WireType::LengthDelimited, buffer);
+                    encoding::encode_varint(text.len() as u64, buffer);
+                    buffer.extend_from_slice(text.as_bytes());
+                    continue;
+                }
+                (_, Some(Constant::Float(value))) => {
+                    match field.kind {
+                        Type::Float => encoding::float::encode(tag, &(*value as f32), buffer),
+                        _ => encoding::double::encode(tag, value, buffer),
+                    }
+                    continue;
+                }
+                (_, Some(Constant::Bool(value))) => *value as i64,
+                (_, Some(Constant::Int(value))) => *value,
+                (_, Some(Constant::UInt(value))) => *value as i64,
+                (Source::Constant(_), None) => continue,
+            };
+            match field.kind {
+                Type::Int32 | Type::Enum => encoding::int32::encode(tag, &(int as i32), buffer),
+                Type::Int64 => encoding::int64::encode(tag, &int, buffer),
+                Type::Uint32 => encoding::uint32::encode(tag, &(int as u32), buffer),
+                Type::Uint64 => encoding::uint64::encode(tag, &(int as u64), buffer),
+                Type::Sint32 => encoding::sint32::encode(tag, &(int as i32), buffer),
+                Type::Sint64 => encoding::sint64::encode(tag, &int, buffer),
+                Type::Fixed32 => encoding::fixed32::encode(tag, &(int as u32), buffer),
+                Type::Fixed64 => encoding::fixed64::encode(tag, &(int as u64), buffer),
+                Type::Sfixed32 => encoding::sfixed32::encode(tag, &(int as i32), buffer),
+                Type::Sfixed64 => encoding::sfixed64::encode(tag, &int, buffer),
+                Type::Bool => encoding::bool::encode(tag, &(int != 0), buffer),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+        syntax = "proto3";
+        import "google/protobuf/timestamp.proto";
+        package acme;
+
+        /* Our video message */
+        message Video {
+          enum Sensor { UNKNOWN = 0; RGB = 1; }
+          google.protobuf.Timestamp timestamp = 1;
+          string frame_id = 2;
+          bytes payload = 3; // the frame
+          string format = 4;
+          string camera_id = 5;
+          double exposure = 6 [deprecated = true];
+          Sensor sensor = 7;
+          uint32 index = 8;
+        }
+    "#;
+
+    #[test]
+    fn test_custom_schema() {
+        let mappings: Vec<FieldMapping> = [
+            "payload=data",
+            "camera_id=front",
+            "exposure=0.5",
+            "sensor=1",
+            "index=sequence",
+        ]
+        .iter()
+        .map(|m| m.parse().unwrap())
+        .collect();
+        let schema = CustomSchema::parse(SCHEMA, "video.proto", "acme.Video", &mappings).unwrap();
+        let set = FileDescriptorSet::decode(schema.descriptor.as_slice()).unwrap();
+        assert_eq!(set.file.len(), 2);
+        let video = &set.file[1].message_type[0];
+        assert_eq!(video.field[0].type_name.as_deref(), Some(TIMESTAMP_TYPE));
+        assert_eq!(
+            video.field[6].type_name.as_deref(),
+            Some(".acme.Video.Sensor")
+        );
+
+        let mut buffer = Vec::new();
+        schema.encode(
+            &Frame {
+                timestamp_ns: 1_500_000_000,
+                frame_id: "cam",
+                data: &[0, 0, 1],
+                format: "h264",
+                sequence: 3,
+                keyframe: true,
+            },
+            &mut buffer,
+        );
+        let mut expected = vec![0x0a, 8, 0x08, 1, 0x10, 0x80, 0xca, 0xb5, 0xee, 0x01];
+        expected.extend_from_slice(&[0x12, 3, b'c', b'a', b'm']);
+        expected.extend_from_slice(&[0x1a, 3, 0, 0, 1]);
+        expected.extend_from_slice(&[0x22, 4, b'h', b'2', b'6', b'4']);
+        expected.extend_from_slice(&[0x2a, 5, b'f', b'r', b'o', b'n', b't']);
+        expected.extend_from_slice(&[0x31, 0, 0, 0, 0, 0, 0, 0xe0, 0x3f]);
+        expected.extend_from_slice(&[0x38, 1, 0x40, 3]);
+        assert_eq!(buffer, expected);
+
+        assert!(CustomSchema::parse(
+            SCHEMA,
+            "video.proto",
+            "acme.Video",
+            &["frame_id=data".parse().unwrap()]
+        )
+        .is_err());
+        assert!(CustomSchema::parse(SCHEMA, "video.proto", "acme.Missing", &[]).is_err());
+        assert!("=data".parse::<FieldMapping>().is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::{ConverterOptions, VideoConverter};
+use crate::timesync;
+
+/// Segments per worker, so a worker that finishes early picks up another
+/// instead of idling while the last ones are converted
+pub const SEGMENTS_PER_JOB: usize = 4;
+
+/// A run of the video from one keyframe up to the next segment's, in PTS of
+/// the source stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    /// Keyframe the segment starts at, `None` for the start of the stream
+    pub start: Option<i64>,
+    /// Start of the next segment, `None` for the end of the stream
+    pub end: Option<i64>,
+    /// The keyframe after `end`, up to which the packets are decoded. The
+    /// leading frames of an open GOP at `end` display before it but decode
+    /// after it, and belong to this segment.
+    pub decode_end: Option<i64>,
+}
+
+impl Segment {
+    fn contains(&self, pts: i64) -> bool {
+        self.start.is_none_or(|start| pts >= start) && self.end.is_none_or(|end| pts < end)
+    }
+
+    fn shifted(&self, ticks: i64) -> Self {
+        Self {
+            start: self.start.map(|start| start + ticks),
+            end: self.end.map(|end| end + ticks),
+            decode_end: self.decode_end.map(|end| end + ticks),
+        }
+    }
+}
+
+/// A packet from the encoder of a segment
+pub struct EncodedFrame {
+    pub pts: Option<i64>,
+    pub dts: Option<i64>,
+    pub keyframe: bool,
+    pub data: Bytes,
+}
+
+impl EncodedFrame {
+    fn from_packet(packet: &ffmpeg::Packet) -> Self {
+        Self {
+            pts: packet.pts(),
+            dts: packet.dts(),
+            keyframe: packet.is_key(),
+            data: packet
+                .data()
+                .map(Bytes::copy_from_slice)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// A packet with the timestamps and flags but not the data, which is
+    /// written from `data` as is
+    pub fn to_packet(&self) -> ffmpeg::Packet {
+        let mut packet = ffmpeg::Packet::empty();
+        packet.set_pts(self.pts);
+        packet.set_dts(self.dts);
+        if self.keyframe {
+            packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        }
+        packet
+    }
+}
+
+/// The number of workers for `--jobs`, where 0 is one per CPU core
+pub fn job_count(jobs: usize) -> usize {
+    match jobs {
+        0 => std::thread::available_parallelism().map_or(1, usize::from),
+        jobs => jobs,
+    }
+}
+
+/// PTS of the keyframes of the video stream, read without decoding
+pub fn keyframes(path: &Path, stream_index: usize) -> Result<Vec<i64>, Box<dyn Error>> {
+    let mut input = ffmpeg::format::input(path)?;
+    let mut keyframes: Vec<i64> = input
+        .packets()
+        .filter(|(stream, packet)| stream.index() == stream_index && packet.is_key())
+        .filter_map(|(_, packet)| packet.pts().or(packet.dts()))
+        .collect();
+    keyframes.sort_unstable();
+    keyframes.dedup();
+    Ok(keyframes)
+}
+
+/// Splits the stream into up to `count` segments of about the same number
+/// of keyframes, each starting at a keyframe
+pub fn plan(keyframes: &[i64], count: usize) -> Vec<Segment> {
+    let count = count.clamp(1, keyframes.len().max(1));
+    let mut segments = Vec::with_capacity(count);
+    let mut start = None;
+    for i in 1..count {
+        let next = i * keyframes.len() / count;
+        segments.push(Segment {
+            start,
+            end: Some(keyframes[next]),
+            decode_end: keyframes.get(next + 1).copied(),
+        });
+        start = Some(keyframes[next]);
+    }
+    segments.push(Segment {
+        start,
+        end: None,
+        decode_end: None,
+    });
+    segments
+}
+
+/// Re-encodes one segment with a converter of its own. The packets are read
+/// through the GOP after the end, so the frames that display before the end
+/// but decode after its keyframe, like the leading frames of an open GOP,
+/// are still in. The next segment drops its own leading frames, which
+/// reference the GOP before its start.
+fn convert_segment(
+    path: &Path,
+    options: &ConverterOptions,
+    stream_index: usize,
+    segment: Segment,
+    time_offset_ns: Option<i64>,
+) -> Result<Vec<EncodedFrame>, Box<dyn Error>> {
+    let (mut converter, mut input) = VideoConverter::new(path, Some(stream_index), options)?;
+    let time_base = input
+        .stream(stream_index)
+        .ok_or(ffmpeg::Error::StreamNotFound)?
+        .time_base();
+    if let Some(start) = segment.start {
+        // Lands on the keyframe at or before it, in AV_TIME_BASE units
+        let timestamp = (start as f64 * time_base.numerator() as f64 * 1_000_000.0
+            / time_base.denominator() as f64)
+            .floor() as i64;
+        input.seek(timestamp, ..timestamp)?;
+    }
+    let ticks = time_offset_ns.map_or(0, |offset_ns| timesync::offset_ticks(time_base, offset_ns));
+    // The frames are moved by the edit list pauses and then the offset, and
+    // so are the bounds they're compared to
+    let shifted = Segment {
+        start: segment.start.map(|start| converter.with_edit_gaps(start)),
+        end: segment.end.map(|end| converter.with_edit_gaps(end)),
+        decode_end: segment.decode_end,
+    }
+    .shifted(ticks);
+
+    let mut frames = Vec::new();
+    let mut frame = ffmpeg::frame::Video::empty();
+    for (stream, mut packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        let decode_ts = packet.dts().or(packet.pts());
+        if segment
+            .decode_end
+            .is_some_and(|end| decode_ts.is_some_and(|ts| ts >= end))
+        {
+            break;
+        }
+        converter.apply_edit_gaps(&mut packet);
+        if let Some(offset_ns) = time_offset_ns {
+            timesync::shift_packet(&mut packet, time_base, offset_ns);
+        }
+        if converter.process_packet(&packet)?.is_none() {
+            continue;
+        }
+        converter.send_packet(&packet)?;
+        encode_frames(&mut converter, &mut frame, shifted, &mut frames)?;
+    }
+    converter.send_eof()?;
+    encode_frames(&mut converter, &mut frame, shifted, &mut frames)?;
+    converter.send_encoder_eof()?;
+    receive_encoded(&mut converter, &mut frames)?;
+    Ok(frames)
+}
+
+/// Encodes the decoded frames that belong to the segment
+fn encode_frames(
+    converter: &mut VideoConverter,
+    frame: &mut ffmpeg::frame::Video,
+    segment: Segment,
+    frames: &mut Vec<EncodedFrame>,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        match converter.receive_frame(frame) {
+            Ok(_) => {
+                let pts = frame.timestamp().or(frame.pts()).unwrap_or(0);
+                if segment.contains(pts) {
+                    converter.encode_frame(frame)?;
+                    receive_encoded(converter, frames)?;
+                }
+            }
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn receive_encoded(
+    converter: &mut VideoConverter,
+    frames: &mut Vec<EncodedFrame>,
+) -> Result<(), Box<dyn Error>> {
+    let mut packet = ffmpeg::Packet::empty();
+    loop {
+        match converter.receive_encoded(&mut packet) {
+            Ok(_) => frames.push(EncodedFrame::from_packet(&packet)),
+            Err(ffmpeg::Error::Other {
+                errno: ffmpeg::error::EAGAIN,
+            })
+            | Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Re-encodes the segments on `jobs` threads and passes the frames to
+/// `write` in stream order, each segment as soon as those before it are done
+pub fn convert(
+    path: &Path,
+    options: &ConverterOptions,
+    stream_index: usize,
+    segments: &[Segment],
+    jobs: usize,
+    time_offset_ns: Option<i64>,
+    mut write: impl FnMut(EncodedFrame) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(segments.len()) {
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&segment) = segments.get(index) else {
+                    return;
+                };
+                let result = convert_segment(path, options, stream_index, segment, time_offset_ns)
+                    .map_err(|e| e.to_string());
+                let failed = result.is_err();
+                if sender.send((index, result)).is_err() || failed {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        // Segments finish out of order and wait here for those before them
+        let mut done = BTreeMap::new();
+        let mut written = 0;
+        for (index, result) in receiver {
+            done.insert(index, result);
+            while let Some(result) = done.remove(&written) {
+                let frames = result.map_err(|e| {
+                    // Workers stop at the next segment instead of finishing all
+                    next.store(segments.len(), Ordering::Relaxed);
+                    format!("Segment {} of {}: {}", written + 1, segments.len(), e)
+                })?;
+                for frame in frames {
+                    write(frame)?;
+                }
+                written += 1;
+            }
+        }
+        if written < segments.len() {
+            return Err(format!(
+                "Segment {} of {} was not converted",
+                written + 1,
+                segments.len()
+            )
+            .into());
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let keyframes: Vec<i64> = (0..10).map(|i| i * 30).collect();
+        assert_eq!(
+            plan(&keyframes, 3),
+            [
+                Segment {
+                    start: None,
+                    end: Some(90),
+                    decode_end: Some(120)
+                },
+                Segment {
+                    start: Some(90),
+                    end: Some(180),
+                    decode_end: Some(210)
+                },
+                Segment {
+                    start: Some(180),
+                    end: None,
+                    decode_end: None
+                },
+            ]
+        );
+        // No more segments than keyframes
+        assert_eq!(plan(&[0, 30], 8).len(), 2);
+        assert_eq!(
+            plan(&[], 4),
+            [Segment {
+                start: None,
+                end: None,
+                decode_end: None
+            }]
+        );
+
+        // The last keyframe ends the decoding of the segment before it at
+        // the end of the stream
+        assert_eq!(plan(&[0, 30], 2)[0].decode_end, None);
+
+        let segment = Segment {
+            start: Some(90),
+            end: Some(180),
+            decode_end: Some(210),
+        };
+        assert!(segment.contains(90) && segment.contains(179));
+        assert!(!segment.contains(89) && !segment.contains(180));
+    }
+}
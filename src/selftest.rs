@@ -0,0 +1,213 @@
+use std::error::Error;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+use mcap::{MessageStream, Summary};
+use prost::Message;
+
+use crate::codec::{CodecType, ParameterSets};
+use crate::foxglove::CompressedVideo;
+use crate::nal;
+use crate::verify::DecodeVerifier;
+
+/// Size, rate and length of the fixtures, small enough to take a moment
+const WIDTH: u32 = 160;
+const HEIGHT: u32 = 120;
+const FPS: i32 = 25;
+const FRAMES: usize = 50;
+/// Frames between keyframes, for a second keyframe with its parameter sets
+const GOP: u32 = 25;
+
+/// A gradient that moves by a pixel per frame, for a stream that isn't all
+/// skipped blocks
+fn fill_frame(frame: &mut ffmpeg::frame::Video, index: usize) {
+    let stride = frame.stride(0);
+    for (y, row) in frame.data_mut(0).chunks_mut(stride).enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = ((x + y + index) % 256) as u8;
+        }
+    }
+    for plane in 1..3 {
+        frame.data_mut(plane).fill(128);
+    }
+}
+
+/// Encodes the fixture to an MP4 with the encoder of the build
+pub fn write_fixture(path: &Path, codec: CodecType) -> Result<(), Box<dyn Error>> {
+    let encoder_codec = ffmpeg::encoder::find_by_name(codec.encoder_lib())
+        .ok_or_else(|| format!("No {} encoder in this FFmpeg", codec.encoder_lib()))?;
+    let time_base = ffmpeg::Rational::new(1, FPS);
+    let mut output = ffmpeg::format::output(path)?;
+    let mut encoder = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
+        .encoder()
+        .video()?;
+    encoder.set_width(WIDTH);
+    encoder.set_height(HEIGHT);
+    encoder.set_format(ffmpeg::format::Pixel::YUV420P);
+    encoder.set_time_base(time_base);
+    encoder.set_frame_rate(Some(ffmpeg::Rational::new(FPS, 1)));
+    encoder.set_max_b_frames(0);
+    encoder.set_gop(GOP);
+    // MP4 keeps the parameter sets in the sample description
+    encoder.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+    let mut options = ffmpeg::Dictionary::new();
+    if codec == CodecType::H265 {
+        options.set("x265-params", "log-level=error");
+    }
+    let mut encoder = encoder.open_with(options)?;
+
+    let mut stream = output.add_stream(encoder_codec)?;
+    stream.set_parameters(&encoder);
+    stream.set_time_base(time_base);
+    let stream_index = stream.index();
+    output.write_header()?;
+    let stream_time_base = output
+        .stream(stream_index)
+        .ok_or(ffmpeg::Error::StreamNotFound)?
+        .time_base();
+
+    let mut frame = ffmpeg::frame::Video::new(ffmpeg::format::Pixel::YUV420P, WIDTH, HEIGHT);
+    let mut packet = ffmpeg::Packet::empty();
+    let mut write_packets =
+        |encoder: &mut ffmpeg::encoder::Video, output: &mut ffmpeg::format::context::Output| loop {
+            match encoder.receive_packet(&mut packet) {
+                Ok(()) => {
+                    packet.set_stream(stream_index);
+                    packet.rescale_ts(time_base, stream_time_base);
+                    packet.write_interleaved(output)?;
+                }
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        };
+    for index in 0..FRAMES {
+        fill_frame(&mut frame, index);
+        frame.set_pts(Some(index as i64));
+        encoder.send_frame(&frame)?;
+        write_packets(&mut encoder, &mut output)?;
+    }
+    encoder.send_eof()?;
+    write_packets(&mut encoder, &mut output)?;
+    output.write_trailer()?;
+    Ok(())
+}
+
+/// Checks the MCAP converted from a fixture: one video channel in the
+/// summary with every frame in order, in the codec of the fixture and all
+/// decoding, and the parameter sets on the first. Returns what was checked.
+pub fn check_output(mcap: &[u8], codec: CodecType) -> Result<String, Box<dyn Error>> {
+    let summary = Summary::read(mcap)?.ok_or("The output has no summary")?;
+    let channels: Vec<_> = summary
+        .channels
+        .values()
+        .filter(|channel| {
+            channel
+                .schema
+                .as_ref()
+                .is_some_and(|schema| schema.name == "foxglove.CompressedVideo")
+        })
+        .collect();
+    let [channel] = channels[..] else {
+        return Err(format!(
+            "Expected one foxglove.CompressedVideo channel, found {}",
+            channels.len()
+        )
+        .into());
+    };
+
+    let mut verifier = DecodeVerifier::new(codec)?;
+    let mut frames = 0;
+    let mut keyframes = 0;
+    let mut last_time = None;
+    for message in MessageStream::new(mcap)? {
+        let message = message?;
+        if message.channel.topic != channel.topic {
+            continue;
+        }
+        let video = CompressedVideo::decode(&message.data[..])?;
+        if video.format != codec.format_str() {
+            return Err(format!(
+                "Frame {} is {:?}, expected {}",
+                frames,
+                video.format,
+                codec.format_str()
+            )
+            .into());
+        }
+        if last_time.is_some_and(|last_time| message.log_time <= last_time) {
+            return Err(format!("Frame {} is not after the one before", frames).into());
+        }
+        last_time = Some(message.log_time);
+        let keyframe = ParameterSets::from_nal_units(&nal::split_annex_b(&video.data), codec)
+            .validate(codec)
+            .is_ok();
+        if frames == 0 && !keyframe {
+            return Err("The first frame has no parameter sets".into());
+        }
+        keyframes += usize::from(keyframe);
+        verifier.add(&video.data, keyframe);
+        frames += 1;
+    }
+    if frames != FRAMES {
+        return Err(format!(
+            "Expected {} frames on {}, found {}",
+            FRAMES, channel.topic, frames
+        )
+        .into());
+    }
+    let (_, failures) = verifier.finish();
+    if let Some(failure) = failures.first() {
+        return Err(format!(
+            "{} frames did not decode, the first is frame {}: {}",
+            failures.len(),
+            failure.frame,
+            failure.reason
+        )
+        .into());
+    }
+    Ok(format!(
+        "{} frames on {}, {} with parameter sets, all decode",
+        frames, channel.topic, keyframes
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use mcap::{Channel, Schema, WriteOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_check_output() {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut writer = WriteOptions::new().create(&mut buffer).unwrap();
+        let channel = Arc::new(Channel {
+            topic: "video".to_string(),
+            schema: Some(Arc::new(Schema {
+                name: "foxglove.CompressedImage".to_string(),
+                encoding: "protobuf".to_string(),
+                data: Cow::Borrowed(&[]),
+            })),
+            message_encoding: "protobuf".to_string(),
+            metadata: BTreeMap::new(),
+        });
+        writer.add_channel(&channel).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+
+        let error = check_output(buffer.get_ref(), CodecType::H264).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected one foxglove.CompressedVideo channel, found 0"
+        );
+        assert!(check_output(b"not an mcap", CodecType::H264).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::nal;
+
+/// What an H.264 or H.265 sequence parameter set says about the pictures
+#[derive(Debug, PartialEq)]
+pub struct SpsInfo {
+    /// Size of the pictures in pixels, after cropping
+    pub width: u32,
+    pub height: u32,
+    /// Width and height ratio of a pixel, from the VUI
+    pub sample_aspect_ratio: Option<(u32, u32)>,
+}
+
+/// Sample aspect ratios of the `aspect_ratio_idc` values below 255
+const ASPECT_RATIOS: [(u32, u32); 17] = [
+    (0, 0),
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+/// Reads the bits of an RBSP, most significant first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bit(&mut self) -> Option<bool> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = byte >> (7 - self.pos % 8) & 1;
+        self.pos += 1;
+        Some(bit == 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some(value << 1 | self.bit()? as u32))
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.pos += count;
+    }
+
+    /// Exp-Golomb coded unsigned integer
+    fn ue(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        while !self.bit()? {
+            zeros += 1;
+            if zeros > 31 {
+                return None;
+            }
+        }
+        Some((1u64 << zeros) as u32 - 1 + self.bits(zeros)?)
+    }
+
+    /// Exp-Golomb coded signed integer
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()? as i64;
+        Some(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        } as i32)
+    }
+
+    /// `aspect_ratio_info` at the start of the VUI, shared by H.264 and H.265
+    fn aspect_ratio(&mut self) -> Option<Option<(u32, u32)>> {
+        if !self.bit()? {
+            return Some(None);
+        }
+        let idc = self.bits(8)?;
+        Some(match idc {
+            255 => Some((self.bits(16)?, self.bits(16)?)).filter(|&(w, h)| w > 0 && h > 0),
+            idc => ASPECT_RATIOS
+                .get(idc as usize)
+                .copied()
+                .filter(|&(w, _)| w > 0),
+        })
+    }
+}
+
+/// Subsampling of the chroma planes as (SubWidthC, SubHeightC)
+fn chroma_subsampling(chroma_format_idc: u32) -> (u32, u32) {
+    match chroma_format_idc {
+        1 => (2, 2),
+        2 => (2, 1),
+        _ => (1, 1),
+    }
+}
+
+/// The size left of `size` after cropping `start` and `end` units of `unit`
+/// samples, or None if the crop overflows or leaves nothing
+fn cropped(size: u32, unit: u32, start: u32, end: u32) -> Option<u32> {
+    let crop = start.checked_add(end)?.checked_mul(unit)?;
+    size.checked_sub(crop).filter(|&size| size > 0)
+}
+
+/// The RBSP of the first SPS in Annex B data, without its NAL unit header
+fn rbsp(sps: &[u8], header_size: usize) -> Option<Vec<u8>> {
+    let nal = nal::split_annex_b(sps).into_iter().next()?;
+    Some(nal::unescape(nal.get(header_size..)?))
+}
+
+/// Parses the first H.264 SPS of Annex B data
+pub fn parse_h264(sps: &[u8]) -> Option<SpsInfo> {
+    let rbsp = rbsp(sps, 1)?;
+    let mut r = BitReader::new(&rbsp);
+    let profile_idc = r.bits(8)?;
+    r.skip(16); // constraint flags and level_idc
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_planes = false;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_planes = r.bit()?;
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.skip(1); // qpprime_y_zero_transform_bypass_flag
+        if r.bit()? {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if r.bit()? {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8, 8);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + r.se()? as i64).rem_euclid(256);
+                        }
+                        if next != 0 {
+                            last = next;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.skip(1); // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    r.ue()?; // max_num_ref_frames
+    r.skip(1); // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = r.ue()?.checked_add(1)?;
+    let height_in_map_units = r.ue()?.checked_add(1)?;
+    let frame_mbs_only = r.bit()?;
+    if !frame_mbs_only {
+        r.skip(1); // mb_adaptive_frame_field_flag
+    }
+    r.skip(1); // direct_8x8_inference_flag
+    let mut crop = [0; 4];
+    if r.bit()? {
+        for value in &mut crop {
+            *value = r.ue()?;
+        }
+    }
+
+    let field_factor = if frame_mbs_only { 1 } else { 2 };
+    let (crop_x, crop_y) = if chroma_format_idc == 0 || separate_colour_planes {
+        (1, field_factor)
+    } else {
+        let (sub_width, sub_height) = chroma_subsampling(chroma_format_idc);
+        (sub_width, sub_height * field_factor)
+    };
+    let width = cropped(width_in_mbs.checked_mul(16)?, crop_x, crop[0], crop[1])?;
+    let height = cropped(
+        height_in_map_units.checked_mul(16 * field_factor)?,
+        crop_y,
+        crop[2],
+        crop[3],
+    )?;
+
+    // The VUI is optional, and its absence or truncation leaves the size valid
+    let sample_aspect_ratio = match r.bit() {
+        Some(true) => r.aspect_ratio().flatten(),
+        _ => None,
+    };
+    Some(SpsInfo {
+        width,
+        height,
+        sample_aspect_ratio,
+    })
+}
+
+/// Skips a `profile_tier_level` structure of an H.265 SPS
+fn skip_profile_tier_level(r: &mut BitReader, max_sub_layers_minus1: u32) -> Option<()> {
+    // General profile space, tier, profile, compatibility and constraint
+    // flags, then the level
+    r.skip(96);
+    let mut sub_layers = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        sub_layers.push((r.bit()?, r.bit()?));
+    }
+    if max_sub_layers_minus1 > 0 {
+        r.skip(2 * (8 - max_sub_layers_minus1 as usize));
+    }
+    for (profile_present, level_present) in sub_layers {
+        if profile_present {
+            r.skip(88);
+        }
+        if level_present {
+            r.skip(8);
+        }
+    }
+    Some(())
+}
+
+/// Skips the `scaling_list_data` of an H.265 SPS
+fn skip_scaling_list_data(r: &mut BitReader) -> Option<()> {
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+        for _ in (0..6).step_by(step) {
+            if !r.bit()? {
+                r.ue()?; // scaling_list_pred_matrix_id_delta
+                continue;
+            }
+            if size_id > 1 {
+                r.se()?; // scaling_list_dc_coef_minus8
+            }
+            for _ in 0..64.min(1 << (4 + (size_id << 1))) {
+                r.se()?; // scaling_list_delta_coef
+            }
+        }
+    }
+    Some(())
+}
+
+/// Skips the short-term reference picture sets of an H.265 SPS
+fn skip_st_ref_pic_sets(r: &mut BitReader, count: u32) -> Option<()> {
+    // Number of delta POCs of each set, which a predicted set refers to
+    let mut delta_pocs: Vec<u32> = Vec::new();
+    for index in 0..count {
+        let inter_ref_pic_set_prediction = index != 0 && r.bit()?;
+        if inter_ref_pic_set_prediction {
+            r.skip(1); // delta_rps_sign
+            r.ue()?; // abs_delta_rps_minus1
+            let mut used = 0;
+            for _ in 0..=*delta_pocs.last()? {
+                let used_by_curr_pic = r.bit()?;
+                if used_by_curr_pic || r.bit()? {
+                    used += 1;
+                }
+            }
+            delta_pocs.push(used);
+        } else {
+            let negative = r.ue()?;
+            let positive = r.ue()?;
+            for _ in 0..negative + positive {
+                r.ue()?; // delta_poc_s0/s1_minus1
+                r.skip(1); // used_by_curr_pic_s0/s1_flag
+            }
+            delta_pocs.push(negative + positive);
+        }
+    }
+    Some(())
+}
+
+/// Parses the first H.265 SPS of Annex B data
+pub fn parse_h265(sps: &[u8]) -> Option<SpsInfo> {
+    let rbsp = rbsp(sps, 2)?;
+    let mut r = BitReader::new(&rbsp);
+    r.skip(4); // sps_video_parameter_set_id
+    let max_sub_layers_minus1 = r.bits(3)?;
+    r.skip(1); // sps_temporal_id_nesting_flag
+    skip_profile_tier_level(&mut r, max_sub_layers_minus1)?;
+    r.ue()?; // sps_seq_parameter_set_id
+    let chroma_format_idc = r.ue()?;
+    let separate_colour_planes = chroma_format_idc == 3 && r.bit()?;
+    let mut width = r.ue()?;
+    let mut height = r.ue()?;
+    if r.bit()? {
+        let (sub_width, sub_height) = if separate_colour_planes {
+            (1, 1)
+        } else {
+            chroma_subsampling(chroma_format_idc)
+        };
+        let (left, right, top, bottom) = (r.ue()?, r.ue()?, r.ue()?, r.ue()?);
+        width = cropped(width, sub_width, left, right)?;
+        height = cropped(height, sub_height, top, bottom)?;
+    }
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    // Everything up to the VUI has to be walked to reach the aspect ratio
+    let sample_aspect_ratio = (|| {
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        let log2_max_pic_order_cnt_lsb = r.ue()?.checked_add(4)?;
+        let sub_layer_ordering_info_present = r.bit()?;
+        let first = if sub_layer_ordering_info_present {
+            0
+        } else {
+            max_sub_layers_minus1
+        };
+        for _ in first..=max_sub_layers_minus1 {
+            r.ue()?; // sps_max_dec_pic_buffering_minus1
+            r.ue()?; // sps_max_num_reorder_pics
+            r.ue()?; // sps_max_latency_increase_plus1
+        }
+        for _ in 0..6 {
+            r.ue()?; // coding and transform block sizes and hierarchy depths
+        }
+        if r.bit()? && r.bit()? {
+            skip_scaling_list_data(&mut r)?;
+        }
+        r.skip(2); // amp_enabled_flag, sample_adaptive_offset_enabled_flag
+        if r.bit()? {
+            r.skip(8); // pcm_sample_bit_depth_luma/chroma_minus1
+            r.ue()?; // log2_min_pcm_luma_coding_block_size_minus3
+            r.ue()?; // log2_diff_max_min_pcm_luma_coding_block_size
+            r.skip(1); // pcm_loop_filter_disabled_flag
+        }
+        let st_ref_pic_sets = r.ue()?;
+        skip_st_ref_pic_sets(&mut r, st_ref_pic_sets)?;
+        if r.bit()? {
+            for _ in 0..r.ue()? {
+                r.skip(log2_max_pic_order_cnt_lsb as usize + 1);
+            }
+        }
+        r.skip(2); // sps_temporal_mvp_enabled_flag, strong_intra_smoothing_enabled_flag
+        if r.bit()? {
+            r.aspect_ratio()?
+        } else {
+            None
+        }
+    })();
+
+    Some(SpsInfo {
+        width,
+        height,
+        sample_aspect_ratio,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_h264() {
+        // 1920x1080 High profile, cropped from 1088, with a 4:3 sample aspect ratio
+        let sps = [
+            0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0xFF,
+            0xC0, 0x01, 0x00, 0x00, 0xC0, 0x20,
+        ];
+        assert_eq!(
+            parse_h264(&sps),
+            Some(SpsInfo {
+                width: 1920,
+                height: 1080,
+                sample_aspect_ratio: Some((4, 3)),
+            })
+        );
+        assert_eq!(parse_h264(&[0, 0, 1, 0x67, 0x42]), None);
+    }
+
+    #[test]
+    fn test_parse_h265() {
+        // 1280x720 Main profile, cropped from 736, with a predicted reference
+        // picture set and a 12:11 sample aspect ratio
+        let sps = [
+            0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x03, 0x00, 0x5D, 0xA0, 0x02, 0x80, 0x80, 0x2E, 0x1F, 0x13, 0x96,
+            0x57, 0x92, 0x44, 0x99, 0xAF, 0x6B, 0xC0, 0x88,
+        ];
+        assert_eq!(
+            parse_h265(&sps),
+            Some(SpsInfo {
+                width: 1280,
+                height: 720,
+                sample_aspect_ratio: Some((12, 11)),
+            })
+        );
+    }
+
+    #[test]
+    fn test_cropped() {
+        assert_eq!(cropped(1088, 2, 0, 4), Some(1080));
+        assert_eq!(cropped(16, 2, 4, 4), None);
+        assert_eq!(cropped(16, 2, u32::MAX, 1), None);
+        assert_eq!(cropped(16, 2, u32::MAX / 2, 0), None);
+    }
+
+    #[test]
+    fn test_exp_golomb() {
+        // 1, 010, 011, 00100, 00101
+        let data = [0b1010_0110, 0b0100_0010, 0b1000_0000];
+        let mut r = BitReader::new(&data);
+        assert_eq!(r.ue(), Some(0));
+        assert_eq!(r.ue(), Some(1));
+        assert_eq!(r.se(), Some(-1));
+        assert_eq!(r.ue(), Some(3));
+        assert_eq!(r.se(), Some(-2));
+    }
+}
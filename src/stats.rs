@@ -0,0 +1,319 @@
+use std::collections::BTreeMap;
+
+use crate::codec::CodecType;
+
+/// What the conversion saw of the bitstream, for diagnosing playback issues
+#[derive(Default)]
+pub struct Stats {
+    /// Kept and skipped counts per NAL unit type
+    nal_units: BTreeMap<u8, (usize, usize)>,
+    keyframes: usize,
+    /// Size in bytes of every written frame
+    frame_sizes: Vec<usize>,
+}
+
+/// Frame sizes in bytes
+#[derive(Debug, PartialEq)]
+pub struct SizeDistribution {
+    pub min: usize,
+    pub mean: f64,
+    pub p50: usize,
+    pub p90: usize,
+    pub p99: usize,
+    pub max: usize,
+}
+
+impl Stats {
+    pub fn record_nal_unit(&mut self, nal_type: u8, kept: bool) {
+        let (kept_count, skipped_count) = self.nal_units.entry(nal_type).or_default();
+        if kept {
+            *kept_count += 1;
+        } else {
+            *skipped_count += 1;
+        }
+    }
+
+    pub fn record_frame(&mut self, size: usize, is_key: bool) {
+        self.frame_sizes.push(size);
+        if is_key {
+            self.keyframes += 1;
+        }
+    }
+
+    /// Average number of frames per keyframe
+    pub fn mean_gop_length(&self) -> Option<f64> {
+        (self.keyframes > 0).then(|| self.frame_sizes.len() as f64 / self.keyframes as f64)
+    }
+
+    pub fn frame_sizes(&self) -> Option<SizeDistribution> {
+        let mut sizes = self.frame_sizes.clone();
+        sizes.sort_unstable();
+        let percentile = |p: usize| sizes[(sizes.len() - 1) * p / 100];
+        Some(SizeDistribution {
+            min: *sizes.first()?,
+            mean: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: *sizes.last()?,
+        })
+    }
+
+    pub fn summary(&self, codec: CodecType) -> Vec<String> {
+        let mut lines = Vec::new();
+        if !self.nal_units.is_empty() {
+            lines.push("NAL units (kept/skipped):".to_string());
+            for (&nal_type, (kept, skipped)) in &self.nal_units {
+                lines.push(format!(
+                    "  {:>2} {:<16} {}/{}",
+                    nal_type,
+                    codec.nal_type_name(nal_type).unwrap_or("other"),
+                    kept,
+                    skipped
+                ));
+            }
+        }
+        lines.push(format!(
+            "Frames: {}, keyframes: {}",
+            self.frame_sizes.len(),
+            self.keyframes
+        ));
+        if let Some(gop) = self.mean_gop_length() {
+            lines.push(format!("Mean GOP length: {:.1} frames", gop));
+        }
+        if let Some(sizes) = self.frame_sizes() {
+            lines.push(format!(
+                "Frame size: min {} / mean {:.0} / p50 {} / p90 {} / p99 {} / max {} bytes",
+                sizes.min, sizes.mean, sizes.p50, sizes.p90, sizes.p99, sizes.max
+            ));
+        }
+        lines
+    }
+
+    pub fn to_json(&self, codec: CodecType) -> String {
+        let nal_units: Vec<String> = self
+            .nal_units
+            .iter()
+            .map(|(&nal_type, (kept, skipped))| {
+                format!(
+                    "{{\"type\":{},\"name\":{},\"kept\":{},\"skipped\":{}}}",
+                    nal_type,
+                    codec
+                        .nal_type_name(nal_type)
+                        .map_or("null".to_string(), |name| format!("\"{}\"", name)),
+                    kept,
+                    skipped
+                )
+            })
+            .collect();
+        let frame_size = self.frame_sizes().map_or("null".to_string(), |sizes| {
+            format!(
+                "{{\"min\":{},\"mean\":{:.1},\"p50\":{},\"p90\":{},\"p99\":{},\"max\":{}}}",
+                sizes.min, sizes.mean, sizes.p50, sizes.p90, sizes.p99, sizes.max
+            )
+        });
+        format!(
+            "{{\"nal_units\":[{}],\"frames\":{},\"keyframes\":{},\"mean_gop_length\":{},\"frame_size\":{}}}",
+            nal_units.join(","),
+            self.frame_sizes.len(),
+            self.keyframes,
+            self.mean_gop_length()
+                .map_or("null".to_string(), |gop| format!("{:.2}", gop)),
+            frame_size
+        )
+    }
+}
+
+/// Sums up the bytes of the written frames per second of video, starting at
+/// the first frame
+#[derive(Default)]
+pub struct BitrateWindows {
+    /// Start of the current window and its bytes so far
+    current: Option<(u64, u64)>,
+}
+
+impl BitrateWindows {
+    pub const WINDOW_NS: u64 = 1_000_000_000;
+
+    /// Adds a frame and returns the windows before it that are now complete,
+    /// as (start, bytes), with empty ones for gaps in the video
+    pub fn add(&mut self, timestamp_ns: u64, bytes: usize) -> Vec<(u64, u64)> {
+        let mut complete = Vec::new();
+        let (start, total) = self.current.get_or_insert((timestamp_ns, 0));
+        while timestamp_ns >= *start + Self::WINDOW_NS {
+            complete.push((*start, *total));
+            *start += Self::WINDOW_NS;
+            *total = 0;
+        }
+        *total += bytes as u64;
+        complete
+    }
+
+    /// The last window, which covers less than a second unless the video
+    /// happens to end on a window boundary
+    pub fn finish(&mut self) -> Option<(u64, u64)> {
+        self.current.take()
+    }
+}
+
+/// A stretch of video where frames are missing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameGap {
+    /// Timestamp of the last frame before the gap
+    pub start_ns: u64,
+    /// Time to the next frame
+    pub duration_ns: u64,
+    /// Number of frames the nominal frame rate would have had in between
+    pub missing: u64,
+}
+
+/// Finds camera dropouts, where consecutive frames are further apart than
+/// the nominal frame interval
+pub struct FrameGaps {
+    interval_ns: u64,
+    last_ns: Option<u64>,
+    gaps: Vec<FrameGap>,
+}
+
+impl FrameGaps {
+    /// Frames further apart than this many intervals count as a gap, which
+    /// leaves room for jitter in the timestamps
+    const TOLERANCE: f64 = 1.5;
+
+    pub fn new(interval_ns: u64) -> Self {
+        Self {
+            interval_ns,
+            last_ns: None,
+            gaps: Vec::new(),
+        }
+    }
+
+    /// Adds the timestamp of a frame in presentation order and returns the
+    /// gap before it, if any
+    pub fn add(&mut self, timestamp_ns: u64) -> Option<FrameGap> {
+        if self.last_ns.is_some_and(|last_ns| timestamp_ns <= last_ns) {
+            return None;
+        }
+        let last_ns = self.last_ns.replace(timestamp_ns)?;
+        let duration_ns = timestamp_ns - last_ns;
+        if (duration_ns as f64) <= self.interval_ns as f64 * Self::TOLERANCE {
+            return None;
+        }
+        let gap = FrameGap {
+            start_ns: last_ns,
+            duration_ns,
+            missing: (duration_ns as f64 / self.interval_ns as f64).round() as u64 - 1,
+        };
+        self.gaps.push(gap);
+        Some(gap)
+    }
+
+    /// A line for the gaps overall, then the longest ones
+    pub fn summary(&self) -> Vec<String> {
+        if self.gaps.is_empty() {
+            return Vec::new();
+        }
+        let missing: u64 = self.gaps.iter().map(|gap| gap.missing).sum();
+        let total_ns: u64 = self.gaps.iter().map(|gap| gap.duration_ns).sum();
+        let mut lines = vec![format!(
+            "Detected {} frame gaps with {} missing frames, {:.3}s in total",
+            self.gaps.len(),
+            missing,
+            total_ns as f64 / 1e9
+        )];
+        let mut longest: Vec<&FrameGap> = self.gaps.iter().collect();
+        longest.sort_by_key(|gap| std::cmp::Reverse(gap.duration_ns));
+        for gap in longest.iter().take(10) {
+            lines.push(format!(
+                "  {:.3}s after {:.3}s",
+                gap.duration_ns as f64 / 1e9,
+                gap.start_ns as f64 / 1e9
+            ));
+        }
+        if longest.len() > 10 {
+            lines.push(format!("  and {} shorter ones", longest.len() - 10));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.frame_sizes(), None);
+        assert_eq!(stats.mean_gop_length(), None);
+
+        for size in 1..=10 {
+            stats.record_frame(size * 100, size % 5 == 1);
+        }
+        stats.record_nal_unit(5, true);
+        stats.record_nal_unit(7, false);
+        stats.record_nal_unit(7, false);
+
+        assert_eq!(stats.mean_gop_length(), Some(5.0));
+        assert_eq!(
+            stats.frame_sizes(),
+            Some(SizeDistribution {
+                min: 100,
+                mean: 550.0,
+                p50: 500,
+                p90: 900,
+                p99: 900,
+                max: 1000,
+            })
+        );
+        assert_eq!(
+            stats.to_json(CodecType::H264),
+            "{\"nal_units\":[{\"type\":5,\"name\":\"IDR slice\",\"kept\":1,\"skipped\":0},\
+            {\"type\":7,\"name\":\"SPS\",\"kept\":0,\"skipped\":2}],\"frames\":10,\
+            \"keyframes\":2,\"mean_gop_length\":5.00,\"frame_size\":{\"min\":100,\
+            \"mean\":550.0,\"p50\":500,\"p90\":900,\"p99\":900,\"max\":1000}}"
+        );
+    }
+
+    #[test]
+    fn test_bitrate_windows() {
+        let mut windows = BitrateWindows::default();
+        assert_eq!(windows.add(500_000_000, 100), []);
+        assert_eq!(windows.add(1_000_000_000, 50), []);
+        assert_eq!(windows.add(1_499_999_999, 25), []);
+        // A gap in the video leaves empty windows
+        assert_eq!(
+            windows.add(3_600_000_000, 10),
+            [(500_000_000, 175), (1_500_000_000, 0), (2_500_000_000, 0)]
+        );
+        assert_eq!(windows.finish(), Some((3_500_000_000, 10)));
+        assert_eq!(windows.finish(), None);
+    }
+
+    #[test]
+    fn test_frame_gaps() {
+        // 25 fps with some jitter, then four frames missing
+        let mut gaps = FrameGaps::new(40_000_000);
+        assert_eq!(gaps.add(0), None);
+        assert_eq!(gaps.add(45_000_000), None);
+        assert_eq!(gaps.add(80_000_000), None);
+        assert_eq!(
+            gaps.add(280_000_000),
+            Some(FrameGap {
+                start_ns: 80_000_000,
+                duration_ns: 200_000_000,
+                missing: 4,
+            })
+        );
+        // Out of order timestamps are skipped
+        assert_eq!(gaps.add(100_000_000), None);
+        assert_eq!(gaps.add(320_000_000), None);
+        assert_eq!(
+            gaps.summary(),
+            [
+                "Detected 1 frame gaps with 4 missing frames, 0.200s in total",
+                "  0.200s after 0.080s"
+            ]
+        );
+    }
+}
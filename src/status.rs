@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::http;
+use crate::logging;
+use crate::progress;
+use crate::summary::json_string;
+
+/// Where the conversion is, as the progress last saw it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Log time of the last frame written
+    pub timestamp_ns: u64,
+    pub frames: u64,
+    pub bytes_in: u64,
+    pub fraction: Option<f64>,
+    pub elapsed: Duration,
+    pub done: bool,
+}
+
+static SNAPSHOT: Mutex<Option<Snapshot>> = Mutex::new(None);
+
+pub fn publish(snapshot: Snapshot) {
+    *SNAPSHOT.lock().unwrap_or_else(|e| e.into_inner()) = Some(snapshot);
+}
+
+fn optional(value: Option<f64>, precision: usize) -> String {
+    value.map_or("null".to_string(), |value| {
+        format!("{:.*}", precision, value)
+    })
+}
+
+fn to_json(
+    input: &Path,
+    output: &Path,
+    snapshot: Option<&Snapshot>,
+    bytes_out: u64,
+    warnings: &[String],
+) -> String {
+    let state = match snapshot {
+        None => "starting",
+        Some(snapshot) if snapshot.done => "done",
+        Some(_) => "converting",
+    };
+    let fraction = snapshot
+        .and_then(|snapshot| snapshot.fraction)
+        .map(|fraction| fraction.clamp(0.0, 1.0));
+    let elapsed = snapshot.map_or(Duration::ZERO, |snapshot| snapshot.elapsed);
+    let timestamp = snapshot.map_or("null".to_string(), |snapshot| {
+        snapshot.timestamp_ns.to_string()
+    });
+    format!(
+        "{{\"state\":\"{}\",\"input\":{},\"output\":{},\"timestamp\":{},\"frames\":{},\
+        \"bytes_in\":{},\"bytes_out\":{},\"percent\":{},\"elapsed_seconds\":{:.1},\
+        \"eta_seconds\":{},\"warnings\":[{}]}}",
+        state,
+        json_string(&input.to_string_lossy()),
+        json_string(&output.to_string_lossy()),
+        timestamp,
+        snapshot.map_or(0, |snapshot| snapshot.frames),
+        snapshot.map_or(0, |snapshot| snapshot.bytes_in),
+        bytes_out,
+        optional(fraction.map(|fraction| fraction * 100.0), 2),
+        elapsed.as_secs_f64(),
+        optional(
+            fraction
+                .and_then(|fraction| progress::eta(fraction, elapsed))
+                .map(|eta| eta.as_secs_f64()),
+            1
+        ),
+        warnings
+            .iter()
+            .map(|warning| json_string(warning))
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// The address to listen on for `--status-addr`, where a port alone is on
+/// the loopback interface, as the status shows paths and warnings
+fn listen_address(address: &str) -> String {
+    match address.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => address.to_string(),
+    }
+}
+
+/// Serves the status of the conversion as JSON on `address`, from a thread
+/// that runs until the process exits
+pub fn serve(address: &str, input: PathBuf, output: PathBuf) -> Result<(), Box<dyn Error>> {
+    let address = http::serve(
+        &listen_address(address),
+        "status",
+        Box::new(move |path| {
+            matches!(path, "/" | "/status").then(|| {
+                let snapshot = SNAPSHOT.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let body = to_json(
+                    &input,
+                    &output,
+                    snapshot.as_ref(),
+                    std::fs::metadata(&output).map_or(0, |m| m.len()),
+                    &logging::recent_warnings(),
+                );
+                ("application/json", body)
+            })
+        }),
+    )?;
+    info!("Serving the status on http://{}/status", address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_address() {
+        assert_eq!(listen_address("8080"), "127.0.0.1:8080");
+        assert_eq!(listen_address("0.0.0.0:8080"), "0.0.0.0:8080");
+        assert_eq!(listen_address("[::1]:8080"), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let snapshot = Snapshot {
+            timestamp_ns: 1_500_000_000,
+            frames: 45,
+            bytes_in: 1_000_000,
+            fraction: Some(0.125),
+            elapsed: Duration::from_secs(10),
+            done: false,
+        };
+        assert_eq!(
+            to_json(
+                Path::new("in.mp4"),
+                Path::new("out.mcap"),
+                Some(&snapshot),
+                900_000,
+                &["non-IDR start: \"x\"".to_string()]
+            ),
+            "{\"state\":\"converting\",\"input\":\"in.mp4\",\"output\":\"out.mcap\",\
+            \"timestamp\":1500000000,\"frames\":45,\"bytes_in\":1000000,\"bytes_out\":900000,\
+            \"percent\":12.50,\"elapsed_seconds\":10.0,\"eta_seconds\":70.0,\
+            \"warnings\":[\"non-IDR start: \\\"x\\\"\"]}"
+        );
+        assert!(
+            to_json(Path::new("in.mp4"), Path::new("out.mcap"), None, 0, &[]).starts_with(
+                "{\"state\":\"starting\",\"input\":\"in.mp4\",\"output\":\"out.mcap\",\
+                \"timestamp\":null"
+            )
+        );
+    }
+}
@@ -0,0 +1,45 @@
+/// How the two views of a stereo rig share a frame, with --stereo-split
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StereoLayout {
+    /// Left view in the left half, right view in the right half
+    SideBySide,
+}
+
+impl std::str::FromStr for StereoLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sbs" => Ok(StereoLayout::SideBySide),
+            other => Err(format!(
+                "Unsupported stereo layout {:?}, expected sbs",
+                other
+            )),
+        }
+    }
+}
+
+impl StereoLayout {
+    /// The topic suffix and crop filter of each view
+    pub fn views(&self) -> [(&'static str, &'static str); 2] {
+        match self {
+            StereoLayout::SideBySide => [
+                ("left", "crop=iw/2:ih:0:0"),
+                ("right", "crop=iw/2:ih:iw/2:0"),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_views() {
+        let layout: StereoLayout = "sbs".parse().unwrap();
+        assert_eq!(layout.views()[0], ("left", "crop=iw/2:ih:0:0"));
+        assert_eq!(layout.views()[1].0, "right");
+        assert!("tab".parse::<StereoLayout>().is_err());
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::BTreeMap;
+
+use ffmpeg_next as ffmpeg;
+
+use ffmpeg::codec::packet::side_data::{SideData, Type};
+use ffmpeg::media;
+
+use crate::failure::Failure;
+
+/// Returns the payload of the stream-level side data of the given kind
+pub fn coded_side_data(params: &ffmpeg::codec::Parameters, kind: Type) -> Option<Vec<u8>> {
+    unsafe {
+        let ptr = params.as_ptr();
+        for i in 0..(*ptr).nb_coded_side_data.max(0) as usize {
+            let side_data = SideData::wrap((*ptr).coded_side_data.add(i));
+            if side_data.kind() == kind {
+                return Some(side_data.data().to_vec());
+            }
+        }
+    }
+    None
+}
+
+/// Width and height of a video stream in pixels
+pub fn dimensions(params: &ffmpeg::codec::Parameters) -> (u32, u32) {
+    unsafe {
+        let ptr = params.as_ptr();
+        ((*ptr).width as u32, (*ptr).height as u32)
+    }
+}
+
+/// Nominal time between frames from the average or base frame rate, if the
+/// container has either
+pub fn frame_interval_ns(stream: &ffmpeg::format::stream::Stream) -> Option<u64> {
+    let mut frame_rate = stream.avg_frame_rate();
+    if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+        frame_rate = stream.rate();
+    }
+    if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+        return None;
+    }
+    Some((frame_rate.denominator() as f64 * 1e9 / frame_rate.numerator() as f64) as u64)
+}
+
+pub fn field_order(params: &ffmpeg::codec::Parameters) -> ffmpeg::FieldOrder {
+    unsafe { ffmpeg::FieldOrder::from((*params.as_ptr()).field_order) }
+}
+
+pub fn is_interlaced(field_order: ffmpeg::FieldOrder) -> bool {
+    matches!(
+        field_order,
+        ffmpeg::FieldOrder::TT
+            | ffmpeg::FieldOrder::BB
+            | ffmpeg::FieldOrder::TB
+            | ffmpeg::FieldOrder::BT
+    )
+}
+
+pub fn color_transfer(params: &ffmpeg::codec::Parameters) -> ffmpeg::color::TransferCharacteristic {
+    unsafe { ffmpeg::color::TransferCharacteristic::from((*params.as_ptr()).color_trc) }
+}
+
+/// How the colors of a video stream are encoded, as tagged by the container
+/// or the codec
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Colors {
+    pub primaries: ffmpeg::color::Primaries,
+    pub transfer: ffmpeg::color::TransferCharacteristic,
+    pub matrix: ffmpeg::color::Space,
+    pub range: ffmpeg::color::Range,
+}
+
+impl Colors {
+    /// What --tonemap sdr converts to
+    pub const BT709: Colors = Colors {
+        primaries: ffmpeg::color::Primaries::BT709,
+        transfer: ffmpeg::color::TransferCharacteristic::BT709,
+        matrix: ffmpeg::color::Space::BT709,
+        range: ffmpeg::color::Range::MPEG,
+    };
+
+    pub fn of(params: &ffmpeg::codec::Parameters) -> Self {
+        unsafe {
+            let ptr = params.as_ptr();
+            Self {
+                primaries: ffmpeg::color::Primaries::from((*ptr).color_primaries),
+                transfer: color_transfer(params),
+                matrix: ffmpeg::color::Space::from((*ptr).color_space),
+                range: ffmpeg::color::Range::from((*ptr).color_range),
+            }
+        }
+    }
+
+    /// Channel metadata for the tagged properties, by their FFmpeg names like
+    /// bt709, smpte170m or bt470bg, with the range as limited or full
+    pub fn metadata(&self) -> Vec<(&'static str, String)> {
+        use ffmpeg::color::{Primaries, Range, Space, TransferCharacteristic};
+        let mut metadata = Vec::new();
+        if self.primaries != Primaries::Unspecified {
+            metadata.extend(self.primaries.name().map(|name| ("color_primaries", name)));
+        }
+        if self.transfer != TransferCharacteristic::Unspecified {
+            metadata.extend(self.transfer.name().map(|name| ("color_transfer", name)));
+        }
+        if self.matrix != Space::Unspecified {
+            metadata.extend(self.matrix.name().map(|name| ("color_matrix", name)));
+        }
+        match self.range {
+            Range::MPEG => metadata.push(("color_range", "limited")),
+            Range::JPEG => metadata.push(("color_range", "full")),
+            Range::Unspecified => {}
+        }
+        metadata
+            .into_iter()
+            .map(|(key, value)| (key, value.to_string()))
+            .collect()
+    }
+}
+
+/// Whether the transfer characteristic is one of the HDR curves, PQ (HDR10)
+/// or HLG
+pub fn is_hdr_transfer(transfer: ffmpeg::color::TransferCharacteristic) -> bool {
+    matches!(
+        transfer,
+        ffmpeg::color::TransferCharacteristic::SMPTE2084
+            | ffmpeg::color::TransferCharacteristic::ARIB_STD_B67
+    )
+}
+
+/// Bits per sample of the first component of a pixel format
+pub fn bit_depth(format: ffmpeg::format::Pixel) -> Option<u8> {
+    let descriptor = format.descriptor()?;
+    unsafe { Some((*descriptor.as_ptr()).comp[0].depth as u8) }
+}
+
+/// Whether frames of a pixel format are 4:2:0 with at most 10 bits per
+/// sample, as in the Main10 and High10 profiles, which hardware and browser
+/// decoders commonly handle, unlike 4:2:2, 4:4:4 or 12-bit video
+pub fn is_widely_decodable(format: ffmpeg::format::Pixel) -> Option<bool> {
+    let descriptor = format.descriptor()?;
+    Some(
+        bit_depth(format)? <= 10
+            && descriptor.log2_chroma_w() == 1
+            && descriptor.log2_chroma_h() == 1,
+    )
+}
+
+/// Name of an H.264 or H.265 profile, e.g. `Main10`
+pub fn profile_name(profile: ffmpeg::codec::Profile) -> Option<String> {
+    match profile {
+        ffmpeg::codec::Profile::H264(profile) => Some(format!("{:?}", profile)),
+        ffmpeg::codec::Profile::HEVC(profile) => Some(format!("{:?}", profile)),
+        _ => None,
+    }
+}
+
+/// Reads the `AVRational` at `index` of a side data payload as a float
+fn rational(data: &[u8], index: usize) -> Option<f64> {
+    let field = |offset: usize| {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(i32::from_ne_bytes(bytes.try_into().ok()?) as f64)
+    };
+    let numerator = field(index * 8)?;
+    let denominator = field(index * 8 + 4)?;
+    (denominator != 0.0).then(|| numerator / denominator)
+}
+
+/// Channel metadata for an `AVMasteringDisplayMetadata` payload: the
+/// chromaticities of the primaries and white point, and the luminance range
+/// in cd/m²
+pub fn mastering_display_metadata(data: &[u8]) -> Vec<(&'static str, String)> {
+    let flag = |index: usize| {
+        data.get(index * 4..index * 4 + 4)
+            .is_some_and(|bytes| bytes != [0; 4])
+    };
+    let point = |index: usize| {
+        Some(format!(
+            "{:.4},{:.4}",
+            rational(data, index)?,
+            rational(data, index + 1)?
+        ))
+    };
+
+    let mut metadata = Vec::new();
+    // Ten rationals are followed by the has_primaries and has_luminance flags
+    if flag(20) {
+        let points: Option<Vec<_>> = [0, 2, 4, 6].into_iter().map(point).collect();
+        let primaries = points.map(|points| {
+            format!(
+                "r={} g={} b={} wp={}",
+                points[0], points[1], points[2], points[3]
+            )
+        });
+        if let Some(primaries) = primaries {
+            metadata.push(("mastering_display_primaries", primaries));
+        }
+    }
+    if flag(21) {
+        if let (Some(min), Some(max)) = (rational(data, 8), rational(data, 9)) {
+            metadata.push((
+                "mastering_display_luminance",
+                format!("min={:.4} max={:.4}", min, max),
+            ));
+        }
+    }
+    metadata
+}
+
+/// Channel metadata for an `AVContentLightMetadata` payload
+pub fn content_light_metadata(data: &[u8]) -> Vec<(&'static str, String)> {
+    let value = |index: usize| {
+        let bytes = data.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+    };
+    match (value(0), value(1)) {
+        (Some(max_cll), Some(max_fall)) => vec![
+            ("max_cll", max_cll.to_string()),
+            ("max_fall", max_fall.to_string()),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Counter-clockwise rotation in degrees encoded in a 3x3 display matrix,
+/// mirroring `av_display_rotation_get`
+pub fn display_rotation(matrix: &[u8]) -> Option<f64> {
+    if matrix.len() < 9 * 4 {
+        return None;
+    }
+    let value = |i: usize| {
+        i32::from_ne_bytes([
+            matrix[i * 4],
+            matrix[i * 4 + 1],
+            matrix[i * 4 + 2],
+            matrix[i * 4 + 3],
+        ]) as f64
+    };
+
+    let scale_x = value(0).hypot(value(3));
+    let scale_y = value(1).hypot(value(4));
+    if scale_x == 0.0 || scale_y == 0.0 {
+        return None;
+    }
+    let rotation = (value(1) / scale_y).atan2(value(0) / scale_x).to_degrees();
+    Some(-rotation)
+}
+
+/// Clockwise rotation needed to display the video upright, snapped to a
+/// multiple of 90 degrees
+pub fn upright_rotation(display_rotation: f64) -> u32 {
+    let quarter_turns = (-display_rotation / 90.0).round() as i64;
+    (quarter_turns.rem_euclid(4) * 90) as u32
+}
+
+/// Filters that bake a clockwise rotation into the frames
+pub fn rotation_filter(rotation: u32) -> Option<&'static str> {
+    match rotation {
+        90 => Some("transpose=clock"),
+        180 => Some("hflip,vflip"),
+        270 => Some("transpose=cclock"),
+        _ => None,
+    }
+}
+
+/// Parses an ISO 6709 location like `+37.7749-122.4194+010.000/`, as in
+/// the ©xyz atom, into latitude, longitude and the altitude if present
+pub fn parse_iso6709(location: &str) -> Option<(f64, f64, Option<f64>)> {
+    let location = location.trim().trim_end_matches('/');
+    // Split before every sign, the first one included
+    let mut values = Vec::new();
+    let mut start = 0;
+    for (i, c) in location.char_indices().skip(1) {
+        if c == '+' || c == '-' {
+            values.push(&location[start..i]);
+            start = i;
+        }
+    }
+    values.push(&location[start..]);
+
+    let mut numbers = values.iter().map(|value| value.parse::<f64>().ok());
+    let latitude = numbers.next()??;
+    let longitude = numbers.next()??;
+    let altitude = numbers.next().flatten();
+    ((-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude))
+        .then_some((latitude, longitude, altitude))
+}
+
+fn medium_name(medium: media::Type) -> &'static str {
+    match medium {
+        media::Type::Video => "video",
+        media::Type::Audio => "audio",
+        media::Type::Subtitle => "subtitle",
+        media::Type::Data => "data",
+        media::Type::Attachment => "attachment",
+        media::Type::Unknown => "unknown",
+    }
+}
+
+/// Why an input without a video stream can't be converted, from the kind
+/// and codec of the streams it has instead
+fn no_video_message(streams: &[(media::Type, &str)]) -> String {
+    if streams.is_empty() {
+        return "The input has no streams at all".to_string();
+    }
+    let found = streams
+        .iter()
+        .map(|(medium, codec)| format!("{} ({})", medium_name(*medium), codec))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if streams
+        .iter()
+        .all(|(medium, _)| *medium == media::Type::Audio)
+    {
+        format!(
+            "The input is audio-only, with no video stream to convert: {}",
+            found
+        )
+    } else {
+        format!("The input has no video stream, only {}", found)
+    }
+}
+
+/// The stream to convert, or a failure that says what the input has instead
+pub fn best_video(
+    input: &ffmpeg::format::context::Input,
+) -> Result<ffmpeg::format::stream::Stream<'_>, Failure> {
+    input.streams().best(media::Type::Video).ok_or_else(|| {
+        let streams = input
+            .streams()
+            .map(|stream| {
+                let params = stream.parameters();
+                (params.medium(), params.id().name())
+            })
+            .collect::<Vec<_>>();
+        Failure::new(
+            "no-video",
+            no_video_message(&streams),
+            "mp42mcap converts video only; pass an MP4 with a video track",
+        )
+    })
+}
+
+/// Length of the video, from the stream or else the container, if known
+pub fn duration_ns(
+    input: &ffmpeg::format::context::Input,
+    stream: &ffmpeg::format::stream::Stream,
+) -> Option<u64> {
+    let stream_ns = Some(stream.duration())
+        .filter(|&duration| duration > 0)
+        .map(|duration| crate::playlist::ticks_to_ns(duration, stream.time_base()) as u64);
+    // The container gives it in microseconds, or a negative value if unknown
+    stream_ns.or_else(|| {
+        u64::try_from(input.duration())
+            .ok()
+            .filter(|&duration| duration > 0)
+            .map(|duration| duration * 1000)
+    })
+}
+
+/// Container-level metadata of the input, like the udta and XMP tags, with
+/// the location also split into its coordinates
+pub fn container_metadata(input: &ffmpeg::format::context::Input) -> BTreeMap<String, String> {
+    let mut metadata: BTreeMap<String, String> = input
+        .metadata()
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let location = metadata
+        .iter()
+        .find(|(key, _)| *key == "location" || key.ends_with("location.ISO6709"))
+        .and_then(|(_, value)| parse_iso6709(value));
+    if let Some((latitude, longitude, altitude)) = location {
+        metadata.insert("location.latitude".to_string(), latitude.to_string());
+        metadata.insert("location.longitude".to_string(), longitude.to_string());
+        if let Some(altitude) = altitude {
+            metadata.insert("location.altitude".to_string(), altitude.to_string());
+        }
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(values: [i32; 9]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn test_display_rotation() {
+        let one = 1 << 16;
+        let identity = matrix([one, 0, 0, 0, one, 0, 0, 0, 1 << 30]);
+        assert_eq!(display_rotation(&identity), Some(0.0));
+
+        // What phones write for portrait recordings
+        let portrait = matrix([0, one, 0, -one, 0, 0, 0, 0, 1 << 30]);
+        assert_eq!(display_rotation(&portrait), Some(-90.0));
+        assert_eq!(upright_rotation(-90.0), 90);
+
+        assert_eq!(display_rotation(&[0; 8]), None);
+        assert_eq!(display_rotation(&matrix([0; 9])), None);
+    }
+
+    #[test]
+    fn test_hdr_metadata() {
+        // P3 primaries, D65 white point and 0.005-1000 cd/m² in the
+        // units HEVC SEI uses
+        let chromaticities = [34000, 16000, 8500, 39850, 6550, 2300, 15635, 16450];
+        let mut mastering: Vec<u8> = chromaticities
+            .iter()
+            .flat_map(|&value| [value, 50000])
+            .chain([50, 10000, 10000000, 10000, 1, 1])
+            .flat_map(|value: i32| value.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            mastering_display_metadata(&mastering),
+            vec![
+                (
+                    "mastering_display_primaries",
+                    "r=0.6800,0.3200 g=0.1700,0.7970 b=0.1310,0.0460 wp=0.3127,0.3290".to_string()
+                ),
+                (
+                    "mastering_display_luminance",
+                    "min=0.0050 max=1000.0000".to_string()
+                ),
+            ]
+        );
+        mastering[80..84].copy_from_slice(&0i32.to_ne_bytes());
+        assert_eq!(mastering_display_metadata(&mastering).len(), 1);
+
+        let light: Vec<u8> = [1000u32, 400]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        assert_eq!(
+            content_light_metadata(&light),
+            vec![
+                ("max_cll", "1000".to_string()),
+                ("max_fall", "400".to_string())
+            ]
+        );
+        assert!(content_light_metadata(&[0; 4]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_iso6709() {
+        assert_eq!(
+            parse_iso6709("+37.7749-122.4194+010.000/"),
+            Some((37.7749, -122.4194, Some(10.0)))
+        );
+        assert_eq!(
+            parse_iso6709("-33.8688+151.2093/"),
+            Some((-33.8688, 151.2093, None))
+        );
+        assert_eq!(parse_iso6709("+91.0+000.0/"), None);
+        assert_eq!(parse_iso6709("garbage"), None);
+    }
+
+    #[test]
+    fn test_is_interlaced() {
+        assert!(is_interlaced(ffmpeg::FieldOrder::TT));
+        assert!(is_interlaced(ffmpeg::FieldOrder::BT));
+        assert!(!is_interlaced(ffmpeg::FieldOrder::Progressive));
+        assert!(!is_interlaced(ffmpeg::FieldOrder::Unknown));
+    }
+
+    #[test]
+    fn test_no_video_message() {
+        assert_eq!(
+            no_video_message(&[(media::Type::Audio, "aac")]),
+            "The input is audio-only, with no video stream to convert: audio (aac)"
+        );
+        assert_eq!(
+            no_video_message(&[
+                (media::Type::Audio, "aac"),
+                (media::Type::Subtitle, "mov_text")
+            ]),
+            "The input has no video stream, only audio (aac), subtitle (mov_text)"
+        );
+        assert_eq!(no_video_message(&[]), "The input has no streams at all");
+    }
+
+    #[test]
+    fn test_profile_name() {
+        use ffmpeg::codec::profile::{Profile, HEVC, VP9};
+
+        assert_eq!(
+            profile_name(Profile::HEVC(HEVC::Main10)).as_deref(),
+            Some("Main10")
+        );
+        assert_eq!(profile_name(Profile::VP9(VP9::_0)), None);
+    }
+
+    #[test]
+    fn test_is_hdr_transfer() {
+        use ffmpeg::color::TransferCharacteristic;
+
+        assert!(is_hdr_transfer(TransferCharacteristic::SMPTE2084));
+        assert!(is_hdr_transfer(TransferCharacteristic::ARIB_STD_B67));
+        assert!(!is_hdr_transfer(TransferCharacteristic::BT709));
+        assert!(!is_hdr_transfer(TransferCharacteristic::Unspecified));
+    }
+
+    #[test]
+    fn test_upright_rotation() {
+        assert_eq!(upright_rotation(0.0), 0);
+        assert_eq!(upright_rotation(90.0), 270);
+        assert_eq!(upright_rotation(180.0), 180);
+        assert_eq!(upright_rotation(-180.0), 180);
+        assert_eq!(upright_rotation(-89.5), 90);
+        assert_eq!(rotation_filter(90), Some("transpose=clock"));
+        assert_eq!(rotation_filter(0), None);
+    }
+
+    #[test]
+    fn test_color_metadata() {
+        use ffmpeg::color::{Primaries, Range, Space, TransferCharacteristic};
+        let untagged = Colors {
+            primaries: Primaries::Unspecified,
+            transfer: TransferCharacteristic::Unspecified,
+            matrix: Space::Unspecified,
+            range: Range::Unspecified,
+        };
+        assert!(untagged.metadata().is_empty());
+        let full = Colors {
+            range: Range::JPEG,
+            ..untagged
+        };
+        assert_eq!(full.metadata(), vec![("color_range", "full".to_string())]);
+    }
+}
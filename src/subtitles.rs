@@ -0,0 +1,94 @@
+/// A subtitle of an SRT file
+#[derive(Debug, PartialEq)]
+pub struct Cue {
+    /// Start of the subtitle, relative to the start of the video
+    pub start_ns: u64,
+    /// The lines after the timing, as they are in the file
+    pub text: String,
+}
+
+/// Parses an `HH:MM:SS,mmm` subtitle time into nanoseconds
+fn parse_time(time: &str) -> Option<u64> {
+    let (hms, millis) = time.trim().split_once([',', '.'])?;
+    let mut seconds = 0;
+    for part in hms.split(':') {
+        seconds = seconds * 60 + part.parse::<u64>().ok()?;
+    }
+    Some(seconds * 1_000_000_000 + millis.parse::<u64>().ok()? * 1_000_000)
+}
+
+/// Removes HTML tags like `<font size="28">` and `<i>` from subtitle text
+pub fn strip_tags(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Parses the cues of an SRT file, skipping blocks without a valid timing
+pub fn parse_srt(text: &str) -> Vec<Cue> {
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    text.split("\n\n")
+        .filter_map(|block| {
+            let (before, after) = block.split_once("-->")?;
+            let (_end, text) = after.split_once('\n').unwrap_or((after, ""));
+            Some(Cue {
+                start_ns: parse_time(before.lines().last()?)?,
+                text: text.trim_end().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The text of a 3GPP timed text (tx3g, mov_text) sample, without its
+/// style boxes
+pub fn mov_text(data: &[u8]) -> Option<String> {
+    let length = u16::from_be_bytes([*data.first()?, *data.get(1)?]) as usize;
+    let text = data.get(2..2 + length)?;
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_srt() {
+        let srt =
+            "\u{feff}1\r\n00:00:01,500 --> 00:00:02,000\r\n<i>Takeoff</i>\r\nsecond line\r\n\r\n\
+            2\r\n01:00:00.250 --> 01:00:01.000\r\nLanding\r\n\r\n\
+            garbage\r\n";
+        assert_eq!(
+            parse_srt(srt),
+            [
+                Cue {
+                    start_ns: 1_500_000_000,
+                    text: "<i>Takeoff</i>\nsecond line".to_string(),
+                },
+                Cue {
+                    start_ns: 3_600_250_000_000,
+                    text: "Landing".to_string(),
+                },
+            ]
+        );
+        assert_eq!(strip_tags("<i>Takeoff</i>"), "Takeoff");
+    }
+
+    #[test]
+    fn test_mov_text() {
+        let mut sample = vec![0, 5];
+        sample.extend_from_slice(b"Hello");
+        // A style box
+        sample.extend_from_slice(&[0, 0, 0, 10, b's', b't', b'y', b'l', 0, 0]);
+        assert_eq!(mov_text(&sample), Some("Hello".to_string()));
+        assert_eq!(mov_text(&[0, 0]), Some(String::new()));
+        assert_eq!(mov_text(&[0, 5, b'H']), None);
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::PathBuf;
+
+/// A JSON string, with quotes and control characters escaped
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// What a conversion did, for batch pipelines to record
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionSummary {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// From the first to the last frame written
+    pub duration_ns: u64,
+    pub frames: u64,
+    pub keyframes: u64,
+    /// Frames that weren't written: duplicates, and packets before the first
+    /// keyframe
+    pub dropped_frames: u64,
+    /// Timestamps that were missing or out of order and repaired
+    pub fixed_timestamps: u64,
+    pub output_bytes: u64,
+    pub elapsed_seconds: f64,
+    /// Where the log times came from, like pts or creation-time
+    pub log_time_source: &'static str,
+}
+
+impl ConversionSummary {
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"input\":{},\"output\":{},\"duration_seconds\":{:.3},\"frames\":{},\
+            \"keyframes\":{},\"dropped_frames\":{},\"fixed_timestamps\":{},\
+            \"output_bytes\":{},\"elapsed_seconds\":{:.3},\"log_time_source\":{}}}",
+            json_string(&self.input.to_string_lossy()),
+            json_string(&self.output.to_string_lossy()),
+            self.duration_ns as f64 / 1e9,
+            self.frames,
+            self.keyframes,
+            self.dropped_frames,
+            self.fixed_timestamps,
+            self.output_bytes,
+            self.elapsed_seconds,
+            json_string(self.log_time_source)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            json_string("say \"hi\"\\\n\u{1}"),
+            "\"say \\\"hi\\\"\\\\\\n\\u0001\""
+        );
+        let summary = ConversionSummary {
+            input: PathBuf::from("in.mp4"),
+            output: PathBuf::from("out.mcap"),
+            duration_ns: 9_966_666_667,
+            frames: 300,
+            keyframes: 10,
+            dropped_frames: 2,
+            fixed_timestamps: 1,
+            output_bytes: 4_000_000,
+            elapsed_seconds: 1.25,
+            log_time_source: "pts",
+        };
+        assert_eq!(
+            summary.to_json(),
+            "{\"input\":\"in.mp4\",\"output\":\"out.mcap\",\"duration_seconds\":9.967,\
+            \"frames\":300,\"keyframes\":10,\"dropped_frames\":2,\"fixed_timestamps\":1,\
+            \"output_bytes\":4000000,\"elapsed_seconds\":1.250,\"log_time_source\":\"pts\"}"
+        );
+    }
+}
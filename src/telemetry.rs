@@ -0,0 +1,203 @@
+use crate::foxglove::{Pose, Quaternion, Vector3};
+use crate::subtitles::{self, strip_tags, Cue};
+
+/// Telemetry of one subtitle block of a DJI SRT sidecar file
+#[derive(Debug, Default, PartialEq)]
+pub struct TelemetrySample {
+    /// Start of the block, relative to the first video frame
+    pub offset_ns: u64,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Altitude above sea level in meters, or above the takeoff point when
+    /// the drone does not record the absolute one
+    pub altitude: Option<f64>,
+    /// Gimbal yaw, pitch and roll in degrees, yaw clockwise from north
+    pub gimbal: Option<[f64; 3]>,
+    pub iso: Option<u32>,
+}
+
+impl TelemetrySample {
+    /// Latitude, longitude and altitude, unless the drone had no GPS fix
+    pub fn position(&self) -> Option<(f64, f64, f64)> {
+        let (latitude, longitude) = (self.latitude?, self.longitude?);
+        // Drones write zeros until they have a fix
+        if latitude == 0.0 && longitude == 0.0 {
+            return None;
+        }
+        Some((latitude, longitude, self.altitude.unwrap_or(0.0)))
+    }
+
+    /// The gimbal orientation in an east-north-up frame, with the camera
+    /// looking along +x
+    pub fn gimbal_pose(&self) -> Option<Pose> {
+        let [yaw, pitch, roll] = self.gimbal?;
+        Some(pose_from_ned_angles(yaw, pitch, roll))
+    }
+}
+
+/// A pose at the origin of an east-north-up frame for aircraft angles in
+/// degrees, which follow the north-east-down convention: yaw clockwise from
+/// north, pitch up and roll right
+pub fn pose_from_ned_angles(yaw: f64, pitch: f64, roll: f64) -> Pose {
+    Pose {
+        position: Some(Vector3::default()),
+        orientation: Some(quaternion_from_euler(
+            (90.0 - yaw).to_radians(),
+            (-pitch).to_radians(),
+            roll.to_radians(),
+        )),
+    }
+}
+
+/// Rotation applying roll about x, then pitch about y, then yaw about z
+pub fn quaternion_from_euler(yaw: f64, pitch: f64, roll: f64) -> Quaternion {
+    let (sy, cy) = (yaw / 2.0).sin_cos();
+    let (sp, cp) = (pitch / 2.0).sin_cos();
+    let (sr, cr) = (roll / 2.0).sin_cos();
+    Quaternion {
+        x: sr * cp * cy - cr * sp * sy,
+        y: cr * sp * cy + sr * cp * sy,
+        z: cr * cp * sy - sr * sp * cy,
+        w: cr * cp * cy + sr * sp * sy,
+    }
+}
+
+/// The `key: value` pairs inside the brackets of a block, e.g.
+/// `[iso: 100] [rel_alt: 1.2 abs_alt: 30.5]`
+fn bracket_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    for group in text.split('[').skip(1) {
+        let group = group
+            .split(']')
+            .next()
+            .unwrap_or_default()
+            .replace(" :", ":");
+        let mut tokens = group.split_whitespace();
+        while let Some(token) = tokens.next() {
+            let Some((key, value)) = token.split_once(':') else {
+                continue;
+            };
+            let value = match value {
+                "" => tokens.next().unwrap_or_default(),
+                value => value,
+            };
+            fields.push((key.to_lowercase(), value.to_string()));
+        }
+    }
+    fields
+}
+
+fn parse_cue(cue: &Cue) -> TelemetrySample {
+    let mut sample = TelemetrySample {
+        offset_ns: cue.start_ns,
+        ..Default::default()
+    };
+    let text = strip_tags(&cue.text);
+
+    let mut relative_altitude = None;
+    let mut gimbal = [None; 3];
+    for (key, value) in bracket_fields(&text) {
+        let number = value.parse::<f64>().ok();
+        match key.as_str() {
+            "latitude" => sample.latitude = number,
+            // Older firmware misspells it
+            "longitude" | "longtitude" => sample.longitude = number,
+            "abs_alt" | "altitude" => sample.altitude = number,
+            "rel_alt" => relative_altitude = number,
+            "gb_yaw" => gimbal[0] = number,
+            "gb_pitch" => gimbal[1] = number,
+            "gb_roll" => gimbal[2] = number,
+            "iso" => sample.iso = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    // Phantom and Mavic Pro firmware write `GPS(lon,lat,alt)` instead
+    if let Some((_, gps)) = text.split_once("GPS") {
+        let gps = gps
+            .trim_start()
+            .strip_prefix('(')
+            .and_then(|gps| gps.split_once(')'));
+        if let Some((gps, _)) = gps {
+            let values: Vec<f64> = gps
+                .split(',')
+                .filter_map(|value| value.trim().trim_end_matches('M').parse().ok())
+                .collect();
+            if let [longitude, latitude, rest @ ..] = values.as_slice() {
+                sample.longitude = Some(*longitude);
+                sample.latitude = Some(*latitude);
+                sample.altitude = rest.first().copied().or(sample.altitude);
+            }
+        }
+    }
+
+    sample.altitude = sample.altitude.or(relative_altitude);
+    if let [Some(yaw), Some(pitch), Some(roll)] = gimbal {
+        sample.gimbal = Some([yaw, pitch, roll]);
+    }
+    sample
+}
+
+/// Parses the blocks of a DJI SRT file, skipping any that have no timing
+pub fn parse_srt(text: &str) -> Vec<TelemetrySample> {
+    subtitles::parse_srt(text).iter().map(parse_cue).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_srt() {
+        let srt = "1\r\n00:00:00,000 --> 00:00:00,033\r\n\
+            <font size=\"28\">FrameCnt: 1, DiffTime: 33ms\r\n\
+            2023-04-10 14:23:45.123\r\n\
+            [iso: 100] [shutter: 1/1000.0] [latitude: 37.123456] [longitude: -122.654321] \
+            [rel_alt: 50.000 abs_alt: 120.500] [gb_yaw: 90.0 gb_pitch: -90.0 gb_roll: 0.0] </font>\r\n\
+            \r\n\
+            2\r\n00:01:02,533 --> 00:01:02,566\r\n\
+            [latitude : 0.0] [longtitude : 0.0] [altitude: 12.0]\r\n\
+            \r\n\
+            3\r\n00:00:05,000 --> 00:00:06,000\r\n\
+            HOME(113.9,22.5) GPS(8.5,47.25,15) BAROMETER:1.9M\r\n";
+        let samples = parse_srt(srt);
+        assert_eq!(samples.len(), 3);
+
+        assert_eq!(
+            samples[0],
+            TelemetrySample {
+                offset_ns: 0,
+                latitude: Some(37.123456),
+                longitude: Some(-122.654321),
+                altitude: Some(120.5),
+                gimbal: Some([90.0, -90.0, 0.0]),
+                iso: Some(100),
+            }
+        );
+        assert_eq!(samples[1].offset_ns, 62_533_000_000);
+        assert_eq!(samples[1].altitude, Some(12.0));
+        assert_eq!(samples[1].position(), None);
+        assert_eq!(samples[2].position(), Some((47.25, 8.5, 15.0)));
+    }
+
+    #[test]
+    fn test_gimbal_pose() {
+        // Facing east and level is the identity in east-north-up
+        let sample = TelemetrySample {
+            gimbal: Some([90.0, 0.0, 0.0]),
+            ..Default::default()
+        };
+        let orientation = sample.gimbal_pose().unwrap().orientation.unwrap();
+        assert!((orientation.w - 1.0).abs() < 1e-9);
+
+        // Looking straight down rotates +x onto -z, a positive turn about y
+        let sample = TelemetrySample {
+            gimbal: Some([90.0, -90.0, 0.0]),
+            ..Default::default()
+        };
+        let orientation = sample.gimbal_pose().unwrap().orientation.unwrap();
+        let half = std::f64::consts::FRAC_1_SQRT_2;
+        assert!((orientation.y - half).abs() < 1e-9);
+        assert!((orientation.w - half).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+/// Bytes per second to stay under, from megabytes per second, with
+/// --limit-rate
+pub fn parse_rate(value: &str) -> Result<f64, String> {
+    let invalid = || {
+        format!(
+            "Invalid rate {:?}, expected megabytes per second like 20",
+            value
+        )
+    };
+    let megabytes: f64 = value.parse().map_err(|_| invalid())?;
+    if !(megabytes > 0.0 && megabytes.is_finite()) {
+        return Err(invalid());
+    }
+    Ok(megabytes * 1e6)
+}
+
+/// How long to wait for `bytes` to have taken `elapsed` at the most at
+/// `bytes_per_second`
+fn delay(bytes: u64, bytes_per_second: f64, elapsed: Duration) -> Duration {
+    Duration::from_secs_f64(bytes as f64 / bytes_per_second).saturating_sub(elapsed)
+}
+
+/// Paces reads or writes to an average rate, by sleeping once they get
+/// ahead of it
+pub struct RateLimit {
+    bytes_per_second: f64,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_second: f64) -> Self {
+        Self {
+            bytes_per_second,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` and waits until they are within the rate
+    pub fn pace(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        let delay = delay(self.bytes, self.bytes_per_second, self.start.elapsed());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Lowers the CPU priority of the process like nice(1), and with it the I/O
+/// priority the Linux schedulers derive from it. Call it before any threads
+/// are started, which inherit it.
+pub fn set_nice(niceness: i32) -> Result<(), String> {
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, niceness) };
+    if result != 0 {
+        return Err(format!(
+            "Could not set the niceness to {}: {}",
+            niceness,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        assert_eq!(parse_rate("20"), Ok(20e6));
+        assert_eq!(parse_rate("0.5"), Ok(500_000.0));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("fast").is_err());
+        // 10 MB at 5 MB/s takes 2 s
+        assert_eq!(
+            delay(10_000_000, 5e6, Duration::from_millis(500)),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(delay(1_000, 5e6, Duration::from_secs(1)), Duration::ZERO);
+    }
+}
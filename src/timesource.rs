@@ -0,0 +1,383 @@
+use std::error::Error;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::{self, CodecType, ParameterSets};
+use crate::timesync::{self, TimeSync};
+use crate::{nal, playlist, stream_info};
+
+/// Where the log times of the video messages come from, with
+/// --log-time-source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTimeSource {
+    /// The presentation timestamps of the container
+    Pts,
+    /// The decoding timestamps of the container
+    Dts,
+    /// The time each packet was read, for live inputs
+    Wall,
+    /// The PTS from the creation_time of the container
+    CreationTime,
+    /// The PTS from the SMPTE timecode of the first frame, on the day of the
+    /// creation_time
+    Timecode,
+    /// The PTS from a Unix time in an SEI user data message
+    Sei,
+    /// The times of a --timestamps file
+    Sidecar,
+}
+
+impl std::str::FromStr for LogTimeSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pts" => Ok(LogTimeSource::Pts),
+            "dts" => Ok(LogTimeSource::Dts),
+            "wall" => Ok(LogTimeSource::Wall),
+            "creation-time" => Ok(LogTimeSource::CreationTime),
+            "timecode" => Ok(LogTimeSource::Timecode),
+            "sei" => Ok(LogTimeSource::Sei),
+            "sidecar" => Ok(LogTimeSource::Sidecar),
+            other => Err(format!(
+                "Unsupported log time source {:?}, expected pts, dts, wall, creation-time, \
+                timecode, sei or sidecar",
+                other
+            )),
+        }
+    }
+}
+
+impl LogTimeSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogTimeSource::Pts => "pts",
+            LogTimeSource::Dts => "dts",
+            LogTimeSource::Wall => "wall",
+            LogTimeSource::CreationTime => "creation-time",
+            LogTimeSource::Timecode => "timecode",
+            LogTimeSource::Sei => "sei",
+            LogTimeSource::Sidecar => "sidecar",
+        }
+    }
+
+    /// Whether the source gives Unix times by itself, so there is nothing
+    /// for --qr-time-sync or --sync-to to offset
+    pub fn is_absolute(&self) -> bool {
+        !matches!(self, LogTimeSource::Pts | LogTimeSource::Dts)
+    }
+
+    /// Whether the source sets the timestamp of each packet, rather than
+    /// the offset of all of them
+    pub fn retimes_packets(&self) -> bool {
+        matches!(self, LogTimeSource::Dts | LogTimeSource::Wall)
+    }
+}
+
+/// Gives a packet of the video stream the time it was read, for
+/// --log-time-source wall. The DTS source leaves the packets as they are,
+/// for the decoder to reorder by their PTS, and logs each frame at the DTS
+/// of its packet when it's written.
+pub fn retime_packet(
+    source: LogTimeSource,
+    packet: &mut ffmpeg::Packet,
+    time_base: ffmpeg::Rational,
+) {
+    if source == LogTimeSource::Wall {
+        let now_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as i64);
+        let ticks = timesync::offset_ticks(time_base, now_ns);
+        packet.set_pts(Some(ticks));
+        packet.set_dts(Some(ticks));
+    }
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parses a creation_time like 2023-10-11T16:00:00.000000Z, in UTC, as
+/// nanoseconds since the Unix epoch
+pub fn parse_creation_time(text: &str) -> Option<u64> {
+    let text = text.trim().trim_end_matches('Z');
+    let (date, time) = text.split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-').map(str::parse::<u32>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hours, minutes, seconds) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 {
+        return None;
+    }
+    let digits: String = fraction.chars().take(9).collect();
+    let nanoseconds = if digits.is_empty() {
+        0
+    } else {
+        digits.parse::<u64>().ok()? * 10u64.pow(9 - digits.len() as u32)
+    };
+    let days = u64::try_from(days_from_civil(year as i64, month, day)).ok()?;
+    Some(
+        ((days * 24 + hours) * 60 + minutes) * 60_000_000_000
+            + seconds * 1_000_000_000
+            + nanoseconds,
+    )
+}
+
+/// Parses an SMPTE timecode like 01:02:03:04 as nanoseconds since midnight
+/// at `frame_ns` per frame. A drop frame timecode like 01:02:03;04 or
+/// 01:02:03.04 counts the frames of 29.97 or 59.94 fps video, leaving out
+/// the first 2 or 4 frame numbers of each minute not divisible by 10.
+pub fn parse_timecode(text: &str, frame_ns: u64) -> Option<u64> {
+    let text = text.trim();
+    let drop_frame = text.contains([';', '.']);
+    let fields: Vec<u64> = text
+        .split([':', ';', '.'])
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    let [hours, minutes, seconds, frames] = fields[..] else {
+        return None;
+    };
+    if hours > 23 || minutes > 59 || seconds > 59 || frame_ns == 0 {
+        return None;
+    }
+    let seconds = (hours * 60 + minutes) * 60 + seconds;
+    if !drop_frame {
+        return Some(seconds * 1_000_000_000 + frames * frame_ns);
+    }
+    // The frame numbers run at the nominal rate, 30 for 29.97 fps
+    let nominal_fps = (1_000_000_000 + frame_ns / 2) / frame_ns;
+    let dropped_per_minute = match nominal_fps {
+        30 => 2,
+        60 => 4,
+        _ => return None,
+    };
+    let total_minutes = hours * 60 + minutes;
+    if frames >= nominal_fps
+        || (seconds % 60 == 0 && minutes % 10 != 0 && frames < dropped_per_minute)
+    {
+        return None;
+    }
+    let frame_number =
+        seconds * nominal_fps + frames - dropped_per_minute * (total_minutes - total_minutes / 10);
+    Some(frame_number * frame_ns)
+}
+
+const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+
+/// A Unix time in the SEI user data messages of a NAL unit, written as text
+/// after the 16 byte UUID
+fn sei_time(nal_unit: &[u8], codec: CodecType) -> Option<u64> {
+    let header_size = match codec {
+        CodecType::H265 => 2,
+        _ => 1,
+    };
+    let header = *nal_unit.first()?;
+    let is_sei = codec
+        .nal_type(header)
+        .is_some_and(|nal_type| codec.is_sei(nal_type));
+    if !is_sei || nal_unit.len() <= header_size {
+        return None;
+    }
+    let rbsp = nal::unescape(&nal_unit[header_size..]);
+    nal::sei_messages(&rbsp)
+        .into_iter()
+        .filter(|(payload_type, payload)| {
+            *payload_type == SEI_USER_DATA_UNREGISTERED && payload.len() > 16
+        })
+        .find_map(|(_, payload)| timesync::parse_epoch_ns(&String::from_utf8_lossy(&payload[16..])))
+}
+
+/// Reads up to `max_packets` packets of the video stream, without decoding,
+/// for the first with a Unix time in its SEI
+pub fn scan_sei(path: &Path, max_packets: usize) -> Result<Option<TimeSync>, Box<dyn Error>> {
+    let mut input = ffmpeg::format::input(path)?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+    let params = stream.parameters();
+    let codec = CodecType::from_ffmpeg_id(params.id())?;
+    if codec == CodecType::Av1 {
+        return Err("AV1 has no SEI to take the log time from".into());
+    }
+    let length_size = codec::extract_extradata(&params)
+        .ok()
+        .filter(|extradata| !extradata.is_empty() && !nal::has_start_code(extradata))
+        .and_then(|extradata| ParameterSets::parse(extradata, codec).ok())
+        .map_or(4, |parameter_sets| parameter_sets.nal_length_size);
+
+    for (stream, packet) in input.packets().take(max_packets) {
+        if stream.index() != stream_index {
+            continue;
+        }
+        let Some(data) = packet.data() else {
+            continue;
+        };
+        let nal_units = if nal::has_start_code(data) {
+            nal::split_annex_b(data)
+        } else {
+            let mut nal_units = Vec::new();
+            let mut pos = 0;
+            while pos + length_size <= data.len() {
+                let size = data[pos..pos + length_size]
+                    .iter()
+                    .fold(0usize, |size, &byte| size << 8 | byte as usize);
+                pos += length_size;
+                nal_units.push(&data[pos..(pos + size).min(data.len())]);
+                pos += size;
+            }
+            nal_units
+        };
+        if let Some(epoch_ns) = nal_units
+            .iter()
+            .find_map(|nal_unit| sei_time(nal_unit, codec))
+        {
+            let pts = packet.pts().or(packet.dts()).unwrap_or(0).max(0);
+            let frame_ns = playlist::ticks_to_ns(pts, time_base) as u64;
+            return Ok(Some(TimeSync { frame_ns, epoch_ns }));
+        }
+    }
+    Ok(None)
+}
+
+/// The creation_time of the container, or of the video stream
+fn creation_time(
+    input: &ffmpeg::format::context::Input,
+    stream: &ffmpeg::format::stream::Stream,
+) -> Option<u64> {
+    input
+        .metadata()
+        .get("creation_time")
+        .and_then(parse_creation_time)
+        .or_else(|| {
+            stream
+                .metadata()
+                .get("creation_time")
+                .and_then(parse_creation_time)
+        })
+}
+
+/// The offset that makes the timestamps of the video absolute for the
+/// sources anchored at a time, or None for those that aren't
+pub fn anchor_offset_ns(
+    source: LogTimeSource,
+    path: &Path,
+    input: &ffmpeg::format::context::Input,
+    stream: &ffmpeg::format::stream::Stream,
+) -> Result<Option<i64>, Box<dyn Error>> {
+    let video_start_ns = timesync::video_start_ns(stream) as i64;
+    match source {
+        LogTimeSource::CreationTime => {
+            let creation_ns = creation_time(input, stream)
+                .ok_or("The input has no creation_time for --log-time-source creation-time")?;
+            Ok(Some(creation_ns as i64 - video_start_ns))
+        }
+        LogTimeSource::Timecode => {
+            // Set on the video stream, or on the tmcd track carrying it
+            let timecode = stream
+                .metadata()
+                .get("timecode")
+                .map(str::to_string)
+                .or_else(|| {
+                    input
+                        .streams()
+                        .find_map(|stream| stream.metadata().get("timecode").map(str::to_string))
+                })
+                .or_else(|| input.metadata().get("timecode").map(str::to_string))
+                .ok_or("The input has no timecode for --log-time-source timecode")?;
+            let frame_ns = stream_info::frame_interval_ns(stream)
+                .ok_or("The video has no frame rate to count the frames of its timecode in")?;
+            let time_of_day_ns = parse_timecode(&timecode, frame_ns)
+                .ok_or_else(|| format!("Invalid timecode {:?}", timecode))?;
+            // The timecode is a time of day, on the day the recording was
+            // created if the container says
+            let day_ns = creation_time(input, stream).map_or(0, |time| time - time % DAY_NS);
+            Ok(Some((day_ns + time_of_day_ns) as i64 - video_start_ns))
+        }
+        LogTimeSource::Sei => {
+            let sync = scan_sei(path, SEI_SCAN_PACKETS)?.ok_or_else(|| {
+                format!(
+                    "No SEI with a time found in the first {} packets for --log-time-source sei",
+                    SEI_SCAN_PACKETS
+                )
+            })?;
+            Ok(Some(sync.offset_ns()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Packets read from the start looking for an SEI time
+const SEI_SCAN_PACKETS: usize = 300;
+
+const DAY_NS: u64 = 86_400_000_000_000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_times() {
+        assert_eq!(
+            parse_creation_time("2023-10-11T16:00:00.000000Z"),
+            Some(1_697_040_000_000_000_000)
+        );
+        assert_eq!(
+            parse_creation_time("1970-01-02 00:00:01.5"),
+            Some(86_401_500_000_000)
+        );
+        assert_eq!(parse_creation_time("2023-13-01T00:00:00Z"), None);
+        assert_eq!(
+            parse_timecode("01:00:00:12", 40_000_000),
+            Some(3_600_480_000_000)
+        );
+        // At 29.97 fps, 108 frame numbers were dropped in the first hour,
+        // which keeps the drop frame timecode on the clock
+        assert_eq!(
+            parse_timecode("01:00:00;12", 33_366_667),
+            Some(3_600_396_835_968)
+        );
+        assert_eq!(
+            parse_timecode("01:00:00:12", 33_366_667),
+            Some(3_600_400_400_004)
+        );
+        assert_eq!(
+            parse_timecode("00:01:00;02", 33_366_667),
+            Some(60_060_000_600)
+        );
+        assert_eq!(parse_timecode("00:01:00;00", 33_366_667), None);
+        assert_eq!(
+            parse_timecode("00:10:00;00", 33_366_667),
+            Some(599_999_405_994)
+        );
+        assert_eq!(parse_timecode("01:00:00;12", 40_000_000), None);
+        assert_eq!(parse_timecode("25:00:00:00", 40_000_000), None);
+        assert_eq!("creation-time".parse(), Ok(LogTimeSource::CreationTime));
+        assert!(LogTimeSource::Wall.is_absolute());
+    }
+
+    #[test]
+    fn test_sei_time() {
+        let mut sei = vec![0x06, SEI_USER_DATA_UNREGISTERED as u8, 29];
+        sei.extend_from_slice(&[0xAB; 16]);
+        sei.extend_from_slice(b"1697040000123");
+        sei.push(0x80);
+        assert_eq!(
+            sei_time(&sei, CodecType::H264),
+            Some(1_697_040_000_123_000_000)
+        );
+        assert_eq!(sei_time(&[0x65, 0x88], CodecType::H264), None);
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::failure::Failure;
+use crate::json::{self, Value};
+use crate::stream_info;
+
+/// What the rows of a --timestamps file are keyed by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKey {
+    /// The index of the frame in presentation order, from 0
+    Index,
+    /// The PTS of the frame in the container
+    Pts,
+}
+
+/// Absolute timestamps of the frames, from a sidecar file written by the
+/// capture rig
+#[derive(Debug, PartialEq)]
+pub struct FrameTimestamps {
+    pub key: FrameKey,
+    pub timestamps: BTreeMap<i64, u64>,
+}
+
+impl FrameTimestamps {
+    /// Reads a CSV file, or JSON by its extension
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Failure::io(format!("Could not read {:?}: {}", path, e)))?;
+        let is_json = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("json"));
+        let timestamps = if is_json {
+            parse_json(&text)
+        } else {
+            parse_csv(&text)
+        };
+        let timestamps = timestamps.map_err(|e| {
+            Failure::invalid_file(format!("Invalid timestamps in {:?}: {}", path, e))
+        })?;
+        if timestamps.timestamps.is_empty() {
+            return Err(Failure::invalid_file(format!("No timestamps in {:?}", path)).into());
+        }
+        Ok(timestamps)
+    }
+
+    /// The timestamps by PTS, given the PTS of every frame
+    pub fn by_pts(self, frame_pts: &[i64]) -> BTreeMap<i64, u64> {
+        match self.key {
+            FrameKey::Pts => self.timestamps,
+            FrameKey::Index => self
+                .timestamps
+                .into_iter()
+                .filter_map(|(index, timestamp)| {
+                    let pts = frame_pts.get(usize::try_from(index).ok()?)?;
+                    Some((*pts, timestamp))
+                })
+                .collect(),
+        }
+    }
+}
+
+fn key_column(name: &str) -> Option<FrameKey> {
+    match name {
+        "frame" | "index" | "frame_index" => Some(FrameKey::Index),
+        "pts" => Some(FrameKey::Pts),
+        _ => None,
+    }
+}
+
+fn is_timestamp_column(name: &str) -> bool {
+    matches!(name, "timestamp" | "timestamp_ns" | "time_ns" | "stamp")
+}
+
+/// Rows of `frame,timestamp_ns`, or `pts,timestamp_ns` with a header naming
+/// the columns, or a timestamp alone per frame. Blank lines and lines
+/// starting with # are skipped.
+fn parse_csv(text: &str) -> Result<FrameTimestamps, String> {
+    let mut rows = text
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+
+    // Without a header, the timestamp is the last column and the frame the first
+    let mut key = FrameKey::Index;
+    let mut key_index = None;
+    let mut timestamp_index = None;
+    if let Some((_, header)) = rows.peek() {
+        let columns: Vec<String> = header
+            .split(',')
+            .map(|column| column.trim().to_ascii_lowercase())
+            .collect();
+        if columns.iter().any(|column| column.parse::<f64>().is_err()) {
+            for (index, column) in columns.iter().enumerate() {
+                if let Some(column_key) = key_column(column) {
+                    key = column_key;
+                    key_index = Some(index);
+                } else if is_timestamp_column(column) {
+                    timestamp_index = Some(index);
+                }
+            }
+            if timestamp_index.is_none() {
+                return Err(format!("no timestamp_ns column in the header {:?}", header));
+            }
+            rows.next();
+        }
+    }
+
+    let mut timestamps = BTreeMap::new();
+    for (row, (number, line)) in rows.enumerate() {
+        let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+        let column = |index: Option<usize>, default: usize| {
+            columns
+                .get(index.unwrap_or(default))
+                .ok_or_else(|| format!("line {} has too few columns", number))
+        };
+        let timestamp = column(timestamp_index, columns.len() - 1)?;
+        let timestamp = timestamp
+            .parse::<u64>()
+            .map_err(|_| format!("invalid timestamp {:?} on line {}", timestamp, number))?;
+        let frame = if key_index.is_none() && columns.len() == 1 {
+            row as i64
+        } else {
+            let frame = column(key_index, 0)?;
+            frame
+                .parse::<i64>()
+                .map_err(|_| format!("invalid frame {:?} on line {}", frame, number))?
+        };
+        timestamps.insert(frame, timestamp);
+    }
+    Ok(FrameTimestamps { key, timestamps })
+}
+
+/// An array of timestamps, one per frame, or an array of objects with a
+/// frame or pts and a timestamp_ns. Other fields of the objects are ignored.
+fn parse_json(text: &str) -> Result<FrameTimestamps, String> {
+    let Value::Array(rows) = json::parse(text)? else {
+        return Err("expected an array".to_string());
+    };
+    let mut key = FrameKey::Index;
+    let mut timestamps = BTreeMap::new();
+    for (frame, row) in rows.iter().enumerate() {
+        let (row_frame, timestamp) = match row {
+            Value::Number(_) => (None, row.number("timestamp")?),
+            Value::Object(fields) => {
+                let (mut row_frame, mut timestamp) = (None, None);
+                for (name, value) in fields {
+                    if let Some(column_key) = key_column(name) {
+                        key = column_key;
+                        row_frame = Some(value.number(name)?);
+                    } else if is_timestamp_column(name) {
+                        timestamp = Some(value.number("timestamp")?);
+                    }
+                }
+                (row_frame, timestamp.ok_or("an object has no timestamp_ns")?)
+            }
+            other => return Err(format!("unexpected {:?} in the array", other)),
+        };
+        timestamps.insert(row_frame.unwrap_or(frame as i64), timestamp);
+    }
+    Ok(FrameTimestamps { key, timestamps })
+}
+
+/// PTS of every frame of a video stream in presentation order, read
+/// without decoding, or of the best one if `stream_index` is None
+pub fn frame_pts(path: &Path, stream_index: Option<usize>) -> Result<Vec<i64>, Box<dyn Error>> {
+    let mut input = ffmpeg::format::input(path)?;
+    let stream_index = match stream_index {
+        Some(stream_index) => stream_index,
+        None => stream_info::best_video(&input)?.index(),
+    };
+    let mut pts: Vec<i64> = input
+        .packets()
+        .filter(|(stream, _)| stream.index() == stream_index)
+        .filter_map(|(_, packet)| packet.pts().or(packet.dts()))
+        .collect();
+    pts.sort_unstable();
+    pts.dedup();
+    Ok(pts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let timestamps = parse_csv("# exposures\nframe,timestamp_ns\n0,1000\n1,2000\n").unwrap();
+        assert_eq!(timestamps.key, FrameKey::Index);
+        assert_eq!(
+            timestamps.timestamps,
+            BTreeMap::from([(0, 1000), (1, 2000)])
+        );
+        let timestamps = parse_csv("timestamp_ns,pts\n1000,512\n2000,1024\n").unwrap();
+        assert_eq!(timestamps.key, FrameKey::Pts);
+        assert_eq!(
+            timestamps.by_pts(&[]),
+            BTreeMap::from([(512, 1000), (1024, 2000)])
+        );
+        let timestamps = parse_csv("1000\n2000\n").unwrap();
+        assert_eq!(
+            timestamps.timestamps,
+            BTreeMap::from([(0, 1000), (1, 2000)])
+        );
+        assert!(parse_csv("frame,time\n0,1\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let timestamps = parse_json("[1000, 2000]").unwrap();
+        assert_eq!(
+            timestamps.by_pts(&[20, 40]),
+            BTreeMap::from([(20, 1000), (40, 2000)])
+        );
+        let timestamps = parse_json(
+            r#"[{"pts": 512, "timestamp_ns": 1000}, {"pts": 1024, "timestamp_ns": 2000}]"#,
+        )
+        .unwrap();
+        assert_eq!(timestamps.key, FrameKey::Pts);
+        assert_eq!(timestamps.timestamps[&1024], 2000);
+        assert!(parse_json(r#"{"frames": []}"#).is_err());
+
+        // String fields other than the frame and timestamp are skipped
+        let timestamps = parse_json(
+            r#"[{"frame": 0, "label": "start", "timestamp_ns": 1000},
+                {"label": "x", "timestamp_ns": 2000, "exposure": 0.01}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            timestamps.timestamps,
+            BTreeMap::from([(0, 1000), (1, 2000)])
+        );
+        assert!(parse_json(r#"[{"frame": "a", "timestamp_ns": 1}]"#).is_err());
+        assert!(parse_json(r#"["1000"]"#).is_err());
+    }
+}
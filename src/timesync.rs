@@ -0,0 +1,263 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+use mcap::records::{Record, Statistics};
+
+use crate::{inject, playlist, qr, stream_info};
+
+/// A decoded frame showing the absolute time it was captured at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeSync {
+    /// Timestamp of the frame in the stream
+    pub frame_ns: u64,
+    /// Time in the QR code, in nanoseconds since the Unix epoch
+    pub epoch_ns: u64,
+}
+
+impl TimeSync {
+    /// What to add to the stream timestamps to make them absolute
+    pub fn offset_ns(&self) -> i64 {
+        self.epoch_ns as i64 - self.frame_ns as i64
+    }
+}
+
+/// Parses the first number in the text as a Unix time in seconds,
+/// milliseconds, microseconds or nanoseconds, told apart by the number of
+/// integer digits
+pub fn parse_epoch_ns(text: &str) -> Option<u64> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let number: String = text[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let (integer, fraction) = number.split_once('.').unwrap_or((&number, ""));
+    let scale = match integer.len() {
+        9..=11 => 9,
+        12..=14 => 6,
+        15..=17 => 3,
+        18..=20 => 0,
+        _ => return None,
+    };
+    let fraction: String = fraction
+        .chars()
+        .filter(char::is_ascii_digit)
+        .chain(std::iter::repeat('0'))
+        .take(scale)
+        .collect();
+    let integer: u64 = integer.parse().ok()?;
+    let fraction: u64 = if scale > 0 { fraction.parse().ok()? } else { 0 };
+    integer
+        .checked_mul(10u64.pow(scale as u32))?
+        .checked_add(fraction)
+}
+
+/// The epoch time in a QR code in the luma plane of the frame
+fn read_time(frame: &ffmpeg::frame::Video) -> Option<u64> {
+    let bit_depth = stream_info::bit_depth(frame.format())?;
+    let width = frame.plane_width(0) as usize;
+    let height = frame.plane_height(0) as usize;
+    let text = if bit_depth > 8 {
+        // Keep the most significant bits of the little-endian samples
+        let plane: Vec<u8> = frame
+            .data(0)
+            .chunks(frame.stride(0))
+            .take(height)
+            .flat_map(|row| {
+                row.chunks_exact(2)
+                    .take(width)
+                    .map(|s| (u16::from_le_bytes([s[0], s[1]]) >> (bit_depth - 8)).min(255) as u8)
+            })
+            .collect();
+        qr::decode(&plane, width, width, height)?
+    } else {
+        qr::decode(frame.data(0), frame.stride(0), width, height)?
+    };
+    parse_epoch_ns(&text)
+}
+
+/// Decodes up to `max_frames` frames from the start of the video stream and
+/// returns the first one showing a QR code with an epoch time
+pub fn scan(path: &Path, max_frames: usize) -> Result<Option<TimeSync>, Box<dyn Error>> {
+    let mut input = ffmpeg::format::input(path)?;
+    let stream = input
+        .streams()
+        .best(ffmpeg::media::Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let stream_index = stream.index();
+    let time_base = stream.time_base();
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?
+        .decoder()
+        .video()?;
+
+    let mut frame = ffmpeg::frame::Video::empty();
+    let mut decoded = 0;
+    let mut packets = input.packets();
+    let mut ended = false;
+    loop {
+        let sent = match packets.next() {
+            Some((stream, packet)) if stream.index() == stream_index => {
+                decoder.send_packet(&packet)
+            }
+            Some(_) => continue,
+            // The frames the decoder still holds at the end of the input
+            None if !ended => {
+                ended = true;
+                decoder.send_eof()
+            }
+            None => return Ok(None),
+        };
+        // A corrupt packet is skipped, the code may be on the frames after it
+        if let Err(e) = sent {
+            debug!("Skipped a packet the decoder rejected: {}", e);
+            continue;
+        }
+        loop {
+            match decoder.receive_frame(&mut frame) {
+                Ok(()) => {}
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                }) => break,
+                Err(ffmpeg::Error::Eof) => return Ok(None),
+                Err(e) => {
+                    debug!("Skipped a frame that did not decode: {}", e);
+                    break;
+                }
+            }
+            if let Some(epoch_ns) = read_time(&frame) {
+                let pts = frame.timestamp().or(frame.pts()).unwrap_or(0).max(0);
+                let frame_ns = playlist::ticks_to_ns(pts, time_base) as u64;
+                return Ok(Some(TimeSync { frame_ns, epoch_ns }));
+            }
+            decoded += 1;
+            if decoded >= max_frames {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// The statistics record of the summary of an MCAP, found from its footer
+/// without reading the data section
+fn summary_stats(mcap: &mut (impl Read + Seek)) -> Result<Option<Statistics>, Box<dyn Error>> {
+    // Opcode, length, summary start, summary offset start and CRC
+    const FOOTER_LEN: usize = 1 + 8 + 8 + 8 + 4;
+    let mut end = [0; FOOTER_LEN + 8];
+    if mcap.seek(SeekFrom::End(-(end.len() as i64))).is_err() {
+        return Ok(None);
+    }
+    mcap.read_exact(&mut end)?;
+    if &end[FOOTER_LEN..] != mcap::MAGIC {
+        return Ok(None);
+    }
+    let Some(Ok(Record::Footer(footer))) =
+        mcap::read::LinearReader::sans_magic(&end[..FOOTER_LEN]).next()
+    else {
+        return Ok(None);
+    };
+    if footer.summary_start == 0 {
+        return Ok(None);
+    }
+    mcap.seek(SeekFrom::Start(footer.summary_start))?;
+    for record in inject::Records::summary(mcap) {
+        if let Record::Statistics(stats) = record? {
+            return Ok(Some(stats));
+        }
+    }
+    Ok(None)
+}
+
+/// Log times of the first and last messages of an MCAP, from the
+/// statistics in its summary, or by reading every message when it has none
+pub fn mcap_time_range(mut mcap: impl Read + Seek) -> Result<Option<(u64, u64)>, Box<dyn Error>> {
+    if let Some(stats) = summary_stats(&mut mcap)? {
+        return Ok(
+            (stats.message_count > 0).then_some((stats.message_start_time, stats.message_end_time))
+        );
+    }
+    mcap.rewind()?;
+    let mut range: Option<(u64, u64)> = None;
+    for record in inject::Records::new(mcap) {
+        if let Record::Message { header, .. } = record? {
+            let time = header.log_time;
+            range = Some(range.map_or((time, time), |(start, end)| {
+                (start.min(time), end.max(time))
+            }));
+        }
+    }
+    Ok(range)
+}
+
+/// Timestamp of the start of the video stream, from the container
+pub fn video_start_ns(stream: &ffmpeg::format::stream::Stream) -> u64 {
+    let time_base = stream.time_base();
+    match stream.start_time() {
+        // Unset is AV_NOPTS_VALUE, below zero
+        start if start > 0 => playlist::ticks_to_ns(start, time_base) as u64,
+        _ => 0,
+    }
+}
+
+/// An offset in nanoseconds in ticks of a time base, rounded to the nearest
+pub fn offset_ticks(time_base: ffmpeg::Rational, offset_ns: i64) -> i64 {
+    let numerator = offset_ns as i128 * time_base.denominator() as i128;
+    let denominator = time_base.numerator() as i128 * 1_000_000_000;
+    let half = denominator / 2 * numerator.signum();
+    ((numerator + half) / denominator).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Moves a time on the timeline of the input, like an --srt cue or a
+/// chapter, by the offset its packets are shifted by
+pub fn shift_ns(time_ns: u64, offset_ns: Option<i64>) -> u64 {
+    time_ns.saturating_add_signed(offset_ns.unwrap_or(0))
+}
+
+/// Moves the timestamps of a packet by an offset, rounded to the time base
+/// of its stream
+pub fn shift_packet(packet: &mut ffmpeg::Packet, time_base: ffmpeg::Rational, offset_ns: i64) {
+    let ticks = offset_ticks(time_base, offset_ns);
+    packet.set_pts(packet.pts().map(|pts| pts + ticks));
+    packet.set_dts(packet.dts().map(|dts| dts + ticks));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_epoch_ns() {
+        assert_eq!(
+            parse_epoch_ns("1697040000"),
+            Some(1_697_040_000_000_000_000)
+        );
+        assert_eq!(
+            parse_epoch_ns("1697040000123"),
+            Some(1_697_040_000_123_000_000)
+        );
+        assert_eq!(
+            parse_epoch_ns("t=1697040000.25s"),
+            Some(1_697_040_000_250_000_000)
+        );
+        assert_eq!(
+            parse_epoch_ns("1697040000123456789"),
+            Some(1_697_040_000_123_456_789)
+        );
+        assert_eq!(parse_epoch_ns("rig 7"), None);
+        assert_eq!(parse_epoch_ns("no time"), None);
+    }
+
+    #[test]
+    fn test_offset_ticks() {
+        // A Unix time in ticks of 1/90000, to the nearest tick
+        let time_base = ffmpeg::Rational::new(1, 90000);
+        let epoch_ns = 1_697_040_000_123_456_789;
+        assert_eq!(offset_ticks(time_base, epoch_ns), 152_733_600_011_111);
+        assert_eq!(offset_ticks(time_base, -epoch_ns), -152_733_600_011_111);
+        assert_eq!(offset_ticks(time_base, 5_556), 1);
+        assert_eq!(offset_ticks(time_base, 5_555), 0);
+        assert_eq!(shift_ns(5_000_000_000, Some(-2_000_000_000)), 3_000_000_000);
+        assert_eq!(shift_ns(1_000, Some(-2_000)), 0);
+        assert_eq!(shift_ns(1_000, None), 1_000);
+    }
+}
@@ -0,0 +1,172 @@
+use std::path::Path;
+
+use ffmpeg_next as ffmpeg;
+
+/// Where --topic-from and --frame-id-from take the name of the video from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NameFrom {
+    /// The handler name of the track, or its udta name
+    Handler,
+}
+
+impl std::str::FromStr for NameFrom {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "handler" => Ok(NameFrom::Handler),
+            other => Err(format!(
+                "Unsupported name source {:?}, expected handler",
+                other
+            )),
+        }
+    }
+}
+
+/// Handler names muxers write for every video track, which say nothing about it
+const DEFAULT_HANDLERS: &[&str] = &[
+    "VideoHandler",
+    "Core Media Video",
+    "Apple Video Media Handler",
+    "GPAC ISO Video Handler",
+    "ISO Media file produced by Google Inc.",
+];
+
+/// Values of the placeholders in a --topic template
+pub struct TopicValues {
+    /// File name of the input without its extension
+    pub stem: String,
+    /// Index of the stream in the input
+    pub stream: usize,
+    /// Handler name of the track, like VideoHandler
+    pub handler: Option<String>,
+    /// The udta name of the track
+    pub title: Option<String>,
+}
+
+impl TopicValues {
+    pub fn new(input: &Path, stream: usize, handler: Option<&str>, title: Option<&str>) -> Self {
+        Self {
+            stem: input
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            stream,
+            handler: non_empty(handler),
+            title: non_empty(title),
+        }
+    }
+
+    /// The values for a stream of the input
+    pub fn from_stream(input: &Path, stream: &ffmpeg::Stream) -> Self {
+        let metadata = stream.metadata();
+        Self::new(
+            input,
+            stream.index(),
+            metadata.get("handler_name"),
+            metadata.get("title"),
+        )
+    }
+
+    /// The label of the track, from its handler name unless that's one every
+    /// track gets, then from its udta name
+    pub fn label(&self) -> Option<&str> {
+        self.handler
+            .as_deref()
+            .filter(|handler| !DEFAULT_HANDLERS.contains(handler))
+            .or(self.title.as_deref())
+    }
+
+    /// The label of the track as a name, like front_camera for "Front Camera"
+    pub fn name(&self, from: NameFrom) -> Option<String> {
+        let label = match from {
+            NameFrom::Handler => self.label()?,
+        };
+        let mut name = String::new();
+        for c in label.chars() {
+            if c.is_ascii_alphanumeric() {
+                name.push(c.to_ascii_lowercase());
+            } else if !name.is_empty() && !name.ends_with('_') {
+                name.push('_');
+            }
+        }
+        let name = name.trim_end_matches('_');
+        (!name.is_empty()).then(|| name.to_string())
+    }
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+    value
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+/// Replaces `{stem}`, `{stream}` and `{handler}` in a topic. A track without
+/// a handler name is `trackN` after its stream index.
+pub fn expand(template: &str, values: &TopicValues) -> Result<String, String> {
+    let mut topic = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        topic.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in topic {:?}", template))?;
+        match &rest[start + 1..start + end] {
+            "stem" => topic.push_str(&values.stem),
+            "stream" => topic.push_str(&values.stream.to_string()),
+            "handler" => match &values.handler {
+                Some(handler) => topic.push_str(handler),
+                None => topic.push_str(&format!("track{}", values.stream)),
+            },
+            other => {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in topic {:?}, expected {{stem}}, {{stream}} or \
+                    {{handler}}",
+                    other, template
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    topic.push_str(rest);
+    Ok(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let values = TopicValues::new(
+            Path::new("/data/run 3.mp4"),
+            0,
+            Some(" VideoHandler "),
+            None,
+        );
+        assert_eq!(
+            expand("camera/{stem}/{stream}/{handler}", &values).unwrap(),
+            "camera/run 3/0/VideoHandler"
+        );
+        assert_eq!(expand("video", &values).unwrap(), "video");
+        let values = TopicValues::new(Path::new("a.mp4"), 2, None, None);
+        assert_eq!(expand("{handler}", &values).unwrap(), "track2");
+        assert!(expand("{name}", &values).is_err());
+        assert!(expand("camera/{stem", &values).is_err());
+    }
+
+    #[test]
+    fn test_name_from_handler() {
+        let values = TopicValues::new(Path::new("a.mp4"), 0, Some("Front Camera #2"), None);
+        assert_eq!(
+            values.name(NameFrom::Handler),
+            Some("front_camera_2".to_string())
+        );
+        let values = TopicValues::new(Path::new("a.mp4"), 0, Some("VideoHandler"), Some("Rear"));
+        assert_eq!(values.name(NameFrom::Handler), Some("rear".to_string()));
+        let values = TopicValues::new(Path::new("a.mp4"), 0, Some("VideoHandler"), None);
+        assert_eq!(values.name(NameFrom::Handler), None);
+    }
+}
@@ -0,0 +1,703 @@
+use std::{collections::BTreeMap, error::Error, fs::File, io::BufWriter};
+
+use ffmpeg_next as ffmpeg;
+use mcap::{records::MessageHeader, Writer};
+use prost::Message;
+
+use crate::camm::{self, CammRecord};
+use crate::codec::extract_extradata;
+use crate::foxglove::{
+    location_fix::PositionCovarianceType, log::Level, points_annotation, Color, GeoJson,
+    ImageAnnotations, LocationFix, Log, Point2, PointsAnnotation, Pose, PoseInFrame,
+    TextAnnotation, Vector3,
+};
+use crate::klv::{self, UasDatalink};
+use crate::mebx::{self, Key, Value};
+use crate::mp42mcap::{KlvPacket, TimedMetadata, Vector3Stamped};
+use crate::onvif;
+use crate::subtitles;
+use crate::validation::Validator;
+use crate::{setup_mcap_channel, to_timestamp};
+
+/// Channels of a metadata track, created as the first message of each
+/// topic shows up
+#[derive(Default)]
+struct TopicChannels {
+    /// Channel ID and next sequence number per topic
+    channels: BTreeMap<String, (u16, u32)>,
+    messages: usize,
+}
+
+impl TopicChannels {
+    fn write(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        topic: &str,
+        schema_name: &str,
+        log_time: u64,
+        encoded: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let (channel_id, sequence) = match self.channels.get_mut(topic) {
+            Some(channel) => channel,
+            None => {
+                let channel_id = setup_mcap_channel(writer, topic, schema_name, BTreeMap::new())?;
+                self.channels
+                    .entry(topic.to_string())
+                    .or_insert((channel_id.try_into()?, 0))
+            }
+        };
+        writer.write_to_known_channel(
+            &MessageHeader {
+                channel_id: *channel_id,
+                sequence: *sequence,
+                log_time,
+                publish_time: log_time,
+            },
+            encoded,
+        )?;
+        *sequence += 1;
+        self.messages += 1;
+        Ok(())
+    }
+}
+
+/// Log time of a packet of a track, from its own time base
+fn packet_timestamp(packet: &ffmpeg::Packet, time_base: ffmpeg::Rational) -> Option<u64> {
+    let pts = packet.pts().or(packet.dts())?;
+    Some(
+        (pts as f64 * time_base.numerator() as f64 / time_base.denominator() as f64
+            * 1_000_000_000.0) as u64,
+    )
+}
+
+fn location_fix(
+    frame_id: &str,
+    log_time: u64,
+    (latitude, longitude, altitude): (f64, f64, f64),
+) -> LocationFix {
+    LocationFix {
+        timestamp: Some(to_timestamp(log_time)),
+        frame_id: frame_id.to_string(),
+        latitude,
+        longitude,
+        altitude,
+        ..Default::default()
+    }
+}
+
+fn pose_in_frame(frame_id: &str, log_time: u64, pose: Pose) -> PoseInFrame {
+    PoseInFrame {
+        timestamp: Some(to_timestamp(log_time)),
+        frame_id: frame_id.to_string(),
+        pose: Some(pose),
+    }
+}
+
+/// Converts the records of a camera motion metadata track to location,
+/// orientation and IMU channels
+pub struct CammOutput {
+    pub stream_index: usize,
+    time_base: ffmpeg::Rational,
+    frame_id: String,
+    channels: TopicChannels,
+}
+
+impl CammOutput {
+    pub fn new(stream: &ffmpeg::Stream, frame_id: &str) -> Self {
+        Self {
+            stream_index: stream.index(),
+            time_base: stream.time_base(),
+            frame_id: frame_id.to_string(),
+            channels: TopicChannels::default(),
+        }
+    }
+
+    /// Number of messages written
+    pub fn messages(&self) -> usize {
+        self.channels.messages
+    }
+
+    pub fn write_packet(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        validator: &mut Validator,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(log_time) = packet_timestamp(packet, self.time_base) else {
+            return Ok(());
+        };
+        let records = match camm::parse_sample(packet.data().unwrap_or_default()) {
+            Ok(records) => records,
+            Err(e) => {
+                validator.warn("malformed CAMM sample", format!("{} at {}ns", e, log_time))?;
+                return Ok(());
+            }
+        };
+
+        for record in records {
+            let frame_id = &self.frame_id;
+            let (schema_name, encoded) = match record {
+                CammRecord::Orientation(angle_axis) => {
+                    let pose = Pose {
+                        position: Some(Vector3::default()),
+                        orientation: Some(camm::angle_axis_to_quaternion(angle_axis)),
+                    };
+                    let message = pose_in_frame(frame_id, log_time, pose);
+                    ("foxglove.PoseInFrame", message.encode_to_vec())
+                }
+                CammRecord::Gyro([x, y, z])
+                | CammRecord::Acceleration([x, y, z])
+                | CammRecord::MagneticField([x, y, z]) => {
+                    let message = Vector3Stamped {
+                        timestamp: Some(to_timestamp(log_time)),
+                        frame_id: frame_id.clone(),
+                        vector: Some(Vector3 {
+                            x: x as f64,
+                            y: y as f64,
+                            z: z as f64,
+                        }),
+                    };
+                    ("mp42mcap.Vector3Stamped", message.encode_to_vec())
+                }
+                CammRecord::Gps {
+                    latitude,
+                    longitude,
+                    altitude,
+                    accuracy,
+                } => {
+                    let mut message =
+                        location_fix(frame_id, log_time, (latitude, longitude, altitude));
+                    if let Some((horizontal, vertical)) = accuracy {
+                        let (h, v) = ((horizontal as f64).powi(2), (vertical as f64).powi(2));
+                        message.position_covariance = vec![h, 0.0, 0.0, 0.0, h, 0.0, 0.0, 0.0, v];
+                        message.position_covariance_type =
+                            PositionCovarianceType::DiagonalKnown as i32;
+                    }
+                    ("foxglove.LocationFix", message.encode_to_vec())
+                }
+            };
+            self.channels
+                .write(writer, record.topic(), schema_name, log_time, &encoded)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a KLV metadata track to raw KLV packets and, for MISB ST 0601
+/// UAS Datalink packets, location, attitude and footprint channels
+pub struct KlvOutput {
+    pub stream_index: usize,
+    time_base: ffmpeg::Rational,
+    frame_id: String,
+    channels: TopicChannels,
+}
+
+impl KlvOutput {
+    pub fn new(stream: &ffmpeg::Stream, frame_id: &str) -> Self {
+        Self {
+            stream_index: stream.index(),
+            time_base: stream.time_base(),
+            frame_id: frame_id.to_string(),
+            channels: TopicChannels::default(),
+        }
+    }
+
+    /// Number of messages written
+    pub fn messages(&self) -> usize {
+        self.channels.messages
+    }
+
+    pub fn write_packet(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        validator: &mut Validator,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(log_time) = packet_timestamp(packet, self.time_base) else {
+            return Ok(());
+        };
+        let packets = match klv::split_packets(packet.data().unwrap_or_default()) {
+            Ok(packets) => packets,
+            Err(e) => {
+                validator.warn("malformed KLV sample", format!("{} at {}ns", e, log_time))?;
+                return Ok(());
+            }
+        };
+
+        for klv_packet in packets {
+            let raw = KlvPacket {
+                timestamp: Some(to_timestamp(log_time)),
+                data: klv_packet.bytes.to_vec(),
+            };
+            self.channels.write(
+                writer,
+                "klv/raw",
+                "mp42mcap.KlvPacket",
+                log_time,
+                &raw.encode_to_vec(),
+            )?;
+            if !klv_packet.is_uas_datalink() {
+                continue;
+            }
+
+            let datalink = match UasDatalink::parse(&klv_packet) {
+                Ok(datalink) => datalink,
+                Err(e) => {
+                    validator.warn(
+                        "malformed UAS Datalink packet",
+                        format!("{} at {}ns", e, log_time),
+                    )?;
+                    continue;
+                }
+            };
+            self.write_datalink(writer, &datalink, log_time)?;
+        }
+        Ok(())
+    }
+
+    fn write_datalink(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        datalink: &UasDatalink,
+        log_time: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let frame_id = &self.frame_id;
+        if let Some(position) = datalink.sensor_position() {
+            let message = location_fix(frame_id, log_time, position);
+            self.channels.write(
+                writer,
+                "klv/sensor_location",
+                "foxglove.LocationFix",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        if let Some(position) = datalink.frame_center() {
+            let message = location_fix(frame_id, log_time, position);
+            self.channels.write(
+                writer,
+                "klv/frame_center",
+                "foxglove.LocationFix",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        if let Some(pose) = datalink.platform_pose() {
+            let message = pose_in_frame(frame_id, log_time, pose);
+            self.channels.write(
+                writer,
+                "klv/platform_pose",
+                "foxglove.PoseInFrame",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        if let Some(pose) = datalink.sensor_pose() {
+            let message = pose_in_frame("platform", log_time, pose);
+            self.channels.write(
+                writer,
+                "klv/sensor_pose",
+                "foxglove.PoseInFrame",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        if let Some(geojson) = datalink.footprint_geojson() {
+            let message = GeoJson { geojson };
+            self.channels.write(
+                writer,
+                "klv/footprint",
+                "foxglove.GeoJSON",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the items of a QuickTime timed metadata track to a channel per
+/// key, named after it where the key table is known
+pub struct MebxOutput {
+    pub stream_index: usize,
+    time_base: ffmpeg::Rational,
+    keys: BTreeMap<u32, Key>,
+    /// Topic of each key, under `mebx/`
+    topics: BTreeMap<u32, String>,
+    channels: TopicChannels,
+}
+
+impl MebxOutput {
+    pub fn new(stream: &ffmpeg::Stream) -> Self {
+        let keys = match extract_extradata(&stream.parameters()) {
+            Ok(extradata) if !extradata.is_empty() => mebx::parse_keys(extradata),
+            _ => BTreeMap::new(),
+        };
+        if keys.is_empty() {
+            warn!(
+                "The timed metadata track {} has no key table, so its items are written to \
+                topics numbered by key",
+                stream.index()
+            );
+        }
+        Self {
+            stream_index: stream.index(),
+            time_base: stream.time_base(),
+            topics: mebx::topics(&keys),
+            keys,
+            channels: TopicChannels::default(),
+        }
+    }
+
+    /// Number of messages written
+    pub fn messages(&self) -> usize {
+        self.channels.messages
+    }
+
+    pub fn write_packet(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(log_time) = packet_timestamp(packet, self.time_base) else {
+            return Ok(());
+        };
+        for (local_id, data) in mebx::parse_sample(packet.data().unwrap_or_default()) {
+            let key = self.keys.get(&local_id);
+            let name = key.map_or_else(
+                || format!("{}/{}", self.stream_index, local_id),
+                |key| key.name.clone(),
+            );
+            let topic = self.topics.get(&local_id).unwrap_or(&name);
+            let mut message = TimedMetadata {
+                timestamp: Some(to_timestamp(log_time)),
+                key: name.clone(),
+                data: data.to_vec(),
+                ..Default::default()
+            };
+            match key
+                .and_then(|key| key.data_type)
+                .and_then(|data_type| mebx::decode_value(data_type, data))
+            {
+                Some(Value::Text(text)) => message.value = text,
+                Some(Value::Number(number)) => {
+                    message.value = number.to_string();
+                    message.number = number;
+                }
+                None => {}
+            }
+            self.channels.write(
+                writer,
+                &format!("mebx/{}", topic),
+                "mp42mcap.TimedMetadata",
+                log_time,
+                &message.encode_to_vec(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes log messages on a single topic, with the track or file they came
+/// from as the name
+pub struct LogOutput {
+    topic: String,
+    channels: TopicChannels,
+}
+
+impl LogOutput {
+    pub fn new(topic: &str) -> Self {
+        Self {
+            topic: topic.to_string(),
+            channels: TopicChannels::default(),
+        }
+    }
+
+    /// Number of messages written
+    pub fn messages(&self) -> usize {
+        self.channels.messages
+    }
+
+    pub fn write(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        name: &str,
+        log_time: u64,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_with_level(writer, Level::Info, name, log_time, text)
+    }
+
+    pub fn warn(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        name: &str,
+        log_time: u64,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.write_with_level(writer, Level::Warning, name, log_time, text)
+    }
+
+    fn write_with_level(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        level: Level,
+        name: &str,
+        log_time: u64,
+        text: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.channels.write(
+            writer,
+            &self.topic,
+            "foxglove.Log",
+            log_time,
+            &encode_log(level, name, log_time, text),
+        )
+    }
+}
+
+/// An encoded `foxglove.Log` message
+pub fn encode_log(level: Level, name: &str, log_time: u64, text: &str) -> Vec<u8> {
+    Log {
+        timestamp: Some(to_timestamp(log_time)),
+        level: level as i32,
+        message: text.to_string(),
+        name: name.to_string(),
+        ..Default::default()
+    }
+    .encode_to_vec()
+}
+
+/// A 3GPP timed text (tx3g, mov_text) subtitle track
+pub struct SubtitleTrack {
+    pub stream_index: usize,
+    time_base: ffmpeg::Rational,
+    name: String,
+}
+
+impl SubtitleTrack {
+    /// Whether the stream is one
+    pub fn is_subtitle_track(parameters: &ffmpeg::codec::Parameters) -> bool {
+        parameters.id() == ffmpeg::codec::Id::MOV_TEXT
+    }
+
+    pub fn new(stream: &ffmpeg::Stream) -> Self {
+        let metadata = stream.metadata();
+        let name = metadata
+            .get("title")
+            .or(metadata.get("handler_name"))
+            .map_or_else(|| format!("subtitles {}", stream.index()), str::to_string);
+        Self {
+            stream_index: stream.index(),
+            time_base: stream.time_base(),
+            name,
+        }
+    }
+
+    pub fn write_packet(
+        &self,
+        writer: &mut Writer<BufWriter<File>>,
+        log: &mut LogOutput,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(log_time) = packet_timestamp(packet, self.time_base) else {
+            return Ok(());
+        };
+        // Empty samples clear the previous subtitle
+        match subtitles::mov_text(packet.data().unwrap_or_default()) {
+            Some(text) if !text.is_empty() => log.write(writer, &self.name, log_time, &text),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Converts an ONVIF metadata track to its raw XML on `onvif` and the
+/// detected objects as annotations of the video on `onvif/annotations`
+pub struct OnvifOutput {
+    pub stream_index: usize,
+    time_base: ffmpeg::Rational,
+    /// Size of the video in pixels, which the boxes are scaled to
+    width: u32,
+    height: u32,
+    channels: TopicChannels,
+}
+
+impl OnvifOutput {
+    pub fn new(stream: &ffmpeg::Stream, (width, height): (u32, u32)) -> Self {
+        Self {
+            stream_index: stream.index(),
+            time_base: stream.time_base(),
+            width,
+            height,
+            channels: TopicChannels::default(),
+        }
+    }
+
+    /// Number of messages written
+    pub fn messages(&self) -> usize {
+        self.channels.messages
+    }
+
+    pub fn write_packet(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        packet: &ffmpeg::Packet,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(log_time) = packet_timestamp(packet, self.time_base) else {
+            return Ok(());
+        };
+        let data = packet.data().unwrap_or_default();
+        let xml = String::from_utf8_lossy(data);
+        let raw = TimedMetadata {
+            timestamp: Some(to_timestamp(log_time)),
+            key: "onvif".to_string(),
+            data: data.to_vec(),
+            value: xml.to_string(),
+            ..Default::default()
+        };
+        self.channels.write(
+            writer,
+            "onvif",
+            "mp42mcap.TimedMetadata",
+            log_time,
+            &raw.encode_to_vec(),
+        )?;
+
+        // Written for every sample, so that boxes disappear with their objects
+        let color = Color {
+            r: 0.0,
+            g: 1.0,
+            b: 0.0,
+            a: 1.0,
+        };
+        let mut annotations = ImageAnnotations::default();
+        for object in onvif::parse_objects(&xml) {
+            let Some(bounding_box) = object.bounding_box else {
+                continue;
+            };
+            let ((left, top), (right, bottom)) = bounding_box.to_pixels(self.width, self.height);
+            let corners = [(left, top), (right, top), (right, bottom), (left, bottom)];
+            annotations.points.push(PointsAnnotation {
+                timestamp: Some(to_timestamp(log_time)),
+                r#type: points_annotation::Type::LineLoop as i32,
+                points: corners.iter().map(|&(x, y)| Point2 { x, y }).collect(),
+                outline_color: Some(color.clone()),
+                thickness: 2.0,
+                ..Default::default()
+            });
+            let label = match (&object.class, object.likelihood) {
+                (Some(class), Some(likelihood)) => {
+                    format!("{} {} ({:.0}%)", class, object.id, likelihood * 100.0)
+                }
+                (Some(class), None) => format!("{} {}", class, object.id),
+                (None, _) => object.id.clone(),
+            };
+            annotations.texts.push(TextAnnotation {
+                timestamp: Some(to_timestamp(log_time)),
+                position: Some(Point2 {
+                    x: left.min(right),
+                    y: top.min(bottom),
+                }),
+                text: label,
+                font_size: 14.0,
+                text_color: Some(color.clone()),
+                ..Default::default()
+            });
+        }
+        self.channels.write(
+            writer,
+            "onvif/annotations",
+            "foxglove.ImageAnnotations",
+            log_time,
+            &annotations.encode_to_vec(),
+        )
+    }
+}
+
+/// The metadata and subtitle tracks of the input, written next to the video
+pub struct Tracks {
+    pub camm: Option<CammOutput>,
+    pub klv: Option<KlvOutput>,
+    pub mebx: Vec<MebxOutput>,
+    pub onvif: Option<OnvifOutput>,
+    pub subtitles: Vec<SubtitleTrack>,
+    /// The subtitles, as log messages
+    pub log: LogOutput,
+}
+
+impl Tracks {
+    /// Finds the tracks of the input. The ONVIF boxes are scaled to
+    /// `video_dimensions`.
+    pub fn new(
+        input: &ffmpeg::format::context::Input,
+        frame_id: &str,
+        video_dimensions: (u32, u32),
+    ) -> Self {
+        Self {
+            camm: input
+                .streams()
+                .find(|stream| camm::is_camm(&stream.parameters()))
+                .map(|stream| CammOutput::new(&stream, frame_id)),
+            klv: input
+                .streams()
+                .find(|stream| klv::is_klv(&stream.parameters()))
+                .map(|stream| KlvOutput::new(&stream, frame_id)),
+            mebx: input
+                .streams()
+                .filter(|stream| mebx::is_mebx(&stream.parameters()))
+                .map(|stream| MebxOutput::new(&stream))
+                .collect(),
+            onvif: input
+                .streams()
+                .find(|stream| onvif::is_onvif(&stream.parameters()))
+                .map(|stream| OnvifOutput::new(&stream, video_dimensions)),
+            subtitles: input
+                .streams()
+                .filter(|stream| SubtitleTrack::is_subtitle_track(&stream.parameters()))
+                .map(|stream| SubtitleTrack::new(&stream))
+                .collect(),
+            log: LogOutput::new("subtitles"),
+        }
+    }
+
+    /// Writes a packet of the stream at `stream_index` if it's one of the
+    /// tracks, and returns whether it was
+    pub fn write_packet(
+        &mut self,
+        writer: &mut Writer<BufWriter<File>>,
+        validator: &mut Validator,
+        stream_index: usize,
+        packet: &ffmpeg::Packet,
+    ) -> Result<bool, Box<dyn Error>> {
+        if let Some(camm) = self
+            .camm
+            .as_mut()
+            .filter(|c| c.stream_index == stream_index)
+        {
+            camm.write_packet(writer, validator, packet)?;
+        } else if let Some(klv) = self.klv.as_mut().filter(|k| k.stream_index == stream_index) {
+            klv.write_packet(writer, validator, packet)?;
+        } else if let Some(mebx) = self
+            .mebx
+            .iter_mut()
+            .find(|m| m.stream_index == stream_index)
+        {
+            mebx.write_packet(writer, packet)?;
+        } else if let Some(onvif) = self
+            .onvif
+            .as_mut()
+            .filter(|o| o.stream_index == stream_index)
+        {
+            onvif.write_packet(writer, packet)?;
+        } else if let Some(track) = self
+            .subtitles
+            .iter()
+            .find(|t| t.stream_index == stream_index)
+        {
+            track.write_packet(writer, &mut self.log, packet)?;
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,575 @@
+use std::error::Error;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::av1::SequenceHeaders;
+use crate::codec::CodecType;
+use crate::filter::FrameFilter;
+use crate::hwaccel::{HardwareEncoder, HwUpload};
+use crate::quality::QualityMeter;
+
+#[derive(Clone, Default)]
+pub struct TranscodeOptions {
+    /// Output codec, the source codec if unset
+    pub codec: Option<CodecType>,
+    /// Maximum distance between keyframes in seconds
+    pub keyframe_interval: Option<f64>,
+    /// Constant rate factor, used instead of a bitrate
+    pub crf: Option<u32>,
+    /// Target bitrate in bits per second
+    pub bitrate: Option<usize>,
+    pub preset: Option<String>,
+    pub profile: Option<String>,
+    pub scale: Option<Scale>,
+    /// Rotate the frames upright according to the display matrix
+    pub apply_rotation: bool,
+    /// Deinterlace with yadif before any other filter
+    pub deinterlace: bool,
+    pub tonemap: Option<ToneMap>,
+    /// Preferred over the software encoder when available
+    pub encoder: Option<HardwareEncoder>,
+    /// Filtergraph applied after the built-in filters
+    pub filter: Option<String>,
+    /// Draw the log time of each frame, and its index if `burn_frame_index`
+    pub burn_timestamps: bool,
+    pub burn_frame_index: bool,
+    /// Encode 8-bit 4:2:0 even if the encoder supports the source format
+    pub force_8bit: bool,
+    /// Leave the encoder version and anything else that varies between
+    /// builds out of the bitstream
+    pub bitexact: bool,
+    /// Make every frame a keyframe, for frames sampled far apart that are
+    /// each decoded on their own
+    pub all_keyframes: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    /// HDR10/HLG to BT.709 SDR
+    Sdr,
+}
+
+impl ToneMap {
+    /// Linearize, map the highlights with hable and convert to BT.709. The
+    /// encoder picks up the BT.709 tags from the filtered frames.
+    pub const SDR_FILTER: &'static str = "zscale=t=linear:npl=100,format=gbrpf32le,\
+        zscale=p=bt709,tonemap=tonemap=hable:desat=0,\
+        zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+}
+
+impl std::str::FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sdr" => Ok(ToneMap::Sdr),
+            other => Err(format!(
+                "Unsupported tone mapping {:?}, expected sdr",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scale {
+    Size(u32, u32),
+    Percent(f64),
+}
+
+impl std::str::FromStr for Scale {
+    type Err = String;
+
+    /// Parses `WxH` or `N%`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid scale {:?}, expected WxH or N%", s);
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|_| invalid())?;
+            if percent <= 0.0 {
+                return Err(invalid());
+            }
+            return Ok(Scale::Percent(percent));
+        }
+        let (width, height) = s.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let width: u32 = width.parse().map_err(|_| invalid())?;
+        let height: u32 = height.parse().map_err(|_| invalid())?;
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+        Ok(Scale::Size(width, height))
+    }
+}
+
+impl Scale {
+    /// Output dimensions for a source of the given size, rounded to even
+    /// numbers as required for 4:2:0 chroma subsampling
+    pub fn apply(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = match *self {
+            Scale::Size(width, height) => (width, height),
+            Scale::Percent(percent) => (
+                (width as f64 * percent / 100.0).round() as u32,
+                (height as f64 * percent / 100.0).round() as u32,
+            ),
+        };
+        ((width & !1).max(2), (height & !1).max(2))
+    }
+}
+
+/// Converts frames into the size and format the encoder expects
+struct Scaler {
+    context: ffmpeg::software::scaling::Context,
+    frame: ffmpeg::frame::Video,
+}
+
+/// The encoder side of the pipeline. It is opened with the first frame, as
+/// filters may change the size and format of the decoded frames.
+struct Output {
+    codec: ffmpeg::Codec,
+    codec_type: CodecType,
+    time_base: ffmpeg::Rational,
+    frame_rate: ffmpeg::Rational,
+    options: TranscodeOptions,
+    scaler: Option<Scaler>,
+    upload: Option<HwUpload>,
+    encoder: Option<ffmpeg::encoder::Video>,
+    /// Compares the encoded frames with the ones sent, with --quality-metrics
+    quality: Option<QualityMeter>,
+}
+
+impl Output {
+    /// Opens the hardware encoder if one was requested, falling back to the
+    /// software encoder if it is missing or fails to open
+    fn open(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+    ) -> Result<ffmpeg::encoder::Video, Box<dyn Error>> {
+        if let Some(hardware) = self.options.encoder {
+            let name = hardware.encoder_name(self.codec_type);
+            let result = match ffmpeg::encoder::find_by_name(&name) {
+                Some(codec) => self.open_codec(frame, codec, Some(hardware)),
+                None => Err("not included in this FFmpeg build".into()),
+            };
+            match result {
+                Ok(encoder) => return Ok(encoder),
+                Err(e) => {
+                    warn!(
+                        "Hardware encoder {} is unavailable ({}), falling back to {}",
+                        name,
+                        e,
+                        self.codec.name()
+                    );
+                    self.scaler = None;
+                    self.upload = None;
+                }
+            }
+        }
+        self.open_codec(frame, self.codec, None)
+    }
+
+    fn open_codec(
+        &mut self,
+        frame: &ffmpeg::frame::Video,
+        codec: ffmpeg::Codec,
+        hardware: Option<HardwareEncoder>,
+    ) -> Result<ffmpeg::encoder::Video, Box<dyn Error>> {
+        let upload = hardware.is_some_and(|hardware| hardware.needs_upload());
+        let format = if upload {
+            HwUpload::SW_FORMAT
+        } else {
+            let source = if self.options.force_8bit {
+                ffmpeg::format::Pixel::YUV420P
+            } else {
+                frame.format()
+            };
+            output_format(source, codec.video()?.formats())
+        };
+        let (width, height) = match self.options.scale {
+            Some(scale) => scale.apply(frame.width(), frame.height()),
+            None => (frame.width(), frame.height()),
+        };
+        if format != frame.format() || width != frame.width() || height != frame.height() {
+            self.scaler = Some(Scaler {
+                context: ffmpeg::software::scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    format,
+                    width,
+                    height,
+                    ffmpeg::software::scaling::Flags::BICUBIC,
+                )?,
+                frame: ffmpeg::frame::Video::empty(),
+            });
+        }
+
+        let mut context = ffmpeg::codec::context::Context::new_with_codec(codec);
+        if self.options.bitexact {
+            // x264 and x265 split the frames between their threads by the
+            // number of cores, and the output changes with it
+            context.set_threading(ffmpeg::threading::Config {
+                kind: ffmpeg::threading::Type::Frame,
+                count: 1,
+            });
+        }
+        let mut encoder = context.encoder().video()?;
+        encoder.set_width(width);
+        encoder.set_height(height);
+        if upload {
+            let frames = HwUpload::new(width, height)?;
+            frames.attach(&mut encoder);
+            encoder.set_format(ffmpeg::format::Pixel::VAAPI);
+            self.upload = Some(frames);
+        } else {
+            encoder.set_format(format);
+        }
+        encoder.set_time_base(self.time_base);
+        encoder.set_frame_rate(Some(self.frame_rate));
+        encoder.set_max_b_frames(0);
+
+        // Tag the stream with the colors of the frames it is made from
+        encoder.set_colorspace(frame.color_space());
+        encoder.set_color_range(frame.color_range());
+        unsafe {
+            let context = encoder.as_mut_ptr();
+            (*context).color_primaries = frame.color_primaries().into();
+            (*context).color_trc = frame.color_transfer_characteristic().into();
+        }
+
+        let mut flags = ffmpeg::codec::Flags::empty();
+        if self.options.all_keyframes {
+            encoder.set_gop(1);
+            flags |= ffmpeg::codec::Flags::CLOSED_GOP;
+        } else if let Some(interval) = self.options.keyframe_interval {
+            encoder.set_gop(gop_size(interval, self.frame_rate));
+            flags |= ffmpeg::codec::Flags::CLOSED_GOP;
+        }
+        if self.options.bitexact {
+            flags |= ffmpeg::codec::Flags::BITEXACT;
+        }
+        encoder.set_flags(flags);
+        if let Some(bitrate) = self.options.bitrate {
+            encoder.set_bit_rate(bitrate);
+        }
+
+        // Without GLOBAL_HEADER the encoders emit Annex B; repeating the
+        // parameter sets makes every keyframe a valid seek target. The
+        // hardware encoders already repeat them on every IDR frame.
+        let mut encoder_options = ffmpeg::Dictionary::new();
+        let quality_option = match hardware {
+            Some(hardware) => hardware.quality_option(),
+            None => {
+                match self.codec_type {
+                    CodecType::H264 => encoder_options.set("x264-params", "repeat-headers=1"),
+                    // libx265 sizes its thread pools itself
+                    CodecType::H265 if self.options.bitexact => encoder_options.set(
+                        "x265-params",
+                        "repeat-headers=1:open-gop=0:pools=1:frame-threads=1",
+                    ),
+                    CodecType::H265 => {
+                        encoder_options.set("x265-params", "repeat-headers=1:open-gop=0")
+                    }
+                    // Sequence headers are added to keyframes in receive_packet
+                    CodecType::Av1 => {}
+                }
+                Some("crf")
+            }
+        };
+        if let (Some(crf), Some(option)) = (self.options.crf, quality_option) {
+            encoder_options.set(option, &crf.to_string());
+        }
+        if let Some(preset) = &self.options.preset {
+            encoder_options.set("preset", preset);
+        }
+        if let Some(profile) = &self.options.profile {
+            encoder_options.set("profile", profile);
+        }
+
+        Ok(encoder.open_with(encoder_options)?)
+    }
+
+    fn send_frame(&mut self, frame: &mut ffmpeg::frame::Video) -> Result<(), Box<dyn Error>> {
+        if self.encoder.is_none() {
+            self.encoder = Some(self.open(frame)?);
+        }
+
+        let pts = frame.pts();
+        let frame = match &mut self.scaler {
+            Some(scaler) => {
+                scaler.context.run(frame, &mut scaler.frame)?;
+                &mut scaler.frame
+            }
+            None => frame,
+        };
+        frame.set_pts(pts);
+        if let Some(quality) = &mut self.quality {
+            quality.add_reference(frame);
+        }
+        // Let the encoder place keyframes instead of copying the source GOP
+        frame.set_kind(ffmpeg::picture::Type::None);
+        if let Some(encoder) = &mut self.encoder {
+            match &self.upload {
+                Some(upload) => {
+                    let uploaded = upload.upload(frame)?;
+                    encoder.send_frame(&uploaded)?
+                }
+                None => encoder.send_frame(frame)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Re-encodes decoded frames into an Annex B stream without B-frames,
+/// optionally passing them through a filter graph first
+pub struct Transcoder {
+    filter: Option<FrameFilter>,
+    filtered: ffmpeg::frame::Video,
+    output: Output,
+    sequence_headers: Option<SequenceHeaders>,
+}
+
+impl Transcoder {
+    pub fn new(
+        decoder: &ffmpeg::decoder::Video,
+        time_base: ffmpeg::Rational,
+        frame_rate: ffmpeg::Rational,
+        codec_type: CodecType,
+        filters: &[String],
+        options: &TranscodeOptions,
+        quality_metrics: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let codec = codec_type
+            .encoder_libs()
+            .iter()
+            .find_map(|name| ffmpeg::encoder::find_by_name(name))
+            .ok_or_else(|| {
+                format!(
+                    "Encoder {} not found",
+                    codec_type.encoder_libs().join(" or ")
+                )
+            })?;
+        if options.crf.is_some() && options.encoder == Some(HardwareEncoder::VideoToolbox) {
+            return Err("--crf is not supported by videotoolbox, use --bitrate instead".into());
+        }
+
+        let filter = if filters.is_empty() {
+            None
+        } else {
+            Some(FrameFilter::new(&filters.join(","), decoder, time_base)?)
+        };
+
+        Ok(Self {
+            filter,
+            filtered: ffmpeg::frame::Video::empty(),
+            output: Output {
+                codec,
+                codec_type,
+                time_base,
+                frame_rate,
+                options: options.clone(),
+                scaler: None,
+                upload: None,
+                encoder: None,
+                quality: if quality_metrics {
+                    Some(QualityMeter::new(codec.id())?)
+                } else {
+                    None
+                },
+            },
+            sequence_headers: (codec_type == CodecType::Av1).then(SequenceHeaders::default),
+        })
+    }
+
+    pub fn send_frame(&mut self, frame: &mut ffmpeg::frame::Video) -> Result<(), Box<dyn Error>> {
+        let timestamp = frame.timestamp();
+        frame.set_pts(timestamp);
+        match &mut self.filter {
+            Some(filter) => {
+                filter.push(frame)?;
+                self.drain_filter()
+            }
+            None => self.output.send_frame(frame),
+        }
+    }
+
+    fn drain_filter(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(filter) = &mut self.filter else {
+            return Ok(());
+        };
+        loop {
+            match filter.pull(&mut self.filtered) {
+                Ok(_) => self.output.send_frame(&mut self.filtered)?,
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Receives the next encoded packet. Fails with `Eof` if no frame was
+    /// ever encoded.
+    pub fn receive_packet(&mut self, packet: &mut ffmpeg::Packet) -> Result<(), ffmpeg::Error> {
+        match &mut self.output.encoder {
+            Some(encoder) => encoder.receive_packet(packet)?,
+            None => return Err(ffmpeg::Error::Eof),
+        }
+
+        if let (Some(headers), Some(data)) = (&mut self.sequence_headers, packet.data()) {
+            if let Some(data) = headers.process(data, packet.is_key()) {
+                let mut fixed = ffmpeg::Packet::copy(&data);
+                fixed.set_pts(packet.pts());
+                fixed.set_dts(packet.dts());
+                fixed.set_flags(packet.flags());
+                *packet = fixed;
+            }
+        }
+        if let Some(quality) = &mut self.output.quality {
+            quality.add_encoded(packet)?;
+        }
+        Ok(())
+    }
+
+    pub fn send_eof(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(filter) = &mut self.filter {
+            filter.flush()?;
+            self.drain_filter()?;
+        }
+        if let Some(encoder) = &mut self.output.encoder {
+            encoder.send_eof()?;
+        }
+        Ok(())
+    }
+
+    pub fn codec_type(&self) -> CodecType {
+        self.output.codec_type
+    }
+
+    pub fn quality_mut(&mut self) -> Option<&mut QualityMeter> {
+        self.output.quality.as_mut()
+    }
+}
+
+/// Keeps the source pixel format when the encoder supports it, otherwise
+/// prefers 8-bit 4:2:0 which every H.264/H.265 decoder handles.
+fn output_format(
+    source: ffmpeg::format::Pixel,
+    supported: Option<impl Iterator<Item = ffmpeg::format::Pixel>>,
+) -> ffmpeg::format::Pixel {
+    let Some(supported) = supported else {
+        return source;
+    };
+    let supported: Vec<_> = supported.collect();
+    if supported.contains(&source) || supported.is_empty() {
+        source
+    } else if supported.contains(&ffmpeg::format::Pixel::YUV420P) {
+        ffmpeg::format::Pixel::YUV420P
+    } else {
+        supported[0]
+    }
+}
+
+/// A drawtext filter showing the PTS in seconds, which is the log time of
+/// the message the frame ends up in
+pub fn timestamp_overlay(frame_index: bool) -> String {
+    let text = if frame_index {
+        "frame %{frame_num}  %{pts\\:flt} s"
+    } else {
+        "%{pts\\:flt} s"
+    };
+    format!(
+        "drawtext=text='{}':x=10:y=10:fontsize=h/24:fontcolor=white:box=1:boxcolor=black@0.5",
+        text
+    )
+}
+
+/// Parses a bitrate in bits per second with an optional `k` or `M` suffix
+pub fn parse_bitrate(value: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1_000.0),
+        Some((index, 'm' | 'M')) => (&value[..index], 1_000_000.0),
+        _ => (value, 1.0),
+    };
+    let bitrate: f64 = digits
+        .parse()
+        .map_err(|_| format!("Invalid bitrate {:?}", value))?;
+    if bitrate <= 0.0 {
+        return Err(format!("Invalid bitrate {:?}", value));
+    }
+    Ok((bitrate * multiplier) as usize)
+}
+
+/// Number of frames in a GOP spanning at most `interval` seconds
+pub fn gop_size(interval: f64, frame_rate: ffmpeg::Rational) -> u32 {
+    let fps = frame_rate.numerator() as f64 / frame_rate.denominator() as f64;
+    ((interval * fps).floor() as u32).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gop_size() {
+        assert_eq!(gop_size(2.0, ffmpeg::Rational::new(30, 1)), 60);
+        assert_eq!(gop_size(1.0, ffmpeg::Rational::new(30000, 1001)), 29);
+        assert_eq!(gop_size(0.01, ffmpeg::Rational::new(30, 1)), 1);
+    }
+
+    #[test]
+    fn test_parse_bitrate() {
+        assert_eq!(parse_bitrate("800000"), Ok(800_000));
+        assert_eq!(parse_bitrate("800k"), Ok(800_000));
+        assert_eq!(parse_bitrate("2.5M"), Ok(2_500_000));
+        assert!(parse_bitrate("fast").is_err());
+        assert!(parse_bitrate("0").is_err());
+        assert!(parse_bitrate("").is_err());
+    }
+
+    #[test]
+    fn test_scale() {
+        assert_eq!("1280x720".parse(), Ok(Scale::Size(1280, 720)));
+        assert_eq!("50%".parse(), Ok(Scale::Percent(50.0)));
+        assert!("1280".parse::<Scale>().is_err());
+        assert!("0x720".parse::<Scale>().is_err());
+        assert!("-50%".parse::<Scale>().is_err());
+
+        assert_eq!(Scale::Size(1280, 720).apply(3840, 2160), (1280, 720));
+        assert_eq!(Scale::Percent(50.0).apply(3840, 2160), (1920, 1080));
+        assert_eq!(Scale::Percent(33.0).apply(1920, 1080), (634, 356));
+        assert_eq!(Scale::Size(641, 1).apply(1920, 1080), (640, 2));
+    }
+
+    #[test]
+    fn test_tonemap() {
+        assert_eq!("sdr".parse(), Ok(ToneMap::Sdr));
+        assert!("hdr".parse::<ToneMap>().is_err());
+        assert!(!ToneMap::SDR_FILTER.contains(' '));
+    }
+
+    #[test]
+    fn test_timestamp_overlay() {
+        assert!(timestamp_overlay(false).starts_with("drawtext=text='%{pts\\:flt} s':"));
+        assert!(timestamp_overlay(true).contains("%{frame_num}"));
+    }
+
+    #[test]
+    fn test_output_format() {
+        use ffmpeg::format::Pixel;
+
+        let supported = [Pixel::YUV420P, Pixel::YUV444P];
+        assert_eq!(
+            output_format(Pixel::YUV444P, Some(supported.into_iter())),
+            Pixel::YUV444P
+        );
+        assert_eq!(
+            output_format(Pixel::RGB24, Some(supported.into_iter())),
+            Pixel::YUV420P
+        );
+        assert_eq!(
+            output_format(Pixel::RGB24, None::<std::vec::IntoIter<Pixel>>),
+            Pixel::RGB24
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+
+use crate::foxglove::{FrameTransform, Quaternion, Vector3};
+use crate::yaml;
+
+/// A fixed transform between two frames, e.g. from the robot to the camera
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticTransform {
+    pub parent: String,
+    pub child: String,
+    pub translation: [f64; 3],
+    /// Rotation as a unit quaternion (x, y, z, w)
+    pub rotation: [f64; 4],
+}
+
+/// Parses `N` comma-separated numbers
+fn numbers<const N: usize>(s: &str) -> Option<[f64; N]> {
+    let values: Vec<f64> = s
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    values.try_into().ok()
+}
+
+impl StaticTransform {
+    fn new(
+        parent: &str,
+        child: &str,
+        translation: [f64; 3],
+        rotation: [f64; 4],
+    ) -> Result<Self, String> {
+        if parent.is_empty() || child.is_empty() {
+            return Err("Transform frame IDs must not be empty".to_string());
+        }
+        let norm = rotation.iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm < 1e-6 {
+            return Err(format!(
+                "Transform rotation of {:?} is not a quaternion",
+                child
+            ));
+        }
+        Ok(Self {
+            parent: parent.to_string(),
+            child: child.to_string(),
+            translation,
+            rotation: rotation.map(|v| v / norm),
+        })
+    }
+
+    pub fn to_message(&self) -> FrameTransform {
+        let [x, y, z] = self.translation;
+        let [qx, qy, qz, qw] = self.rotation;
+        FrameTransform {
+            parent_frame_id: self.parent.clone(),
+            child_frame_id: self.child.clone(),
+            translation: Some(Vector3 { x, y, z }),
+            rotation: Some(Quaternion {
+                x: qx,
+                y: qy,
+                z: qz,
+                w: qw,
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+impl std::str::FromStr for StaticTransform {
+    type Err = String;
+
+    /// Parses `parent:child:x,y,z,qx,qy,qz,qw`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid transform {:?}, expected parent:child:x,y,z,qx,qy,qz,qw",
+                s
+            )
+        };
+        let mut parts = s.splitn(3, ':');
+        let (Some(parent), Some(child), Some(values)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let [x, y, z, qx, qy, qz, qw] = numbers(values).ok_or_else(invalid)?;
+        Self::new(parent, child, [x, y, z], [qx, qy, qz, qw])
+    }
+}
+
+/// Parses a YAML list of transforms, each with `parent`, `child`,
+/// `translation: [x, y, z]` and `rotation: [qx, qy, qz, qw]`, optionally
+/// under a top-level `transforms` key
+pub fn parse_yaml(text: &str) -> Result<Vec<StaticTransform>, String> {
+    // Fields of each list item by key
+    let mut items: BTreeMap<usize, BTreeMap<&str, &str>> = BTreeMap::new();
+    let values = yaml::flatten(text);
+    for (path, value) in &values {
+        let path = path.strip_prefix("transforms.").unwrap_or(path);
+        let Some((index, key)) = path.split_once('.') else {
+            continue;
+        };
+        if let Ok(index) = index.parse() {
+            items.entry(index).or_default().insert(key, value);
+        }
+    }
+
+    items
+        .values()
+        .enumerate()
+        .map(|(index, fields)| {
+            let field = |name: &str| {
+                fields
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| format!("Transform {} has no {}", index + 1, name))
+            };
+            let list = |name: &str| -> Result<String, String> {
+                let value = field(name)?;
+                value
+                    .strip_prefix('[')
+                    .and_then(|v| v.strip_suffix(']'))
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("{} of transform {} is not a list", name, index + 1))
+            };
+            let invalid = |name: &str| format!("Invalid {} of transform {}", name, index + 1);
+            let translation =
+                numbers(&list("translation")?).ok_or_else(|| invalid("translation"))?;
+            let rotation = numbers(&list("rotation")?).ok_or_else(|| invalid("rotation"))?;
+            StaticTransform::new(field("parent")?, field("child")?, translation, rotation)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_transform() {
+        let transform: StaticTransform = "base_link:camera:0.1,0,0.5,0,0,0,2".parse().unwrap();
+        assert_eq!(
+            transform,
+            StaticTransform {
+                parent: "base_link".to_string(),
+                child: "camera".to_string(),
+                translation: [0.1, 0.0, 0.5],
+                rotation: [0.0, 0.0, 0.0, 1.0],
+            }
+        );
+        assert!("base_link:camera:0,0,0".parse::<StaticTransform>().is_err());
+        assert!("base_link:camera:0,0,0,0,0,0,0"
+            .parse::<StaticTransform>()
+            .is_err());
+        assert!(":camera:0,0,0,0,0,0,1".parse::<StaticTransform>().is_err());
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let yaml = "transforms:
+  - parent: base_link
+    child: camera
+    translation: [0.1, 0, 0.5]
+    rotation: [0, 0, 0, 1]
+  # The gimbal mount
+  - parent: \"camera\"
+    child: gimbal
+    translation: [0, 0, -0.05]
+    rotation: [0, 1, 0, 1]
+";
+        let transforms = parse_yaml(yaml).unwrap();
+        assert_eq!(transforms.len(), 2);
+        assert_eq!(transforms[0].translation, [0.1, 0.0, 0.5]);
+        assert_eq!(transforms[1].parent, "camera");
+        assert!((transforms[1].rotation[1] - std::f64::consts::FRAC_1_SQRT_2).abs() < 1e-9);
+
+        assert_eq!(
+            parse_yaml("- parent: a\n  child: b\n  translation: [0, 0, 0]"),
+            Err("Transform 1 has no rotation".to_string())
+        );
+    }
+}
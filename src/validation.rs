@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::failure::Failure;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strictness {
+    /// Any anomaly aborts the conversion
+    Strict,
+    /// Recoverable anomalies are reported, anomalies that need repairs abort
+    Normal,
+    /// Anomalies are repaired on a best-effort basis and reported
+    Lenient,
+}
+
+impl Strictness {
+    pub fn from_flags(strict: bool, lenient: bool) -> Self {
+        if strict {
+            Strictness::Strict
+        } else if lenient {
+            Strictness::Lenient
+        } else {
+            Strictness::Normal
+        }
+    }
+}
+
+/// Collects deviations from a well-formed input so they can be reported once
+/// the conversion is done.
+pub struct Validator {
+    strictness: Strictness,
+    deviations: BTreeMap<&'static str, (usize, String)>,
+}
+
+impl Validator {
+    pub fn new(strictness: Strictness) -> Self {
+        Self {
+            strictness,
+            deviations: BTreeMap::new(),
+        }
+    }
+
+    /// Reports an anomaly the output is unaffected by, or one that has already
+    /// been corrected without guessing. Only `--strict` rejects it.
+    pub fn warn(&mut self, kind: &'static str, detail: String) -> Result<(), Box<dyn Error>> {
+        if self.strictness == Strictness::Strict {
+            return Err(Failure::new(
+                "invalid-input",
+                format!("{} (rejected by --strict)", detail),
+                "Convert without --strict to let it through",
+            )
+            .into());
+        }
+        self.record(kind, detail);
+        Ok(())
+    }
+
+    /// Reports an anomaly that can only be fixed by altering the input.
+    /// Returns an error unless running with `--lenient`, in which case the
+    /// caller is expected to apply its fix.
+    pub fn repair(&mut self, kind: &'static str, detail: String) -> Result<(), Box<dyn Error>> {
+        if self.strictness != Strictness::Lenient {
+            return Err(Failure::new(
+                "invalid-input",
+                format!("{} (use --lenient to repair)", detail),
+                "Convert with --lenient to repair it",
+            )
+            .into());
+        }
+        self.record(kind, detail);
+        Ok(())
+    }
+
+    fn record(&mut self, kind: &'static str, detail: String) {
+        debug!("{}: {}", kind, detail);
+        crate::logging::remember(format!("{}: {}", kind, detail));
+        self.deviations
+            .entry(kind)
+            .and_modify(|(count, _)| *count += 1)
+            .or_insert((1, detail));
+    }
+
+    /// How often a kind of deviation was seen
+    pub fn count(&self, kind: &str) -> usize {
+        self.deviations.get(kind).map_or(0, |(count, _)| *count)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.deviations.is_empty()
+    }
+
+    /// One line per kind of deviation with its count and first occurrence
+    pub fn summary(&self) -> Vec<String> {
+        self.deviations
+            .iter()
+            .map(|(kind, (count, first))| format!("{}: {} (first: {})", kind, count, first))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strictness_from_flags() {
+        assert_eq!(Strictness::from_flags(true, false), Strictness::Strict);
+        assert_eq!(Strictness::from_flags(false, true), Strictness::Lenient);
+        assert_eq!(Strictness::from_flags(false, false), Strictness::Normal);
+    }
+
+    #[test]
+    fn test_validator_levels() {
+        let mut strict = Validator::new(Strictness::Strict);
+        assert!(strict.warn("a", "x".into()).is_err());
+        assert!(strict.repair("a", "x".into()).is_err());
+
+        let mut normal = Validator::new(Strictness::Normal);
+        assert!(normal.warn("a", "x".into()).is_ok());
+        assert!(normal.repair("b", "y".into()).is_err());
+
+        let mut lenient = Validator::new(Strictness::Lenient);
+        assert!(lenient.warn("a", "x".into()).is_ok());
+        assert!(lenient.repair("b", "y".into()).is_ok());
+        assert!(!lenient.is_clean());
+    }
+
+    #[test]
+    fn test_validator_summary() {
+        let mut validator = Validator::new(Strictness::Lenient);
+        validator.repair("timestamp", "first".into()).unwrap();
+        validator.repair("timestamp", "second".into()).unwrap();
+        validator.warn("nal", "truncated".into()).unwrap();
+        assert_eq!(
+            validator.summary(),
+            vec!["nal: 1 (first: truncated)", "timestamp: 2 (first: first)"]
+        );
+    }
+}
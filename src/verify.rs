@@ -0,0 +1,168 @@
+use std::error::Error;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::codec::CodecType;
+use crate::scene;
+
+/// An access unit that was written but didn't decode cleanly
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeFailure {
+    /// Index of the frame among the written ones
+    pub frame: u64,
+    pub keyframe: bool,
+    pub reason: String,
+}
+
+/// Decodes the payloads exactly as they are written, with a decoder of its
+/// own that only sees what a player of the MCAP file would
+pub struct DecodeVerifier {
+    decoder: ffmpeg::decoder::Video,
+    decoded: ffmpeg::frame::Video,
+    /// Keyframe flags of the frames sent, by index
+    sent: Vec<bool>,
+    frames_out: u64,
+    failures: Vec<DecodeFailure>,
+}
+
+impl DecodeVerifier {
+    pub fn new(codec: CodecType) -> Result<Self, Box<dyn Error>> {
+        let decoder = ffmpeg::decoder::find(codec.ffmpeg_id()).ok_or_else(|| {
+            format!(
+                "No {} decoder to verify the output with",
+                codec.format_str()
+            )
+        })?;
+        Ok(Self {
+            decoder: ffmpeg::codec::context::Context::new_with_codec(decoder)
+                .decoder()
+                .video()?,
+            decoded: ffmpeg::frame::Video::empty(),
+            sent: Vec::new(),
+            frames_out: 0,
+            failures: Vec::new(),
+        })
+    }
+
+    /// Decodes the payload of a written frame
+    pub fn add(&mut self, data: &[u8], keyframe: bool) {
+        let frame = self.sent.len() as u64;
+        let mut packet = ffmpeg::Packet::copy(data);
+        // The index stands in for the timestamp, to tell the frames apart
+        packet.set_pts(Some(frame as i64));
+        packet.set_dts(Some(frame as i64));
+        if keyframe {
+            packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        }
+        self.sent.push(keyframe);
+        if let Err(e) = self.decoder.send_packet(&packet) {
+            self.fail(frame, format!("rejected by the decoder: {}", e));
+            return;
+        }
+        self.drain();
+    }
+
+    /// Flushes the decoder and returns the frames that failed, including
+    /// those that never came out of it
+    pub fn finish(mut self) -> (u64, Vec<DecodeFailure>) {
+        if self.decoder.send_eof().is_ok() {
+            self.drain();
+        }
+        let sent = self.sent.len() as u64;
+        let accounted = self.frames_out + self.failures.len() as u64;
+        for frame in accounted..sent {
+            self.fail(frame, "produced no picture".to_string());
+        }
+        (sent, self.failures)
+    }
+
+    fn drain(&mut self) {
+        loop {
+            match self.decoder.receive_frame(&mut self.decoded) {
+                Ok(_) if scene::is_corrupt(&self.decoded) => {
+                    let frame = self.decoded.pts().unwrap_or(0).max(0) as u64;
+                    self.fail(frame, "decoded with errors".to_string());
+                }
+                Ok(_) => self.frames_out += 1,
+                Err(ffmpeg::Error::Other {
+                    errno: ffmpeg::error::EAGAIN,
+                })
+                | Err(ffmpeg::Error::Eof) => return,
+                Err(e) => {
+                    let frame = self.sent.len().saturating_sub(1) as u64;
+                    self.fail(frame, format!("failed to decode: {}", e));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn fail(&mut self, frame: u64, reason: String) {
+        let keyframe = self.sent.get(frame as usize).copied().unwrap_or(false);
+        self.failures.push(DecodeFailure {
+            frame,
+            keyframe,
+            reason,
+        });
+    }
+}
+
+/// One line per failure, keyframes first as they break everything up to the
+/// next one
+pub fn summary(sent: u64, failures: &[DecodeFailure]) -> Vec<String> {
+    if failures.is_empty() {
+        return vec![format!("Verified that all {} written frames decode", sent)];
+    }
+    let mut sorted: Vec<&DecodeFailure> = failures.iter().collect();
+    sorted.sort_by_key(|failure| (!failure.keyframe, failure.frame));
+    let mut lines = vec![format!(
+        "{} of {} written frames did not decode cleanly:",
+        failures.len(),
+        sent
+    )];
+    for failure in sorted.iter().take(10) {
+        lines.push(format!(
+            "  frame {}{}: {}",
+            failure.frame,
+            if failure.keyframe { " (keyframe)" } else { "" },
+            failure.reason
+        ));
+    }
+    if sorted.len() > 10 {
+        lines.push(format!("  and {} more", sorted.len() - 10));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            summary(3, &[]),
+            ["Verified that all 3 written frames decode"]
+        );
+        let failures = [
+            DecodeFailure {
+                frame: 7,
+                keyframe: false,
+                reason: "decoded with errors".to_string(),
+            },
+            DecodeFailure {
+                frame: 30,
+                keyframe: true,
+                reason: "failed to decode: Invalid data".to_string(),
+            },
+        ];
+        assert_eq!(
+            summary(60, &failures),
+            [
+                "2 of 60 written frames did not decode cleanly:",
+                "  frame 30 (keyframe): failed to decode: Invalid data",
+                "  frame 7: decoded with errors",
+            ]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Flattens a YAML document into its scalar values keyed by their dotted
+/// path, e.g. `camera_matrix.data`, with the items of a block sequence
+/// numbered from 0, e.g. `transforms.1.child`. Covers the block maps and
+/// sequences and the flow sequences that ROS camera_info, OpenCV
+/// calibration and --transform-file files are made of.
+pub fn flatten(text: &str) -> BTreeMap<String, String> {
+    let mut values = BTreeMap::new();
+    // Keys of the enclosing maps and sequence items with their indentation
+    let mut parents: Vec<(usize, String)> = Vec::new();
+    // Items seen so far of each sequence, by its path
+    let mut items: HashMap<String, usize> = HashMap::new();
+    let path = |parents: &[(usize, String)], key: &str| {
+        parents
+            .iter()
+            .map(|(_, parent)| parent.as_str())
+            .chain([key])
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(".")
+    };
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        let mut content = line.trim();
+        if content.is_empty() || content.starts_with(['#', '%']) || content.starts_with("---") {
+            continue;
+        }
+        let mut indent = line.len() - line.trim_start().len();
+        while parents.last().is_some_and(|(parent, _)| *parent >= indent) {
+            parents.pop();
+        }
+        if let Some(item) = content.strip_prefix('-') {
+            let count = items.entry(path(&parents, "")).or_default();
+            parents.push((indent, count.to_string()));
+            *count += 1;
+            content = item.trim();
+            indent = line.len() - item.trim_start().len();
+            if content.is_empty() {
+                continue;
+            }
+            if !content.contains(':') {
+                let (_, index) = parents.pop().unwrap_or_default();
+                values.insert(
+                    path(&parents, &index),
+                    content.trim_matches(['"', '\'']).to_string(),
+                );
+                continue;
+            }
+        }
+        let Some((key, value)) = content.split_once(':') else {
+            continue;
+        };
+
+        let key = key.trim().trim_matches(['"', '\'']).to_string();
+        // Tags like OpenCV's `!!opencv-matrix` only name the type
+        let mut value = value.trim().to_string();
+        if value.starts_with('!') {
+            value = value
+                .split_once(' ')
+                .map_or("", |(_, v)| v)
+                .trim()
+                .to_string();
+        }
+        if value.is_empty() {
+            parents.push((indent, key));
+            continue;
+        }
+        // OpenCV wraps long sequences over several lines
+        if value.starts_with('[') {
+            while !value.contains(']') {
+                let Some(next) = lines.next() else {
+                    break;
+                };
+                value.push(' ');
+                value.push_str(next.trim());
+            }
+        }
+        values.insert(
+            path(&parents, &key),
+            value.trim_matches(['"', '\'']).to_string(),
+        );
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let yaml = "%YAML:1.0
+---
+camera_matrix: !!opencv-matrix
+   rows: 3
+   data: [ 1, 0,
+       2 ]
+transforms:
+  # The mount
+  - parent: base_link
+    child: \"camera\"
+  - parent: camera
+names:
+  - first
+  - 'second'
+";
+        let values = flatten(yaml);
+        let value = |path: &str| values.get(path).map(String::as_str);
+        assert_eq!(value("camera_matrix.rows"), Some("3"));
+        assert_eq!(value("camera_matrix.data"), Some("[ 1, 0, 2 ]"));
+        assert_eq!(value("transforms.0.parent"), Some("base_link"));
+        assert_eq!(value("transforms.0.child"), Some("camera"));
+        assert_eq!(value("transforms.1.parent"), Some("camera"));
+        assert_eq!(value("names.1"), Some("second"));
+        assert_eq!(values.len(), 7);
+
+        let values = flatten("- parent: a\n  child: b\n");
+        assert_eq!(values.get("0.child").map(String::as_str), Some("b"));
+    }
+}